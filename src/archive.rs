@@ -0,0 +1,1109 @@
+//! writing archives of save directories and saving them to backup directories.
+
+use crate::checksum::{HashingReader, HashingWriter};
+use crate::config::{BackupMode, BackupSetting, Compression, Config, PathFilter, SaveDir};
+use crate::encryption::EncryptingReader;
+use crate::files_txt::parse_files_txt;
+use crate::rotation::record_backup;
+use crate::sink::{BackupSink, DryRunSink, LocalSink, SftpSink};
+use crate::store::Store;
+use crate::tar::{
+    append_dir_all_sorted, append_reserved, new_builder, read_backup_ignore, ArchiveManifest,
+    Progress, ReadOptions, MANIFEST_NAME,
+};
+use crate::throttle::Throttled;
+use crate::{asyncify, encryption, hook, lock, notify, sink, store};
+use anyhow::{bail, Context as _, Error, Result};
+use chrono::NaiveDateTime;
+use flate2::read::GzEncoder;
+use futures::stream::{self, StreamExt};
+use log::{debug, error, info, trace, warn};
+use std::fs::{File as StdFile, OpenOptions as StdOpenOptions};
+use std::future::Future;
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// builds temporal tar of save directories of each group of settings sharing one tar.
+/// at most `max_parallel` tars are built at once.
+///
+/// each tar occupies one thread of the blocking pool for the whole traversal,
+/// so at most `max_parallel` blocking threads are used for reading save directories.
+/// async tasks like rcon commands and the health endpoint run on runtime workers
+/// and keep responding while tars are built.
+///
+/// errors of building each tar are returned in the list not to stop other settings.
+pub(crate) async fn build_tars(
+    sources: &[&[&BackupSetting]],
+    max_parallel: usize,
+    now: &NaiveDateTime,
+) -> Vec<Result<StdFile>> {
+    stream::iter(sources)
+        .map(|settings| build_tar(settings, now))
+        .buffered(max_parallel)
+        .collect()
+        .await
+}
+
+/// builds temporal tar of save directories shared by the settings
+pub(crate) async fn build_tar(settings: &[&BackupSetting], now: &NaiveDateTime) -> Result<StdFile> {
+    let setting = settings[0];
+    let save_dirs = setting.save_dirs.clone();
+    let filter = setting.filter.clone();
+    let options = setting.read_options();
+    let reserved = reserved_files(settings, now);
+    let name = settings
+        .iter()
+        .map(|x| x.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    // remote destinations have no local volume to be placed on
+    let temp_dir = setting.sftp.is_none().then(|| setting.directory.clone());
+    // the strictest limit of the settings sharing the tar
+    let rate_limit = settings.iter().filter_map(|x| x.io_rate_limit).min();
+    asyncify(move || {
+        let mut file = temp_tar_file(temp_dir.as_deref())?;
+        let write = BufWriter::new(Throttled::new(&mut file, rate_limit));
+        let mut progress = Progress::new(log_progress(name));
+        write_tar(
+            write,
+            &save_dirs,
+            &filter,
+            options,
+            &reserved,
+            &mut progress,
+        )?
+        .flush()?;
+        Ok(file)
+    })
+    .await
+    .context("saving to temporal tar file.")
+}
+
+/// creates temporal file for tar in `directory` to be on the same volume as backups
+/// so that copying to the backup doesn't cross devices.
+/// falls back to the system temporal directory if the file cannot be created there.
+fn temp_tar_file(directory: Option<&Path>) -> std::io::Result<StdFile> {
+    if let Some(directory) = directory {
+        match tempfile::tempfile_in(directory) {
+            Ok(file) => return Ok(file),
+            Err(e) => debug!(
+                "using system temporal directory since temporal tar cannot be created in {}: {}",
+                directory.display(),
+                e
+            ),
+        }
+    }
+    tempfile::tempfile()
+}
+
+/// files written in the reserved directory of the archive shared by the settings.
+/// the manifest comes first so that it can be read without reading whole archive.
+fn reserved_files(
+    settings: &[&BackupSetting],
+    now: &NaiveDateTime,
+) -> Vec<(&'static str, Vec<u8>)> {
+    let manifest = ArchiveManifest::new(settings, now);
+    let manifest = serde_json::to_vec_pretty(&manifest).expect("manifest is serializable");
+    let mut files = vec![(MANIFEST_NAME, manifest)];
+    // settings sharing the archive include the same config file or none
+    if let Some(config) = &settings[0].included_config {
+        files.push((config.name, config.content.to_vec()));
+    }
+    files
+}
+
+/// the interval progress of reading save directories is logged at
+const PROGRESS_LOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// the callback of [Progress] logging progress of reading save directories of `name`
+/// at most once per [PROGRESS_LOG_INTERVAL] so that long backups don't look hung.
+fn log_progress(name: String) -> impl FnMut(u64, u64) {
+    let start = std::time::Instant::now();
+    let mut logged = start;
+    move |files, bytes| {
+        if logged.elapsed() >= PROGRESS_LOG_INTERVAL {
+            logged = std::time::Instant::now();
+            info!(
+                "reading save directories of {}: {} files ({} bytes) in {:?}",
+                name,
+                files,
+                bytes,
+                start.elapsed()
+            );
+        }
+    }
+}
+
+/// writes tar of the save directories to `write` and returns `write`.
+/// `reserved` files are written in the reserved directory before the save directories.
+pub(crate) fn write_tar<W: Write>(
+    write: W,
+    save_dirs: &[SaveDir],
+    filter: &PathFilter,
+    options: ReadOptions,
+    reserved: &[(&str, Vec<u8>)],
+    progress: &mut Progress<impl FnMut(u64, u64)>,
+) -> std::io::Result<W> {
+    let mut tar = new_builder(write);
+    for (name, content) in reserved {
+        append_reserved(&mut tar, name, content)?;
+    }
+    for save_dir in save_dirs {
+        let ignore = read_backup_ignore(&save_dir.path)?;
+        append_dir_all_sorted(
+            &mut tar,
+            &save_dir.prefix,
+            &save_dir.path,
+            filter,
+            ignore,
+            options,
+            progress,
+        )?;
+    }
+    tar.into_inner()
+}
+
+/// returns true if the backup can be written to the backup directory while reading save directories
+/// instead of copying temporal tar.
+///
+/// encrypted archives and remote sinks need temporal tar,
+/// and diff backup modes need uncompressed tar of the backup.
+pub(crate) fn can_stream(config: &BackupSetting, dry_run: bool) -> bool {
+    let mode_can_stream = match config.backup_mode {
+        BackupMode::Simple => true,
+        BackupMode::ModifiesOnly | BackupMode::FileDiff => config.compression == Compression::None,
+        // the tar is split into objects
+        BackupMode::Dedup => false,
+    };
+    !dry_run && config.sftp.is_none() && config.encryption.is_none() && mode_can_stream
+}
+
+/// writes the archive of the setting to the backup directory while reading save directories.
+///
+/// the archive is written with `.tmp` suffix and renamed after written
+/// so no incomplete archive is left with the name of archive.
+pub(crate) async fn stream_archive(
+    config: &BackupSetting,
+    now: &NaiveDateTime,
+) -> Result<WrittenArchive> {
+    let sink = LocalSink::new(&config.directory);
+    sink.prepare().await.context("back up directory creation")?;
+    let backup_name = now.format(&config.name_format).to_string();
+    let file_name = archive_file_name(&backup_name, config);
+    let temp_name = sink::temp_file_name(&file_name);
+
+    let temp_path = sink.path(&temp_name);
+    let save_dirs = config.save_dirs.clone();
+    let filter = config.filter.clone();
+    let options = config.read_options();
+    let reserved = reserved_files(&[config], now);
+    let compression = config.compression;
+    let buffer_size = config.copy_buffer_size;
+    let rate_limit = config.io_rate_limit;
+    let name = config.name.clone();
+    let start = std::time::Instant::now();
+    let written = asyncify(move || {
+        let file = StdOpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(temp_path)?;
+        let mut tar_size = 0;
+        let writer = BufWriter::with_capacity(buffer_size, file);
+        let writer = compression.write(HashingWriter::new(writer), |write| {
+            let counting = CountingWriter::new(Throttled::new(write, rate_limit));
+            let mut progress = Progress::new(log_progress(name));
+            let counting = write_tar(
+                counting,
+                &save_dirs,
+                &filter,
+                options,
+                &reserved,
+                &mut progress,
+            )?;
+            tar_size = counting.count;
+            Ok(())
+        })?;
+        let (writer, hash) = writer.finish();
+        let file = writer.into_inner()?;
+        file.sync_all()?;
+        Ok((hash, file.metadata()?.len(), tar_size))
+    })
+    .await;
+    let result = match written {
+        Ok(written) => sink.rename(&temp_name, &file_name).await.map(|_| written),
+        Err(e) => Err(e),
+    };
+    let (hash, size, tar_size) = match result {
+        Ok(written) => written,
+        Err(e) => {
+            if let Err(e) = sink.delete(&temp_name).await {
+                error!("error removing {}: {}", temp_name, e);
+            }
+            return Err(Error::new(e).context("saving backup to file"));
+        }
+    };
+    let copy_duration = start.elapsed();
+    trace!("saved to {}", sink.path(&file_name).display());
+
+    // the archive is the tar itself if not compressed
+    let tar = match compression {
+        Compression::None => Some(StdFile::open(sink.path(&file_name)).context("reading backup")?),
+        Compression::Gzip => None,
+    };
+    Ok(WrittenArchive {
+        backup_name,
+        file_name,
+        hash,
+        size,
+        tar_size,
+        copy_duration,
+        tar,
+    })
+}
+
+/// writer which counts written bytes
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// saves the backup with `save` and reports the result.
+pub(crate) async fn save_backup(
+    app_config: &Config,
+    save: impl Future<Output = Result<SavedBackup>>,
+    now: &NaiveDateTime,
+    config: &BackupSetting,
+    dry_run: bool,
+) -> bool {
+    // settings sharing a directory would rewrite files.txt of each other at once
+    let lock = lock::lock_directory(config).await;
+    let start = std::time::Instant::now();
+    let result = save.await;
+    let duration = start.elapsed();
+    drop(lock);
+    if let Ok(saved) = &result {
+        info!(
+            setting = config.name.as_str(), event = "backup", bytes = saved.tar_size,
+            duration_ms = saved.copy_duration.as_millis() as u64;
+            "saved backup {} for {}: {} bytes of tar in {:?} ({:.2} MB/s)",
+            saved.name,
+            config.name,
+            saved.tar_size,
+            saved.copy_duration,
+            megabytes_per_second(saved.tar_size, saved.copy_duration)
+        );
+        if dry_run {
+            if !config.post_backup_command.is_empty() {
+                info!(
+                    "dry run: would run post backup command {:?}",
+                    config.post_backup_command
+                );
+            }
+        } else if !config.post_backup_command.is_empty() {
+            let run = hook::run_post_backup_command(
+                &config.post_backup_command,
+                &saved.path,
+                &saved.name,
+                &saved.directory,
+            );
+            if let Err(err) = run.await {
+                error!(
+                    setting = config.name.as_str(), event = "post_backup_command_failed", error:% = err;
+                    "error running post backup command for {}: {:?}",
+                    config.name, err
+                );
+            }
+        }
+    }
+    if let Err(err) = &result {
+        error!(
+            setting = config.name.as_str(), event = "backup_failed",
+            duration_ms = duration.as_millis() as u64, error:% = err;
+            "error during backing up for {} at {}: {:?}",
+            config.name, now, err
+        );
+    }
+    if dry_run {
+        info!("dry run: would notify the result of {}", config.name);
+        return result.is_ok();
+    }
+    let report = notify::BackupReport {
+        setting: &config.name,
+        time: now,
+        duration,
+        result: result.as_ref().map(|x| x.size),
+    };
+    notify::notify(app_config, &report).await;
+    result.is_ok()
+}
+
+/// the file the fingerprint of save directories at the last backup is saved in
+/// with the name of the backup like `{name} {fingerprint}`
+pub(crate) const FINGERPRINT_FILE: &str = "fingerprint.txt";
+
+/// saves the backup and the fingerprint of save directories at the backup if any.
+pub(crate) async fn save_with_fingerprint(
+    backup_tar: StdFile,
+    now: &NaiveDateTime,
+    config: &BackupSetting,
+    dry_run: bool,
+    fingerprint: Option<&String>,
+) -> Result<SavedBackup> {
+    match &config.sftp {
+        Some(sftp) => {
+            let sink = SftpSink::connect(sftp)
+                .await
+                .with_context(|| format!("connecting to sftp server {}", sftp.host))?;
+            if dry_run {
+                return save_to_sink(&DryRunSink::new(sink), backup_tar, now, config, true).await;
+            }
+            let saved = save_to_sink(&sink, backup_tar, now, config, false).await?;
+            if let Some(fingerprint) = fingerprint {
+                write_fingerprint(&sink, config, &saved.name, fingerprint).await;
+            }
+            Ok(saved)
+        }
+        None => {
+            let sink = LocalSink::new(&config.directory).with_buffer_size(config.copy_buffer_size);
+            if dry_run {
+                return save_to_sink(&DryRunSink::new(sink), backup_tar, now, config, true).await;
+            }
+            let saved = save_to_sink(&sink, backup_tar, now, config, false).await?;
+            if let Some(fingerprint) = fingerprint {
+                write_fingerprint(&sink, config, &saved.name, fingerprint).await;
+            }
+            Ok(saved)
+        }
+    }
+}
+
+/// saves the fingerprint of save directories at the backup `name` to compare at the next backup.
+/// errors are logged since the backup is saved anyway and the next backup is just not skipped.
+pub(crate) async fn write_fingerprint(
+    sink: &impl BackupSink,
+    config: &BackupSetting,
+    name: &str,
+    fingerprint: &str,
+) {
+    let line = format!("{} {}\n", name, fingerprint);
+    if let Err(err) = sink.write(FINGERPRINT_FILE, line.as_bytes()).await {
+        warn!("error writing fingerprint of {}: {}", config.name, err);
+    }
+}
+
+/// the fingerprint of the last backup of the setting.
+/// None if the fingerprint is not of the newest backup in files.txt
+pub(crate) async fn last_fingerprint(config: &BackupSetting) -> Result<Option<String>> {
+    match &config.sftp {
+        Some(sftp) => {
+            let sink = SftpSink::connect(sftp)
+                .await
+                .with_context(|| format!("connecting to sftp server {}", sftp.host))?;
+            read_fingerprint(&sink).await
+        }
+        None => read_fingerprint(&LocalSink::new(&config.directory)).await,
+    }
+}
+
+async fn read_fingerprint(sink: &impl BackupSink) -> Result<Option<String>> {
+    let files_txt = sink.read("files.txt").await.context("reading files.txt")?;
+    let files_txt = files_txt.unwrap_or_default();
+    let last = match parse_files_txt(&files_txt).last() {
+        Some(last) => String::from_utf8_lossy(last).into_owned(),
+        None => return Ok(None),
+    };
+    let fingerprint = sink
+        .read(FINGERPRINT_FILE)
+        .await
+        .with_context(|| format!("reading {}", FINGERPRINT_FILE))?
+        .unwrap_or_default();
+    // names may contain spaces but fingerprints don't
+    Ok(
+        match String::from_utf8_lossy(&fingerprint)
+            .trim_end()
+            .rsplit_once(' ')
+        {
+            Some((name, fingerprint)) if name == last => Some(fingerprint.to_owned()),
+            _ => None,
+        },
+    )
+}
+
+/// saves the backup without fingerprint
+#[cfg(test)]
+pub(crate) async fn do_save_backup(
+    backup_tar: StdFile,
+    now: &NaiveDateTime,
+    config: &BackupSetting,
+    dry_run: bool,
+) -> Result<SavedBackup> {
+    save_with_fingerprint(backup_tar, now, config, dry_run, None).await
+}
+
+pub(crate) async fn save_to_sink(
+    sink: &impl BackupSink,
+    backup_tar: StdFile,
+    now: &NaiveDateTime,
+    config: &BackupSetting,
+    dry_run: bool,
+) -> Result<SavedBackup> {
+    sink.prepare().await.context("back up directory creation")?;
+
+    //let time_for_save = config.interval.get_last_date_until(now);
+    let backup_name = now.format(&config.name_format).to_string();
+    let tar_file_name = archive_file_name(&backup_name, config);
+    let tar_path = sink.path(&tar_file_name);
+
+    let mut backup_tar = backup_tar;
+    let tar_size = backup_tar
+        .seek(SeekFrom::End(0))
+        .context("saving backup to file")?;
+
+    // check space before writing anything not to leave truncated archive.
+    // old backups are removed after the new backup is saved so they are not counted
+    let available = sink
+        .available_space()
+        .await
+        .context("checking available space")?;
+    if let Some(available) = available {
+        if available < tar_size {
+            bail!(
+                "not enough space in {}: {} bytes are required but {} bytes are available",
+                sink.directory().display(),
+                tar_size,
+                available
+            );
+        }
+    }
+
+    // first, copy backup tar to expected place and close
+
+    let copy_start = std::time::Instant::now();
+    let (backup_tar, hash, size) = {
+        backup_tar
+            .seek(SeekFrom::Start(0))
+            .context("saving backup to file")?;
+        let source = backup_tar.try_clone().context("saving backup to file")?;
+        let mut reader: Box<dyn Read + Send> = if config.backup_mode == BackupMode::Dedup {
+            // the manifest is saved as the archive of the backup
+            let store = match dry_run {
+                true => Store::dry_run(sink.directory()),
+                false => Store::new(sink.directory()),
+            };
+            let manifest = asyncify(move || store.save(BufReader::new(source)))
+                .await
+                .context("saving objects of backup")?;
+            Box::new(Cursor::new(manifest))
+        } else {
+            match config.compression {
+                Compression::None => Box::new(source),
+                Compression::Gzip => {
+                    Box::new(GzEncoder::new(source, flate2::Compression::default()))
+                }
+            }
+        };
+        if let Some(encryption) = &config.encryption {
+            reader = Box::new(
+                EncryptingReader::new(reader, encryption).context("initializing encryption")?,
+            );
+        }
+        let reader = Throttled::new(reader, config.io_rate_limit);
+        // hash is computed while writing not to read the file again
+        let (reader, size) = sink
+            .put(&tar_file_name, HashingReader::new(reader))
+            .await
+            .context("saving backup to file")?;
+        let (_, hash) = reader.finish();
+        (backup_tar, hash, size)
+    };
+    let copy_duration = copy_start.elapsed();
+    trace!("saved to {}", tar_path.display());
+
+    let archive = WrittenArchive {
+        backup_name,
+        file_name: tar_file_name,
+        hash,
+        size,
+        tar_size,
+        copy_duration,
+        tar: Some(backup_tar),
+    };
+    record_backup(sink, archive, now, config, dry_run).await
+}
+
+/// the name of archive file of the backup
+fn archive_file_name(backup_name: &str, config: &BackupSetting) -> String {
+    if config.backup_mode == BackupMode::Dedup {
+        return store::manifest_file_name(backup_name);
+    }
+    match config.encryption {
+        Some(_) => format!(
+            "{}.{}.{}",
+            backup_name,
+            config.compression.extension(),
+            encryption::EXTENSION
+        ),
+        None => format!("{}.{}", backup_name, config.compression.extension()),
+    }
+}
+
+/// the archive of backup written to the sink
+pub(crate) struct WrittenArchive {
+    pub(crate) backup_name: String,
+    pub(crate) file_name: String,
+    /// hex encoded SHA-256 of the archive
+    pub(crate) hash: String,
+    /// the size of saved archive
+    pub(crate) size: u64,
+    /// the size of tar before compression and encryption
+    pub(crate) tar_size: u64,
+    pub(crate) copy_duration: std::time::Duration,
+    /// the tar of the backup. None if it's not kept since it's not needed for diff
+    pub(crate) tar: Option<StdFile>,
+}
+
+/// the backup saved by [save_backup]
+pub(crate) struct SavedBackup {
+    pub(crate) name: String,
+    pub(crate) path: PathBuf,
+    /// the directory backup is saved in
+    pub(crate) directory: PathBuf,
+    /// the size of saved archive
+    pub(crate) size: u64,
+    /// the size of tar before compression and encryption
+    pub(crate) tar_size: u64,
+    /// the time taken to copy tar to the archive
+    pub(crate) copy_duration: std::time::Duration,
+}
+
+/// computes throughput in MB/s
+fn megabytes_per_second(bytes: u64, duration: std::time::Duration) -> f64 {
+    bytes as f64 / 1_000_000.0 / duration.as_secs_f64().max(f64::MIN_POSITIVE)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::files_txt;
+    use crate::rcon::Context;
+    use crate::scheduler::run_now;
+    use crate::{checksum, config, sftp_mock, verify};
+    use std::io::Read;
+
+    pub(crate) fn make_tar() -> StdFile {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut tar = ::tar::Builder::new(&mut file);
+        let data = b"hello world";
+        let mut header = ::tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "level.dat", &data[..])
+            .unwrap();
+        tar.finish().unwrap();
+        drop(tar);
+        file
+    }
+
+    #[tokio::test]
+    async fn reported_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = setting(dir.path(), Compression::Gzip);
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let mut tar = make_tar();
+        let tar_size = tar.seek(SeekFrom::End(0)).unwrap();
+        let saved = do_save_backup(tar, &now, &config, false).await.unwrap();
+
+        assert_eq!(saved.tar_size, tar_size);
+        assert_eq!(saved.size, std::fs::metadata(&saved.path).unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn io_rate_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            io_rate_limit: Some(256 * 1024),
+            ..setting(dir.path(), Compression::None)
+        };
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let mut tar = tempfile::tempfile().unwrap();
+        tar.write_all(&[1; 128 * 1024]).unwrap();
+
+        let start = std::time::Instant::now();
+        let saved = do_save_backup(tar, &now, &config, false).await.unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(500));
+        assert_eq!(saved.size, 128 * 1024);
+    }
+
+    #[tokio::test]
+    async fn copy_large_file() {
+        let data = (0..4 * 1024 * 1024u32)
+            .flat_map(|x| x.wrapping_mul(2654435761).to_le_bytes())
+            .collect::<Vec<_>>();
+        // buffer sizes smaller than, not dividing, and larger than the file
+        for copy_buffer_size in [4096, 1000 * 1000, 64 * 1024 * 1024] {
+            let dir = tempfile::tempdir().unwrap();
+            let config = BackupSetting {
+                copy_buffer_size,
+                ..setting(dir.path(), Compression::None)
+            };
+            let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+            let mut tar = tempfile::tempfile().unwrap();
+            tar.write_all(&data).unwrap();
+
+            let saved = do_save_backup(tar, &now, &config, false).await.unwrap();
+            assert_eq!(saved.tar_size, data.len() as u64);
+            assert_eq!(saved.size, data.len() as u64);
+            assert!(std::fs::read(&saved.path).unwrap() == data);
+            let (hash, _) = checksum::read_checksum(dir.path(), &saved.name)
+                .unwrap()
+                .unwrap();
+            assert_eq!(hash, checksum::hash_file(&saved.path).unwrap());
+        }
+    }
+
+    #[test]
+    fn megabytes_per_second_test() {
+        use std::time::Duration as StdDuration;
+        assert_eq!(
+            megabytes_per_second(3_000_000, StdDuration::from_secs(2)),
+            1.5
+        );
+        assert!(megabytes_per_second(1, StdDuration::ZERO).is_finite());
+    }
+
+    pub(crate) fn setting(directory: &Path, compression: Compression) -> BackupSetting {
+        BackupSetting {
+            compression,
+            ..BackupSetting::for_test("test", directory)
+        }
+    }
+
+    #[tokio::test]
+    async fn gzip() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = setting(dir.path(), Compression::Gzip);
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        do_save_backup(make_tar(), &now, &config, false)
+            .await
+            .unwrap();
+
+        let path = dir.path().join("backup-1970-01-01-00-00-00.tar.gz");
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(StdFile::open(&path).unwrap())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        let mut expected = Vec::new();
+        let mut tar = make_tar();
+        tar.seek(SeekFrom::Start(0)).unwrap();
+        tar.read_to_end(&mut expected).unwrap();
+        assert_eq!(decoded, expected);
+    }
+
+    #[tokio::test]
+    async fn checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            max_backups: 3,
+            backup_mode: BackupMode::FileDiff,
+            ..setting(dir.path(), Compression::Gzip)
+        };
+        let first = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let second = chrono::DateTime::from_timestamp(300, 0)
+            .unwrap()
+            .naive_utc();
+        do_save_backup(make_tar(), &first, &config, false)
+            .await
+            .unwrap();
+        do_save_backup(make_tar(), &second, &config, false)
+            .await
+            .unwrap();
+
+        for file_name in [
+            "backup-1970-01-01-00-00-00.diff.tar.gz",
+            "backup-1970-01-01-00-05-00.tar.gz",
+        ] {
+            let name = file_name.split('.').next().unwrap();
+            assert_eq!(
+                checksum::read_checksum(dir.path(), name).unwrap(),
+                Some((
+                    checksum::hash_file(&dir.path().join(file_name)).unwrap(),
+                    file_name.to_owned()
+                ))
+            );
+        }
+        assert_eq!(verify::verify_backups(dir.path()).unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn local_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            max_backups: 2,
+            ..setting(dir.path(), Compression::Gzip)
+        };
+        for seconds in [0, 300, 600] {
+            let now = chrono::DateTime::from_timestamp(seconds, 0)
+                .unwrap()
+                .naive_utc();
+            do_save_backup(make_tar(), &now, &config, false)
+                .await
+                .unwrap();
+        }
+
+        let mut files = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "backup-1970-01-01-00-05-00.sha256",
+                "backup-1970-01-01-00-05-00.tar.gz",
+                "backup-1970-01-01-00-10-00.sha256",
+                "backup-1970-01-01-00-10-00.tar.gz",
+                "files.txt",
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            "backup-1970-01-01-00-05-00\nbackup-1970-01-01-00-10-00\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("backup-1970-01-01-00-10-00.sha256")).unwrap(),
+            format!(
+                "{}  backup-1970-01-01-00-10-00.tar.gz\n",
+                checksum::hash_file(&dir.path().join("backup-1970-01-01-00-10-00.tar.gz")).unwrap()
+            )
+        );
+    }
+
+    pub(crate) fn sftp_setting(
+        local: &Path,
+        sftp: &sftp_mock::MockSftp,
+        host_key: &str,
+    ) -> BackupSetting {
+        BackupSetting {
+            sftp: Some(config::Sftp {
+                host: "127.0.0.1".to_owned(),
+                port: sftp.address.port(),
+                user: sftp_mock::USER.to_owned(),
+                auth: config::SftpAuth::Password(sftp_mock::PASSWORD.to_owned()),
+                host_key: Some(russh::keys::PublicKey::from_openssh(host_key).unwrap()),
+                remote_dir: "/backups".to_owned(),
+            }),
+            ..setting(&local.join("local"), Compression::Gzip)
+        }
+    }
+
+    #[tokio::test]
+    async fn sftp() {
+        let local = tempfile::tempdir().unwrap();
+        let remote = tempfile::tempdir().unwrap();
+        let server = sftp_mock::MockSftp::start(remote.path()).await;
+        let host_key = server.host_key.to_openssh().unwrap();
+        let config = sftp_setting(local.path(), &server, &host_key);
+        let first = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let second = chrono::DateTime::from_timestamp(300, 0)
+            .unwrap()
+            .naive_utc();
+        do_save_backup(make_tar(), &first, &config, false)
+            .await
+            .unwrap();
+        let saved = do_save_backup(make_tar(), &second, &config, false)
+            .await
+            .unwrap();
+
+        let remote_dir = remote.path().join("backups");
+        assert_eq!(
+            saved.path,
+            Path::new("/backups/backup-1970-01-01-00-05-00.tar.gz")
+        );
+        let mut files = std::fs::read_dir(&remote_dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "backup-1970-01-01-00-05-00.sha256",
+                "backup-1970-01-01-00-05-00.tar.gz",
+                "files.txt",
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(remote_dir.join("files.txt")).unwrap(),
+            "backup-1970-01-01-00-05-00\n"
+        );
+        assert_eq!(verify::verify_backups(&remote_dir).unwrap(), vec![]);
+        assert!(!local.path().join("local").exists());
+    }
+
+    #[tokio::test]
+    async fn sftp_host_key_mismatch() {
+        let local = tempfile::tempdir().unwrap();
+        let remote = tempfile::tempdir().unwrap();
+        let server = sftp_mock::MockSftp::start(remote.path()).await;
+        let other_key =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFpSZZTJ0YoXcsl701wrfUVhTG+bFuovcvkXzfb4Zo6/";
+        let config = sftp_setting(local.path(), &server, other_key);
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        assert!(do_save_backup(make_tar(), &now, &config, false)
+            .await
+            .is_err());
+        assert!(!remote.path().join("backups").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn post_backup_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            post_backup_command: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                format!(
+                    "echo \"$0 $1 $2\" > {:?}",
+                    out.path().join("args").to_string_lossy()
+                ),
+                "{file}".to_owned(),
+                "{name}".to_owned(),
+                "{dir}".to_owned(),
+            ],
+            commands_before: None,
+            commands_after: None,
+            ..setting(dir.path(), Compression::None)
+        };
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let save = do_save_backup(make_tar(), &now, &config, false);
+        assert!(save_backup(&Config::for_test(), save, &now, &config, false).await);
+        assert_eq!(
+            std::fs::read_to_string(out.path().join("args")).unwrap(),
+            format!(
+                "{} backup-1970-01-01-00-00-00 {}\n",
+                dir.path().join("backup-1970-01-01-00-00-00.tar").display(),
+                dir.path().display()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn build_tars_in_parallel() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(first.path().join("first.dat"), b"first").unwrap();
+        std::fs::write(second.path().join("second.dat"), b"second").unwrap();
+        let setting_of = |name: &str, path: &Path| BackupSetting {
+            name: name.to_owned(),
+            save_dirs: vec![SaveDir {
+                path: path.to_owned(),
+                prefix: PathBuf::new(),
+            }],
+            ..setting(&backups.path().join(name), Compression::None)
+        };
+        let first = setting_of("first", first.path());
+        let second = setting_of("second", second.path());
+
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let tars = build_tars(&[&[&first], &[&second]], 2, &now).await;
+        let names = tars
+            .into_iter()
+            .map(|tar| {
+                let mut tar = tar.unwrap();
+                tar.seek(SeekFrom::Start(0)).unwrap();
+                ::tar::Archive::new(tar)
+                    .entries()
+                    .unwrap()
+                    .map(|x| x.unwrap().path().unwrap().into_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let manifest = PathBuf::from(".game-save-backuper/manifest.json");
+        assert_eq!(
+            names,
+            vec![
+                vec![manifest.clone(), PathBuf::from("first.dat")],
+                vec![manifest, PathBuf::from("second.dat")]
+            ]
+        );
+    }
+
+    // the runtime has only one worker so the ticker would stop if the traversal blocked it
+    #[tokio::test(flavor = "current_thread")]
+    async fn responsive_while_building_tar() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        for i in 0..20 {
+            let dir = save.path().join(format!("region{}", i));
+            std::fs::create_dir(&dir).unwrap();
+            for j in 0..25 {
+                std::fs::write(dir.join(format!("chunk{}.dat", j)), [0; 64]).unwrap();
+            }
+        }
+        // about 500 KiB of tar at 1 MiB/s takes about half a second
+        let setting = BackupSetting {
+            save_dirs: vec![SaveDir {
+                path: save.path().to_owned(),
+                prefix: PathBuf::new(),
+            }],
+            io_rate_limit: Some(1024 * 1024),
+            ..setting(backups.path(), Compression::None)
+        };
+
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let mut ticks = 0;
+        let ticker = async {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                ticks += 1;
+            }
+        };
+        let sources: &[&[&BackupSetting]] = &[&[&setting]];
+        let tars = tokio::select! {
+            tars = build_tars(sources, 1, &now) => tars,
+            _ = ticker => unreachable!(),
+        };
+        tars.into_iter().next().unwrap().unwrap();
+        // the ticker could not run at all until the tar is built if the worker were blocked.
+        // how many ticks run depends on the load of the machine so only the progress is checked
+        assert!(ticks > 0, "{}", ticks);
+    }
+
+    #[test]
+    fn temp_tar_on_destination_volume() {
+        let backups = tempfile::tempdir().unwrap();
+        let file = temp_tar_file(Some(backups.path())).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let dev = std::fs::metadata(backups.path()).unwrap().dev();
+            assert_eq!(file.metadata().unwrap().dev(), dev);
+        }
+        drop(file);
+        // the unnamed temporal file leaves nothing
+        assert_eq!(std::fs::read_dir(backups.path()).unwrap().count(), 0);
+
+        // the system temporal directory is used if the destination doesn't exist yet
+        temp_tar_file(Some(&backups.path().join("missing"))).unwrap();
+        assert!(!backups.path().join("missing").exists());
+    }
+
+    #[tokio::test]
+    async fn stream_single_setting() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+
+        let config = Config {
+            backups: vec![BackupSetting {
+                save_dirs: vec![SaveDir {
+                    path: save.path().to_owned(),
+                    prefix: PathBuf::new(),
+                }],
+                ..setting(backups.path(), Compression::Gzip)
+            }],
+            ..Config::for_test()
+        };
+        assert!(can_stream(&config.backups[0], false));
+        let mut ctx = Context::new(&config);
+        run_now(&mut ctx).await.unwrap();
+
+        let name = files_txt::read_files_txt(backups.path()).unwrap().remove(0);
+        let mut files = std::fs::read_dir(backups.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                format!("{}.sha256", name),
+                format!("{}.tar.gz", name),
+                "files.txt".to_owned(),
+            ]
+        );
+        let archive = backups.path().join(format!("{}.tar.gz", name));
+        let (hash, _) = checksum::read_checksum(backups.path(), &name)
+            .unwrap()
+            .unwrap();
+        assert_eq!(hash, checksum::hash_file(&archive).unwrap());
+        let entries =
+            ::tar::Archive::new(Compression::Gzip.reader(StdFile::open(archive).unwrap()))
+                .entries()
+                .unwrap()
+                .map(|x| x.unwrap().path().unwrap().into_owned())
+                .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                PathBuf::from(".game-save-backuper/manifest.json"),
+                PathBuf::from("level.dat")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_failure_removes_temporal_file() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+
+        let config = Config {
+            backups: vec![BackupSetting {
+                save_dirs: vec![SaveDir {
+                    path: save.path().join("missing"),
+                    prefix: PathBuf::new(),
+                }],
+                ..setting(backups.path(), Compression::None)
+            }],
+            ..Config::for_test()
+        };
+        let mut ctx = Context::new(&config);
+        let err = run_now(&mut ctx).await.unwrap_err();
+        assert_eq!(err.to_string(), "1 backup setting(s) failed");
+        assert_eq!(std::fs::read_dir(backups.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn can_stream_test() {
+        let dir = Path::new("backups");
+        assert!(can_stream(&setting(dir, Compression::None), false));
+        assert!(!can_stream(&setting(dir, Compression::None), true));
+        let diff = BackupSetting {
+            backup_mode: BackupMode::FileDiff,
+            ..setting(dir, Compression::Gzip)
+        };
+        assert!(!can_stream(&diff, false));
+        let diff = BackupSetting {
+            backup_mode: BackupMode::FileDiff,
+            ..setting(dir, Compression::None)
+        };
+        assert!(can_stream(&diff, false));
+        let skip = BackupSetting {
+            skip_unchanged: true,
+            ..setting(dir, Compression::None)
+        };
+        assert!(can_stream(&skip, false));
+    }
+}
@@ -0,0 +1,146 @@
+//! the entry point of the library to embed the backup scheduler into other programs.
+
+use crate::config::{Config, DirOverrides};
+use crate::health::{self, Heartbeat};
+use crate::rcon::Context;
+use crate::reload::{self, SharedConfig};
+use crate::scheduler::{
+    catch_up, main_loop, recover_all_files_txt, recover_backup_files_txt, run_backups,
+};
+use anyhow::{bail, Context as _, Result};
+use chrono::{TimeZone, Utc};
+use log::{error, info};
+use notify::RecommendedWatcher;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+
+/// takes backups with the config.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> anyhow::Result<()> {
+/// use game_save_backuper::{Backuper, Config};
+///
+/// let dir = tempfile::tempdir()?;
+/// std::fs::create_dir(dir.path().join("world"))?;
+/// std::fs::write(dir.path().join("world/level.dat"), b"level")?;
+/// let config = Config::from_yaml(&format!(
+///     "preset: filesystem\n\
+///     save_dir: {:?}\n\
+///     backup_dir: {:?}\n\
+///     backups:\n\
+///     - name: hourly\n  interval: hourly\n  max_backups: 24\n",
+///     dir.path().join("world"),
+///     dir.path().join("backups"),
+/// ))?;
+///
+/// let backuper = Backuper::new(config);
+/// backuper.backup_now("hourly").await?;
+///
+/// let files_txt = std::fs::read_to_string(dir.path().join("backups/hourly/files.txt"))?;
+/// assert_eq!(files_txt.lines().count(), 1);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Backuper {
+    shared: Arc<SharedConfig>,
+    dry_run: bool,
+}
+
+impl Backuper {
+    pub fn new(config: Config) -> Self {
+        Self::from_boxed(Box::new(config))
+    }
+
+    pub(crate) fn from_boxed(config: Box<Config>) -> Self {
+        Self {
+            shared: Arc::new(SharedConfig::new(Arc::from(config))),
+            dry_run: false,
+        }
+    }
+
+    /// if true, what would be done is logged without writing backups or sending rcon commands
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// the config backups are taken with
+    pub fn config(&self) -> Arc<Config> {
+        self.shared.get()
+    }
+
+    /// reloads the config backups are taken with when the config file at `path` is changed
+    pub(crate) fn watch_config(
+        &self,
+        path: &Path,
+        overrides: DirOverrides,
+    ) -> Result<RecommendedWatcher> {
+        reload::watch_config(path, self.shared.clone(), overrides)
+    }
+
+    /// backs up with the setting now regardless of its interval
+    pub async fn backup_now(&self, setting: &str) -> Result<()> {
+        let config = self.shared.get();
+        let backup = match config.backups.iter().find(|x| x.name == setting) {
+            Some(backup) => backup,
+            None => bail!("no backup setting named {}", setting),
+        };
+        if !self.dry_run {
+            recover_backup_files_txt(backup).await?;
+        }
+        let now = config
+            .timezone
+            .from_utc_datetime(&Utc::now().naive_utc())
+            .naive_local();
+        info!("backing up {} at {}", backup.name, now);
+        let mut ctx = Context::new(&config);
+        ctx.dry_run = self.dry_run;
+        let results = run_backups(&mut ctx, vec![backup], &now).await?;
+        if results.iter().any(|(_, succeeded)| !succeeded) {
+            bail!("backup of {} failed", backup.name)
+        }
+        Ok(())
+    }
+
+    /// takes backups at their intervals forever
+    pub async fn run(&self) -> Result<()> {
+        self.run_until(futures::future::pending()).await
+    }
+
+    /// takes backups at their intervals until `shutdown` completes.
+    /// if `shutdown` completes while backing up, the backup is finished before return.
+    pub async fn run_until(&self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let config = self.shared.get();
+        if !self.dry_run {
+            recover_all_files_txt(&config).await?;
+        }
+        let mut ctx = Context::new(&config);
+        ctx.dry_run = self.dry_run;
+        if config.catch_up {
+            let now = config
+                .timezone
+                .from_utc_datetime(&Utc::now().naive_utc())
+                .naive_local();
+            if let Err(err) = catch_up(&mut ctx, &now).await {
+                error!("error catching up missed backups: {:#}", err);
+            }
+        }
+        let connections = std::mem::take(&mut ctx.connections);
+        let heartbeat = Arc::new(Heartbeat::new());
+        if let Some(address) = config.health_address {
+            health::start(address, heartbeat.clone())
+                .with_context(|| format!("starting health endpoint at {}", address))?;
+        }
+        main_loop(
+            &self.shared,
+            connections,
+            self.dry_run,
+            &heartbeat,
+            shutdown,
+        )
+        .await;
+        Ok(())
+    }
+}
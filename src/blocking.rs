@@ -0,0 +1,14 @@
+//! running blocking io off the async runtime.
+
+use tokio::task::spawn_blocking;
+
+pub(crate) async fn asyncify<F, T>(f: F) -> std::io::Result<T>
+where
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match spawn_blocking(f).await {
+        Ok(res) => res,
+        Err(_) => Err(std::io::Error::other("background task failed")),
+    }
+}
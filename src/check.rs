@@ -0,0 +1,237 @@
+use crate::config::{BackupSetting, Config, GamePreset};
+use crate::rcon::Context;
+use crate::sink::SftpSink;
+use anyhow::{bail, Context as _, Result};
+use log::{error, info};
+use std::path::Path;
+
+/// checks things that cannot be verified by parsing config.
+/// returns the problems found.
+pub(crate) async fn check_config(config: &Config, check_rcon: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+    for setting in &config.backups {
+        for save_dir in &setting.save_dirs {
+            if !save_dir.path.is_dir() && !save_dir.path.is_file() {
+                problems.push(format!(
+                    "save_dir {} of {} is not a directory or a file",
+                    save_dir.path.display(),
+                    setting.name
+                ));
+            }
+        }
+        if let Err(e) = check_backup_dir(setting).await {
+            problems.push(format!("backup directory of {}: {:#}", setting.name, e));
+        }
+    }
+    if check_rcon {
+        if let Err(e) = connect_rcon(config).await {
+            problems.push(format!("rcon: {:#}", e));
+        }
+    }
+    problems
+}
+
+/// checks the backup directory is writable, or can be created in its nearest existing ancestor.
+/// nothing is created to check.
+async fn check_backup_dir(setting: &BackupSetting) -> Result<()> {
+    if let Some(sftp) = &setting.sftp {
+        let sink = SftpSink::connect(sftp)
+            .await
+            .with_context(|| format!("connecting to sftp server {}", sftp.host))?;
+        return sink
+            .check_directory()
+            .await
+            .with_context(|| format!("{} on {} is not writable", sftp.remote_dir, sftp.host));
+    }
+    let check = |directory: &Path| -> Result<()> {
+        let directory = std::path::absolute(directory)?;
+        let (existing, metadata) = directory
+            .ancestors()
+            .find_map(|x| Some((x, std::fs::metadata(x).ok()?)))
+            .context("no ancestor exists")?;
+        if !metadata.is_dir() {
+            bail!("{} is not a directory", existing.display());
+        }
+        if !is_writable(existing, &metadata) {
+            bail!("{} is read only", existing.display());
+        }
+        Ok(())
+    };
+    check(&setting.directory)
+        .with_context(|| format!("{} is not writable", setting.directory.display()))
+}
+
+/// whether files can be created in the directory
+#[cfg(unix)]
+fn is_writable(directory: &Path, _: &std::fs::Metadata) -> bool {
+    use nix::unistd::{access, AccessFlags};
+    access(directory, AccessFlags::W_OK | AccessFlags::X_OK).is_ok()
+}
+
+/// whether files can be created in the directory
+#[cfg(not(unix))]
+fn is_writable(_: &Path, metadata: &std::fs::Metadata) -> bool {
+    !metadata.permissions().readonly()
+}
+
+async fn connect_rcon(config: &Config) -> Result<()> {
+    if matches!(config.preset, Some(GamePreset::Filesystem)) || config.rcon_servers.is_empty() {
+        info!("rcon is not used");
+        return Ok(());
+    }
+    let mut ctx = Context::new(config);
+    for (index, server) in config.rcon_servers.iter().enumerate() {
+        ctx.client(index)
+            .reconnect_rcon()
+            .await
+            .with_context(|| format!("connecting to {}", server.name))?;
+    }
+    Ok(())
+}
+
+/// checks config and prints summary of it.
+pub(crate) async fn run(config: &Config, check_rcon: bool) -> Result<()> {
+    for setting in &config.backups {
+        println!("{} ({}):", setting.name, setting.directory.display());
+        println!("  interval: {}", setting.interval);
+        for save_dir in &setting.save_dirs {
+            println!(
+                "  save_dir: {} as {}",
+                save_dir.path.display(),
+                save_dir.prefix.display()
+            );
+        }
+    }
+    let problems = check_config(config, check_rcon).await;
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    if !problems.is_empty() {
+        bail!("{} problem(s) found in config", problems.len())
+    }
+    println!("config is ok");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::parse_config;
+    use std::fs;
+
+    fn parse(save_dir: &Path, backup_dir: &Path, extra: &str) -> Box<Config> {
+        parse_config(
+            format!(
+                "save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                {}\
+                backups:\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 1\n",
+                save_dir, backup_dir, extra,
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn valid() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        let config = parse(save.path(), &backups.path().join("backups"), "");
+        assert_eq!(check_config(&config, true).await, Vec::<String>::new());
+        // checking doesn't create the backup directory
+        assert!(!backups.path().join("backups").exists());
+    }
+
+    #[tokio::test]
+    async fn backup_dir_is_file() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        fs::write(backups.path().join("backups"), b"").unwrap();
+        let config = parse(save.path(), &backups.path().join("backups"), "");
+        let problems = check_config(&config, false).await;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("not writable"), "{:?}", problems);
+    }
+
+    #[tokio::test]
+    async fn sftp_backup_dir() {
+        let save = tempfile::tempdir().unwrap();
+        let remote = tempfile::tempdir().unwrap();
+        let server = crate::sftp_mock::MockSftp::start(remote.path()).await;
+        let config_of = |remote_dir: &str| Config {
+            backups: vec![BackupSetting {
+                save_dirs: vec![crate::config::SaveDir {
+                    path: save.path().to_owned(),
+                    prefix: Default::default(),
+                }],
+                sftp: Some(crate::config::Sftp {
+                    host: "127.0.0.1".to_owned(),
+                    port: server.address.port(),
+                    user: crate::sftp_mock::USER.to_owned(),
+                    auth: crate::config::SftpAuth::Password(crate::sftp_mock::PASSWORD.to_owned()),
+                    host_key: Some(server.host_key.clone()),
+                    remote_dir: remote_dir.to_owned(),
+                }),
+                ..BackupSetting::for_test("remote", Path::new("local"))
+            }],
+            ..Config::for_test()
+        };
+        // created on the first backup
+        let config = config_of("/backups");
+        assert_eq!(check_config(&config, false).await, Vec::<String>::new());
+        assert!(!remote.path().join("backups").exists());
+
+        let config = config_of("/not/exists");
+        let problems = check_config(&config, false).await;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("/not/exists"), "{:?}", problems);
+
+        fs::write(remote.path().join("file"), b"").unwrap();
+        let config = config_of("/file");
+        let problems = check_config(&config, false).await;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("not a directory"), "{:?}", problems);
+    }
+
+    #[tokio::test]
+    async fn save_dir_removed() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        fs::create_dir(save.path().join("world")).unwrap();
+        let config = parse(&save.path().join("world"), backups.path(), "");
+        fs::remove_dir(save.path().join("world")).unwrap();
+        let problems = check_config(&config, false).await;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("not a directory"), "{:?}", problems);
+    }
+
+    #[tokio::test]
+    async fn rcon() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        let server = crate::rcon_mock::MockRcon::start(0).await;
+        let extra = format!(
+            "rcon_address: {:?}\nrcon_password: password\n",
+            server.address.to_string()
+        );
+        let config = parse(save.path(), backups.path(), &extra);
+        assert_eq!(check_config(&config, true).await, Vec::<String>::new());
+
+        // nothing listens on the port after the listener is dropped
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let extra = format!(
+            "rcon_address: {:?}\nrcon_password: password\n",
+            address.to_string()
+        );
+        let config = parse(save.path(), backups.path(), &extra);
+        assert_eq!(check_config(&config, false).await, Vec::<String>::new());
+        let problems = check_config(&config, true).await;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("rcon"), "{:?}", problems);
+    }
+}
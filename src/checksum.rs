@@ -0,0 +1,156 @@
+//! SHA-256 checksum files of backups.
+//!
+//! `{name}.sha256` holds the checksum of the archive of backup `{name}` in the format of
+//! `sha256sum` so it can be verified with `sha256sum -c`.
+
+use sha2::{Digest, Sha256};
+use std::fs::File as StdFile;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// writer which computes SHA-256 of written bytes
+pub(crate) struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// returns the inner writer and hex encoded hash
+    pub(crate) fn finish(self) -> (W, String) {
+        (self.inner, format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// reader which computes SHA-256 of read bytes
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// returns the inner reader and hex encoded hash
+    pub(crate) fn finish(self) -> (R, String) {
+        (self.inner, format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// the line of checksum file of archive `file_name`
+pub(crate) fn checksum_line(file_name: &str, hash: &str) -> String {
+    format!("{}  {}\n", hash, file_name)
+}
+
+pub(crate) fn checksum_path(directory: &Path, name: &str) -> PathBuf {
+    directory.join(format!("{}.sha256", name))
+}
+
+/// writes checksum file of backup `name` whose archive is `file_name`.
+pub(crate) fn write_checksum(
+    directory: &Path,
+    name: &str,
+    file_name: &str,
+    hash: &str,
+) -> io::Result<()> {
+    let mut file = StdFile::create(checksum_path(directory, name))?;
+    file.write_all(checksum_line(file_name, hash).as_bytes())?;
+    file.sync_all()
+}
+
+/// reads checksum file of backup `name`. returns the hash and the name of archive file.
+pub(crate) fn read_checksum(directory: &Path, name: &str) -> io::Result<Option<(String, String)>> {
+    let file = match StdFile::open(checksum_path(directory, name)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut line = String::new();
+    BufReader::new(file).read_line(&mut line)?;
+    match line.trim_end().split_once("  ") {
+        Some((hash, file_name)) => Ok(Some((hash.to_owned(), file_name.to_owned()))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid checksum file of {}", name),
+        )),
+    }
+}
+
+/// computes hex encoded SHA-256 of the file
+pub(crate) fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = StdFile::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hashing_writer() {
+        let mut writer = HashingWriter::new(Vec::new());
+        writer.write_all(b"hello world").unwrap();
+        let (data, hash) = writer.finish();
+        assert_eq!(data, b"hello world");
+        assert_eq!(
+            hash,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("backup.tar"), b"hello world").unwrap();
+        let hash = hash_file(&dir.path().join("backup.tar")).unwrap();
+        write_checksum(dir.path(), "backup", "backup.tar", &hash).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("backup.sha256")).unwrap(),
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9  backup.tar\n"
+        );
+        assert_eq!(
+            read_checksum(dir.path(), "backup").unwrap(),
+            Some((hash, "backup.tar".to_owned()))
+        );
+        assert_eq!(read_checksum(dir.path(), "other").unwrap(), None);
+    }
+}
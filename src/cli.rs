@@ -0,0 +1,216 @@
+//! the command line interface of game-save-backuper.
+
+use crate::config::interval::list_intervals;
+use crate::config::{load_config, DirOverrides};
+use crate::rcon::Context;
+use crate::scheduler::{recover_all_files_txt, run_now};
+use crate::{check, list, logging, prune, restore, schedule, verify, Backuper};
+use anyhow::{Context as _, Result};
+use log::{error, trace};
+use std::path::{Path, PathBuf};
+use tokio::io;
+
+#[derive(clap::Parser)]
+#[command(version, about)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// logs what would be done without writing backups or sending rcon commands
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// the path to the config file. defaults to config.yml in the current directory
+    #[arg(long, global = true, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// the directory to be backed up. overrides SAVE_DIR and save_dir in the config file
+    #[arg(long, global = true)]
+    save_dir: Option<PathBuf>,
+    /// the directory backups are saved to. overrides BACKUP_DIR and backup_dir in the config file
+    #[arg(long, global = true)]
+    backup_dir: Option<PathBuf>,
+    /// logs more. -v for debug and -vv for trace logs. overrides the default level of RUST_LOG
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// logs less. -q for warnings and errors and -qq for errors only. overrides the default level of RUST_LOG
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    quiet: u8,
+    /// the format of logs
+    #[arg(long, global = true, env = "LOG_FORMAT", value_enum, default_value_t)]
+    log_format: logging::LogFormat,
+    /// prints all supported intervals and their spellings, then exits
+    #[arg(long)]
+    list_intervals: bool,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// backs up with all backup settings now regardless of interval, then exits
+    RunNow,
+    /// lists backups of all backup settings
+    List {
+        /// print in json
+        #[arg(long)]
+        json: bool,
+    },
+    /// restores a backup to the directory
+    Restore {
+        /// the name of backup. e.g. backup-2022-01-01-00-00-00
+        backup_name: String,
+        /// the directory the backup will be extracted to
+        dest: PathBuf,
+        /// the name of backup setting the backup belongs to
+        #[arg(long)]
+        setting: Option<String>,
+        /// restore even if the destination directory is not empty
+        /// or the backup is taken for another setting
+        #[arg(long)]
+        force: bool,
+        /// the age identity file to decrypt encrypted backups
+        #[arg(long)]
+        identity: Option<PathBuf>,
+        /// restores owners of files by numeric uid and gid. usually requires root
+        #[arg(long)]
+        same_owner: bool,
+        /// verifies checksums of the backup and extracted files after restoring.
+        /// fails if the backup or the chain of diffs is broken
+        #[arg(long)]
+        verify: bool,
+    },
+    /// removes old backups with the retention of all backup settings without taking backups
+    Prune,
+    /// verifies checksums and archives of all backups
+    Verify,
+    /// checks the config file and directories without starting backups
+    Check {
+        /// also checks connecting to rcon server
+        #[arg(long)]
+        check_rcon: bool,
+    },
+    /// prints when backups will be taken without taking them
+    PrintSchedule {
+        /// the hours to simulate from now
+        #[arg(long, default_value_t = 24)]
+        hours: u32,
+    },
+}
+
+/// runs the command with the command line arguments
+pub async fn run() -> Result<()> {
+    let args = <Args as clap::Parser>::parse();
+
+    logging::init(
+        args.log_format,
+        logging::level_from_flags(args.verbose, args.quiet),
+    );
+
+    if args.list_intervals {
+        print!("{}", list_intervals());
+        return Ok(());
+    }
+
+    let config_path = args.config.as_deref();
+    let overrides = DirOverrides {
+        save_dir: args.save_dir,
+        backup_dir: args.backup_dir,
+    };
+    let config = load_config(config_path, &overrides)
+        .await
+        .with_context(|| {
+            format!(
+                "loading config file ({})",
+                config_path
+                    .unwrap_or_else(|| Path::new("config.yml"))
+                    .display()
+            )
+        })?;
+
+    trace!("load config: {:?}", config);
+
+    match args.command {
+        None => {
+            let backuper = Backuper::from_boxed(config).dry_run(args.dry_run);
+            let watch_path = config_path.unwrap_or_else(|| Path::new("config.yml"));
+            let _watcher = if watch_path.exists() {
+                backuper
+                    .watch_config(watch_path, overrides)
+                    .map_err(|e| {
+                        error!(
+                            "error watching config file. config won't be reloaded: {:#}",
+                            e
+                        )
+                    })
+                    .ok()
+            } else {
+                None
+            };
+            backuper.run_until(shutdown_signal()).await
+        }
+        Some(Command::List { json }) => list::run(&config, json),
+        Some(Command::Prune) => {
+            if !args.dry_run {
+                recover_all_files_txt(&config).await?;
+            }
+            prune::run(&config, args.dry_run).await
+        }
+        Some(Command::Verify) => verify::run(&config),
+        Some(Command::Check { check_rcon }) => check::run(&config, check_rcon).await,
+        Some(Command::PrintSchedule { hours }) => {
+            schedule::run(&config, hours);
+            Ok(())
+        }
+        Some(Command::RunNow) => {
+            if !args.dry_run {
+                recover_all_files_txt(&config).await?;
+            }
+            let mut ctx = Context::new(&config);
+            ctx.dry_run = args.dry_run;
+            run_now(&mut ctx).await
+        }
+        Some(Command::Restore {
+            backup_name,
+            dest,
+            setting,
+            force,
+            identity,
+            same_owner,
+            verify,
+        }) => {
+            let options = restore::RestoreOptions {
+                force,
+                same_owner,
+                verify,
+            };
+            restore::run(
+                &config,
+                setting.as_deref(),
+                &backup_name,
+                &dest,
+                identity.as_deref(),
+                options,
+            )
+            .await
+        }
+    }
+}
+
+/// waits for SIGTERM or SIGINT
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let result: io::Result<()> = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+        Ok(())
+    }
+    .await;
+    #[cfg(not(unix))]
+    let result = tokio::signal::ctrl_c().await;
+
+    if let Err(err) = result {
+        error!("error waiting for signal: {}", err);
+        futures::future::pending::<()>().await
+    }
+}
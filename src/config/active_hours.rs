@@ -0,0 +1,123 @@
+use anyhow::{bail, Context as _, Error, Result};
+use chrono::NaiveTime;
+use serde::{Deserialize, Deserializer};
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+/// the window of local time backups are allowed in like `02:00-06:00`.
+///
+/// the start is inclusive and the end is exclusive.
+/// the window wraps past midnight if the end is before the start like `22:00-04:00`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ActiveHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl ActiveHours {
+    /// returns true if the time is in the window
+    pub(crate) fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for ActiveHours {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        fn parse_time(s: &str) -> Result<NaiveTime> {
+            NaiveTime::parse_from_str(s.trim(), "%H:%M")
+                .with_context(|| format!("invalid time {:?}. expected like 02:00", s.trim()))
+        }
+        let (start, end) = match s.split_once('-') {
+            Some(pair) => pair,
+            None => bail!("expected start and end like 02:00-06:00 but was {:?}", s),
+        };
+        let start = parse_time(start)?;
+        let end = parse_time(end)?;
+        if start == end {
+            bail!("start and end of active hours must differ")
+        }
+        Ok(ActiveHours { start, end })
+    }
+}
+
+impl<'de> Deserialize<'de> for ActiveHours {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct VisitorImpl;
+
+        impl<'de> serde::de::Visitor<'de> for VisitorImpl {
+            type Value = ActiveHours;
+
+            fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+                write!(formatter, "expecting active hours like 02:00-06:00")
+            }
+
+            fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                ActiveHours::from_str(v).map_err(|e| E::custom(format!("{:#}", e)))
+            }
+        }
+
+        deserializer.deserialize_str(VisitorImpl)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn in_window() {
+        let hours = "02:00-06:00".parse::<ActiveHours>().unwrap();
+        assert!(hours.contains(at(2, 0)));
+        assert!(hours.contains(at(4, 30)));
+        assert!(hours.contains(at(5, 59)));
+    }
+
+    #[test]
+    fn out_of_window() {
+        let hours = "02:00-06:00".parse::<ActiveHours>().unwrap();
+        assert!(!hours.contains(at(1, 59)));
+        assert!(!hours.contains(at(6, 0)));
+        assert!(!hours.contains(at(12, 0)));
+        assert!(!hours.contains(at(0, 0)));
+    }
+
+    #[test]
+    fn wrapping_window() {
+        let hours = "22:00-04:00".parse::<ActiveHours>().unwrap();
+        assert!(hours.contains(at(22, 0)));
+        assert!(hours.contains(at(23, 59)));
+        assert!(hours.contains(at(0, 0)));
+        assert!(hours.contains(at(3, 59)));
+        assert!(!hours.contains(at(4, 0)));
+        assert!(!hours.contains(at(12, 0)));
+        assert!(!hours.contains(at(21, 59)));
+    }
+
+    #[test]
+    fn parse_error() {
+        assert!("02:00".parse::<ActiveHours>().is_err());
+        assert!("2am-6am".parse::<ActiveHours>().is_err());
+        assert!("25:00-06:00".parse::<ActiveHours>().is_err());
+        assert!("02:00-02:00".parse::<ActiveHours>().is_err());
+        assert_eq!(
+            " 02:00 - 06:00 ".parse::<ActiveHours>().unwrap(),
+            "02:00-06:00".parse::<ActiveHours>().unwrap()
+        );
+    }
+}
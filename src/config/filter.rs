@@ -0,0 +1,81 @@
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Path, PathBuf};
+
+/// include/exclude filter of paths in backup archive.
+///
+/// patterns are matched against the path in the archive, including prefix of the save
+/// directory. `*` doesn't match `/` so use `**` to match any directories.
+#[derive(Debug, Clone)]
+pub(crate) struct PathFilter {
+    include_patterns: Vec<String>,
+    exclude_patterns: Vec<String>,
+    include: GlobSet,
+    exclude: GlobSet,
+    /// paths excluded regardless of patterns like backup_dir in the save directory
+    excluded_paths: Vec<PathBuf>,
+}
+
+impl PathFilter {
+    pub(crate) fn new(include: Vec<String>, exclude: Vec<String>) -> Result<Self, globset::Error> {
+        fn compile(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(glob(pattern)?);
+            }
+            builder.build()
+        }
+
+        fn glob(pattern: &str) -> Result<Glob, globset::Error> {
+            GlobBuilder::new(pattern).literal_separator(true).build()
+        }
+
+        Ok(Self {
+            include: compile(&include)?,
+            exclude: compile(&exclude)?,
+            include_patterns: include,
+            exclude_patterns: exclude,
+            excluded_paths: vec![],
+        })
+    }
+
+    /// excludes the path in backup archive with its descendants.
+    pub(crate) fn exclude_path(&mut self, path: PathBuf) {
+        self.excluded_paths.push(path);
+    }
+
+    /// returns true if the path is excluded.
+    /// excluded directories should not be traversed.
+    pub(crate) fn is_excluded(&self, path: &Path) -> bool {
+        self.exclude.is_match(path) || self.excluded_paths.iter().any(|x| x == path)
+    }
+
+    /// returns true if the path or any of its ancestors is excluded
+    /// so that the path is never traversed.
+    pub(crate) fn is_pruned(&self, path: &Path) -> bool {
+        path.ancestors()
+            .filter(|x| *x != Path::new(""))
+            .any(|x| self.is_excluded(x))
+    }
+
+    /// returns true if the path is explicitly included.
+    /// if no include patterns are specified, all paths are included.
+    pub(crate) fn is_included(&self, path: &Path) -> bool {
+        self.include_patterns.is_empty() || self.include.is_match(path)
+    }
+}
+
+impl Default for PathFilter {
+    fn default() -> Self {
+        Self::new(vec![], vec![]).unwrap()
+    }
+}
+
+impl PartialEq for PathFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.include_patterns == other.include_patterns
+            && self.exclude_patterns == other.exclude_patterns
+            && self.excluded_paths == other.excluded_paths
+    }
+}
+
+impl Eq for PathFilter {}
@@ -1,51 +1,102 @@
-use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike, Weekday};
+//! intervals of backups like `hourly` or `every 2 weeks` and the parser of them.
+//!
+//! ```
+//! use chrono::NaiveDate;
+//! use game_save_backuper::interval::SaveInterval;
+//!
+//! let interval: SaveInterval = "every 6 hours".parse().unwrap();
+//! assert_eq!(interval, SaveInterval::Every6Hour);
+//! assert_eq!(interval.to_string(), "every 6 hour");
+//!
+//! let at = |h, m| NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().and_hms_opt(h, m, 0).unwrap();
+//! assert_eq!(interval.get_last_date_until(&at(7, 30)), at(6, 0));
+//! assert!(interval.is_passed(&at(5, 55), &at(6, 0)));
+//! assert!(!interval.is_passed(&at(6, 0), &at(11, 55)));
+//! ```
+//!
+//! intervals not dividing a day or a year evenly are rejected with a hint.
+//!
+//! ```
+//! use game_save_backuper::interval::{Error, SaveInterval};
+//!
+//! let err = "7 hours".parse::<SaveInterval>().unwrap_err();
+//! assert!(matches!(err, Error::Unsupported { .. }));
+//! assert_eq!(err.to_string(), r#"unsupported interval: "7 hour". try 6 or 8 hour"#);
+//! ```
+
+use chrono::{Datelike, Duration, Months, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Weekday};
 use serde::{Deserialize, Deserializer};
 use std::fmt::Formatter;
 use std::str::FromStr;
 
+/// the interval of backups.
+/// times are in the timezone of the config, UTC by default.
+///
+/// intervals shorter than a day are counted from midnight so they must divide a day evenly.
+/// minute intervals also must be multiple of 5 minutes, or 1 to 4 minutes only allowed with allow_frequent.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub(crate) enum SaveInterval {
-    /// every **:\[012345]\[05]:00 UTC
+pub enum SaveInterval {
+    /// every **:\[012345]\[05]:00
     Every5Minute,
-    /// every **:\[012345]0:00 UTC
+    /// every **:\[012345]0:00
     Every10Minute,
-    /// every **:(00|15|30|45):00 UTC
+    /// every **:(00|15|30|45):00
     Every15Minute,
-    /// every **:\[024]0:00 UTC
+    /// every **:\[024]0:00
     Every20Minute,
-    /// every **:\[03]0:00 UTC
+    /// every **:\[03]0:00
     /// alias: half-hourly
     Every30Minute,
-    /// every **:00:00 UTC
+    /// every **:00:00
     Every1Hour,
-    /// every \[0-2]\[02468]:00:00 UTC
+    /// every \[0-2]\[02468]:00:00
     Every2Hour,
-    /// every (00|04|08|12|16|20):00:00 UTC
+    /// every (00|04|08|12|16|20):00:00
     Every4Hour,
-    /// every (00|06|12|18):00:00 UTC
+    /// every (00|06|12|18):00:00
     Every6Hour,
-    /// every (00|08|16):00:00 UTC
+    /// every (00|08|16):00:00
     Every8Hour,
-    /// every (00|12):00:00 UTC
+    /// every (00|12):00:00
     /// alias: half-daily
     Every12Hour,
-    /// every 00:00:00 UTC
+    /// every N minutes from 00:00:00.
+    /// N is multiple of 5 or less than 5 which divides a day and is not listed above.
+    /// e.g. 1 or 40 or 45 or 90
+    ///
+    /// constructed with [SaveInterval::every_n_minutes] which validates N.
+    #[non_exhaustive]
+    EveryNMinutes(u32),
+    /// every N hours from 00:00:00.
+    /// N divides a day and is not listed above.
+    /// e.g. 3
+    ///
+    /// constructed with [SaveInterval::every_n_hours] which validates N.
+    #[non_exhaustive]
+    EveryNHours(u32),
+    /// every 00:00:00
     // alias: 24 hour
     Every1Day,
-    /// every Monday 00:00:00 UTC
+    /// every Monday 00:00:00
     Every1Week,
-    /// every 1st 00:00:00 UTC
+    /// every Monday of odd ISO weeks 00:00:00.
+    /// alias: biweekly, fortnightly
+    ///
+    /// in years with ISO week 53, both week 53 and week 1 of the next year are boundaries
+    /// so the interval over the new year is a week.
+    Every2Week,
+    /// every 1st 00:00:00
     Every1Month,
-    /// every (Jan|Mar|May|Jul|Sep|Nov) 1st 00:00:00 UTC
+    /// every (Jan|Mar|May|Jul|Sep|Nov) 1st 00:00:00
     Every2Month,
-    /// every (Jan|Apr|Jul|Oct) 1st 00:00:00 UTC
+    /// every (Jan|Apr|Jul|Oct) 1st 00:00:00
     Every3Month,
-    /// every (Jan|May|Nov) 1st 00:00:00 UTC
+    /// every (Jan|May|Sep) 1st 00:00:00
     Every4Month,
-    /// every (Jan|Jun) 1st 00:00:00 UTC
+    /// every (Jan|Jun) 1st 00:00:00
     /// alias: half-year
     Every6Month,
-    /// every Jan 1st 00:00:00 UTC
+    /// every Jan 1st 00:00:00
     Every1Year,
 }
 
@@ -96,8 +147,11 @@ impl std::fmt::Display for SaveInterval {
             Self::Every3Month => write!(f, "every 3 month"),
             Self::Every2Month => write!(f, "every 2 month"),
             Self::Every1Month => write!(f, "every 1 month"),
+            Self::Every2Week => write!(f, "every 2 week"),
             Self::Every1Week => write!(f, "every 1 week"),
             Self::Every1Day => write!(f, "every 1 day"),
+            Self::EveryNHours(n) => write!(f, "every {} hour", n),
+            Self::EveryNMinutes(n) => write!(f, "every {} minute", n),
             Self::Every12Hour => write!(f, "every 12 hour"),
             Self::Every8Hour => write!(f, "every 8 hour"),
             Self::Every6Hour => write!(f, "every 6 hour"),
@@ -113,8 +167,239 @@ impl std::fmt::Display for SaveInterval {
     }
 }
 
+/// the age of backups to be removed like `30 days`.
+/// parsed like intervals but any number is allowed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum MaxAge {
+    Minutes(u32),
+    Hours(u32),
+    Days(u32),
+    Weeks(u32),
+    Months(u32),
+    Years(u32),
+}
+
+impl<'de> Deserialize<'de> for MaxAge {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct VisitorImpl;
+
+        impl<'de> serde::de::Visitor<'de> for VisitorImpl {
+            type Value = MaxAge;
+
+            fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+                write!(formatter, "expecting age like 30 days")
+            }
+
+            fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                MaxAge::from_str(v).map_err(|e| E::custom(e))
+            }
+        }
+
+        deserializer.deserialize_str(VisitorImpl)
+    }
+}
+
+impl std::str::FromStr for MaxAge {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Parser {
+            src: s.as_bytes(),
+            index: 0,
+        }
+        .parse_age()
+    }
+}
+
+/// the period the scheduler wakes up at like `5 minutes`.
+/// ticks are aligned to multiples of the period from midnight so it must divide a day.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Tick {
+    minutes: u32,
+}
+
+impl Tick {
+    pub(crate) const DEFAULT: Tick = Tick { minutes: 5 };
+
+    pub(crate) fn from_minutes(minutes: u32) -> Option<Tick> {
+        (minutes != 0 && SaveInterval::MINUTES_PER_DAY.is_multiple_of(minutes))
+            .then_some(Tick { minutes })
+    }
+
+    pub(crate) fn seconds(self) -> u32 {
+        self.minutes * 60
+    }
+}
+
+impl Default for Tick {
+    fn default() -> Self {
+        Tick::DEFAULT
+    }
+}
+
+impl<'de> Deserialize<'de> for Tick {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct VisitorImpl;
+
+        impl<'de> serde::de::Visitor<'de> for VisitorImpl {
+            type Value = Tick;
+
+            fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+                write!(formatter, "expecting tick like 5 minutes")
+            }
+
+            fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Tick::from_str(v).map_err(|e| E::custom(e))
+            }
+        }
+
+        deserializer.deserialize_str(VisitorImpl)
+    }
+}
+
+impl std::str::FromStr for Tick {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let minutes = match MaxAge::from_str(s)? {
+            MaxAge::Minutes(n) => Some(n),
+            MaxAge::Hours(n) => n.checked_mul(60),
+            _ => None,
+        };
+        // the tick must divide a day to be aligned to wall-clock
+        minutes.and_then(Tick::from_minutes).ok_or_else(|| {
+            let divisors = (1..=SaveInterval::MINUTES_PER_DAY)
+                .filter(|n| SaveInterval::MINUTES_PER_DAY.is_multiple_of(*n));
+            Error::Unsupported {
+                interval: s.trim().to_owned(),
+                hint: minutes.and_then(|n| nearest(n, Token::Minute, divisors)),
+            }
+        })
+    }
+}
+
+impl MaxAge {
+    /// the time backups older than which are removed at `now`
+    pub(crate) fn oldest(self, now: &NaiveDateTime) -> NaiveDateTime {
+        // ages over the range of dates include all backups
+        let before = |duration: Option<Duration>| {
+            duration
+                .and_then(|x| now.checked_sub_signed(x))
+                .unwrap_or(NaiveDateTime::MIN)
+        };
+        match self {
+            MaxAge::Minutes(n) => before(Duration::try_minutes(n.into())),
+            MaxAge::Hours(n) => before(Duration::try_hours(n.into())),
+            MaxAge::Days(n) => before(Duration::try_days(n.into())),
+            MaxAge::Weeks(n) => before(Duration::try_weeks(n.into())),
+            MaxAge::Months(n) => now
+                .checked_sub_months(Months::new(n))
+                .unwrap_or(NaiveDateTime::MIN),
+            MaxAge::Years(n) => now
+                .checked_sub_months(Months::new(n.saturating_mul(12)))
+                .unwrap_or(NaiveDateTime::MIN),
+        }
+    }
+}
+
 impl SaveInterval {
-    pub(crate) fn is_passed(self, since: &NaiveDateTime, until: &NaiveDateTime) -> bool {
+    const MINUTES_PER_DAY: u32 = 24 * 60;
+
+    /// the interval of every `n` minutes with the same rules as the parser.
+    /// named variant is returned if exists.
+    ///
+    /// ```
+    /// use game_save_backuper::interval::SaveInterval;
+    ///
+    /// assert_eq!(SaveInterval::every_n_minutes(60).unwrap(), SaveInterval::Every1Hour);
+    /// assert_eq!(SaveInterval::every_n_minutes(45).unwrap().to_string(), "every 45 minute");
+    /// assert!(SaveInterval::every_n_minutes(0).is_err());
+    /// assert!(SaveInterval::every_n_minutes(7).is_err());
+    /// ```
+    pub fn every_n_minutes(n: u32) -> Result<Self, Error> {
+        Self::minutes(n)
+    }
+
+    /// the interval of every `n` hours with the same rules as the parser.
+    /// named variant is returned if exists.
+    pub fn every_n_hours(n: u32) -> Result<Self, Error> {
+        Self::hours(n)
+    }
+
+    /// the interval of every `n` minutes. named variant is returned if exists.
+    fn minutes(n: u32) -> Result<Self, Error> {
+        let supported = |n: u32| {
+            n != 0 && (n < 5 || n.is_multiple_of(5)) && Self::MINUTES_PER_DAY.is_multiple_of(n)
+        };
+        if !supported(n) {
+            // intervals shorter than 5 minutes are not suggested since they need allow_frequent
+            let suggested = (5..=Self::MINUTES_PER_DAY).filter(|n| supported(*n));
+            return Err(Error::Unsupported {
+                interval: format!("{} minute", n),
+                hint: nearest(n, Token::Minute, suggested),
+            });
+        }
+        if n.is_multiple_of(60) {
+            return Self::hours(n / 60);
+        }
+        Ok(match n {
+            30 => SaveInterval::Every30Minute,
+            20 => SaveInterval::Every20Minute,
+            15 => SaveInterval::Every15Minute,
+            10 => SaveInterval::Every10Minute,
+            5 => SaveInterval::Every5Minute,
+            n => SaveInterval::EveryNMinutes(n),
+        })
+    }
+
+    /// the minutes of the interval if it's shorter than 5 minutes.
+    /// such intervals are only for testing configs so they must be allowed explicitly.
+    pub(crate) fn frequent_minutes(self) -> Option<u32> {
+        match self {
+            SaveInterval::EveryNMinutes(n) if n < 5 => Some(n),
+            _ => None,
+        }
+    }
+
+    /// the interval of every `n` hours. named variant is returned if exists.
+    fn hours(n: u32) -> Result<Self, Error> {
+        if n == 0 || !24u32.is_multiple_of(n) {
+            return Err(Error::Unsupported {
+                interval: format!("{} hour", n),
+                hint: nearest(
+                    n,
+                    Token::Hour,
+                    (1..=24).filter(|n| 24u32.is_multiple_of(*n)),
+                ),
+            });
+        }
+        Ok(match n {
+            24 => SaveInterval::Every1Day,
+            12 => SaveInterval::Every12Hour,
+            8 => SaveInterval::Every8Hour,
+            6 => SaveInterval::Every6Hour,
+            4 => SaveInterval::Every4Hour,
+            2 => SaveInterval::Every2Hour,
+            1 => SaveInterval::Every1Hour,
+            n => SaveInterval::EveryNHours(n),
+        })
+    }
+
+    /// whether a boundary of the interval is in `since` (exclusive) to `until` (inclusive).
+    /// `since` must be before `until`
+    pub fn is_passed(self, since: &NaiveDateTime, until: &NaiveDateTime) -> bool {
         debug_assert!(since < until);
 
         //since.time().num_seconds_from_midnight() / 300
@@ -124,9 +409,11 @@ impl SaveInterval {
                 since.$method() / $per != until.$method() / $per
             };
         }
+        // midnight is a boundary of all intervals shorter than a day so
+        // the window over midnight like one after the clock jumped forward always passes
         macro_rules! compare_date {
             ($per_sec: expr) => {
-                compare!(num_seconds_from_midnight / $per_sec)
+                since.date() != until.date() || compare!(num_seconds_from_midnight / $per_sec)
             };
         }
 
@@ -136,25 +423,34 @@ impl SaveInterval {
             SaveInterval::Every15Minute => compare_date!(60 * 15),
             SaveInterval::Every20Minute => compare_date!(60 * 20),
             SaveInterval::Every30Minute => compare_date!(60 * 30),
-            SaveInterval::Every1Hour => compare_date!(60 * 60 * 1),
+            SaveInterval::Every1Hour => compare_date!(60 * 60),
             SaveInterval::Every2Hour => compare_date!(60 * 60 * 2),
             SaveInterval::Every4Hour => compare_date!(60 * 60 * 4),
             SaveInterval::Every6Hour => compare_date!(60 * 60 * 6),
             SaveInterval::Every8Hour => compare_date!(60 * 60 * 8),
             SaveInterval::Every12Hour => compare_date!(60 * 60 * 12),
+            SaveInterval::EveryNMinutes(n) => compare_date!(60 * n),
+            SaveInterval::EveryNHours(n) => compare_date!(60 * 60 * n),
             SaveInterval::Every1Day => since.date() != until.date(),
             SaveInterval::Every1Week => since.iso_week() != until.iso_week(),
-            SaveInterval::Every1Month => compare!(month0 / 1),
-            SaveInterval::Every2Month => compare!(month0 / 2),
-            SaveInterval::Every3Month => compare!(month0 / 3),
-            SaveInterval::Every4Month => compare!(month0 / 4),
-            SaveInterval::Every6Month => compare!(month0 / 6),
+            SaveInterval::Every2Week => {
+                self.get_last_date_until(since) != self.get_last_date_until(until)
+            }
+            // compared with the last boundary so that the window over the new year
+            // agrees with get_last_date_until
+            SaveInterval::Every1Month
+            | SaveInterval::Every2Month
+            | SaveInterval::Every3Month
+            | SaveInterval::Every4Month
+            | SaveInterval::Every6Month => {
+                self.get_last_date_until(since) != self.get_last_date_until(until)
+            }
             SaveInterval::Every1Year => compare!(year / 1),
         }
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn get_last_date_until(self, time: &NaiveDateTime) -> NaiveDateTime {
+    /// returns the last boundary of the interval at or before `time`
+    pub fn get_last_date_until(self, time: &NaiveDateTime) -> NaiveDateTime {
         //noinspection SpellCheckingInspection
         /// returns greatest multiple of m less than or equal to num
         /// for div opimization
@@ -167,48 +463,142 @@ impl SaveInterval {
         }
 
         match self {
-            SaveInterval::Every5Minute => {
-                time.date().and_hms(time.hour(), gmon(time.minute(), 5), 0)
+            SaveInterval::Every5Minute => time
+                .date()
+                .and_hms_opt(time.hour(), gmon(time.minute(), 5), 0)
+                .unwrap(),
+            SaveInterval::Every10Minute => time
+                .date()
+                .and_hms_opt(time.hour(), gmon(time.minute(), 10), 0)
+                .unwrap(),
+            SaveInterval::Every15Minute => time
+                .date()
+                .and_hms_opt(time.hour(), gmon(time.minute(), 15), 0)
+                .unwrap(),
+            SaveInterval::Every20Minute => time
+                .date()
+                .and_hms_opt(time.hour(), gmon(time.minute(), 20), 0)
+                .unwrap(),
+            SaveInterval::Every30Minute => time
+                .date()
+                .and_hms_opt(time.hour(), gmon(time.minute(), 30), 0)
+                .unwrap(),
+            SaveInterval::Every1Hour => {
+                time.date().and_hms_opt(gmon(time.hour(), 1), 0, 0).unwrap()
+            }
+            SaveInterval::Every2Hour => {
+                time.date().and_hms_opt(gmon(time.hour(), 2), 0, 0).unwrap()
             }
-            SaveInterval::Every10Minute => {
-                time.date().and_hms(time.hour(), gmon(time.minute(), 10), 0)
+            SaveInterval::Every4Hour => {
+                time.date().and_hms_opt(gmon(time.hour(), 4), 0, 0).unwrap()
             }
-            SaveInterval::Every15Minute => {
-                time.date().and_hms(time.hour(), gmon(time.minute(), 15), 0)
+            SaveInterval::Every6Hour => {
+                time.date().and_hms_opt(gmon(time.hour(), 6), 0, 0).unwrap()
             }
-            SaveInterval::Every20Minute => {
-                time.date().and_hms(time.hour(), gmon(time.minute(), 20), 0)
+            SaveInterval::Every8Hour => {
+                time.date().and_hms_opt(gmon(time.hour(), 8), 0, 0).unwrap()
             }
-            SaveInterval::Every30Minute => {
-                time.date().and_hms(time.hour(), gmon(time.minute(), 30), 0)
+            SaveInterval::Every12Hour => time
+                .date()
+                .and_hms_opt(gmon(time.hour(), 12), 0, 0)
+                .unwrap(),
+            SaveInterval::EveryNMinutes(n) => time.date().and_time(
+                NaiveTime::from_num_seconds_from_midnight_opt(
+                    gmon(time.num_seconds_from_midnight(), 60 * n),
+                    0,
+                )
+                .unwrap(),
+            ),
+            SaveInterval::EveryNHours(n) => {
+                time.date().and_hms_opt(gmon(time.hour(), n), 0, 0).unwrap()
             }
-            SaveInterval::Every1Hour => time.date().and_hms(gmon(time.hour(), 1), 0, 0),
-            SaveInterval::Every2Hour => time.date().and_hms(gmon(time.hour(), 2), 0, 0),
-            SaveInterval::Every4Hour => time.date().and_hms(gmon(time.hour(), 4), 0, 0),
-            SaveInterval::Every6Hour => time.date().and_hms(gmon(time.hour(), 6), 0, 0),
-            SaveInterval::Every8Hour => time.date().and_hms(gmon(time.hour(), 8), 0, 0),
-            SaveInterval::Every12Hour => time.date().and_hms(gmon(time.hour(), 12), 0, 0),
-            SaveInterval::Every1Day => time.date().and_hms(0, 0, 0),
+            SaveInterval::Every1Day => time.date().and_hms_opt(0, 0, 0).unwrap(),
             SaveInterval::Every1Week => {
                 let week = time.iso_week();
-                NaiveDate::from_isoywd(week.year(), week.week(), Weekday::Mon).and_hms(0, 0, 0)
+                NaiveDate::from_isoywd_opt(week.year(), week.week(), Weekday::Mon)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap()
             }
-            SaveInterval::Every1Month => {
-                NaiveDate::from_ymd(time.year(), time.month(), 1).and_hms(0, 0, 0)
+            SaveInterval::Every2Week => {
+                let week = time.iso_week();
+                // week 1 is odd so the odd week before an even week is in the same year
+                let odd = if week.week().is_multiple_of(2) {
+                    week.week() - 1
+                } else {
+                    week.week()
+                };
+                NaiveDate::from_isoywd_opt(week.year(), odd, Weekday::Mon)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap()
             }
+            SaveInterval::Every1Month => NaiveDate::from_ymd_opt(time.year(), time.month(), 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
             SaveInterval::Every2Month => {
-                NaiveDate::from_ymd(time.year(), gmon(time.month0(), 2) + 1, 1).and_hms(0, 0, 0)
+                NaiveDate::from_ymd_opt(time.year(), gmon(time.month0(), 2) + 1, 1)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap()
             }
             SaveInterval::Every3Month => {
-                NaiveDate::from_ymd(time.year(), gmon(time.month0(), 3) + 1, 1).and_hms(0, 0, 0)
+                NaiveDate::from_ymd_opt(time.year(), gmon(time.month0(), 3) + 1, 1)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap()
             }
             SaveInterval::Every4Month => {
-                NaiveDate::from_ymd(time.year(), gmon(time.month0(), 4) + 1, 1).and_hms(0, 0, 0)
+                NaiveDate::from_ymd_opt(time.year(), gmon(time.month0(), 4) + 1, 1)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap()
             }
             SaveInterval::Every6Month => {
-                NaiveDate::from_ymd(time.year(), gmon(time.month0(), 6) + 1, 1).and_hms(0, 0, 0)
+                NaiveDate::from_ymd_opt(time.year(), gmon(time.month0(), 6) + 1, 1)
+                    .unwrap()
+                    .and_hms_opt(0, 0, 0)
+                    .unwrap()
             }
-            SaveInterval::Every1Year => NaiveDate::from_ymd(time.year(), 1, 1).and_hms(0, 0, 0),
+            SaveInterval::Every1Year => NaiveDate::from_ymd_opt(time.year(), 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+        }
+    }
+
+    /// returns the first boundary of the interval strictly after `time`
+    pub(crate) fn get_next_date_after(self, time: &NaiveDateTime) -> NaiveDateTime {
+        let last = self.get_last_date_until(time);
+        let midnight = time.date().succ_opt().unwrap().and_time(NaiveTime::MIN);
+        // the last interval of the day ends at midnight
+        let within_day = |seconds: u32| (last + Duration::seconds(seconds.into())).min(midnight);
+
+        match self {
+            SaveInterval::Every5Minute => within_day(60 * 5),
+            SaveInterval::Every10Minute => within_day(60 * 10),
+            SaveInterval::Every15Minute => within_day(60 * 15),
+            SaveInterval::Every20Minute => within_day(60 * 20),
+            SaveInterval::Every30Minute => within_day(60 * 30),
+            SaveInterval::Every1Hour => within_day(60 * 60),
+            SaveInterval::Every2Hour => within_day(60 * 60 * 2),
+            SaveInterval::Every4Hour => within_day(60 * 60 * 4),
+            SaveInterval::Every6Hour => within_day(60 * 60 * 6),
+            SaveInterval::Every8Hour => within_day(60 * 60 * 8),
+            SaveInterval::Every12Hour => within_day(60 * 60 * 12),
+            SaveInterval::EveryNMinutes(n) => within_day(60 * n),
+            SaveInterval::EveryNHours(n) => within_day(60 * 60 * n),
+            SaveInterval::Every1Day => midnight,
+            SaveInterval::Every1Week => last + Duration::weeks(1),
+            // two weeks after week 53 is week 2 of the next year
+            SaveInterval::Every2Week => self.get_last_date_until(&(last + Duration::weeks(2))),
+            SaveInterval::Every1Month => last + Months::new(1),
+            SaveInterval::Every2Month => last + Months::new(2),
+            SaveInterval::Every3Month => last + Months::new(3),
+            SaveInterval::Every4Month => last + Months::new(4),
+            SaveInterval::Every6Month => last + Months::new(6),
+            SaveInterval::Every1Year => last + Months::new(12),
         }
     }
 }
@@ -220,84 +610,537 @@ mod get_last_date_until_test {
 
     #[test]
     fn get_last_date_until() {
-        let date = NaiveDate::from_ymd(2022, 1, 2);
-        let date_time = date.and_hms(3, 28, 30);
+        let date = NaiveDate::from_ymd_opt(2022, 1, 2).unwrap();
+        let date_time = date.and_hms_opt(3, 28, 30).unwrap();
 
         assert_eq!(
             Every1Year.get_last_date_until(&date_time),
-            NaiveDate::from_ymd(2022, 1, 1).and_hms(0, 0, 0),
+            NaiveDate::from_ymd_opt(2022, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
         );
         assert_eq!(
             Every6Month.get_last_date_until(&date_time),
-            NaiveDate::from_ymd(2022, 1, 1).and_hms(0, 0, 0),
+            NaiveDate::from_ymd_opt(2022, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
         );
         assert_eq!(
             Every1Month.get_last_date_until(&date_time),
-            NaiveDate::from_ymd(2022, 1, 1).and_hms(0, 0, 0),
+            NaiveDate::from_ymd_opt(2022, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
         );
         assert_eq!(
             Every1Week.get_last_date_until(&date_time),
-            NaiveDate::from_ymd(2021, 12, 27).and_hms(0, 0, 0)
+            NaiveDate::from_ymd_opt(2021, 12, 27)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
         );
 
         assert_eq!(
             Every1Day.get_last_date_until(&date_time),
-            date.and_hms(0, 0, 0)
+            date.and_hms_opt(0, 0, 0).unwrap()
         );
         assert_eq!(
             Every12Hour.get_last_date_until(&date_time),
-            date.and_hms(0, 0, 0)
+            date.and_hms_opt(0, 0, 0).unwrap()
         );
         assert_eq!(
             Every8Hour.get_last_date_until(&date_time),
-            date.and_hms(0, 0, 0)
+            date.and_hms_opt(0, 0, 0).unwrap()
         );
         assert_eq!(
             Every6Hour.get_last_date_until(&date_time),
-            date.and_hms(0, 0, 0)
+            date.and_hms_opt(0, 0, 0).unwrap()
         );
         assert_eq!(
             Every4Hour.get_last_date_until(&date_time),
-            date.and_hms(0, 0, 0)
+            date.and_hms_opt(0, 0, 0).unwrap()
         );
         assert_eq!(
             Every2Hour.get_last_date_until(&date_time),
-            date.and_hms(2, 0, 0)
+            date.and_hms_opt(2, 0, 0).unwrap()
         );
         assert_eq!(
             Every1Hour.get_last_date_until(&date_time),
-            date.and_hms(3, 0, 0)
+            date.and_hms_opt(3, 0, 0).unwrap()
         );
         assert_eq!(
             Every30Minute.get_last_date_until(&date_time),
-            date.and_hms(3, 0, 0)
+            date.and_hms_opt(3, 0, 0).unwrap()
         );
         assert_eq!(
             Every20Minute.get_last_date_until(&date_time),
-            date.and_hms(3, 20, 0)
+            date.and_hms_opt(3, 20, 0).unwrap()
         );
         assert_eq!(
             Every15Minute.get_last_date_until(&date_time),
-            date.and_hms(3, 15, 0)
+            date.and_hms_opt(3, 15, 0).unwrap()
         );
         assert_eq!(
             Every10Minute.get_last_date_until(&date_time),
-            date.and_hms(3, 20, 0)
+            date.and_hms_opt(3, 20, 0).unwrap()
         );
         assert_eq!(
             Every5Minute.get_last_date_until(&date_time),
-            date.and_hms(3, 25, 0)
+            date.and_hms_opt(3, 25, 0).unwrap()
+        );
+    }
+}
+
+#[cfg(test)]
+mod get_next_date_after_test {
+    use super::*;
+    use SaveInterval::*;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, mo, d)
+            .unwrap()
+            .and_hms_opt(h, mi, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn get_next_date_after() {
+        let date = NaiveDate::from_ymd_opt(2022, 1, 2).unwrap();
+        let date_time = date.and_hms_opt(3, 28, 30).unwrap();
+
+        assert_eq!(
+            Every1Year.get_next_date_after(&date_time),
+            at(2023, 1, 1, 0, 0)
+        );
+        assert_eq!(
+            Every6Month.get_next_date_after(&date_time),
+            at(2022, 7, 1, 0, 0)
+        );
+        assert_eq!(
+            Every4Month.get_next_date_after(&date_time),
+            at(2022, 5, 1, 0, 0)
+        );
+        assert_eq!(
+            Every3Month.get_next_date_after(&date_time),
+            at(2022, 4, 1, 0, 0)
+        );
+        assert_eq!(
+            Every2Month.get_next_date_after(&date_time),
+            at(2022, 3, 1, 0, 0)
+        );
+        assert_eq!(
+            Every1Month.get_next_date_after(&date_time),
+            at(2022, 2, 1, 0, 0)
+        );
+        assert_eq!(
+            Every1Week.get_next_date_after(&date_time),
+            at(2022, 1, 3, 0, 0)
+        );
+
+        assert_eq!(
+            Every1Day.get_next_date_after(&date_time),
+            at(2022, 1, 3, 0, 0)
+        );
+        assert_eq!(
+            Every12Hour.get_next_date_after(&date_time),
+            date.and_hms_opt(12, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Every8Hour.get_next_date_after(&date_time),
+            date.and_hms_opt(8, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Every6Hour.get_next_date_after(&date_time),
+            date.and_hms_opt(6, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Every4Hour.get_next_date_after(&date_time),
+            date.and_hms_opt(4, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Every2Hour.get_next_date_after(&date_time),
+            date.and_hms_opt(4, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Every1Hour.get_next_date_after(&date_time),
+            date.and_hms_opt(4, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Every30Minute.get_next_date_after(&date_time),
+            date.and_hms_opt(3, 30, 0).unwrap()
+        );
+        assert_eq!(
+            Every20Minute.get_next_date_after(&date_time),
+            date.and_hms_opt(3, 40, 0).unwrap()
+        );
+        assert_eq!(
+            Every15Minute.get_next_date_after(&date_time),
+            date.and_hms_opt(3, 30, 0).unwrap()
+        );
+        assert_eq!(
+            Every10Minute.get_next_date_after(&date_time),
+            date.and_hms_opt(3, 30, 0).unwrap()
+        );
+        assert_eq!(
+            Every5Minute.get_next_date_after(&date_time),
+            date.and_hms_opt(3, 30, 0).unwrap()
+        );
+        assert_eq!(
+            EveryNHours(3).get_next_date_after(&date_time),
+            date.and_hms_opt(6, 0, 0).unwrap()
+        );
+        assert_eq!(
+            EveryNMinutes(45).get_next_date_after(&date_time),
+            date.and_hms_opt(3, 45, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn on_boundary() {
+        // the boundary at the time itself is not the next one
+        assert_eq!(
+            Every1Hour.get_next_date_after(&at(2022, 1, 2, 3, 0)),
+            at(2022, 1, 2, 4, 0)
+        );
+        assert_eq!(
+            Every3Month.get_next_date_after(&at(2022, 4, 1, 0, 0)),
+            at(2022, 7, 1, 0, 0)
+        );
+    }
+
+    #[test]
+    fn over_midnight() {
+        // the last interval of the day ends at midnight
+        assert_eq!(
+            EveryNMinutes(45).get_next_date_after(&at(2022, 1, 2, 23, 50)),
+            at(2022, 1, 3, 0, 0)
+        );
+        assert_eq!(
+            Every5Minute.get_next_date_after(&at(2022, 1, 2, 23, 57)),
+            at(2022, 1, 3, 0, 0)
+        );
+    }
+
+    #[test]
+    fn december_to_january() {
+        let time = at(2021, 12, 15, 12, 0);
+        assert_eq!(Every1Year.get_next_date_after(&time), at(2022, 1, 1, 0, 0));
+        assert_eq!(Every6Month.get_next_date_after(&time), at(2022, 1, 1, 0, 0));
+        assert_eq!(Every4Month.get_next_date_after(&time), at(2022, 1, 1, 0, 0));
+        assert_eq!(Every3Month.get_next_date_after(&time), at(2022, 1, 1, 0, 0));
+        assert_eq!(Every2Month.get_next_date_after(&time), at(2022, 1, 1, 0, 0));
+        assert_eq!(Every1Month.get_next_date_after(&time), at(2022, 1, 1, 0, 0));
+        let time = at(2021, 12, 31, 23, 30);
+        assert_eq!(Every1Day.get_next_date_after(&time), at(2022, 1, 1, 0, 0));
+        assert_eq!(Every1Hour.get_next_date_after(&time), at(2022, 1, 1, 0, 0));
+        // 2022-01-01 is Saturday in the ISO week 52 of 2021
+        assert_eq!(Every1Week.get_next_date_after(&time), at(2022, 1, 3, 0, 0));
+    }
+
+    #[test]
+    fn leap_year_february() {
+        assert_eq!(
+            Every1Day.get_next_date_after(&at(2024, 2, 28, 12, 0)),
+            at(2024, 2, 29, 0, 0)
+        );
+        assert_eq!(
+            Every1Day.get_next_date_after(&at(2024, 2, 29, 12, 0)),
+            at(2024, 3, 1, 0, 0)
+        );
+        assert_eq!(
+            Every1Day.get_next_date_after(&at(2023, 2, 28, 12, 0)),
+            at(2023, 3, 1, 0, 0)
+        );
+        assert_eq!(
+            Every1Month.get_next_date_after(&at(2024, 2, 29, 12, 0)),
+            at(2024, 3, 1, 0, 0)
+        );
+        assert_eq!(
+            Every2Month.get_next_date_after(&at(2024, 2, 29, 12, 0)),
+            at(2024, 3, 1, 0, 0)
+        );
+        assert_eq!(
+            Every1Week.get_next_date_after(&at(2024, 2, 29, 12, 0)),
+            at(2024, 3, 4, 0, 0)
+        );
+    }
+}
+
+#[cfg(test)]
+mod month_test {
+    use super::*;
+    use SaveInterval::*;
+
+    fn at(y: i32, m: u32, d: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    }
+
+    fn first(y: i32, m: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn december_to_january() {
+        for interval in [
+            Every1Month,
+            Every2Month,
+            Every3Month,
+            Every4Month,
+            Every6Month,
+        ] {
+            assert!(
+                interval.is_passed(&at(2021, 12, 31), &first(2022, 1)),
+                "{}",
+                interval
+            );
+            assert!(
+                interval.is_passed(&at(2021, 12, 15), &at(2022, 1, 15)),
+                "{}",
+                interval
+            );
+            assert!(
+                !interval.is_passed(&first(2022, 1), &at(2022, 1, 15)),
+                "{}",
+                interval
+            );
+            assert_eq!(
+                interval.get_last_date_until(&at(2021, 12, 31)),
+                interval.get_last_date_until(&(first(2022, 1) - Duration::seconds(1))),
+                "{}",
+                interval
+            );
+            assert_eq!(
+                interval.get_last_date_until(&first(2022, 1)),
+                first(2022, 1),
+                "{}",
+                interval
+            );
+        }
+
+        // the same month of the next year is in another bucket
+        assert!(Every2Month.is_passed(&at(2021, 1, 15), &at(2022, 2, 15)));
+        assert!(Every6Month.is_passed(&at(2021, 8, 15), &at(2022, 7, 15)));
+    }
+
+    #[test]
+    fn boundaries() {
+        let boundaries = |interval: SaveInterval| {
+            (1..=12)
+                .filter(|&m| {
+                    let first = first(2022, m);
+                    interval.is_passed(
+                        &(first - Duration::hours(12)),
+                        &(first + Duration::hours(12)),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(boundaries(Every1Month), (1..=12).collect::<Vec<_>>());
+        assert_eq!(boundaries(Every2Month), vec![1, 3, 5, 7, 9, 11]);
+        assert_eq!(boundaries(Every3Month), vec![1, 4, 7, 10]);
+        assert_eq!(boundaries(Every4Month), vec![1, 5, 9]);
+        assert_eq!(boundaries(Every6Month), vec![1, 7]);
+    }
+
+    #[test]
+    fn agrees_with_last_date() {
+        // every month from December through the next January
+        let months = (0..15).map(|i| first(2021, 1) + Months::new(10 + i));
+        let times = months
+            .flat_map(|m| [m - Duration::seconds(1), m, m + Duration::days(14)])
+            .collect::<Vec<_>>();
+        for interval in [
+            Every1Month,
+            Every2Month,
+            Every3Month,
+            Every4Month,
+            Every6Month,
+        ] {
+            for since in &times {
+                for until in times.iter().filter(|x| *x > since) {
+                    let last = interval.get_last_date_until(until);
+                    assert_eq!(
+                        interval.is_passed(since, until),
+                        since < &last,
+                        "{} {} {}",
+                        interval,
+                        since,
+                        until
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod every_2_week_test {
+    use super::*;
+    use SaveInterval::*;
+
+    fn at(y: i32, m: u32, d: u32, h: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn get_last_date_until() {
+        // 2022-01-03 is Monday of ISO week 1
+        assert_eq!(
+            Every2Week.get_last_date_until(&at(2022, 1, 5, 12)),
+            at(2022, 1, 3, 0)
+        );
+        // week 2
+        assert_eq!(
+            Every2Week.get_last_date_until(&at(2022, 1, 12, 12)),
+            at(2022, 1, 3, 0)
+        );
+        // week 3
+        assert_eq!(
+            Every2Week.get_last_date_until(&at(2022, 1, 17, 0)),
+            at(2022, 1, 17, 0)
+        );
+        // 2022-01-01 is in week 52 of 2021
+        assert_eq!(
+            Every2Week.get_last_date_until(&at(2022, 1, 1, 12)),
+            at(2021, 12, 20, 0)
+        );
+        // 2020 has week 53 from 2020-12-28
+        assert_eq!(
+            Every2Week.get_last_date_until(&at(2021, 1, 1, 12)),
+            at(2020, 12, 28, 0)
+        );
+    }
+
+    #[test]
+    fn is_passed() {
+        assert!(!Every2Week.is_passed(&at(2022, 1, 3, 0), &at(2022, 1, 16, 23)));
+        assert!(Every2Week.is_passed(&at(2022, 1, 16, 23), &at(2022, 1, 17, 0)));
+        // week 52 to week 1
+        assert!(Every2Week.is_passed(&at(2022, 1, 2, 23), &at(2022, 1, 3, 0)));
+        // week 51 to week 52
+        assert!(!Every2Week.is_passed(&at(2021, 12, 26, 23), &at(2021, 12, 27, 0)));
+        // week 53 and week 1 are both boundaries
+        assert!(Every2Week.is_passed(&at(2020, 12, 27, 23), &at(2020, 12, 28, 0)));
+        assert!(Every2Week.is_passed(&at(2021, 1, 3, 23), &at(2021, 1, 4, 0)));
+        assert!(!Every2Week.is_passed(&at(2021, 1, 4, 0), &at(2021, 1, 17, 23)));
+    }
+
+    #[test]
+    fn get_next_date_after() {
+        assert_eq!(
+            Every2Week.get_next_date_after(&at(2022, 1, 3, 0)),
+            at(2022, 1, 17, 0)
+        );
+        assert_eq!(
+            Every2Week.get_next_date_after(&at(2021, 12, 22, 0)),
+            at(2022, 1, 3, 0)
+        );
+        assert_eq!(
+            Every2Week.get_next_date_after(&at(2020, 12, 16, 0)),
+            at(2020, 12, 28, 0)
+        );
+        assert_eq!(
+            Every2Week.get_next_date_after(&at(2020, 12, 30, 0)),
+            at(2021, 1, 4, 0)
         );
     }
 }
 
+#[cfg(test)]
+mod every_n_test {
+    use super::*;
+    use SaveInterval::*;
+
+    #[test]
+    fn get_last_date_until() {
+        let date = NaiveDate::from_ymd_opt(2022, 1, 2).unwrap();
+        let date_time = date.and_hms_opt(7, 28, 30).unwrap();
+
+        assert_eq!(
+            EveryNHours(3).get_last_date_until(&date_time),
+            date.and_hms_opt(6, 0, 0).unwrap()
+        );
+        assert_eq!(
+            EveryNMinutes(45).get_last_date_until(&date_time),
+            date.and_hms_opt(6, 45, 0).unwrap()
+        );
+        assert_eq!(
+            EveryNMinutes(90).get_last_date_until(&date_time),
+            date.and_hms_opt(6, 0, 0).unwrap()
+        );
+        assert_eq!(
+            EveryNMinutes(90).get_last_date_until(&date.and_hms_opt(7, 30, 0).unwrap()),
+            date.and_hms_opt(7, 30, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn is_passed() {
+        let date = NaiveDate::from_ymd_opt(2022, 1, 2).unwrap();
+        let at = |h, m| date.and_hms_opt(h, m, 0).unwrap();
+
+        assert!(!EveryNHours(3).is_passed(&at(3, 0), &at(5, 55)));
+        assert!(EveryNHours(3).is_passed(&at(5, 55), &at(6, 0)));
+        assert!(!EveryNMinutes(45).is_passed(&at(0, 0), &at(0, 40)));
+        assert!(EveryNMinutes(45).is_passed(&at(0, 40), &at(0, 45)));
+        assert!(!EveryNMinutes(45).is_passed(&at(0, 45), &at(1, 25)));
+        assert!(EveryNMinutes(45).is_passed(&at(1, 25), &at(1, 30)));
+        // the last interval of the day ends at midnight
+        let next = NaiveDate::from_ymd_opt(2022, 1, 3)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        assert!(EveryNMinutes(45).is_passed(&at(23, 55), &next));
+    }
+
+    #[test]
+    fn is_passed_long_window() {
+        // windows like ones after the clock jumped forward contain boundaries
+        // even if the time of day or the month is the same
+        let since = NaiveDate::from_ymd_opt(2022, 1, 2)
+            .unwrap()
+            .and_hms_opt(12, 3, 0)
+            .unwrap();
+        let day_after = since + chrono::Duration::days(1);
+        assert!(Every5Minute.is_passed(&since, &day_after));
+        assert!(Every1Hour.is_passed(&since, &day_after));
+        assert!(EveryNHours(3).is_passed(&since, &day_after));
+        let year_after = since.with_year(2023).unwrap();
+        assert!(Every1Month.is_passed(&since, &year_after));
+        assert!(Every6Month.is_passed(&since, &year_after));
+        assert!(Every1Week.is_passed(&since, &year_after));
+    }
+}
+
+/// the error parsing intervals
 #[derive(Debug, PartialEq, Clone)]
 pub enum Error {
+    /// the character at the offset can't be a part of intervals
     InvalidCharacter(usize),
+    /// the token is not expected there. empty if the unit is missing
     UnexpectedToken(String),
-    Unsupported(String),
+    /// the interval is valid but not supported. hint suggests supported ones if any
+    Unsupported {
+        interval: String,
+        hint: Option<String>,
+    },
+    /// the number is too large
     NumberOverflow,
+    /// the value has no tokens
     Empty,
+    /// the unit can be read as multiple units
+    AmbiguousUnit(String),
 }
 
 impl std::error::Error for Error {}
@@ -311,19 +1154,52 @@ impl std::fmt::Display for Error {
                 "expected unit token. year, month, week, day, hour, and minute are allowed"
             ),
             Error::UnexpectedToken(token) => write!(f, "unknown token {:?}", token),
-            Error::Unsupported(token) => write!(f, "unsupported interval: {:?}", token),
+            Error::Unsupported {
+                interval,
+                hint: Some(hint),
+            } => write!(f, "unsupported interval: {:?}. {}", interval, hint),
+            Error::Unsupported {
+                interval,
+                hint: None,
+            } => write!(f, "unsupported interval: {:?}", interval),
             Error::NumberOverflow => write!(f, "number is too large"),
             Error::Empty => write!(f, "value was empty"),
+            Error::AmbiguousUnit(unit) => write!(
+                f,
+                "ambiguous unit {:?}. units are case-insensitive; \
+                use \"m\" or \"min\" for minutes and \"mo\" or \"month\" for months",
+                unit
+            ),
         }
     }
 }
 
+/// suggests the nearest supported values below and above `n`. `supported` must be sorted
+fn nearest(n: u32, unit: Token, supported: impl IntoIterator<Item = u32>) -> Option<String> {
+    let mut lower = None;
+    let mut upper = None;
+    for value in supported {
+        if value < n {
+            lower = Some(value);
+        } else if value > n {
+            upper = Some(value);
+            break;
+        }
+    }
+    match (lower, upper) {
+        (Some(lower), Some(upper)) => Some(format!("try {} or {} {}", lower, upper, unit)),
+        (Some(value), None) | (None, Some(value)) => Some(format!("try {} {}", value, unit)),
+        (None, None) => None,
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 enum Token {
     Minute,
     Hour,
     Day,
     Week,
+    Fortnight,
     Month,
     Year,
     Every,
@@ -338,6 +1214,7 @@ impl std::fmt::Display for Token {
             Token::Hour => write!(f, "hour"),
             Token::Day => write!(f, "day"),
             Token::Week => write!(f, "week"),
+            Token::Fortnight => write!(f, "fortnight"),
             Token::Month => write!(f, "month"),
             Token::Year => write!(f, "year"),
             Token::Every => write!(f, "every"),
@@ -347,6 +1224,105 @@ impl std::fmt::Display for Token {
     }
 }
 
+/// keywords of tokens. keywords are case-insensitive
+const KEYWORDS: &[(Token, &[&str])] = &[
+    (
+        Token::Minute,
+        &["minutely", "minutes", "minute", "min", "mins", "m"],
+    ),
+    (Token::Hour, &["hourly", "hours", "hour", "hr", "hrs", "h"]),
+    (Token::Day, &["daily", "days", "day", "d"]),
+    (Token::Week, &["weekly", "weeks", "week", "w"]),
+    (
+        Token::Fortnight,
+        &["biweekly", "fortnightly", "fortnights", "fortnight"],
+    ),
+    (Token::Month, &["monthly", "months", "month", "mo"]),
+    (Token::Year, &["yearly", "years", "year", "y"]),
+    (Token::Half, &["half"]),
+    (Token::Every, &["every"]),
+];
+
+/// intervals written as half of the unit like `half year`
+const HALVES: &[(Token, SaveInterval)] = &[
+    (Token::Year, SaveInterval::Every6Month),
+    (Token::Day, SaveInterval::Every12Hour),
+    (Token::Hour, SaveInterval::Every30Minute),
+];
+
+/// the counts of the unit which have their own variant of [SaveInterval]
+fn named_counts(unit: Token) -> &'static [u32] {
+    match unit {
+        Token::Minute => &[5, 10, 15, 20, 30],
+        Token::Hour => &[1, 2, 4, 6, 8, 12],
+        Token::Week => &[1, 2],
+        Token::Month => &[1, 2, 3, 4, 6],
+        _ => &[1],
+    }
+}
+
+/// examples of intervals without their own variant, which are every N minutes or hours
+const OTHER_INTERVAL_EXAMPLES: &[&str] = &["40 minutes", "90 minutes", "1h30m", "3 hours"];
+
+/// all spellings of intervals with their own variant, generated from the tables of the parser.
+/// the first spelling of each interval is the canonical form by [Display](std::fmt::Display).
+fn interval_spellings() -> Vec<(SaveInterval, Vec<String>)> {
+    let mut spellings = Vec::<(SaveInterval, Vec<String>)>::new();
+    let mut add = |interval: SaveInterval, spelling: String| match spellings
+        .iter_mut()
+        .find(|(x, _)| *x == interval)
+    {
+        Some((_, list)) => list.push(spelling),
+        None => spellings.push((interval, vec![interval.to_string(), spelling])),
+    };
+    let units = KEYWORDS
+        .iter()
+        .filter(|(x, _)| !matches!(x, Token::Half | Token::Every));
+    for (unit, keywords) in units {
+        for &n in named_counts(*unit) {
+            let interval = Parser {
+                src: format!("{} {}", n, unit).as_bytes(),
+                index: 0,
+            }
+            .parse()
+            .expect("named counts are supported");
+            for keyword in *keywords {
+                match n {
+                    1 => add(interval, keyword.to_string()),
+                    n => add(interval, format!("{} {}", n, keyword)),
+                }
+            }
+        }
+    }
+    for (unit, interval) in HALVES {
+        let (_, keywords) = KEYWORDS.iter().find(|(x, _)| x == unit).unwrap();
+        for keyword in *keywords {
+            add(*interval, format!("half {}", keyword));
+        }
+    }
+    spellings
+}
+
+/// the list of all supported intervals with their spellings, one interval per line
+pub(crate) fn list_intervals() -> String {
+    let quote = |x: &str| format!("'{}'", x);
+    let mut list = String::new();
+    for (interval, spellings) in interval_spellings() {
+        let spellings = spellings.iter().map(|x| quote(x)).collect::<Vec<_>>();
+        list.push_str(&format!("{:?}: {}\n", interval, spellings.join(", ")));
+    }
+    let examples = OTHER_INTERVAL_EXAMPLES
+        .iter()
+        .map(|x| quote(x))
+        .collect::<Vec<_>>();
+    list.push_str(&format!(
+        "other minutes or hours dividing a day are also supported like {}\n",
+        examples.join(", ")
+    ));
+    list.push_str("any of them can be prefixed with 'every' like 'every 6 hours'\n");
+    list
+}
+
 struct Parser<'a> {
     src: &'a [u8],
     index: usize,
@@ -358,21 +1334,19 @@ impl<'a> Parser<'a> {
         while matches!(self.src.get(self.index), Some(b'a'..=b'z' | b'A'..=b'Z')) {
             self.index += 1
         }
-        match &self.src[begin..self.index] {
-            b"minutely" | b"minutes" | b"minute" | b"min" | b"mins" | b"m" => Ok(Token::Minute),
-            b"hourly" | b"hours" | b"hour" | b"hr" | b"hrs" | b"h" => Ok(Token::Hour),
-            b"daily" | b"days" | b"day" | b"d" => Ok(Token::Day),
-            b"weekly" | b"weeks" | b"week" | b"w" => Ok(Token::Week),
-            b"monthly" | b"months" | b"month" | b"M" => Ok(Token::Month),
-            b"yearly" | b"years" | b"year" | b"y" => Ok(Token::Year),
-
-            b"half" => Ok(Token::Half),
-            b"every" => Ok(Token::Every),
-
-            e => Err(Error::UnexpectedToken(unsafe {
-                String::from_utf8_unchecked(e.to_owned())
-            })),
+        let keyword = &self.src[begin..self.index];
+        // `M` was month but it's easily confused with `m` for minute
+        if keyword == b"M" {
+            return Err(Error::AmbiguousUnit("M".to_owned()));
         }
+        let lower = keyword.to_ascii_lowercase();
+        KEYWORDS
+            .iter()
+            .find(|(_, keywords)| keywords.iter().any(|x| x.as_bytes() == lower))
+            .map(|(token, _)| *token)
+            .ok_or_else(|| {
+                Error::UnexpectedToken(unsafe { String::from_utf8_unchecked(keyword.to_owned()) })
+            })
     }
 
     fn parse_number(&mut self) -> Result<Token, Error> {
@@ -424,11 +1398,14 @@ impl<'a> Parser<'a> {
             t = self
                 .parse_token()?
                 .ok_or(Error::UnexpectedToken("half".to_owned()))?;
-            match t {
-                Token::Year => SaveInterval::Every6Month,
-                Token::Day => SaveInterval::Every12Hour,
-                Token::Hour => SaveInterval::Every30Minute,
-                token => return Err(Error::Unsupported(format!("half {}", token))),
+            match HALVES.iter().find(|(unit, _)| *unit == t) {
+                Some((_, interval)) => *interval,
+                None => {
+                    return Err(Error::Unsupported {
+                        interval: format!("half {}", t),
+                        hint: Some("try half year, half day, or half hour".to_owned()),
+                    })
+                }
             }
         } else {
             let n = if let Token::Number(n) = t {
@@ -440,30 +1417,88 @@ impl<'a> Parser<'a> {
                 1
             };
             match (n, t) {
+                (n, Token::Hour | Token::Minute) => match self.parse_compound(n, t)? {
+                    Some(minutes) => SaveInterval::minutes(minutes)?,
+                    None if t == Token::Hour => SaveInterval::hours(n)?,
+                    None => SaveInterval::minutes(n)?,
+                },
                 (1, Token::Year) => SaveInterval::Every1Year,
                 (6, Token::Month) => SaveInterval::Every6Month,
                 (4, Token::Month) => SaveInterval::Every4Month,
                 (3, Token::Month) => SaveInterval::Every3Month,
                 (2, Token::Month) => SaveInterval::Every2Month,
                 (1, Token::Month) => SaveInterval::Every1Month,
+                (2, Token::Week) | (1, Token::Fortnight) => SaveInterval::Every2Week,
                 (1, Token::Week) => SaveInterval::Every1Week,
                 (1, Token::Day) => SaveInterval::Every1Day,
-                (12, Token::Hour) => SaveInterval::Every12Hour,
-                (8, Token::Hour) => SaveInterval::Every8Hour,
-                (6, Token::Hour) => SaveInterval::Every6Hour,
-                (4, Token::Hour) => SaveInterval::Every4Hour,
-                (2, Token::Hour) => SaveInterval::Every2Hour,
-                (1, Token::Hour) => SaveInterval::Every1Hour,
-                (30, Token::Minute) => SaveInterval::Every30Minute,
-                (20, Token::Minute) => SaveInterval::Every20Minute,
-                (15, Token::Minute) => SaveInterval::Every15Minute,
-                (10, Token::Minute) => SaveInterval::Every10Minute,
-                (5, Token::Minute) => SaveInterval::Every5Minute,
                 (_, Token::Every) => return Err(Error::UnexpectedToken("every".to_owned())),
                 (_, Token::Half) => return Err(Error::UnexpectedToken("half".to_owned())),
                 (_, Token::Number(_)) => return Err(Error::UnexpectedToken(String::new())),
-                (n, token) => return Err(Error::Unsupported(format!("{} {}", n, token))),
+                (n, token) => {
+                    return Err(Error::Unsupported {
+                        interval: format!("{} {}", n, token),
+                        hint: nearest(n, token, named_counts(token).iter().copied()),
+                    });
+                }
+            }
+        };
+
+        match self.parse_token()? {
+            None => {}
+            Some(t) => return Err(Error::UnexpectedToken(t.to_string())),
+        }
+
+        Ok(interval)
+    }
+
+    /// parses the rest of compound durations like `1h30m` and returns total minutes.
+    /// None if the duration is not compound.
+    fn parse_compound(&mut self, n: u32, unit: Token) -> Result<Option<u32>, Error> {
+        fn to_minutes(n: u32, unit: Token) -> Result<u32, Error> {
+            match unit {
+                Token::Hour => n.checked_mul(60).ok_or(Error::NumberOverflow),
+                Token::Minute => Ok(n),
+                token => Err(Error::UnexpectedToken(token.to_string())),
             }
+        }
+
+        let mut minutes = to_minutes(n, unit)?;
+        let mut compound = false;
+        while let Some(t) = self.parse_token()? {
+            let Token::Number(n) = t else {
+                return Err(Error::UnexpectedToken(t.to_string()));
+            };
+            let unit = self
+                .parse_token()?
+                .ok_or_else(|| Error::UnexpectedToken(n.to_string()))?;
+            minutes = minutes
+                .checked_add(to_minutes(n, unit)?)
+                .ok_or(Error::NumberOverflow)?;
+            compound = true;
+        }
+        Ok(compound.then_some(minutes))
+    }
+
+    fn parse_age(mut self) -> Result<MaxAge, Error> {
+        let mut t = self.parse_token()?.ok_or(Error::Empty)?;
+        let n = if let Token::Number(n) = t {
+            t = self
+                .parse_token()?
+                .ok_or_else(|| Error::UnexpectedToken(n.to_string()))?;
+            n
+        } else {
+            1
+        };
+        let age = match t {
+            Token::Minute => MaxAge::Minutes(n),
+            Token::Hour => MaxAge::Hours(n),
+            Token::Day => MaxAge::Days(n),
+            Token::Week => MaxAge::Weeks(n),
+            Token::Fortnight => MaxAge::Weeks(n.checked_mul(2).ok_or(Error::NumberOverflow)?),
+            Token::Month => MaxAge::Months(n),
+            Token::Year => MaxAge::Years(n),
+            Token::Number(_) => return Err(Error::UnexpectedToken(String::new())),
+            token => return Err(Error::UnexpectedToken(token.to_string())),
         };
 
         match self.parse_token()? {
@@ -471,7 +1506,7 @@ impl<'a> Parser<'a> {
             Some(t) => return Err(Error::UnexpectedToken(t.to_string())),
         }
 
-        return Ok(interval);
+        Ok(age)
     }
 }
 
@@ -484,6 +1519,35 @@ mod parse_test {
         str.parse().unwrap()
     }
 
+    #[test]
+    fn spellings_round_trip() {
+        let spellings = interval_spellings();
+        for (interval, spellings) in &spellings {
+            for spelling in spellings {
+                assert_eq!(parse(spelling), *interval, "{}", spelling);
+                if !spelling.starts_with("every ") {
+                    assert_eq!(parse(&format!("every {}", spelling)), *interval);
+                }
+            }
+        }
+        // all variants with their own name are listed
+        assert_eq!(spellings.len(), 20);
+        assert_eq!(
+            spellings.iter().find(|(x, _)| *x == Every6Month).unwrap().1[..2],
+            ["every 6 month", "6 monthly"]
+        );
+        for example in OTHER_INTERVAL_EXAMPLES {
+            assert!(matches!(parse(example), EveryNMinutes(_) | EveryNHours(_)));
+        }
+        let list = list_intervals();
+        assert!(
+            list.contains("Every6Month: 'every 6 month', '6 monthly'"),
+            "{}",
+            list
+        );
+        assert!(list.contains("'half year'"), "{}", list);
+    }
+
     #[test]
     fn formal() {
         assert_eq!(parse("every 1 year"), Every1Year);
@@ -628,9 +1692,268 @@ mod parse_test {
         assert_eq!(parse("half-hourly"), Every30Minute);
     }
 
+    #[test]
+    fn every_n() {
+        assert_eq!(parse("every 3 hours"), EveryNHours(3));
+        assert_eq!(parse("every 45 minutes"), EveryNMinutes(45));
+        assert_eq!(parse("every 90 minutes"), EveryNMinutes(90));
+        assert_eq!(parse("every 480 minutes"), Every8Hour);
+        assert_eq!(parse("every 60 minutes"), Every1Hour);
+        assert_eq!(parse("every 120 minutes"), Every2Hour);
+        assert_eq!(parse("every 180 minutes"), EveryNHours(3));
+        assert_eq!(parse("every 24 hours"), Every1Day);
+        assert_eq!(parse("every 1440 minutes"), Every1Day);
+    }
+
+    #[test]
+    fn biweekly() {
+        assert_eq!(parse("every 2 week"), Every2Week);
+        assert_eq!(parse("2 weeks"), Every2Week);
+        assert_eq!(parse("2-weekly"), Every2Week);
+        assert_eq!(parse("biweekly"), Every2Week);
+        assert_eq!(parse("fortnightly"), Every2Week);
+        assert_eq!(parse("every fortnight"), Every2Week);
+        assert_eq!(parse(&Every2Week.to_string()), Every2Week);
+        assert_eq!(
+            "3 weeks".parse::<SaveInterval>().unwrap_err().to_string(),
+            "unsupported interval: \"3 week\". try 2 week"
+        );
+        assert_eq!(
+            "2 fortnights"
+                .parse::<SaveInterval>()
+                .unwrap_err()
+                .to_string(),
+            "unsupported interval: \"2 fortnight\". try 1 fortnight"
+        );
+    }
+
+    #[test]
+    fn frequent() {
+        assert_eq!(parse("every 1 minute"), EveryNMinutes(1));
+        assert_eq!(parse("2 minutes"), EveryNMinutes(2));
+        assert_eq!(parse("3 minutely"), EveryNMinutes(3));
+        assert_eq!(parse("minutely"), EveryNMinutes(1));
+        assert_eq!(parse("1 minute").frequent_minutes(), Some(1));
+        assert_eq!(parse("4 minutes").frequent_minutes(), Some(4));
+        assert_eq!(parse("5 minutes").frequent_minutes(), None);
+        assert_eq!(parse("45 minutes").frequent_minutes(), None);
+        assert_eq!(parse(&EveryNMinutes(2).to_string()), EveryNMinutes(2));
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(parse("5m"), Every5Minute);
+        assert_eq!(parse("5min"), Every5Minute);
+        assert_eq!(parse("5mins"), Every5Minute);
+        assert_eq!(parse("5 MIN"), Every5Minute);
+        assert_eq!(parse("5 Minutes"), Every5Minute);
+        assert_eq!(parse("2hr"), Every2Hour);
+        assert_eq!(parse("2hrs"), Every2Hour);
+        assert_eq!(parse("2H"), Every2Hour);
+        assert_eq!(parse("Daily"), Every1Day);
+        assert_eq!(parse("6month"), Every6Month);
+        assert_eq!(parse("6mo"), Every6Month);
+        assert_eq!(parse("6 MONTHS"), Every6Month);
+
+        let err = "5M".parse::<SaveInterval>().unwrap_err();
+        assert_eq!(err, Error::AmbiguousUnit("M".to_owned()));
+        assert!(
+            err.to_string().contains("\"m\" or \"min\" for minutes"),
+            "{}",
+            err
+        );
+        assert!(
+            err.to_string().contains("\"mo\" or \"month\" for months"),
+            "{}",
+            err
+        );
+        assert_eq!(
+            "6M".parse::<MaxAge>().unwrap_err(),
+            Error::AmbiguousUnit("M".to_owned())
+        );
+    }
+
+    #[test]
+    fn compound() {
+        assert_eq!(parse("1h30m"), EveryNMinutes(90));
+        assert_eq!(parse("90 min"), EveryNMinutes(90));
+        assert_eq!(parse("1 hour 30 minute"), EveryNMinutes(90));
+        assert_eq!(parse("every 1 hour 30 minutes"), EveryNMinutes(90));
+        assert_eq!(parse("1h 30m"), EveryNMinutes(90));
+        assert_eq!(parse("30m 30m"), Every1Hour);
+        assert_eq!(parse("2h 0m"), Every2Hour);
+        assert_eq!(parse("1h 30m 30m"), Every2Hour);
+
+        fn err(str: &str) -> Error {
+            str.parse::<SaveInterval>().unwrap_err()
+        }
+        assert_eq!(
+            err("1h10m").to_string(),
+            "unsupported interval: \"70 minute\". try 60 or 80 minute"
+        );
+        assert_eq!(err("1h30"), Error::UnexpectedToken("30".to_owned()));
+        assert_eq!(err("1h m"), Error::UnexpectedToken("minute".to_owned()));
+        assert_eq!(err("1h 1d"), Error::UnexpectedToken("day".to_owned()));
+        assert_eq!(err("1d 1h"), Error::UnexpectedToken("1".to_owned()));
+    }
+
+    #[test]
+    fn unsupported_hint() {
+        fn err(str: &str) -> String {
+            str.parse::<SaveInterval>().unwrap_err().to_string()
+        }
+        assert_eq!(err("2 days"), "unsupported interval: \"2 day\". try 1 day");
+        assert_eq!(
+            err("5 months"),
+            "unsupported interval: \"5 month\". try 4 or 6 month"
+        );
+        assert_eq!(
+            err("every 2000 minutes"),
+            "unsupported interval: \"2000 minute\". try 1440 minute"
+        );
+        assert_eq!(
+            err("half month"),
+            "unsupported interval: \"half month\". try half year, half day, or half hour"
+        );
+        assert_eq!(
+            "7 minutes".parse::<Tick>().unwrap_err().to_string(),
+            "unsupported interval: \"7 minutes\". try 6 or 8 minute"
+        );
+        assert_eq!(
+            "1 day".parse::<Tick>().unwrap_err().to_string(),
+            "unsupported interval: \"1 day\""
+        );
+    }
+
+    #[test]
+    fn every_n_unsupported() {
+        fn err(str: &str) -> Error {
+            str.parse::<SaveInterval>().unwrap_err()
+        }
+        assert_eq!(
+            err("every 7 minutes").to_string(),
+            "unsupported interval: \"7 minute\". try 5 or 10 minute"
+        );
+        assert_eq!(
+            err("every 35 minutes").to_string(),
+            "unsupported interval: \"35 minute\". try 30 or 40 minute"
+        );
+        assert_eq!(
+            err("every 0 minutes").to_string(),
+            "unsupported interval: \"0 minute\". try 5 minute"
+        );
+        assert_eq!(
+            err("every 5 hours").to_string(),
+            "unsupported interval: \"5 hour\". try 4 or 6 hour"
+        );
+        assert_eq!(
+            err("every 48 hours").to_string(),
+            "unsupported interval: \"48 hour\". try 24 hour"
+        );
+        assert_eq!(
+            err("every 0 hours").to_string(),
+            "unsupported interval: \"0 hour\". try 1 hour"
+        );
+    }
+
+    #[test]
+    fn every_n_constructors() {
+        assert_eq!(
+            SaveInterval::every_n_minutes(45).unwrap(),
+            EveryNMinutes(45)
+        );
+        assert_eq!(SaveInterval::every_n_minutes(120).unwrap(), Every2Hour);
+        assert_eq!(SaveInterval::every_n_hours(3).unwrap(), EveryNHours(3));
+        assert_eq!(SaveInterval::every_n_hours(24).unwrap(), Every1Day);
+        for n in [0, 7, 35, 1441] {
+            assert!(SaveInterval::every_n_minutes(n).is_err(), "{}", n);
+        }
+        for n in [0, 5, 48] {
+            assert!(SaveInterval::every_n_hours(n).is_err(), "{}", n);
+        }
+    }
+
     #[test]
     fn trim() {
         assert_eq!(parse("   every 1 year  "), Every1Year);
         assert_eq!(parse(" - every 1 year -"), Every1Year);
     }
 }
+
+#[cfg(test)]
+mod max_age_test {
+    use super::*;
+
+    fn parse(str: &str) -> MaxAge {
+        str.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_age() {
+        assert_eq!(parse("30 days"), MaxAge::Days(30));
+        assert_eq!(parse("2 weeks"), MaxAge::Weeks(2));
+        assert_eq!(parse("12h"), MaxAge::Hours(12));
+        assert_eq!(parse("90 minutes"), MaxAge::Minutes(90));
+        assert_eq!(parse("6 months"), MaxAge::Months(6));
+        assert_eq!(parse("year"), MaxAge::Years(1));
+        assert!("".parse::<MaxAge>().is_err());
+        assert!("30".parse::<MaxAge>().is_err());
+        assert!("every 30 days".parse::<MaxAge>().is_err());
+        assert!("30 days 1 hour".parse::<MaxAge>().is_err());
+    }
+
+    #[test]
+    fn oldest() {
+        let now = NaiveDate::from_ymd_opt(2021, 3, 31)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let at = |y, m, d, h| {
+            NaiveDate::from_ymd_opt(y, m, d)
+                .unwrap()
+                .and_hms_opt(h, 0, 0)
+                .unwrap()
+        };
+        assert_eq!(MaxAge::Hours(12).oldest(&now), at(2021, 3, 31, 0));
+        assert_eq!(MaxAge::Days(30).oldest(&now), at(2021, 3, 1, 12));
+        assert_eq!(MaxAge::Weeks(1).oldest(&now), at(2021, 3, 24, 12));
+        assert_eq!(MaxAge::Months(1).oldest(&now), at(2021, 2, 28, 12));
+        assert_eq!(MaxAge::Years(1).oldest(&now), at(2020, 3, 31, 12));
+    }
+
+    #[test]
+    fn oldest_overflow() {
+        let now = NaiveDate::from_ymd_opt(2021, 3, 31)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        for age in [
+            "100000000 days",
+            "4294967295 weeks",
+            "4294967295 hours",
+            "4294967295 years",
+        ] {
+            let age = age.parse::<MaxAge>().unwrap();
+            assert_eq!(age.oldest(&now), NaiveDateTime::MIN, "{:?}", age);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tick_test {
+    use super::*;
+
+    #[test]
+    fn parse_tick() {
+        assert_eq!("5 minutes".parse::<Tick>().unwrap().seconds(), 300);
+        assert_eq!("1 minute".parse::<Tick>().unwrap().seconds(), 60);
+        assert_eq!("2m".parse::<Tick>().unwrap().seconds(), 120);
+        assert_eq!("1 hour".parse::<Tick>().unwrap().seconds(), 3600);
+        assert_eq!(Tick::default().seconds(), 300);
+        // ticks not dividing a day and not fixed length are rejected
+        assert!("7 minutes".parse::<Tick>().is_err());
+        assert!("0 minutes".parse::<Tick>().is_err());
+        assert!("1 day".parse::<Tick>().is_err());
+        assert!("1 month".parse::<Tick>().is_err());
+    }
+}
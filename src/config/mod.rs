@@ -1,18 +1,36 @@
-mod interval;
+mod active_hours;
+mod filter;
+pub mod interval;
+mod retention;
 
-use anyhow::{bail, Error, Result};
+use crate::files_txt::BACKUP_NAME_FORMAT;
+use crate::tar::ReadOptions;
+use anyhow::{anyhow, bail, Context as _, Error, Result};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use log::trace;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::io;
+use std::io::{BufReader, Read, Write};
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
-pub(crate) use self::interval::SaveInterval;
+pub(crate) use self::active_hours::ActiveHours;
+pub(crate) use self::filter::PathFilter;
+pub use self::interval::SaveInterval;
+pub(crate) use self::interval::{MaxAge, Tick};
+pub(crate) use self::retention::Retention;
 
-async fn read_config_file() -> io::Result<Vec<u8>> {
-    let mut config_file = File::open("config.yml").await?;
+async fn read_config_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut config_file = File::open(path).await?;
     let config_file_len = config_file.metadata().await?.len();
     let mut config_file_bytes = Vec::with_capacity(config_file_len as usize);
     config_file.read_to_end(&mut config_file_bytes).await?;
@@ -20,11 +38,18 @@ async fn read_config_file() -> io::Result<Vec<u8>> {
     Ok(config_file_bytes)
 }
 
-pub(crate) async fn load_config() -> Result<Box<Config>> {
-    trace!("loading config.yml to memory");
-    let config_file_bytes = match read_config_file().await {
+/// loads config from `path`, or `config.yml` in the current directory if not specified.
+///
+/// GAME_CONFIG_YAML is used only if `path` is not specified and there's no `config.yml`.
+pub(crate) async fn load_config(
+    path: Option<&Path>,
+    overrides: &DirOverrides,
+) -> Result<Box<Config>> {
+    let config_path = path.unwrap_or_else(|| Path::new("config.yml"));
+    trace!("loading {} to memory", config_path.display());
+    let config_file_bytes = match read_config_file(config_path).await {
         Ok(v) => v,
-        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && path.is_none() => {
             trace!("config.yml not found. trying GAME_CONFIG_YAML");
             match std::env::var("GAME_CONFIG_YAML") {
                 Ok(v) => v.into_bytes(),
@@ -34,114 +59,1159 @@ pub(crate) async fn load_config() -> Result<Box<Config>> {
                 Err(std::env::VarError::NotUnicode(_)) => bail!("invalid GAME_CONFIG_YAML found"),
             }
         }
-        Err(e) => bail!(e),
+        Err(e) => return Err(Error::new(e).context(format!("reading {}", config_path.display()))),
     };
-    trace!("parsing config.yml");
-    let config_file: ConfigFile = serde_yaml::from_slice(&config_file_bytes)?;
+    parse_config_as(
+        &config_file_bytes,
+        ConfigFormat::from_path(config_path)?,
+        overrides,
+    )
+}
 
-    trace!("verifying config.yml");
-    let preset = config_file.preset;
-    let rcon_address: Vec<SocketAddr> = match config_file.rcon_address {
-        Some(addr) => addr.to_socket_addrs()?.collect(),
-        None => match config_file.preset {
-            None if config_file.commands_before.is_none()
-                && config_file.commands_after.is_none() =>
-            {
-                vec![]
+/// save_dir and backup_dir given on the command line.
+/// they take precedence over environment variables and the config file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DirOverrides {
+    pub(crate) save_dir: Option<PathBuf>,
+    pub(crate) backup_dir: Option<PathBuf>,
+}
+
+/// the format of config file
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// detects the format from the extension. files without extension are yaml
+    pub(crate) fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|x| x.to_str()) {
+            None | Some("yml" | "yaml") => Ok(Self::Yaml),
+            Some("toml") => Ok(Self::Toml),
+            Some("json") => Ok(Self::Json),
+            Some(extension) => bail!("unknown config file extension: {}", extension),
+        }
+    }
+
+    /// the name of config file embedded in backups
+    fn file_name(self) -> &'static str {
+        match self {
+            Self::Yaml => "config.yml",
+            Self::Toml => "config.toml",
+            Self::Json => "config.json",
+        }
+    }
+
+    fn deserialize(self, config_file_bytes: &[u8]) -> Result<ConfigFile> {
+        Ok(match self {
+            Self::Yaml => serde_yaml::from_slice(config_file_bytes)?,
+            Self::Toml => toml::from_slice(config_file_bytes)?,
+            Self::Json => serde_json::from_slice(config_file_bytes)?,
+        })
+    }
+
+    /// the config file with passwords and webhook urls replaced to be embedded in backups.
+    /// comments and formatting are not kept
+    fn redact(self, config_file_bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut value: serde_json::Value = match self {
+            Self::Yaml => serde_yaml::from_slice(config_file_bytes)?,
+            Self::Toml => toml::from_slice(config_file_bytes)?,
+            Self::Json => serde_json::from_slice(config_file_bytes)?,
+        };
+        redact_secrets(&mut value);
+        Ok(match self {
+            Self::Yaml => serde_yaml::to_vec(&value)?,
+            // converted to toml::Value first to emit tables after other values
+            Self::Toml => toml::to_vec(&toml::Value::try_from(value)?)?,
+            Self::Json => serde_json::to_vec_pretty(&value)?,
+        })
+    }
+}
+
+/// the placeholder of secrets in the config file embedded in backups
+const REDACTED: &str = "<redacted>";
+
+/// replaces values of secret keys anywhere in the config file
+fn redact_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match key.as_str() {
+                    "rcon_password" | "password" | "discord_webhook" => {
+                        *value = REDACTED.into();
+                    }
+                    "webhook" => {
+                        if let Some(url) = value.get_mut("url") {
+                            *url = REDACTED.into();
+                        }
+                    }
+                    _ => redact_secrets(value),
+                }
             }
-            Some(GamePreset::Minecraft) => "localhost:25575".to_socket_addrs().unwrap().collect(),
-            None => {
-                return Err(Error::msg(
-                    "rcon_address is required if no preset are defined",
-                ))
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
+}
+
+/// parses yaml config
+#[cfg(test)]
+pub(crate) fn parse_config(config_file_bytes: &[u8]) -> Result<Box<Config>> {
+    parse_config_as(
+        config_file_bytes,
+        ConfigFormat::Yaml,
+        &DirOverrides::default(),
+    )
+}
+
+fn parse_config_as(
+    config_file_bytes: &[u8],
+    format: ConfigFormat,
+    overrides: &DirOverrides,
+) -> Result<Box<Config>> {
+    trace!("parsing config file as {:?}", format);
+    let config_file = format.deserialize(config_file_bytes)?;
+
+    trace!("verifying config file");
+    let preset = config_file.preset;
+    let has_commands = config_file.commands_before.is_some()
+        || config_file.commands_after.is_some()
+        || config_file
+            .backups
+            .iter()
+            .any(|x| x.commands_before.is_some() || x.commands_after.is_some());
+    if matches!(preset, Some(GamePreset::Filesystem))
+        && (config_file.rcon_address.is_some()
+            || !config_file.rcon_servers.is_empty()
+            || config_file.rcon_ssh_tunnel.is_some()
+            || has_commands)
+    {
+        bail!("rcon_address, rcon_servers, rcon_ssh_tunnel, commands_before, and commands_after can't be used with filesystem preset")
+    }
+    let timezone = match &config_file.timezone {
+        Some(timezone) => timezone
+            .parse::<Tz>()
+            .map_err(|e| anyhow!("invalid timezone {:?}: {}", timezone, e))?,
+        None => Tz::UTC,
+    };
+    // the default address of the preset is used only if no rcon server is specified
+    let rcon_address = match config_file.rcon_address {
+        Some(addr) => Some(addr),
+        None if config_file.rcon_servers.is_empty() => config_file
+            .preset
+            .and_then(GamePreset::default_rcon_address)
+            .map(str::to_owned),
+        None => None,
+    };
+    let rcon_ssh_tunnel = config_file
+        .rcon_ssh_tunnel
+        .map(SshTunnelFile::into_tunnel)
+        .transpose()?;
+    let mut rcon_servers = Vec::new();
+    if let Some(address) = rcon_address {
+        let password = match (config_file.rcon_password, config_file.rcon_password_file) {
+            (Some(_), Some(_)) => {
+                bail!("rcon_password and rcon_password_file can't be used together")
             }
-        },
+            (password, None) => password.unwrap_or_default(),
+            (None, Some(file)) => read_password_file(&file)?,
+        };
+        let ssh_tunnel = rcon_ssh_tunnel.clone();
+        rcon_servers.push(RconServer::new(address, password, preset, ssh_tunnel)?);
+    }
+    for server in config_file.rcon_servers {
+        if matches!(server.preset, Some(GamePreset::Filesystem)) {
+            bail!(
+                "filesystem preset can't be used for rcon server {}",
+                server.address
+            )
+        }
+        let server_preset = server.preset.or(preset);
+        let password = match (server.password, server.password_file) {
+            (Some(_), Some(_)) => bail!(
+                "password and password_file of rcon server {} can't be used together",
+                server.address
+            ),
+            (password, None) => password.unwrap_or_default(),
+            (None, Some(file)) => read_password_file(&file)?,
+        };
+        let ssh_tunnel = match server.ssh_tunnel {
+            Some(tunnel) => Some(tunnel.into_tunnel()?),
+            None => rcon_ssh_tunnel.clone(),
+        };
+        rcon_servers.push(RconServer::new(
+            server.address,
+            password,
+            server_preset,
+            ssh_tunnel,
+        )?);
+    }
+    if rcon_servers.is_empty() && has_commands {
+        bail!("rcon_address is required if commands are specified without preset with rcon")
+    }
+    let rcon_retry = RconRetry::from_file(config_file.rcon_retry)?;
+    let rcon_timeout = match config_file.rcon_timeout {
+        Some(secs) => Duration::try_from_secs_f64(secs)
+            .ok()
+            .filter(|x| !x.is_zero())
+            .ok_or_else(|| anyhow!("invalid rcon_timeout: {}", secs))?,
+        None => Duration::from_secs(30),
+    };
+    let save_settle_delay = match config_file.save_settle_delay {
+        Some(secs) => Duration::try_from_secs_f64(secs)
+            .map_err(|_| anyhow!("invalid save_settle_delay: {}", secs))?,
+        None => Duration::ZERO,
     };
-    let rcon_password = config_file.rcon_password;
+    if config_file.max_parallel_tars == 0 {
+        bail!("max_parallel_tars must not be 0");
+    }
     let commands_before = command_lines(
-        config_file.commands_before.as_ref().map(String::as_str),
+        config_file.commands_before.as_deref(),
         config_file.preset,
         true,
     );
     let commands_after = command_lines(
-        config_file.commands_after.as_ref().map(String::as_str),
+        config_file.commands_after.as_deref(),
         config_file.preset,
         false,
     );
-    let backup_dir = config_file
+    let setting_commands_before = config_file
+        .backups
+        .iter()
+        .filter_map(|x| x.commands_before.as_deref())
+        .flat_map(str::lines)
+        .collect::<Vec<_>>();
+    if let Some(command) = config_file.expect_response.keys().find(|command| {
+        !commands_before.contains(command) && !setting_commands_before.contains(&command.as_str())
+    }) {
+        bail!(
+            "expect_response for {:?} is specified but it's not in commands_before",
+            command
+        )
+    }
+    // the command line, environment variables, and the config file in the order of precedence
+    let backup_dir = overrides
         .backup_dir
+        .clone()
         .or_else(|| std::env::var_os("BACKUP_DIR").map(PathBuf::from))
+        .or(config_file.backup_dir)
         .ok_or_else(|| Error::msg("backup_dir not found"))?;
-    let save_dir = config_file
+    let save_dirs = overrides
         .save_dir
-        .or_else(|| std::env::var_os("SAVE_DIR").map(PathBuf::from))
-        .ok_or_else(|| Error::msg("save_dir not found"))?;
-    let backups = config_file
+        .clone()
+        .map(SaveDirsFile::Single)
+        .or_else(|| std::env::var_os("SAVE_DIR").map(|x| SaveDirsFile::Single(x.into())))
+        .or(config_file.save_dir)
+        .map(save_dirs_from_file)
+        .transpose()?;
+    let included_config = config_file
+        .backups
+        .iter()
+        .any(|x| x.include_config)
+        .then(|| -> Result<_> {
+            Ok(IncludedConfig {
+                name: format.file_name(),
+                content: Arc::from(format.redact(config_file_bytes)?),
+            })
+        })
+        .transpose()
+        .context("redacting config file to include in backups")?;
+    let mut backups = config_file
         .backups
         .into_iter()
-        .map(|backup| BackupSetting {
-            directory: backup_dir.join(&backup.name),
-            name: backup.name,
-            max_backups: backup.max_backups,
-            interval: backup.interval,
-            backup_mode: backup.backup_mode,
+        .map(|backup| {
+            let save_dirs = match backup.save_dir {
+                Some(save_dirs) => save_dirs_from_file(save_dirs)?,
+                None => save_dirs
+                    .clone()
+                    .ok_or_else(|| Error::msg("save_dir not found"))?,
+            };
+            let filter = PathFilter::new(backup.include, backup.exclude)
+                .with_context(|| format!("compiling include/exclude of {}", backup.name))?;
+            let sftp = backup
+                .sftp
+                .map(SftpFile::into_sftp)
+                .transpose()
+                .with_context(|| format!("sftp of {}", backup.name))?;
+            let encryption = backup
+                .encryption
+                .map(EncryptionFile::into_encryption)
+                .transpose()
+                .with_context(|| format!("encryption of {}", backup.name))?;
+            let max_backups = match backup.max_backups {
+                Some(max_backups) => max_backups,
+                // only retention or max_age limits backups
+                None if backup.retention.is_some() || backup.max_age.is_some() => usize::MAX,
+                None => bail!(
+                    "max_backups, retention, or max_age of backup setting {} is required",
+                    backup.name
+                ),
+            };
+            Ok(BackupSetting {
+                directory: backup_dir.join(&backup.name),
+                name: backup.name,
+                save_dirs,
+                filter,
+                retry_on_change: backup.retry_on_change,
+                preserve_permissions: backup.preserve_permissions,
+                follow_symlinks: backup.follow_symlinks,
+                copy_buffer_size: backup.copy_buffer_size,
+                io_rate_limit: backup.io_rate_limit,
+                max_backups,
+                retention: backup.retention,
+                max_age: backup.max_age,
+                min_free_bytes: backup.min_free_bytes,
+                name_format: decorate_name_format(
+                    &backup.name_prefix,
+                    &backup.name_format,
+                    &backup.name_suffix,
+                ),
+                interval: backup.interval,
+                active_hours: backup.active_hours,
+                min_spacing: backup.min_spacing,
+                backup_mode: backup.backup_mode,
+                compression: backup.compression,
+                post_backup_command: backup
+                    .post_backup_command
+                    .map(CommandFile::into_args)
+                    .unwrap_or_default(),
+                commands_before: backup
+                    .commands_before
+                    .map(|x| command_lines(Some(&x), preset, true)),
+                commands_after: backup
+                    .commands_after
+                    .map(|x| command_lines(Some(&x), preset, false)),
+                sftp,
+                encryption,
+                included_config: included_config.clone().filter(|_| backup.include_config),
+                skip_unchanged: backup.skip_unchanged,
+            })
         })
-        .collect();
+        .collect::<Result<Vec<_>>>()?;
+    if config_file.skip_backup_dir {
+        for backup in &mut backups {
+            exclude_backup_dir(backup, &backup_dir)?;
+        }
+    }
+    verify_backups(&backups)?;
+    for backup in &backups {
+        if let Some(minutes) = backup.interval.frequent_minutes() {
+            if !config_file.allow_frequent {
+                bail!(
+                    "interval of backup setting {} is shorter than 5 minutes. set allow_frequent to use it",
+                    backup.name
+                )
+            }
+            if !(minutes * 60).is_multiple_of(config_file.tick.seconds()) {
+                bail!(
+                    "interval of backup setting {} must be multiple of tick",
+                    backup.name
+                )
+            }
+        }
+    }
 
     Ok(Box::new(Config {
         preset,
-        rcon_address,
-        rcon_password,
+        rcon_servers,
+        rcon_retry,
+        rcon_timeout,
+        on_rcon_timeout: config_file.on_rcon_timeout,
+        on_rcon_failure: config_file.on_rcon_failure,
         commands_before,
         commands_after,
-        save_dir,
+        expect_response: config_file.expect_response,
+        save_settle_delay,
+        timezone,
+        discord_webhook: config_file.discord_webhook,
+        notify_on: config_file.notify_on,
+        webhook: config_file.webhook.map(|webhook| Webhook {
+            url: webhook.url,
+            notify_on: webhook.notify_on,
+            retries: webhook.retries,
+            retry_delay: Duration::from_secs(1),
+        }),
+        max_parallel_tars: config_file.max_parallel_tars,
+        tick: config_file.tick,
+        catch_up: config_file.catch_up,
+        health_address: config_file.health_address,
+        status_file: config_file.status_file,
         backups,
     }))
 }
 
+fn save_dirs_from_file(file: SaveDirsFile) -> Result<Vec<SaveDir>> {
+    let save_dirs = match file {
+        SaveDirsFile::Single(path) => vec![SaveDir {
+            path,
+            prefix: PathBuf::new(),
+        }],
+        SaveDirsFile::Multiple(list) => list
+            .into_iter()
+            .map(|dir| {
+                let (path, prefix) = match dir {
+                    SaveDirFile::Path(path) => (path, None),
+                    SaveDirFile::Detailed { path, prefix } => (path, prefix),
+                };
+                let prefix = match prefix {
+                    Some(prefix) => prefix,
+                    None => path.file_name().map(PathBuf::from).ok_or_else(|| {
+                        anyhow!("cannot infer prefix for save_dir {}", path.display())
+                    })?,
+                };
+                Ok(SaveDir { path, prefix })
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+    verify_save_dirs(&save_dirs)?;
+    Ok(save_dirs)
+}
+
+fn verify_save_dirs(save_dirs: &[SaveDir]) -> Result<()> {
+    if save_dirs.is_empty() {
+        bail!("save_dir must not be empty")
+    }
+    for (i, a) in save_dirs.iter().enumerate() {
+        if !a.path.is_dir() && !a.path.is_file() {
+            bail!("save_dir {} is not a directory or a file", a.path.display())
+        }
+        for b in &save_dirs[i + 1..] {
+            if a.prefix.starts_with(&b.prefix) || b.prefix.starts_with(&a.prefix) {
+                bail!(
+                    "prefix of save_dir {} and {} conflicts: {:?} and {:?}",
+                    a.path.display(),
+                    b.path.display(),
+                    a.prefix,
+                    b.prefix,
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+fn verify_backups(backups: &[BackupSetting]) -> Result<()> {
+    for (i, a) in backups.iter().enumerate() {
+        a.validate()?;
+        if backups[..i].iter().any(|b| b.name == a.name) {
+            bail!(
+                "backup name {:?} is used by multiple backup settings. \
+                they would share a backup directory",
+                a.name
+            )
+        }
+    }
+    verify_not_overlapping(backups)
+}
+
+/// excludes `backup_dir` from the archive of the setting if it's in save directories.
+fn exclude_backup_dir(backup: &mut BackupSetting, backup_dir: &Path) -> Result<()> {
+    let backup_dir_path = canonicalize_lenient(backup_dir)
+        .with_context(|| format!("resolving backup_dir {}", backup_dir.display()))?;
+    for save_dir in &backup.save_dirs {
+        let save_path = save_dir
+            .path
+            .canonicalize()
+            .with_context(|| format!("resolving save_dir {}", save_dir.path.display()))?;
+        if let Ok(relative) = backup_dir_path.strip_prefix(&save_path) {
+            if relative == Path::new("") {
+                bail!(
+                    "backup_dir {} must not be save_dir {}",
+                    backup_dir.display(),
+                    save_dir.path.display()
+                )
+            }
+            backup.filter.exclude_path(save_dir.prefix.join(relative));
+        }
+    }
+    Ok(())
+}
+
+/// rejects save directories overlapping with local backup directories of any setting.
+/// backups in save directories would be backed up again and grow each time
+/// unless they are excluded from the archive.
+fn verify_not_overlapping(backups: &[BackupSetting]) -> Result<()> {
+    let mut directories = Vec::new();
+    for backup in backups.iter().filter(|x| x.sftp.is_none()) {
+        let directory = canonicalize_lenient(&backup.directory).with_context(|| {
+            format!("resolving backup directory {}", backup.directory.display())
+        })?;
+        directories.push((backup, directory));
+    }
+    for backup in backups {
+        for save_dir in &backup.save_dirs {
+            let save_path = save_dir
+                .path
+                .canonicalize()
+                .with_context(|| format!("resolving save_dir {}", save_dir.path.display()))?;
+            for (other, directory) in &directories {
+                if let Ok(relative) = directory.strip_prefix(&save_path) {
+                    if backup.filter.is_pruned(&save_dir.prefix.join(relative)) {
+                        continue;
+                    }
+                    bail!(
+                        "backup directory {} of {} is inside save_dir {} of {}. \
+                        backups would be backed up again",
+                        other.directory.display(),
+                        other.name,
+                        save_dir.path.display(),
+                        backup.name,
+                    )
+                }
+                if save_path.starts_with(directory) {
+                    bail!(
+                        "save_dir {} of {} is inside backup directory {} of {}",
+                        save_dir.path.display(),
+                        backup.name,
+                        other.directory.display(),
+                        other.name,
+                    )
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// canonicalizes the path which may not exist yet
+/// by canonicalizing the nearest existing ancestor.
+fn canonicalize_lenient(path: &Path) -> io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    let mut existing = path.as_path();
+    let mut rest = Vec::new();
+    loop {
+        // fails if not found or an ancestor is not a directory
+        if let Ok(canonical) = existing.canonicalize() {
+            return Ok(rest.iter().rev().fold(canonical, |x, y| x.join(y)));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            // `..` in paths not existing cannot be resolved
+            _ => return Ok(path.clone()),
+        }
+    }
+}
+
 fn command_lines(str: Option<&str>, preset: Option<GamePreset>, before: bool) -> Vec<String> {
     match str {
         None => match preset {
             Some(preset) => preset.get_default_command(before),
             None => Vec::new(),
         },
-        Some(s) if s.is_empty() => Vec::new(),
+        Some("") => Vec::new(),
         Some(s) => s.lines().map(str::to_owned).collect(),
     }
 }
 
 #[derive(Debug)]
-pub(crate) struct Config {
+pub struct Config {
     /// the preset. this may be used to help rcon connection
     pub(crate) preset: Option<GamePreset>,
-    /// the address to rcon server
-    pub(crate) rcon_address: Vec<SocketAddr>,
-    /// the password of rcon
-    pub(crate) rcon_password: String,
+    /// the rcon servers commands are sent to. empty if rcon is not used
+    pub(crate) rcon_servers: Vec<RconServer>,
+    /// how to retry rcon connection
+    pub(crate) rcon_retry: RconRetry,
+    /// the timeout of connecting to rcon and each command
+    pub(crate) rcon_timeout: Duration,
+    /// what to do if rcon timed out while sending commands
+    pub(crate) on_rcon_timeout: RconTimeoutAction,
+    /// what to do if sending commands to one of rcon servers failed
+    pub(crate) on_rcon_failure: RconFailureAction,
     /// the command will be ran before backup
     pub(crate) commands_before: Vec<String>,
     /// the command will be ran after backup
     pub(crate) commands_after: Vec<String>,
-    /// the path to save directory
-    pub(crate) save_dir: PathBuf,
+    /// the substring expected in responses of commands_before. the backup is aborted if absent
+    pub(crate) expect_response: BTreeMap<String, String>,
+    /// the time waited after commands_before before reading save directories
+    pub(crate) save_settle_delay: Duration,
+    /// the timezone intervals and names of backups are based on
+    pub(crate) timezone: Tz,
+    /// the url of discord webhook to notify backup results
+    pub(crate) discord_webhook: Option<String>,
+    /// which backup results are notified
+    pub(crate) notify_on: NotifyOn,
+    /// the webhook to post backup results in json
+    pub(crate) webhook: Option<Webhook>,
+    /// the maximum count of temporal tars built at once
+    pub(crate) max_parallel_tars: usize,
+    /// the period the scheduler checks intervals at
+    pub(crate) tick: Tick,
+    /// if true, backups missed while the process was not running are taken on startup
+    pub(crate) catch_up: bool,
+    /// the address the health endpoint is served at
+    pub(crate) health_address: Option<SocketAddr>,
+    /// the json file the status of each setting is written to after each step
+    pub(crate) status_file: Option<PathBuf>,
     /// verified BackupSettings
     pub(crate) backups: Vec<BackupSetting>,
 }
 
+impl Config {
+    /// loads config from `path`, or `config.yml` in the current directory if not specified.
+    /// the format is detected from the extension like the command line
+    pub async fn load(path: Option<&Path>) -> Result<Self> {
+        load_config(path, &DirOverrides::default())
+            .await
+            .map(|x| *x)
+    }
+
+    /// parses yaml config
+    pub fn from_yaml(yaml: &str) -> Result<Self> {
+        parse_config_as(
+            yaml.as_bytes(),
+            ConfigFormat::Yaml,
+            &DirOverrides::default(),
+        )
+        .map(|x| *x)
+    }
+
+    /// the backup settings in the order of the config
+    pub fn backups(&self) -> &[BackupSetting] {
+        &self.backups
+    }
+
+    /// the commands sent before reading save directories of the setting
+    pub(crate) fn commands_before_of<'a>(&'a self, setting: &'a BackupSetting) -> &'a [String] {
+        setting
+            .commands_before
+            .as_deref()
+            .unwrap_or(&self.commands_before)
+    }
+
+    /// the commands sent after reading save directories of the setting
+    pub(crate) fn commands_after_of<'a>(&'a self, setting: &'a BackupSetting) -> &'a [String] {
+        setting
+            .commands_after
+            .as_deref()
+            .unwrap_or(&self.commands_after)
+    }
+}
+
+/// exponential backoff of retrying rcon connection
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct RconRetry {
+    /// the delay before the first retry. doubled for each retry
+    pub(crate) base_delay: Duration,
+    /// the maximum delay between retries
+    pub(crate) max_delay: Duration,
+    /// the maximum count of attempts including the first one
+    pub(crate) max_attempts: u32,
+}
+
+impl Default for RconRetry {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: 5,
+        }
+    }
+}
+
+impl RconRetry {
+    fn from_file(file: RconRetryFile) -> Result<Self> {
+        fn seconds(name: &str, value: Option<f64>, default: Duration) -> Result<Duration> {
+            match value {
+                None => Ok(default),
+                Some(value) => Duration::try_from_secs_f64(value)
+                    .map_err(|e| anyhow!("invalid rcon_retry.{}: {}", name, e)),
+            }
+        }
+
+        let default = Self::default();
+        let max_attempts = file.max_attempts.unwrap_or(default.max_attempts);
+        if max_attempts == 0 {
+            bail!("rcon_retry.max_attempts must be at least 1")
+        }
+        Ok(Self {
+            base_delay: seconds("base_delay", file.base_delay, default.base_delay)?,
+            max_delay: seconds("max_delay", file.max_delay, default.max_delay)?,
+            max_attempts,
+        })
+    }
+
+    /// the delay before `retry`-th retry, 0-based
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        self.base_delay
+            .checked_mul(1 << retry.min(31))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RconTimeoutAction {
+    /// skip the backup
+    #[default]
+    Skip,
+    /// back up without remaining commands
+    Proceed,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RconFailureAction {
+    /// skip the backup if any of rcon servers failed
+    #[default]
+    Abort,
+    /// report the failure and back up anyway
+    Continue,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RconServer {
+    /// the address as written in the config, used in logs
+    pub(crate) name: String,
+    pub(crate) address: Vec<SocketAddr>,
+    pub(crate) password: String,
+    /// the preset quirks of the connection are chosen by
+    pub(crate) preset: Option<GamePreset>,
+    /// if specified, `name` is connected to from the ssh server
+    /// and `address` is empty since it may not be resolvable here
+    pub(crate) ssh_tunnel: Option<SshTunnel>,
+}
+
+impl RconServer {
+    fn new(
+        name: String,
+        password: String,
+        preset: Option<GamePreset>,
+        ssh_tunnel: Option<SshTunnel>,
+    ) -> Result<Self> {
+        let address = match ssh_tunnel {
+            Some(_) => {
+                tunnel_target(&name)?;
+                vec![]
+            }
+            None => name
+                .to_socket_addrs()
+                .with_context(|| format!("resolving rcon address {}", name))?
+                .collect(),
+        };
+        Ok(Self {
+            name,
+            address,
+            password,
+            preset,
+            ssh_tunnel,
+        })
+    }
+}
+
+/// splits the rcon address into the host and port to connect to from the ssh server
+pub(crate) fn tunnel_target(address: &str) -> Result<(&str, u16)> {
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("port of rcon address {} is not specified", address))?;
+    let port = port
+        .parse()
+        .with_context(|| format!("invalid port of rcon address {}", address))?;
+    // brackets of ipv6 addresses like [::1]:25575
+    let host = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host);
+    Ok((host, port))
+}
+
+/// reads the password from the file to keep secrets out of the config file.
+/// a trailing newline is trimmed since editors usually add it.
+fn read_password_file(file: &Path) -> Result<String> {
+    // trimmed in place not to leave copies of the password in memory
+    let mut password =
+        std::fs::read_to_string(file).with_context(|| format!("reading {}", file.display()))?;
+    if password.ends_with('\n') {
+        password.pop();
+        if password.ends_with('\r') {
+            password.pop();
+        }
+    }
+    Ok(password)
+}
+
+#[derive(Deserialize)]
+struct RconServerFile {
+    address: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    password_file: Option<PathBuf>,
+    /// defaults to the top-level preset
+    #[serde(default)]
+    preset: Option<GamePreset>,
+    /// defaults to the top-level rcon_ssh_tunnel
+    #[serde(default)]
+    ssh_tunnel: Option<SshTunnelFile>,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum NotifyOn {
+    /// notify all backup results
+    #[default]
+    All,
+    /// notify only failures
+    Failure,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Webhook {
+    /// the url to post to
+    pub(crate) url: String,
+    /// which backup results are posted
+    pub(crate) notify_on: WebhookNotifyOn,
+    /// the count of retries on server errors
+    pub(crate) retries: u32,
+    /// the delay between retries
+    pub(crate) retry_delay: Duration,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum WebhookNotifyOn {
+    Success,
+    Failure,
+    #[default]
+    Always,
+}
+
+#[derive(Deserialize)]
+struct WebhookFile {
+    url: String,
+    #[serde(default)]
+    notify_on: WebhookNotifyOn,
+    #[serde(default = "webhook_retries_default")]
+    retries: u32,
+}
+
+fn webhook_retries_default() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct SaveDir {
+    /// the path to save directory
+    pub(crate) path: PathBuf,
+    /// the path in backup archive the save directory will be placed at
+    pub(crate) prefix: PathBuf,
+}
+
+/// the config file embedded in backups
+#[derive(Clone)]
+pub(crate) struct IncludedConfig {
+    /// the file name in the reserved directory of archives
+    pub(crate) name: &'static str,
+    pub(crate) content: Arc<[u8]>,
+}
+
+impl std::fmt::Debug for IncludedConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the content is redacted but may still be large
+        f.debug_struct("IncludedConfig")
+            .field("name", &self.name)
+            .field("len", &self.content.len())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Sftp {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) user: String,
+    pub(crate) auth: SftpAuth,
+    /// the expected host key. known_hosts is used if not specified
+    pub(crate) host_key: Option<russh::keys::PublicKey>,
+    /// the directory on the server backups are saved to
+    pub(crate) remote_dir: String,
+}
+
+/// the ssh server rcon connections are forwarded through
+#[derive(Debug, Clone)]
+pub(crate) struct SshTunnel {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) user: String,
+    pub(crate) auth: SftpAuth,
+    /// the expected host key. known_hosts is used if not specified
+    pub(crate) host_key: Option<russh::keys::PublicKey>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum SftpAuth {
+    Password(String),
+    /// the path to private key file
+    PrivateKey(PathBuf),
+}
+
+#[derive(Debug)]
+pub(crate) enum Encryption {
+    /// encrypts to the age x25519 recipient
+    Recipient(age::x25519::Recipient),
+    /// encrypts with the passphrase
+    Passphrase(age::secrecy::SecretString),
+}
+
 #[derive(Debug)]
-pub(crate) struct BackupSetting {
+pub struct BackupSetting {
     /// the name of backup setting
     pub(crate) name: String,
     /// the path to backup directory
     pub(crate) directory: PathBuf,
+    /// the save directories to be backed up
+    pub(crate) save_dirs: Vec<SaveDir>,
+    /// the filter of files to be backed up
+    pub(crate) filter: PathFilter,
+    /// the count of retries to read a file changed while reading. 0 to not check changes
+    pub(crate) retry_on_change: usize,
+    /// records permissions and owners of files in archives if true
+    pub(crate) preserve_permissions: bool,
+    /// archives targets of symlinks if true. otherwise, symlinks are archived as is
+    pub(crate) follow_symlinks: bool,
+    /// the size of buffer to write archives in bytes
+    pub(crate) copy_buffer_size: usize,
+    /// the maximum bytes per second of reading save directories and writing backups
+    pub(crate) io_rate_limit: Option<u64>,
     /// the count of backups wil be kept
     pub(crate) max_backups: usize,
+    /// the policy to select backups to be kept. applied before max_backups
+    pub(crate) retention: Option<Retention>,
+    /// backups older than this are removed
+    pub(crate) max_age: Option<MaxAge>,
+    /// the oldest backups are removed until this bytes are free in the backup directory
+    pub(crate) min_free_bytes: Option<u64>,
+    /// the format of names of backups passed to [chrono::NaiveDateTime::format].
+    /// name_prefix and name_suffix are included as literals
+    pub(crate) name_format: String,
     /// the interval of backup.
-    /// It's not allowed to be less than 5 minutes.
+    /// It's not allowed to be less than 5 minutes unless allow_frequent is set.
     pub(crate) interval: SaveInterval,
-    #[cfg_attr(not(feature = "diff-save"), allow(dead_code))]
+    /// the local time backups are allowed in. None for any time
+    pub(crate) active_hours: Option<ActiveHours>,
+    /// the minimum time between backups. None to take backups once per interval
+    pub(crate) min_spacing: Option<MaxAge>,
     /// the mode of backup
     pub(crate) backup_mode: BackupMode,
+    /// the compression of backup archives
+    pub(crate) compression: Compression,
+    /// the command run after backup is saved. empty if not specified
+    pub(crate) post_backup_command: Vec<String>,
+    /// overrides commands_before of the config for this setting if specified
+    pub(crate) commands_before: Option<Vec<String>>,
+    /// overrides commands_after of the config for this setting if specified
+    pub(crate) commands_after: Option<Vec<String>>,
+    /// the sftp server backups are streamed to. None to save to local directory
+    pub(crate) sftp: Option<Sftp>,
+    /// the key backups are encrypted with. None to not encrypt
+    pub(crate) encryption: Option<Encryption>,
+    /// the config file embedded in backups. None if include_config is false
+    pub(crate) included_config: Option<IncludedConfig>,
+    /// if true, no backup is saved if save directories are unchanged since the last backup
+    pub(crate) skip_unchanged: bool,
+}
+
+impl BackupSetting {
+    /// the name of backup setting
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// the directory backups are saved to. not used if backups are streamed to sftp server
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// the interval of backup
+    pub fn interval(&self) -> SaveInterval {
+        self.interval
+    }
+
+    /// returns true if backups are allowed at the local time by active_hours
+    pub(crate) fn is_active_at(&self, time: &NaiveDateTime) -> bool {
+        self.active_hours
+            .is_none_or(|hours| hours.contains(time.time()))
+    }
+
+    /// returns true if a backup can be taken at `now` after the last backup at `last`.
+    /// without min_spacing, an interval must be passed since the last backup.
+    pub(crate) fn is_spaced(&self, last: &NaiveDateTime, now: &NaiveDateTime) -> bool {
+        match self.min_spacing {
+            Some(spacing) => *last <= spacing.oldest(now),
+            None => last < now && self.interval.is_passed(last, now),
+        }
+    }
+
+    /// options to read files of save directories
+    pub(crate) fn read_options(&self) -> ReadOptions {
+        ReadOptions {
+            retry_on_change: self.retry_on_change,
+            preserve_permissions: self.preserve_permissions,
+            follow_symlinks: self.follow_symlinks,
+        }
+    }
+
+    /// verifies values which can't be verified while parsing
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            bail!("name of backup setting must not be empty")
+        }
+        if self.max_backups == 0 {
+            bail!(
+                "max_backups of backup setting {} must be at least 1",
+                self.name
+            )
+        }
+        if let Some(retention) = &self.retention {
+            if retention.is_empty() {
+                bail!(
+                    "retention of backup setting {} must keep some backups",
+                    self.name
+                )
+            }
+            // removing backups in the middle breaks the chain of diffs
+            if self.backup_mode.is_diff() {
+                bail!(
+                    "retention of backup setting {} can't be used with {:?} backup_mode",
+                    self.name,
+                    self.backup_mode
+                )
+            }
+        }
+        verify_name_format(&self.name_format)
+            .with_context(|| format!("name_format of backup setting {}", self.name))?;
+        if self.copy_buffer_size == 0 {
+            bail!(
+                "copy_buffer_size of backup setting {} must not be 0",
+                self.name
+            )
+        }
+        if self.io_rate_limit == Some(0) {
+            bail!(
+                "io_rate_limit of backup setting {} must not be 0",
+                self.name
+            )
+        }
+        if self.backup_mode == BackupMode::Dedup && self.compression != Compression::None {
+            bail!(
+                "compression of backup setting {} must be none with dedup backup_mode",
+                self.name
+            )
+        }
+        if self.sftp.is_some() && self.backup_mode != BackupMode::Simple {
+            bail!(
+                "backup_mode of backup setting {} must be simple with sftp",
+                self.name
+            )
+        }
+        // free space of sftp servers is unknown
+        if self.sftp.is_some() && self.min_free_bytes.is_some() {
+            bail!(
+                "min_free_bytes of backup setting {} can't be used with sftp",
+                self.name
+            )
+        }
+        if self.encryption.is_some() && self.backup_mode != BackupMode::Simple {
+            bail!(
+                "backup_mode of backup setting {} must be simple with encryption",
+                self.name
+            )
+        }
+        if let Some(active_hours) = self.active_hours {
+            // boundaries of intervals shorter than a day repeat every day
+            // and boundaries of longer intervals are at midnight
+            let midnight = NaiveDate::from_ymd_opt(2021, 1, 1)
+                .unwrap()
+                .and_time(chrono::NaiveTime::MIN);
+            let next_day = midnight + chrono::Duration::days(1);
+            let in_window = std::iter::successors(Some(midnight), |x| {
+                Some(self.interval.get_next_date_after(x))
+            })
+            .take_while(|x| *x < next_day)
+            .any(|x| active_hours.contains(x.time()));
+            if !in_window {
+                bail!(
+                    "no backup of backup setting {} is taken since no boundary of interval {} \
+                    is in active_hours",
+                    self.name,
+                    self.interval
+                )
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// a config for tests without rcon servers, notifications, or backup settings.
+    /// other fields are changed with struct update syntax
+    pub(crate) fn for_test() -> Config {
+        Config {
+            preset: None,
+            rcon_servers: vec![],
+            rcon_retry: Default::default(),
+            rcon_timeout: Duration::from_secs(30),
+            on_rcon_timeout: RconTimeoutAction::Skip,
+            on_rcon_failure: RconFailureAction::Abort,
+            commands_before: vec![],
+            commands_after: vec![],
+            expect_response: Default::default(),
+            save_settle_delay: Default::default(),
+            timezone: Tz::UTC,
+            discord_webhook: None,
+            notify_on: NotifyOn::All,
+            webhook: None,
+            max_parallel_tars: 2,
+            tick: Default::default(),
+            catch_up: false,
+            health_address: None,
+            status_file: None,
+            backups: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+impl BackupSetting {
+    /// a simple local backup setting for tests keeping one backup every 5 minutes.
+    /// other fields are changed with struct update syntax
+    pub(crate) fn for_test(name: &str, directory: &Path) -> BackupSetting {
+        BackupSetting {
+            name: name.to_owned(),
+            directory: directory.to_owned(),
+            save_dirs: vec![],
+            filter: PathFilter::default(),
+            retry_on_change: 0,
+            preserve_permissions: true,
+            follow_symlinks: true,
+            copy_buffer_size: 1024 * 1024,
+            io_rate_limit: None,
+            max_backups: 1,
+            retention: None,
+            max_age: None,
+            min_free_bytes: None,
+            name_format: BACKUP_NAME_FORMAT.to_owned(),
+            interval: SaveInterval::Every5Minute,
+            active_hours: None,
+            min_spacing: None,
+            backup_mode: BackupMode::Simple,
+            compression: Compression::None,
+            post_backup_command: vec![],
+            commands_before: None,
+            commands_after: None,
+            sftp: None,
+            encryption: None,
+            included_config: None,
+            skip_unchanged: false,
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -151,62 +1221,1837 @@ struct ConfigFile {
     #[serde(default)]
     rcon_address: Option<String>,
     #[serde(default)]
-    rcon_password: String,
+    rcon_password: Option<String>,
+    #[serde(default)]
+    rcon_password_file: Option<PathBuf>,
+    #[serde(default)]
+    rcon_ssh_tunnel: Option<SshTunnelFile>,
+    #[serde(default)]
+    rcon_retry: RconRetryFile,
+    /// in seconds
+    #[serde(default)]
+    rcon_timeout: Option<f64>,
+    #[serde(default)]
+    on_rcon_timeout: RconTimeoutAction,
+    #[serde(default)]
+    rcon_servers: Vec<RconServerFile>,
+    #[serde(default)]
+    on_rcon_failure: RconFailureAction,
+    #[serde(default)]
+    discord_webhook: Option<String>,
+    #[serde(default)]
+    notify_on: NotifyOn,
+    #[serde(default)]
+    webhook: Option<WebhookFile>,
     #[serde(default)]
     commands_before: Option<String>,
     #[serde(default)]
     commands_after: Option<String>,
+    #[serde(default)]
+    expect_response: BTreeMap<String, String>,
+    /// in seconds
+    #[serde(default)]
+    save_settle_delay: Option<f64>,
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(default = "max_parallel_tars_default")]
+    max_parallel_tars: usize,
+    #[serde(default)]
+    tick: Tick,
+    #[serde(default)]
+    catch_up: bool,
+    #[serde(default)]
+    allow_frequent: bool,
+    #[serde(default)]
+    skip_backup_dir: bool,
+    #[serde(default)]
+    health_address: Option<SocketAddr>,
+    #[serde(default)]
+    status_file: Option<PathBuf>,
     backup_dir: Option<PathBuf>,
-    save_dir: Option<PathBuf>,
+    save_dir: Option<SaveDirsFile>,
     backups: Vec<BackupSettingFile>,
 }
 
 #[derive(Deserialize)]
-struct BackupSettingFile {
-    name: String,
-    max_backups: usize,
-    interval: SaveInterval,
-    #[serde(default = "backup_mode_default")]
-    backup_mode: BackupMode,
+#[serde(untagged)]
+enum SaveDirsFile {
+    Single(PathBuf),
+    Multiple(Vec<SaveDirFile>),
 }
 
-#[cfg(feature = "diff-save")]
-fn backup_mode_default() -> BackupMode {
-    BackupMode::ModifiesOnly
-}
-#[cfg(not(feature = "diff-save"))]
-fn backup_mode_default() -> BackupMode {
-    BackupMode::Simple
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SaveDirFile {
+    Path(PathBuf),
+    Detailed {
+        path: PathBuf,
+        #[serde(default)]
+        prefix: Option<PathBuf>,
+    },
 }
 
-#[derive(Deserialize, Debug, Copy, Clone)]
-#[serde(rename_all = "kebab-case")]
-pub(crate) enum GamePreset {
-    Minecraft,
+#[derive(Deserialize, Default)]
+struct RconRetryFile {
+    /// in seconds
+    #[serde(default)]
+    base_delay: Option<f64>,
+    /// in seconds
+    #[serde(default)]
+    max_delay: Option<f64>,
+    #[serde(default)]
+    max_attempts: Option<u32>,
 }
 
-impl GamePreset {
-    pub(super) fn get_default_command(&self, before: bool) -> Vec<String> {
-        match self {
-            GamePreset::Minecraft => {
-                if before {
-                    vec!["save-off".to_owned(), "save-all".to_owned()]
-                } else {
-                    vec!["save-on".to_owned()]
+#[derive(Deserialize)]
+struct BackupSettingFile {
+    name: String,
+    #[serde(default)]
+    max_backups: Option<usize>,
+    #[serde(default)]
+    retention: Option<Retention>,
+    #[serde(default)]
+    max_age: Option<MaxAge>,
+    #[serde(default)]
+    min_free_bytes: Option<u64>,
+    #[serde(default = "name_format_default")]
+    name_format: String,
+    #[serde(default)]
+    name_prefix: String,
+    #[serde(default)]
+    name_suffix: String,
+    interval: SaveInterval,
+    #[serde(default)]
+    active_hours: Option<ActiveHours>,
+    #[serde(default)]
+    min_spacing: Option<MaxAge>,
+    #[serde(default = "backup_mode_default")]
+    backup_mode: BackupMode,
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default)]
+    save_dir: Option<SaveDirsFile>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    retry_on_change: usize,
+    #[serde(default = "preserve_permissions_default")]
+    preserve_permissions: bool,
+    #[serde(default = "follow_symlinks_default")]
+    follow_symlinks: bool,
+    /// in bytes
+    #[serde(default = "copy_buffer_size_default")]
+    copy_buffer_size: usize,
+    #[serde(default)]
+    io_rate_limit: Option<u64>,
+    #[serde(default)]
+    post_backup_command: Option<CommandFile>,
+    #[serde(default)]
+    commands_before: Option<String>,
+    #[serde(default)]
+    commands_after: Option<String>,
+    #[serde(default)]
+    sftp: Option<SftpFile>,
+    #[serde(default)]
+    encryption: Option<EncryptionFile>,
+    #[serde(default)]
+    include_config: bool,
+    #[serde(default)]
+    skip_unchanged: bool,
+}
+
+/// key material is read from files or environment variables not to be written in config file
+#[derive(Deserialize)]
+struct EncryptionFile {
+    #[serde(default)]
+    recipient_file: Option<PathBuf>,
+    #[serde(default)]
+    recipient_env: Option<String>,
+    #[serde(default)]
+    passphrase_file: Option<PathBuf>,
+    #[serde(default)]
+    passphrase_env: Option<String>,
+}
+
+impl EncryptionFile {
+    fn into_encryption(self) -> Result<Encryption> {
+        fn read_file(path: &PathBuf) -> Result<String> {
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))
+        }
+        fn read_env(name: &str) -> Result<String> {
+            std::env::var(name).with_context(|| format!("reading environment variable {}", name))
+        }
+        let (recipient, passphrase) = match self {
+            EncryptionFile {
+                recipient_file: Some(path),
+                recipient_env: None,
+                passphrase_file: None,
+                passphrase_env: None,
+            } => (Some(read_file(&path)?), None),
+            EncryptionFile {
+                recipient_file: None,
+                recipient_env: Some(name),
+                passphrase_file: None,
+                passphrase_env: None,
+            } => (Some(read_env(&name)?), None),
+            EncryptionFile {
+                recipient_file: None,
+                recipient_env: None,
+                passphrase_file: Some(path),
+                passphrase_env: None,
+            } => (None, Some(read_file(&path)?)),
+            EncryptionFile {
+                recipient_file: None,
+                recipient_env: None,
+                passphrase_file: None,
+                passphrase_env: Some(name),
+            } => (None, Some(read_env(&name)?)),
+            _ => bail!(
+                "exactly one of recipient_file, recipient_env, passphrase_file, \
+                or passphrase_env is required for encryption"
+            ),
+        };
+        if let Some(recipient) = recipient {
+            // recipient files may have comments like age-keygen output
+            let recipient = recipient
+                .lines()
+                .map(str::trim)
+                .find(|x| !x.is_empty() && !x.starts_with('#'))
+                .ok_or_else(|| anyhow!("recipient for encryption is empty"))?;
+            let recipient = recipient
+                .parse::<age::x25519::Recipient>()
+                .map_err(|e| anyhow!("parsing recipient for encryption: {}", e))?;
+            Ok(Encryption::Recipient(recipient))
+        } else {
+            let passphrase = passphrase.unwrap();
+            let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+            if passphrase.is_empty() {
+                bail!("passphrase for encryption is empty")
+            }
+            Ok(Encryption::Passphrase(passphrase.to_owned().into()))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SftpFile {
+    host: String,
+    #[serde(default = "sftp_port_default")]
+    port: u16,
+    user: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    private_key: Option<PathBuf>,
+    #[serde(default)]
+    host_key: Option<String>,
+    remote_dir: String,
+}
+
+fn sftp_port_default() -> u16 {
+    22
+}
+
+impl SftpFile {
+    fn into_sftp(self) -> Result<Sftp> {
+        Ok(Sftp {
+            auth: ssh_auth(self.password, self.private_key, "sftp")?,
+            host_key: parse_host_key(self.host_key, "sftp")?,
+            host: self.host,
+            port: self.port,
+            user: self.user,
+            remote_dir: self.remote_dir,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct SshTunnelFile {
+    host: String,
+    #[serde(default = "sftp_port_default")]
+    port: u16,
+    user: String,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    private_key: Option<PathBuf>,
+    #[serde(default)]
+    host_key: Option<String>,
+}
+
+impl SshTunnelFile {
+    fn into_tunnel(self) -> Result<SshTunnel> {
+        Ok(SshTunnel {
+            auth: ssh_auth(self.password, self.private_key, "ssh_tunnel")?,
+            host_key: parse_host_key(self.host_key, "ssh_tunnel")?,
+            host: self.host,
+            port: self.port,
+            user: self.user,
+        })
+    }
+}
+
+/// `of` is the name of the section used in errors
+fn ssh_auth(password: Option<String>, private_key: Option<PathBuf>, of: &str) -> Result<SftpAuth> {
+    Ok(match (password, private_key) {
+        (Some(password), None) => SftpAuth::Password(password),
+        (None, Some(private_key)) => SftpAuth::PrivateKey(private_key),
+        (None, None) => bail!("either password or private_key of {} is required", of),
+        (Some(_), Some(_)) => bail!("both password and private_key of {} are specified", of),
+    })
+}
+
+fn parse_host_key(host_key: Option<String>, of: &str) -> Result<Option<russh::keys::PublicKey>> {
+    host_key
+        .map(|key| russh::keys::PublicKey::from_openssh(&key))
+        .transpose()
+        .with_context(|| format!("parsing host_key of {}", of))
+}
+
+/// a command line. a string is split by whitespaces
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CommandFile {
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl CommandFile {
+    fn into_args(self) -> Vec<String> {
+        match self {
+            CommandFile::Line(line) => line.split_whitespace().map(str::to_owned).collect(),
+            CommandFile::Args(args) => args,
+        }
+    }
+}
+
+fn backup_mode_default() -> BackupMode {
+    BackupMode::Simple
+}
+
+fn max_parallel_tars_default() -> usize {
+    2
+}
+
+fn name_format_default() -> String {
+    BACKUP_NAME_FORMAT.to_owned()
+}
+
+/// adds prefix and suffix to the format as literals
+fn decorate_name_format(prefix: &str, format: &str, suffix: &str) -> String {
+    format!(
+        "{}{}{}",
+        prefix.replace('%', "%%"),
+        format,
+        suffix.replace('%', "%%")
+    )
+}
+
+/// verifies names of backups with the format are safe as file names and
+/// sortable by time, and the time can be parsed from the names.
+fn verify_name_format(format: &str) -> Result<()> {
+    if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        bail!("invalid format");
+    }
+    // characters not allowed in file names on common filesystems
+    if let Some(c) = format.chars().find(|c| {
+        matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control()
+    }) {
+        bail!("{:?} is not allowed in names of backups", c);
+    }
+    // times changing each field and the number of digits
+    let times = [
+        (2021, 1, 1, 0, 0),
+        (2021, 1, 1, 0, 1),
+        (2021, 1, 1, 0, 10),
+        (2021, 1, 1, 1, 0),
+        (2021, 1, 1, 10, 0),
+        (2021, 1, 2, 0, 0),
+        (2021, 1, 10, 0, 0),
+        (2021, 2, 1, 0, 0),
+        (2021, 10, 1, 0, 0),
+        (2022, 1, 1, 0, 0),
+    ]
+    .map(|(y, mo, d, h, mi)| {
+        NaiveDate::from_ymd_opt(y, mo, d)
+            .unwrap()
+            .and_hms_opt(h, mi, 0)
+            .unwrap()
+    });
+    let mut previous = None::<String>;
+    for time in times {
+        let name = time.format(format).to_string();
+        if name.trim().is_empty() || name.starts_with('.') {
+            bail!("the name {:?} is not allowed", name);
+        }
+        if NaiveDateTime::parse_from_str(&name, format).ok() != Some(time) {
+            bail!("the time cannot be parsed from the name {:?}", name);
+        }
+        if let Some(previous) = previous.filter(|previous| *previous >= name) {
+            bail!(
+                "the names are not sorted by time: {:?} is sorted after {:?}",
+                previous,
+                name
+            );
+        }
+        previous = Some(name);
+    }
+    Ok(())
+}
+
+fn preserve_permissions_default() -> bool {
+    true
+}
+
+fn copy_buffer_size_default() -> usize {
+    1024 * 1024
+}
+
+fn follow_symlinks_default() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum GamePreset {
+    Minecraft,
+    /// terraria dedicated server. it has no rcon and saves world automatically.
+    Terraria,
+    Factorio,
+    /// valheim dedicated server. it has no rcon and saves world automatically.
+    Valheim,
+    /// no game server. only files are backed up and rcon is never used.
+    #[serde(alias = "none")]
+    Filesystem,
+}
+
+impl GamePreset {
+    /// the address of rcon server used if rcon_address is not specified.
+    /// None if the game doesn't have rcon.
+    pub(super) fn default_rcon_address(self) -> Option<&'static str> {
+        match self {
+            GamePreset::Minecraft => Some("localhost:25575"),
+            GamePreset::Terraria | GamePreset::Valheim | GamePreset::Filesystem => None,
+            // factorio doesn't have default but 27015 is commonly used
+            GamePreset::Factorio => Some("localhost:27015"),
+        }
+    }
+
+    pub(super) fn get_default_command(&self, before: bool) -> Vec<String> {
+        match self {
+            GamePreset::Minecraft => {
+                if before {
+                    // flush blocks until the world is written to disk
+                    vec!["save-off".to_owned(), "save-all flush".to_owned()]
+                } else {
+                    vec!["save-on".to_owned()]
+                }
+            }
+            // terraria and valheim have no command to stop auto save
+            GamePreset::Terraria | GamePreset::Valheim | GamePreset::Filesystem => vec![],
+            GamePreset::Factorio => {
+                if before {
+                    vec!["/server-save".to_owned()]
+                } else {
+                    vec![]
                 }
             }
         }
     }
 }
 
-#[derive(Deserialize, Debug, Eq, PartialEq)]
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) enum BackupMode {
     Simple,
-    #[cfg(feature = "diff-save")]
     /// this will replace previously newest backup with a backup only with modified files.
     ModifiesOnly,
-    #[cfg(feature = "diff-save")]
     /// this will replace previously newest backup with a backup with bsdiff binary patch file.
     FileDiff,
+    /// this will save contents of files once in content-addressed store shared by backups.
+    /// see [crate::store]
+    Dedup,
+}
+
+impl BackupMode {
+    /// returns true if previously newest backup is replaced with diff
+    pub(crate) fn is_diff(self) -> bool {
+        matches!(self, BackupMode::ModifiesOnly | BackupMode::FileDiff)
+    }
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+impl Compression {
+    /// all compressions. used to find or remove backups with any compression.
+    pub(crate) const ALL: &'static [Compression] = &[Compression::None, Compression::Gzip];
+
+    /// the extension of backup archive without leading dot
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "tar",
+            Compression::Gzip => "tar.gz",
+        }
+    }
+
+    /// wraps reader of backup archive with decoder
+    pub(crate) fn reader<'a>(self, read: impl Read + 'a) -> Box<dyn Read + 'a> {
+        match self {
+            Compression::None => Box::new(BufReader::new(read)),
+            Compression::Gzip => Box::new(GzDecoder::new(BufReader::new(read))),
+        }
+    }
+
+    /// calls `f` with writer which compresses bytes into `write`.
+    /// returns `write` after all bytes are written.
+    pub(crate) fn write<W: Write>(
+        self,
+        write: W,
+        f: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<W> {
+        match self {
+            Compression::None => {
+                let mut write = write;
+                f(&mut write)?;
+                Ok(write)
+            }
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(write, flate2::Compression::default());
+                f(&mut encoder)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod save_dirs_test {
+    use super::*;
+
+    fn parse(yaml: &str) -> Result<Vec<SaveDir>> {
+        save_dirs_from_file(serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[test]
+    fn single() {
+        let dir = tempfile::tempdir().unwrap();
+        let dirs = parse(&format!("{:?}", dir.path())).unwrap();
+        assert_eq!(
+            dirs,
+            vec![SaveDir {
+                path: dir.path().to_owned(),
+                prefix: PathBuf::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn multiple() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        std::fs::create_dir(dir.path().join("plugins")).unwrap();
+        let dirs = parse(&format!(
+            "[{:?}, {{ path: {:?}, prefix: data/plugins }}]",
+            dir.path().join("world"),
+            dir.path().join("plugins"),
+        ))
+        .unwrap();
+        assert_eq!(
+            dirs,
+            vec![
+                SaveDir {
+                    path: dir.path().join("world"),
+                    prefix: PathBuf::from("world"),
+                },
+                SaveDir {
+                    path: dir.path().join("plugins"),
+                    prefix: PathBuf::from("data/plugins"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn conflicting_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/world")).unwrap();
+        std::fs::create_dir_all(dir.path().join("b/world")).unwrap();
+        let err = parse(&format!(
+            "[{:?}, {:?}]",
+            dir.path().join("a/world"),
+            dir.path().join("b/world"),
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("conflicts"), "{}", err);
+
+        let err = parse(&format!(
+            "[{{ path: {:?}, prefix: data }}, {{ path: {:?}, prefix: data/world }}]",
+            dir.path().join("a/world"),
+            dir.path().join("b/world"),
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("conflicts"), "{}", err);
+    }
+
+    #[test]
+    fn file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("world.db"), b"").unwrap();
+        let dirs = parse(&format!("{:?}", dir.path().join("world.db"))).unwrap();
+        assert_eq!(
+            dirs,
+            vec![SaveDir {
+                path: dir.path().join("world.db"),
+                prefix: PathBuf::new(),
+            }]
+        );
+        let dirs = parse(&format!("[{:?}]", dir.path().join("world.db"))).unwrap();
+        assert_eq!(dirs[0].prefix, PathBuf::from("world.db"));
+    }
+
+    #[test]
+    fn not_exists() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = parse(&format!("[{:?}]", dir.path().join("not-exists"))).unwrap_err();
+        assert!(err.to_string().contains("not a directory"), "{}", err);
+    }
+}
+
+#[cfg(test)]
+mod verify_backups_test {
+    use super::*;
+
+    fn setting(name: &str, max_backups: usize) -> BackupSetting {
+        BackupSetting {
+            max_backups,
+            ..BackupSetting::for_test(name, &PathBuf::from("backups").join(name))
+        }
+    }
+
+    #[test]
+    fn valid() {
+        verify_backups(&[setting("hourly", 24), setting("daily", 7)]).unwrap();
+    }
+
+    #[test]
+    fn zero_max_backups() {
+        let err = verify_backups(&[setting("hourly", 0)]).unwrap_err();
+        assert!(err.to_string().contains("max_backups"), "{}", err);
+    }
+
+    #[test]
+    fn active_hours_without_boundary() {
+        let with = |interval, active_hours: &str| BackupSetting {
+            interval,
+            active_hours: Some(active_hours.parse().unwrap()),
+            ..setting("night", 1)
+        };
+        let err = verify_backups(&[with(SaveInterval::Every1Day, "02:00-06:00")]).unwrap_err();
+        assert!(err.to_string().contains("active_hours"), "{}", err);
+        let err = verify_backups(&[with(SaveInterval::Every1Week, "02:00-06:00")]).unwrap_err();
+        assert!(err.to_string().contains("active_hours"), "{}", err);
+        let err = verify_backups(&[with(SaveInterval::Every6Hour, "01:00-05:00")]).unwrap_err();
+        assert!(err.to_string().contains("active_hours"), "{}", err);
+        verify_backups(&[with(SaveInterval::Every1Day, "22:00-04:00")]).unwrap();
+        verify_backups(&[with(SaveInterval::Every6Hour, "01:00-06:01")]).unwrap();
+        verify_backups(&[with(SaveInterval::Every1Hour, "02:00-06:00")]).unwrap();
+    }
+
+    #[test]
+    fn zero_copy_buffer_size() {
+        let err = verify_backups(&[BackupSetting {
+            copy_buffer_size: 0,
+            ..setting("hourly", 1)
+        }])
+        .unwrap_err();
+        assert!(err.to_string().contains("copy_buffer_size"), "{}", err);
+    }
+
+    #[test]
+    fn zero_io_rate_limit() {
+        let err = verify_backups(&[BackupSetting {
+            io_rate_limit: Some(0),
+            ..setting("hourly", 1)
+        }])
+        .unwrap_err();
+        assert!(err.to_string().contains("io_rate_limit"), "{}", err);
+    }
+
+    #[test]
+    fn dedup_with_compression() {
+        let err = verify_backups(&[BackupSetting {
+            backup_mode: BackupMode::Dedup,
+            compression: Compression::Gzip,
+            ..setting("hourly", 1)
+        }])
+        .unwrap_err();
+        assert!(err.to_string().contains("dedup"), "{}", err);
+    }
+
+    #[test]
+    fn empty_name() {
+        let err = verify_backups(&[setting("", 1)]).unwrap_err();
+        assert!(err.to_string().contains("must not be empty"), "{}", err);
+        let err = verify_backups(&[setting("  ", 1)]).unwrap_err();
+        assert!(err.to_string().contains("must not be empty"), "{}", err);
+    }
+
+    #[test]
+    fn retention_with_diff() {
+        let err = verify_backups(&[BackupSetting {
+            backup_mode: BackupMode::FileDiff,
+            retention: Some(Retention {
+                daily: 7,
+                ..Default::default()
+            }),
+            ..setting("hourly", 1)
+        }])
+        .unwrap_err();
+        assert!(err.to_string().contains("retention"), "{}", err);
+    }
+
+    #[test]
+    fn retention_without_max_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        let parse = |backup: &str| {
+            parse_config(
+                format!(
+                    "save_dir: {:?}\n\
+                    backup_dir: {:?}\n\
+                    backups:\n\
+                    - name: hourly\n  interval: hourly\n{}",
+                    dir.path().join("world"),
+                    dir.path().join("backups"),
+                    backup,
+                )
+                .as_bytes(),
+            )
+        };
+        let config = parse("  retention:\n    hourly: 24\n    monthly: 12\n").unwrap();
+        assert_eq!(config.backups[0].max_backups, usize::MAX);
+        assert_eq!(
+            config.backups[0].retention,
+            Some(Retention {
+                hourly: 24,
+                monthly: 12,
+                ..Default::default()
+            })
+        );
+        let config = parse("  max_age: 30 days\n").unwrap();
+        assert_eq!(config.backups[0].max_backups, usize::MAX);
+        assert_eq!(config.backups[0].max_age, Some(MaxAge::Days(30)));
+        let config = parse("  max_backups: 1\n  min_spacing: 30 minutes\n").unwrap();
+        assert_eq!(config.backups[0].min_spacing, Some(MaxAge::Minutes(30)));
+        let err = parse("").unwrap_err();
+        assert!(err.to_string().contains("is required"), "{}", err);
+    }
+
+    #[test]
+    fn name_format() {
+        let with_format = |name_format: &str| {
+            verify_backups(&[BackupSetting {
+                name_format: name_format.to_owned(),
+                ..setting("hourly", 1)
+            }])
+        };
+        with_format(BACKUP_NAME_FORMAT).unwrap();
+        with_format("%Y%m%dT%H%M%S").unwrap();
+        with_format("%Y-%m-%dT%H.%M.%S").unwrap();
+        let err = with_format("backups/%Y-%m-%d-%H-%M-%S").unwrap_err();
+        assert!(format!("{:#}", err).contains("not allowed"), "{:#}", err);
+        let err = with_format("%Y-%m-%dT%H:%M:%S").unwrap_err();
+        assert!(format!("{:#}", err).contains("not allowed"), "{:#}", err);
+        let err = with_format("%d-%m-%Y-%H-%M-%S").unwrap_err();
+        assert!(format!("{:#}", err).contains("not sorted"), "{:#}", err);
+        let err = with_format("backup-%Y-%m-%d").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("cannot be parsed"),
+            "{:#}",
+            err
+        );
+        let err = with_format("backup-%Y-%-m-%d-%H-%M-%S").unwrap_err();
+        assert!(format!("{:#}", err).contains("not sorted"), "{:#}", err);
+        let err = with_format("%Q").unwrap_err();
+        assert!(format!("{:#}", err).contains("invalid format"), "{:#}", err);
+    }
+
+    #[test]
+    fn name_prefix_and_suffix() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        let parse = |backup: &str| {
+            parse_config(
+                format!(
+                    "save_dir: {:?}\n\
+                    backup_dir: {:?}\n\
+                    backups:\n\
+                    - name: hourly\n  interval: hourly\n  max_backups: 1\n{}",
+                    dir.path().join("world"),
+                    dir.path().join("backups"),
+                    backup,
+                )
+                .as_bytes(),
+            )
+        };
+        let config = parse("  name_prefix: survival-\n  name_suffix: \"-100%\"\n").unwrap();
+        assert_eq!(
+            config.backups[0].name_format,
+            "survival-backup-%Y-%m-%d-%H-%M-%S-100%%"
+        );
+        let config = parse("").unwrap();
+        assert_eq!(config.backups[0].name_format, BACKUP_NAME_FORMAT);
+        let err = parse("  name_prefix: survival/\n").unwrap_err();
+        assert!(format!("{:#}", err).contains("not allowed"), "{:#}", err);
+    }
+
+    #[test]
+    fn duplicate_name() {
+        let err = verify_backups(&[setting("hourly", 1), setting("hourly", 2)]).unwrap_err();
+        assert!(
+            err.to_string().contains("multiple backup settings"),
+            "{}",
+            err
+        );
+    }
+    fn parse_dirs(save_dir: &Path, backup_dir: &Path) -> Result<Box<Config>> {
+        parse_config(
+            format!(
+                "save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups:\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 24\n",
+                save_dir, backup_dir,
+            )
+            .as_bytes(),
+        )
+    }
+
+    #[test]
+    fn backup_dir_in_save_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = parse_dirs(dir.path(), &dir.path().join("backups")).unwrap_err();
+        assert!(err.to_string().contains("backed up again"), "{}", err);
+    }
+
+    #[test]
+    fn save_dir_in_backup_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("hourly/world")).unwrap();
+        let err = parse_dirs(&dir.path().join("hourly/world"), dir.path()).unwrap_err();
+        assert!(
+            err.to_string().contains("inside backup directory"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn identical_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("hourly")).unwrap();
+        // the backup directory of the setting is `backup_dir/hourly`
+        let err = parse_dirs(&dir.path().join("hourly"), dir.path()).unwrap_err();
+        assert!(err.to_string().contains("backed up again"), "{}", err);
+    }
+
+    #[test]
+    fn disjoint_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        parse_dirs(&dir.path().join("world"), &dir.path().join("backups")).unwrap();
+        // sibling with the same prefix is not inside
+        parse_dirs(&dir.path().join("world"), &dir.path().join("world-backups")).unwrap();
+    }
+
+    #[test]
+    fn skip_backup_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("world/region")).unwrap();
+        std::fs::create_dir_all(dir.path().join("backups/hourly")).unwrap();
+        std::fs::write(dir.path().join("world/level.dat"), b"level").unwrap();
+        std::fs::write(dir.path().join("backups/hourly/old.tar"), b"old").unwrap();
+        let config = parse_config(
+            format!(
+                "save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                skip_backup_dir: true\n\
+                backups:\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 24\n",
+                dir.path(),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let setting = &config.backups[0];
+        let tar = crate::archive::write_tar(
+            Vec::new(),
+            &setting.save_dirs,
+            &setting.filter,
+            setting.read_options(),
+            &[],
+            &mut crate::tar::Progress::new(|_, _| {}),
+        )
+        .unwrap();
+        let entries = tar::Archive::new(tar.as_slice())
+            .entries()
+            .unwrap()
+            .map(|x| x.unwrap().path().unwrap().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                PathBuf::from("world"),
+                PathBuf::from("world/region"),
+                PathBuf::from("world/level.dat"),
+            ]
+        );
+
+        // excluding with patterns is also accepted
+        let exclude = format!(
+            "save_dir: {:?}\n\
+            backup_dir: {:?}\n\
+            backups:\n\
+            - name: hourly\n  interval: hourly\n  max_backups: 24\n  exclude: [backups]\n",
+            dir.path(),
+            dir.path().join("backups"),
+        );
+        parse_config(exclude.as_bytes()).unwrap();
+
+        // nothing would be backed up
+        let err = parse_config(
+            format!(
+                "save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                skip_backup_dir: true\n\
+                backups:\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 24\n",
+                dir.path(),
+                dir.path(),
+            )
+            .as_bytes(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("must not be save_dir"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn overlapping_through_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("world"), dir.path().join("link")).unwrap();
+        let err = parse_dirs(&dir.path().join("world"), &dir.path().join("link/backups"));
+        assert!(err.is_err());
+    }
+}
+
+#[cfg(test)]
+mod preset_test {
+    use super::*;
+
+    #[test]
+    fn terraria() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        let config = parse_config(
+            format!(
+                "preset: terraria\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups:\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 24\n",
+                dir.path().join("world"),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        assert!(matches!(config.preset, Some(GamePreset::Terraria)));
+        assert!(config.rcon_servers.is_empty());
+        assert_eq!(config.commands_before, Vec::<String>::new());
+        assert_eq!(config.commands_after, Vec::<String>::new());
+        assert_eq!(
+            config.backups[0].directory,
+            dir.path().join("backups/hourly")
+        );
+    }
+
+    #[test]
+    fn expect_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let parse = |expect_response: &str| {
+            parse_config(
+                format!(
+                    "preset: minecraft\n\
+                    save_dir: {:?}\n\
+                    backup_dir: {:?}\n\
+                    expect_response:\n  {}\n\
+                    backups: []\n",
+                    dir.path(),
+                    dir.path().join("backups"),
+                    expect_response,
+                )
+                .as_bytes(),
+            )
+        };
+        let config = parse("save-all flush: Saved the game").unwrap();
+        assert_eq!(
+            config
+                .expect_response
+                .get("save-all flush")
+                .map(String::as_str),
+            Some("Saved the game")
+        );
+        let err = parse("save-on: Automatic saving is now enabled").unwrap_err();
+        assert!(err.to_string().contains("commands_before"), "{}", err);
+    }
+
+    #[test]
+    fn setting_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        let config = parse_config(
+            format!(
+                "preset: minecraft\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                expect_response:\n  save-all world: Saved\n\
+                backups:\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 24\n\
+                - name: daily\n  interval: daily\n  max_backups: 7\n  \
+                commands_before: \"save-off\\nsave-all world\"\n  commands_after: \"\"\n",
+                dir.path().join("world"),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let [hourly, daily] = &config.backups[..] else {
+            panic!("two settings expected")
+        };
+        assert_eq!(config.commands_before_of(hourly), config.commands_before);
+        assert_eq!(config.commands_after_of(hourly), config.commands_after);
+        assert_eq!(
+            config.commands_before_of(daily),
+            ["save-off", "save-all world"]
+        );
+        assert!(config.commands_after_of(daily).is_empty());
+
+        let err = parse_config(
+            format!(
+                "preset: filesystem\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups:\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 24\n  commands_before: save-off\n",
+                dir.path().join("world"),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("filesystem preset"), "{}", err);
+    }
+
+    fn parse_preset(preset: &str) -> Box<Config> {
+        let dir = tempfile::tempdir().unwrap();
+        parse_config(
+            format!(
+                "preset: {}\nsave_dir: {:?}\nbackup_dir: {:?}\nbackups: []\n",
+                preset,
+                dir.path(),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn minecraft() {
+        let config = parse_preset("minecraft");
+        assert_eq!(config.rcon_servers.len(), 1);
+        assert_eq!(
+            config.rcon_servers[0].address,
+            "localhost:25575"
+                .to_socket_addrs()
+                .unwrap()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(config.commands_before, vec!["save-off", "save-all flush"]);
+        assert_eq!(config.commands_after, vec!["save-on"]);
+    }
+
+    #[test]
+    fn minecraft_without_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = parse_config(
+            format!(
+                "preset: minecraft\n\
+                commands_before: |\n  save-off\n  save-all\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups: []\n",
+                dir.path(),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(config.commands_before, vec!["save-off", "save-all"]);
+        assert_eq!(config.commands_after, vec!["save-on"]);
+    }
+
+    #[test]
+    fn factorio() {
+        let config = parse_preset("factorio");
+        assert!(matches!(config.preset, Some(GamePreset::Factorio)));
+        assert_eq!(config.rcon_servers.len(), 1);
+        assert_eq!(
+            config.rcon_servers[0].address,
+            "localhost:27015"
+                .to_socket_addrs()
+                .unwrap()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(config.commands_before, vec!["/server-save"]);
+        assert_eq!(config.commands_after, Vec::<String>::new());
+    }
+
+    #[test]
+    fn valheim() {
+        let config = parse_preset("valheim");
+        assert!(matches!(config.preset, Some(GamePreset::Valheim)));
+        assert!(config.rcon_servers.is_empty());
+        assert_eq!(config.commands_before, Vec::<String>::new());
+        assert_eq!(config.commands_after, Vec::<String>::new());
+    }
+
+    #[test]
+    fn filesystem() {
+        for preset in ["filesystem", "none"] {
+            let config = parse_preset(preset);
+            assert!(matches!(config.preset, Some(GamePreset::Filesystem)));
+            assert!(config.rcon_servers.is_empty());
+            assert_eq!(config.commands_before, Vec::<String>::new());
+            assert_eq!(config.commands_after, Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn filesystem_with_rcon() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = parse_config(
+            format!(
+                "preset: filesystem\n\
+                rcon_address: localhost:25575\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups: []\n",
+                dir.path(),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("filesystem preset"), "{}", err);
+    }
+
+    #[test]
+    fn max_parallel_tars() {
+        let dir = tempfile::tempdir().unwrap();
+        let parse = |extra: &str| {
+            parse_config(
+                format!(
+                    "{}save_dir: {:?}\n\
+                    backup_dir: {:?}\n\
+                    backups: []\n",
+                    extra,
+                    dir.path(),
+                    dir.path().join("backups"),
+                )
+                .as_bytes(),
+            )
+        };
+        assert_eq!(parse("").unwrap().max_parallel_tars, 2);
+        assert_eq!(
+            parse("max_parallel_tars: 4\n").unwrap().max_parallel_tars,
+            4
+        );
+        let err = parse("max_parallel_tars: 0\n").unwrap_err();
+        assert_eq!(err.to_string(), "max_parallel_tars must not be 0");
+    }
+
+    #[test]
+    fn terraria_commands_without_rcon() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = parse_config(
+            format!(
+                "preset: terraria\n\
+                commands_before: save\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups: []\n",
+                dir.path(),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("rcon_address is required"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn rcon_password_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let parse = |rcon: &str| {
+            parse_config(
+                format!(
+                    "preset: minecraft\n{}save_dir: {:?}\nbackup_dir: {:?}\nbackups: []\n",
+                    rcon,
+                    dir.path(),
+                    dir.path().join("backups"),
+                )
+                .as_bytes(),
+            )
+        };
+        let password_file = dir.path().join("rcon_password");
+        std::fs::write(&password_file, "secret \n").unwrap();
+
+        let config = parse(&format!("rcon_password_file: {:?}\n", password_file)).unwrap();
+        // only the trailing newline is trimmed
+        assert_eq!(config.rcon_servers[0].password, "secret ");
+
+        let config = parse("rcon_password: inline\n").unwrap();
+        assert_eq!(config.rcon_servers[0].password, "inline");
+
+        let err = parse(&format!(
+            "rcon_password: inline\nrcon_password_file: {:?}\n",
+            password_file
+        ))
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("can't be used together"),
+            "{}",
+            err
+        );
+
+        let err = parse(&format!(
+            "rcon_password_file: {:?}\n",
+            dir.path().join("missing")
+        ))
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("missing"), "{:#}", err);
+
+        let config = parse(&format!(
+            "rcon_servers:\n- address: 127.0.0.1:25576\n  password_file: {:?}\n",
+            password_file
+        ))
+        .unwrap();
+        assert_eq!(config.rcon_servers[0].password, "secret ");
+    }
+
+    #[test]
+    fn rcon_ssh_tunnel() {
+        let dir = tempfile::tempdir().unwrap();
+        let parse = |rcon: &str| {
+            parse_config(
+                format!(
+                    "preset: minecraft\n{}save_dir: {:?}\nbackup_dir: {:?}\nbackups: []\n",
+                    rcon,
+                    dir.path(),
+                    dir.path().join("backups"),
+                )
+                .as_bytes(),
+            )
+        };
+
+        let config = parse(
+            "rcon_address: minecraft.invalid:25575\n\
+            rcon_ssh_tunnel:\n  host: gateway.example.com\n  user: backup\n  password: secret\n\
+            rcon_servers:\n\
+            - address: 127.0.0.1:25576\n\
+            - address: \"[::1]:25577\"\n  ssh_tunnel:\n    host: other.example.com\n    port: 2222\n    user: other\n    private_key: /keys/id_ed25519\n",
+        )
+        .unwrap();
+        // the address is resolved on the ssh server
+        let tunnel = config.rcon_servers[0].ssh_tunnel.as_ref().unwrap();
+        assert!(config.rcon_servers[0].address.is_empty());
+        assert_eq!(tunnel.host, "gateway.example.com");
+        assert_eq!(tunnel.port, 22);
+        assert_eq!(tunnel.user, "backup");
+        assert!(matches!(&tunnel.auth, SftpAuth::Password(password) if password == "secret"));
+        // defaults to the top-level tunnel
+        let tunnel = config.rcon_servers[1].ssh_tunnel.as_ref().unwrap();
+        assert_eq!(tunnel.host, "gateway.example.com");
+        let tunnel = config.rcon_servers[2].ssh_tunnel.as_ref().unwrap();
+        assert_eq!(tunnel.host, "other.example.com");
+        assert_eq!(tunnel.port, 2222);
+        assert!(
+            matches!(&tunnel.auth, SftpAuth::PrivateKey(path) if path == Path::new("/keys/id_ed25519"))
+        );
+        assert_eq!(tunnel_target("[::1]:25577").unwrap(), ("::1", 25577));
+
+        let err =
+            parse("rcon_ssh_tunnel:\n  host: gateway.example.com\n  user: backup\n").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("either password or private_key of ssh_tunnel is required"),
+            "{}",
+            err
+        );
+        let err = parse(
+            "rcon_address: minecraft.invalid\n\
+            rcon_ssh_tunnel:\n  host: gateway.example.com\n  user: backup\n  password: secret\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("port of rcon address"), "{}", err);
+    }
+
+    #[test]
+    fn multiple_rcon_servers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = parse_config(
+            format!(
+                "preset: minecraft\n\
+                rcon_password: main\n\
+                rcon_servers:\n\
+                - address: 127.0.0.1:25576\n  password: nether\n\
+                - address: 127.0.0.1:27015\n  preset: factorio\n\
+                on_rcon_failure: continue\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups: []\n",
+                dir.path(),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        // the default address of the preset is not used with rcon_servers
+        let servers = &config.rcon_servers;
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].name, "127.0.0.1:25576");
+        assert_eq!(servers[0].password, "nether");
+        assert!(matches!(servers[0].preset, Some(GamePreset::Minecraft)));
+        assert_eq!(servers[1].address, vec!["127.0.0.1:27015".parse().unwrap()]);
+        assert_eq!(servers[1].password, "");
+        assert!(matches!(servers[1].preset, Some(GamePreset::Factorio)));
+        assert_eq!(config.on_rcon_failure, RconFailureAction::Continue);
+        assert_eq!(config.commands_before, vec!["save-off", "save-all flush"]);
+
+        let config = parse_config(
+            format!(
+                "rcon_address: 127.0.0.1:25575\n\
+                rcon_servers:\n\
+                - address: 127.0.0.1:25576\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups: []\n",
+                dir.path(),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let names = config.rcon_servers.iter().map(|x| x.name.as_str());
+        assert_eq!(
+            names.collect::<Vec<_>>(),
+            vec!["127.0.0.1:25575", "127.0.0.1:25576"]
+        );
+        assert_eq!(config.on_rcon_failure, RconFailureAction::Abort);
+    }
+}
+
+#[cfg(test)]
+mod rcon_retry_test {
+    use super::*;
+
+    #[test]
+    fn delay() {
+        let retry = RconRetry {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            max_attempts: 10,
+        };
+        assert_eq!(retry.delay(0), Duration::from_secs(1));
+        assert_eq!(retry.delay(1), Duration::from_secs(2));
+        assert_eq!(retry.delay(3), Duration::from_secs(8));
+        assert_eq!(retry.delay(4), Duration::from_secs(10));
+        assert_eq!(retry.delay(100), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn from_file() {
+        let file: RconRetryFile = serde_yaml::from_str("base_delay: 0.5\nmax_attempts: 3").unwrap();
+        assert_eq!(
+            RconRetry::from_file(file).unwrap(),
+            RconRetry {
+                base_delay: Duration::from_millis(500),
+                max_delay: Duration::from_secs(60),
+                max_attempts: 3,
+            }
+        );
+
+        let file: RconRetryFile = serde_yaml::from_str("max_attempts: 0").unwrap();
+        assert!(RconRetry::from_file(file).is_err());
+        let file: RconRetryFile = serde_yaml::from_str("base_delay: -1").unwrap();
+        assert!(RconRetry::from_file(file).is_err());
+    }
+}
+
+#[cfg(test)]
+mod frequent_test {
+    use super::*;
+
+    fn parse(extra: &str) -> Result<Box<Config>> {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        parse_config(
+            format!(
+                "save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                {}\
+                backups:\n\
+                - name: frequent\n  interval: 2 minutes\n  max_backups: 5\n",
+                dir.path().join("world"),
+                dir.path().join("backups"),
+                extra,
+            )
+            .as_bytes(),
+        )
+    }
+
+    #[test]
+    fn allow_frequent() {
+        let err = parse("tick: 1 minute\n").unwrap_err();
+        assert!(err.to_string().contains("allow_frequent"), "{}", err);
+
+        let config = parse("allow_frequent: true\ntick: 1 minute\n").unwrap();
+        assert_eq!(config.backups[0].interval, SaveInterval::EveryNMinutes(2));
+        parse("allow_frequent: true\ntick: 2 minutes\n").unwrap();
+    }
+
+    #[test]
+    fn tick_must_divide() {
+        let err = parse("allow_frequent: true\n").unwrap_err();
+        assert!(err.to_string().contains("tick"), "{}", err);
+    }
+}
+
+#[cfg(test)]
+mod include_config_test {
+    use super::*;
+
+    #[test]
+    fn include_config() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        let text = format!(
+            "save_dir: {:?}\n\
+            backup_dir: {:?}\n\
+            backups:\n\
+            - name: included\n  interval: hourly\n  max_backups: 1\n  include_config: true\n\
+            - name: not-included\n  interval: hourly\n  max_backups: 1\n",
+            dir.path().join("world"),
+            dir.path().join("backups"),
+        );
+        let config = parse_config(text.as_bytes()).unwrap();
+        let included = config.backups[0].included_config.as_ref().unwrap();
+        assert_eq!(included.name, "config.yml");
+        let content: serde_yaml::Value = serde_yaml::from_slice(&included.content).unwrap();
+        assert_eq!(
+            content,
+            serde_yaml::from_str::<serde_yaml::Value>(&text).unwrap()
+        );
+        assert!(config.backups[1].included_config.is_none());
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        let text = format!(
+            "rcon_address: 127.0.0.1:25575\n\
+            rcon_password: rcon-secret\n\
+            rcon_servers:\n\
+            - address: 127.0.0.1:25576\n  password: server-secret\n\
+            discord_webhook: https://discord.com/api/webhooks/discord-secret\n\
+            webhook:\n  url: https://example.com/webhook-secret\n\
+            save_dir: {:?}\n\
+            backup_dir: {:?}\n\
+            backups:\n\
+            - name: included\n  interval: hourly\n  max_backups: 1\n  include_config: true\n",
+            dir.path().join("world"),
+            dir.path().join("backups"),
+        );
+        let config = parse_config(text.as_bytes()).unwrap();
+        let included = config.backups[0].included_config.as_ref().unwrap();
+        let content = std::str::from_utf8(&included.content).unwrap();
+        assert!(!content.contains("secret"), "{}", content);
+        let content: serde_yaml::Value = serde_yaml::from_str(content).unwrap();
+        assert_eq!(content["rcon_password"].as_str(), Some(REDACTED));
+        assert_eq!(
+            content["rcon_servers"][0]["password"].as_str(),
+            Some(REDACTED)
+        );
+        assert_eq!(
+            content["rcon_servers"][0]["address"].as_str(),
+            Some("127.0.0.1:25576")
+        );
+        assert_eq!(content["discord_webhook"].as_str(), Some(REDACTED));
+        assert_eq!(content["webhook"]["url"].as_str(), Some(REDACTED));
+        assert_eq!(content["backups"][0]["name"].as_str(), Some("included"));
+    }
+
+    #[test]
+    fn secrets_are_redacted_in_toml() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        let text = format!(
+            "rcon_address = \"127.0.0.1:25575\"\n\
+            rcon_password = \"rcon-secret\"\n\
+            save_dir = {:?}\n\
+            backup_dir = {:?}\n\
+            [[backups]]\n\
+            name = \"included\"\n\
+            interval = \"hourly\"\n\
+            max_backups = 1\n\
+            include_config = true\n",
+            dir.path().join("world"),
+            dir.path().join("backups"),
+        );
+        let config = parse_config_as(
+            text.as_bytes(),
+            ConfigFormat::Toml,
+            &DirOverrides::default(),
+        )
+        .unwrap();
+        let included = config.backups[0].included_config.as_ref().unwrap();
+        assert_eq!(included.name, "config.toml");
+        let content: toml::Value = toml::from_slice(&included.content).unwrap();
+        assert_eq!(content["rcon_password"].as_str(), Some(REDACTED));
+        assert_eq!(content["backups"][0]["name"].as_str(), Some("included"));
+    }
+}
+
+#[cfg(test)]
+mod sftp_test {
+    use super::*;
+
+    fn parse_sftp(backup: &str) -> Result<Box<Config>> {
+        let dir = tempfile::tempdir().unwrap();
+        parse_config(
+            format!(
+                "preset: filesystem\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups:\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 24\n{}",
+                dir.path(),
+                dir.path().join("backups"),
+                backup,
+            )
+            .as_bytes(),
+        )
+    }
+
+    #[test]
+    fn sftp() {
+        let config = parse_sftp(
+            "  sftp:\n    host: example.com\n    user: backup\n    password: secret\n    \
+            host_key: ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAd9gvJMWEwzsAmGoILEdD3daY2PpQJ58ltZiUPD2fGh\n    \
+            remote_dir: /backups\n",
+        )
+        .unwrap();
+        let sftp = config.backups[0].sftp.as_ref().unwrap();
+        assert_eq!(sftp.host, "example.com");
+        assert_eq!(sftp.port, 22);
+        assert!(matches!(&sftp.auth, SftpAuth::Password(password) if password == "secret"));
+        assert!(sftp.host_key.is_some());
+        assert_eq!(sftp.remote_dir, "/backups");
+    }
+
+    #[test]
+    fn sftp_requires_auth() {
+        assert!(parse_sftp(
+            "  sftp:\n    host: example.com\n    user: backup\n    remote_dir: /backups\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sftp_rejects_diff() {
+        assert!(parse_sftp(
+            "  backup_mode: file-diff\n  sftp:\n    host: example.com\n    user: backup\n    \
+            private_key: /path/to/id_ed25519\n    remote_dir: /backups\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sftp_rejects_min_free_bytes() {
+        let err = parse_sftp(
+            "  min_free_bytes: 1000000\n  sftp:\n    host: example.com\n    user: backup\n    \
+            private_key: /path/to/id_ed25519\n    remote_dir: /backups\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("min_free_bytes"), "{}", err);
+    }
+}
+
+#[cfg(test)]
+mod encryption_test {
+    use super::*;
+
+    fn parse_encryption(dir: &std::path::Path, backup: &str) -> Result<Box<Config>> {
+        std::fs::create_dir_all(dir.join("world")).unwrap();
+        parse_config(
+            format!(
+                "save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups:\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 24\n{}",
+                dir.join("world"),
+                dir.join("backups"),
+                backup,
+            )
+            .as_bytes(),
+        )
+    }
+
+    #[test]
+    fn recipient_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let recipient = age::x25519::Identity::generate().to_public();
+        std::fs::write(
+            dir.path().join("recipient.txt"),
+            format!("# public key\n{}\n", recipient),
+        )
+        .unwrap();
+        let config = parse_encryption(
+            dir.path(),
+            &format!(
+                "  encryption:\n    recipient_file: {:?}\n",
+                dir.path().join("recipient.txt")
+            ),
+        )
+        .unwrap();
+        assert!(matches!(
+            &config.backups[0].encryption,
+            Some(Encryption::Recipient(x)) if x.to_string() == recipient.to_string()
+        ));
+    }
+
+    #[test]
+    fn passphrase_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("passphrase"), "secret\n").unwrap();
+        let config = parse_encryption(
+            dir.path(),
+            &format!(
+                "  encryption:\n    passphrase_file: {:?}\n",
+                dir.path().join("passphrase")
+            ),
+        )
+        .unwrap();
+        assert!(matches!(
+            &config.backups[0].encryption,
+            Some(Encryption::Passphrase(_))
+        ));
+    }
+
+    #[test]
+    fn no_key() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(parse_encryption(dir.path(), "  encryption: {}\n").is_err());
+        assert!(parse_encryption(
+            dir.path(),
+            "  encryption:\n    passphrase_env: GAME_SAVE_BACKUPER_TEST_NOT_SET\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("passphrase"), "secret").unwrap();
+        assert!(parse_encryption(
+            dir.path(),
+            &format!(
+                "  backup_mode: modifies-only\n  encryption:\n    passphrase_file: {:?}\n",
+                dir.path().join("passphrase")
+            ),
+        )
+        .is_err());
+    }
+}
+
+#[cfg(test)]
+mod load_config_test {
+    use super::*;
+
+    #[tokio::test]
+    async fn custom_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        let config_path = dir.path().join("custom.yml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups:\n\
+                - name: custom\n  interval: hourly\n  max_backups: 1\n",
+                dir.path().join("world"),
+                dir.path().join("backups"),
+            ),
+        )
+        .unwrap();
+        let config = load_config(Some(&config_path), &DirOverrides::default())
+            .await
+            .unwrap();
+        assert_eq!(config.backups[0].name, "custom");
+
+        let missing = dir.path().join("missing.yml");
+        let err = load_config(Some(&missing), &DirOverrides::default())
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains(&missing.display().to_string()),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn dir_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        std::fs::create_dir(dir.path().join("copy")).unwrap();
+        let config_path = dir.path().join("config.yml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups:\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 1\n",
+                dir.path().join("world"),
+                dir.path().join("backups"),
+            ),
+        )
+        .unwrap();
+        let overrides = DirOverrides {
+            save_dir: Some(dir.path().join("copy")),
+            backup_dir: Some(dir.path().join("test-backups")),
+        };
+        let config = load_config(Some(&config_path), &overrides).await.unwrap();
+        assert_eq!(config.backups[0].save_dirs[0].path, dir.path().join("copy"));
+        assert_eq!(
+            config.backups[0].directory,
+            dir.path().join("test-backups").join("hourly")
+        );
+    }
+}
+
+#[cfg(test)]
+mod config_format_test {
+    use super::*;
+
+    #[test]
+    fn from_path() {
+        let format = |path: &str| ConfigFormat::from_path(Path::new(path)).unwrap();
+        assert_eq!(format("config.yml"), ConfigFormat::Yaml);
+        assert_eq!(format("config.yaml"), ConfigFormat::Yaml);
+        assert_eq!(format("config"), ConfigFormat::Yaml);
+        assert_eq!(format("config.toml"), ConfigFormat::Toml);
+        assert_eq!(format("config.json"), ConfigFormat::Json);
+        ConfigFormat::from_path(Path::new("config.ini")).unwrap_err();
+    }
+
+    #[test]
+    fn same_config() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        let save_dir = dir.path().join("world").display().to_string();
+        let backup_dir = dir.path().join("backups").display().to_string();
+        let yaml = format!(
+            "preset: minecraft\n\
+            timezone: Asia/Tokyo\n\
+            save_dir:\n\
+            - path: {save_dir:?}\n  prefix: world\n\
+            backup_dir: {backup_dir:?}\n\
+            rcon_retry:\n  max_attempts: 3\n\
+            backups:\n\
+            - name: hourly\n  interval: hourly\n  max_backups: 24\n  max_age: 30 days\n\
+            - name: daily\n  interval: daily\n  max_backups: 7\n  compression: gzip\n  \
+            retention:\n    weekly: 4\n",
+        );
+        let toml = format!(
+            "preset = \"minecraft\"\n\
+            timezone = \"Asia/Tokyo\"\n\
+            save_dir = [{{ path = {save_dir:?}, prefix = \"world\" }}]\n\
+            backup_dir = {backup_dir:?}\n\
+            [rcon_retry]\nmax_attempts = 3\n\
+            [[backups]]\nname = \"hourly\"\ninterval = \"hourly\"\nmax_backups = 24\nmax_age = \"30 days\"\n\
+            [[backups]]\nname = \"daily\"\ninterval = \"daily\"\nmax_backups = 7\ncompression = \"gzip\"\n\
+            [backups.retention]\nweekly = 4\n",
+        );
+        let json = serde_json::json!({
+            "preset": "minecraft",
+            "timezone": "Asia/Tokyo",
+            "save_dir": [{ "path": save_dir, "prefix": "world" }],
+            "backup_dir": backup_dir,
+            "rcon_retry": { "max_attempts": 3 },
+            "backups": [
+                { "name": "hourly", "interval": "hourly", "max_backups": 24, "max_age": "30 days" },
+                {
+                    "name": "daily",
+                    "interval": "daily",
+                    "max_backups": 7,
+                    "compression": "gzip",
+                    "retention": { "weekly": 4 },
+                },
+            ],
+        })
+        .to_string();
+
+        let yaml = parse_config_as(
+            yaml.as_bytes(),
+            ConfigFormat::Yaml,
+            &DirOverrides::default(),
+        )
+        .unwrap();
+        let toml = parse_config_as(
+            toml.as_bytes(),
+            ConfigFormat::Toml,
+            &DirOverrides::default(),
+        )
+        .unwrap();
+        let json = parse_config_as(
+            json.as_bytes(),
+            ConfigFormat::Json,
+            &DirOverrides::default(),
+        )
+        .unwrap();
+        assert_eq!(yaml.backups.len(), 2);
+        assert_eq!(format!("{:?}", yaml), format!("{:?}", toml));
+        assert_eq!(format!("{:?}", yaml), format!("{:?}", json));
+    }
 }
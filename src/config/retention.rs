@@ -0,0 +1,234 @@
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// grandfather-father-son retention policy.
+///
+/// for each rule, the newest backup in each of the latest N hours, days, ISO weeks, months, or
+/// years which have backups is kept. backups kept by any rule are kept.
+#[derive(Deserialize, Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Retention {
+    #[serde(default)]
+    pub(crate) hourly: usize,
+    #[serde(default)]
+    pub(crate) daily: usize,
+    #[serde(default)]
+    pub(crate) weekly: usize,
+    #[serde(default)]
+    pub(crate) monthly: usize,
+    #[serde(default)]
+    pub(crate) yearly: usize,
+}
+
+/// the period backups are bucketed by
+#[derive(Copy, Clone)]
+enum Period {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    /// the key of the bucket the time belongs to
+    fn bucket(self, time: &NaiveDateTime) -> (i32, u32, u32, u32) {
+        match self {
+            Period::Hour => (time.year(), time.ordinal(), time.hour(), 0),
+            Period::Day => (time.year(), time.ordinal(), 0, 0),
+            Period::Week => (time.iso_week().year(), time.iso_week().week(), 0, 0),
+            Period::Month => (time.year(), time.month(), 0, 0),
+            Period::Year => (time.year(), 0, 0, 0),
+        }
+    }
+}
+
+impl Retention {
+    /// returns true if no rule keeps backups
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules().iter().all(|(_, count)| *count == 0)
+    }
+
+    fn rules(&self) -> [(Period, usize); 5] {
+        [
+            (Period::Hour, self.hourly),
+            (Period::Day, self.daily),
+            (Period::Week, self.weekly),
+            (Period::Month, self.monthly),
+            (Period::Year, self.yearly),
+        ]
+    }
+
+    /// selects backups to be kept. `names` are names of backups in `name_format`, oldest first.
+    ///
+    /// the newest backup and backups whose time cannot be parsed from the name are always kept.
+    pub(crate) fn keep(&self, names: &[&str], name_format: &str) -> Vec<bool> {
+        let times = names
+            .iter()
+            .map(|name| {
+                let time = NaiveDateTime::parse_from_str(name, name_format).ok();
+                if time.is_none() {
+                    warn!("keeping {} since time cannot be parsed from the name", name);
+                }
+                time
+            })
+            .collect::<Vec<_>>();
+        let mut keep = times.iter().map(Option::is_none).collect::<Vec<_>>();
+        if let Some(newest) = keep.last_mut() {
+            *newest = true;
+        }
+
+        for (period, count) in self.rules() {
+            let mut buckets = HashSet::new();
+            // newest first so the first backup found in a bucket is the newest in the bucket
+            for (i, time) in times.iter().enumerate().rev() {
+                if buckets.len() == count {
+                    break;
+                }
+                if let Some(time) = time {
+                    if buckets.insert(period.bucket(time)) {
+                        keep[i] = true;
+                    }
+                }
+            }
+        }
+        keep
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::files_txt::BACKUP_NAME_FORMAT;
+    use chrono::{Duration, NaiveDate};
+
+    fn name(time: NaiveDateTime) -> String {
+        time.format(BACKUP_NAME_FORMAT).to_string()
+    }
+
+    fn time(y: i32, m: u32, d: u32, h: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, 0, 0)
+            .unwrap()
+    }
+
+    /// simulates rotation after each backup like do_save_backup
+    fn simulate(retention: &Retention, start: NaiveDateTime, end: NaiveDateTime) -> Vec<String> {
+        let mut names = Vec::<String>::new();
+        let mut now = start;
+        while now <= end {
+            names.push(name(now));
+            let refs = names.iter().map(String::as_str).collect::<Vec<_>>();
+            let keep = retention.keep(&refs, BACKUP_NAME_FORMAT);
+            names = names
+                .into_iter()
+                .zip(keep)
+                .filter_map(|(name, keep)| keep.then_some(name))
+                .collect();
+            now += Duration::hours(1);
+        }
+        names
+    }
+
+    #[test]
+    fn year_of_hourly_backups() {
+        let retention = Retention {
+            hourly: 24,
+            daily: 7,
+            weekly: 4,
+            monthly: 12,
+            yearly: 0,
+        };
+        let survivors = simulate(&retention, time(2021, 1, 1, 0), time(2021, 12, 31, 23));
+
+        let mut expected = Vec::new();
+        // monthly: the last backup of each month from January to November
+        for month in 1..=11 {
+            let last_day = NaiveDate::from_ymd_opt(2021, month + 1, 1)
+                .unwrap()
+                .pred_opt()
+                .unwrap()
+                .day();
+            expected.push(time(2021, month, last_day, 23));
+        }
+        // weekly: the last backups of ISO weeks 49 and 50
+        expected.push(time(2021, 12, 12, 23));
+        expected.push(time(2021, 12, 19, 23));
+        // daily: the last backup of each day from Dec 25 to Dec 30. Dec 26 is also of week 51
+        for day in 25..=30 {
+            expected.push(time(2021, 12, day, 23));
+        }
+        // hourly: the last 24 hours which includes the newest of the day, week, and month
+        for hour in 0..24 {
+            expected.push(time(2021, 12, 31, hour));
+        }
+        let expected = expected.into_iter().map(name).collect::<Vec<_>>();
+        assert_eq!(survivors.len(), 43);
+        assert_eq!(survivors, expected);
+    }
+
+    #[test]
+    fn yearly() {
+        let retention = Retention {
+            yearly: 2,
+            ..Default::default()
+        };
+        let names = [
+            name(time(2020, 6, 1, 0)),
+            name(time(2020, 12, 31, 0)),
+            name(time(2021, 12, 31, 0)),
+            name(time(2022, 1, 1, 0)),
+        ];
+        let refs = names.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(
+            retention.keep(&refs, BACKUP_NAME_FORMAT),
+            vec![false, false, true, true]
+        );
+    }
+
+    #[test]
+    fn keeps_newest_and_unknown() {
+        let retention = Retention {
+            daily: 1,
+            ..Default::default()
+        };
+        let first = name(time(2021, 1, 1, 0));
+        let second = name(time(2021, 1, 1, 1));
+        assert_eq!(
+            retention.keep(&["custom", &first, &second], BACKUP_NAME_FORMAT),
+            vec![true, false, true]
+        );
+        assert_eq!(
+            Retention::default().keep(&[&first, &second], BACKUP_NAME_FORMAT),
+            vec![false, true]
+        );
+    }
+
+    #[test]
+    fn is_empty() {
+        assert!(Retention::default().is_empty());
+        assert!(!Retention {
+            weekly: 1,
+            ..Default::default()
+        }
+        .is_empty());
+    }
+
+    #[test]
+    fn custom_name_format() {
+        let retention = Retention {
+            daily: 2,
+            ..Default::default()
+        };
+        let format = "%Y-%m-%dT%H.%M.%S";
+        let names = [
+            time(2021, 1, 1, 0).format(format).to_string(),
+            time(2021, 1, 1, 1).format(format).to_string(),
+            time(2021, 1, 2, 0).format(format).to_string(),
+        ];
+        let refs = names.iter().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(retention.keep(&refs, format), vec![false, true, true]);
+    }
+}
@@ -0,0 +1,636 @@
+//! The diff archive between two backups.
+//!
+//! A diff archive (`{name}.diff.tar`, or `{name}.diff.tar.gz` if compressed) holds enough information to reconstruct a backup from
+//! the backup taken just after it. The first entry is a manifest at [`MANIFEST_PATH`] which
+//! lists every entry of the original backup in order, with how the entry can be reconstructed:
+//!
+//! - `unchanged`: the entry is same as the one in the newer backup, so not stored.
+//! - `whole`: the entry is stored as is in the diff archive.
+//! - `patch`: the entry is stored as a bsdiff patch from the entry in the newer backup.
+//!   entries larger than [`PATCH_SIZE_LIMIT`] are stored whole instead.
+//!
+//! Paths which are not valid UTF-8 are kept as raw bytes in `raw_path` of the manifest.
+//!
+//! Entries only in the newer backup are not listed so they will be dropped on reconstruction.
+//! Entries stored in the diff archive follow the manifest in the order of the manifest.
+
+use crate::config::BackupMode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tar::{Archive, Builder, Header};
+
+pub(crate) const MANIFEST_PATH: &str = ".game-save-backuper/diff-manifest.yml";
+
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
+pub(crate) struct DiffManifest {
+    pub(crate) entries: Vec<DiffEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub(crate) struct DiffEntry {
+    /// the path of the entry, which is lossily converted if the path is not valid utf8
+    pub(crate) path: String,
+    /// the path of the entry as is if the path is not valid utf8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) raw_path: Option<Vec<u8>>,
+    pub(crate) kind: DiffKind,
+}
+
+impl DiffEntry {
+    fn new(path: &[u8], kind: DiffKind) -> Self {
+        match std::str::from_utf8(path) {
+            Ok(path) => Self {
+                path: path.to_owned(),
+                raw_path: None,
+                kind,
+            },
+            Err(_) => Self {
+                path: String::from_utf8_lossy(path).into_owned(),
+                raw_path: Some(path.to_vec()),
+                kind,
+            },
+        }
+    }
+
+    fn path_bytes(&self) -> &[u8] {
+        self.raw_path.as_deref().unwrap_or(self.path.as_bytes())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DiffKind {
+    Unchanged,
+    Whole,
+    Patch,
+}
+
+/// entries larger than this are compared while streamed and stored whole if changed
+/// since bsdiff needs the entries of both backups in memory
+const PATCH_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// the size of chunks large entries are compared in
+const COMPARE_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
+struct IndexedEntry {
+    header: Header,
+    /// the link name which may be longer than the header can hold
+    link_name: Option<Vec<u8>>,
+    position: u64,
+    size: u64,
+}
+
+/// the header, the link name, and the content of an entry
+type EntryData = (Header, Option<Vec<u8>>, Vec<u8>);
+
+/// random-access index of a plain (not compressed) tar file
+struct TarIndex<'a, F> {
+    file: &'a mut F,
+    entries: HashMap<Vec<u8>, IndexedEntry>,
+}
+
+impl<'a, F: Read + Seek> TarIndex<'a, F> {
+    fn new(file: &'a mut F) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut entries = HashMap::new();
+        for entry in Archive::new(&mut *file).entries()? {
+            let entry = entry?;
+            entries.insert(
+                entry.path_bytes().into_owned(),
+                IndexedEntry {
+                    header: entry.header().clone(),
+                    link_name: entry.link_name_bytes().map(|x| x.into_owned()),
+                    position: entry.raw_file_position(),
+                    size: entry.size(),
+                },
+            );
+        }
+        Ok(Self { file, entries })
+    }
+
+    fn get(&self, path: &[u8]) -> Option<&IndexedEntry> {
+        self.entries.get(path)
+    }
+
+    /// the reader of the content of the entry
+    fn open(&mut self, entry: &IndexedEntry) -> io::Result<io::Take<&mut F>> {
+        self.file.seek(SeekFrom::Start(entry.position))?;
+        Ok((&mut *self.file).take(entry.size))
+    }
+
+    fn read(&mut self, path: &[u8]) -> io::Result<Option<EntryData>> {
+        let entry = match self.entries.get(path) {
+            Some(e) => e.clone(),
+            None => return Ok(None),
+        };
+        let mut data = Vec::with_capacity(entry.size as usize);
+        self.open(&entry)?.read_to_end(&mut data)?;
+        Ok(Some((entry.header, entry.link_name, data)))
+    }
+}
+
+/// the path of the path or the link name in tar, which may not be valid utf8
+#[cfg(unix)]
+fn tar_path(path: &[u8]) -> io::Result<&Path> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(Path::new(std::ffi::OsStr::from_bytes(path)))
+}
+
+/// the path of the path or the link name in tar, which may not be valid utf8
+#[cfg(not(unix))]
+fn tar_path(path: &[u8]) -> io::Result<&Path> {
+    std::str::from_utf8(path).map(Path::new).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("non utf8 path in tar: {:x?}", path),
+        )
+    })
+}
+
+/// appends the entry with `size` bytes of `data` to `out`.
+/// paths and link names too long for the header are written in GNU extension entries.
+fn append_entry(
+    out: &mut Builder<impl Write>,
+    header: &mut Header,
+    path: &[u8],
+    link_name: Option<&[u8]>,
+    size: u64,
+    data: impl Read,
+) -> io::Result<()> {
+    header.set_size(size);
+    match link_name {
+        Some(link_name) => out.append_link(header, tar_path(path)?, tar_path(link_name)?),
+        None => out.append_data(header, tar_path(path)?, data),
+    }
+}
+
+/// whether `a` and `b` have same `size` bytes, compared in chunks
+fn same_contents(mut a: impl Read, mut b: impl Read, size: u64) -> io::Result<bool> {
+    let mut chunk_a = vec![0; COMPARE_CHUNK_SIZE];
+    let mut chunk_b = vec![0; COMPARE_CHUNK_SIZE];
+    let mut remaining = size;
+    while remaining != 0 {
+        let len = remaining.min(COMPARE_CHUNK_SIZE as u64) as usize;
+        a.read_exact(&mut chunk_a[..len])?;
+        b.read_exact(&mut chunk_b[..len])?;
+        if chunk_a[..len] != chunk_b[..len] {
+            return Ok(false);
+        }
+        remaining -= len as u64;
+    }
+    Ok(true)
+}
+
+/// creates diff archive to reconstruct `prev` from `new`.
+///
+/// `new` must be a plain tar file because it's read randomly.
+/// entries larger than [PATCH_SIZE_LIMIT] are not read into memory.
+pub(crate) fn create_diff<F: Read + Seek>(
+    prev: impl Read,
+    new: &mut F,
+    mode: BackupMode,
+    out: impl Write,
+) -> io::Result<()> {
+    create_diff_with_limit(prev, new, mode, out, PATCH_SIZE_LIMIT)
+}
+
+fn create_diff_with_limit<F: Read + Seek>(
+    prev: impl Read,
+    new: &mut F,
+    mode: BackupMode,
+    out: impl Write,
+    patch_size_limit: u64,
+) -> io::Result<()> {
+    let mut index = TarIndex::new(new)?;
+    let mut manifest = DiffManifest::default();
+    let mut stored = tempfile::tempfile()?;
+    let mut stored_tar = Builder::new(io::BufWriter::new(&mut stored));
+    // large entries compared with the newer backup are kept here to be stored if changed
+    let mut spool = None;
+
+    for entry in Archive::new(prev).entries()? {
+        let mut entry = entry?;
+        let path = entry.path_bytes().into_owned();
+        let mut header = entry.header().clone();
+        let link_name = entry.link_name_bytes().map(|x| x.into_owned());
+        let size = entry.size();
+        let new_entry = index.get(&path).cloned();
+
+        let large = size > patch_size_limit
+            || new_entry
+                .as_ref()
+                .is_some_and(|x| x.size > patch_size_limit);
+        let kind = if large {
+            match new_entry {
+                Some(new_entry)
+                    if new_entry.header.as_bytes() == header.as_bytes()
+                        && new_entry.link_name == link_name =>
+                {
+                    let spool = match &mut spool {
+                        Some(spool) => spool,
+                        None => spool.insert(tempfile::tempfile()?),
+                    };
+                    spool.set_len(0)?;
+                    spool.seek(SeekFrom::Start(0))?;
+                    io::copy(&mut entry, spool)?;
+                    spool.seek(SeekFrom::Start(0))?;
+                    if same_contents(&mut *spool, index.open(&new_entry)?, size)? {
+                        DiffKind::Unchanged
+                    } else {
+                        spool.seek(SeekFrom::Start(0))?;
+                        let link_name = link_name.as_deref();
+                        append_entry(
+                            &mut stored_tar,
+                            &mut header,
+                            &path,
+                            link_name,
+                            size,
+                            &mut *spool,
+                        )?;
+                        DiffKind::Whole
+                    }
+                }
+                _ => {
+                    let link_name = link_name.as_deref();
+                    append_entry(&mut stored_tar, &mut header, &path, link_name, size, entry)?;
+                    DiffKind::Whole
+                }
+            }
+        } else {
+            let mut data = Vec::with_capacity(size as usize);
+            entry.read_to_end(&mut data)?;
+            let kind = match new_entry {
+                None => DiffKind::Whole,
+                Some(new_entry) if new_entry.header.entry_type() != header.entry_type() => {
+                    DiffKind::Whole
+                }
+                Some(new_entry) => {
+                    let same_header = new_entry.header.as_bytes() == header.as_bytes();
+                    let (_, new_link_name, new_data) = index.read(&path)?.unwrap();
+                    if same_header && new_link_name == link_name && new_data == data {
+                        DiffKind::Unchanged
+                    } else if mode == BackupMode::FileDiff && header.entry_type().is_file() {
+                        let mut patch = Vec::new();
+                        bsdiff::diff(&new_data, &data, &mut patch)?;
+                        data = patch;
+                        DiffKind::Patch
+                    } else {
+                        DiffKind::Whole
+                    }
+                }
+            };
+            if kind != DiffKind::Unchanged {
+                let link_name = link_name.as_deref();
+                let len = data.len() as u64;
+                append_entry(&mut stored_tar, &mut header, &path, link_name, len, &*data)?;
+            }
+            kind
+        };
+        manifest.entries.push(DiffEntry::new(&path, kind));
+    }
+    stored_tar.into_inner()?.flush()?;
+
+    let manifest =
+        serde_yaml::to_vec(&manifest).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut out = Builder::new(out);
+    let mut header = Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    out.append_data(&mut header, MANIFEST_PATH, manifest.as_slice())?;
+
+    stored.seek(SeekFrom::Start(0))?;
+    for entry in Archive::new(&mut stored).entries()? {
+        let mut entry = entry?;
+        let path = entry.path_bytes().into_owned();
+        let mut header = entry.header().clone();
+        let link_name = entry.link_name_bytes().map(|x| x.into_owned());
+        let size = entry.size();
+        append_entry(
+            &mut out,
+            &mut header,
+            &path,
+            link_name.as_deref(),
+            size,
+            &mut entry,
+        )?;
+    }
+    out.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// reconstructs the tar the diff archive is created from.
+///
+/// `new` must be a plain tar file because it's read randomly.
+/// only patched entries are read into memory.
+pub(crate) fn apply_diff<F: Read + Seek>(
+    new: &mut F,
+    diff: impl Read,
+    out: impl Write,
+) -> io::Result<()> {
+    fn invalid(msg: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    let mut index = TarIndex::new(new)?;
+    let mut diff = Archive::new(diff);
+    let mut entries = diff.entries()?;
+
+    let manifest: DiffManifest = {
+        let mut entry = entries
+            .next()
+            .ok_or_else(|| invalid("diff archive is empty".to_owned()))??;
+        if entry.path_bytes().as_ref() != MANIFEST_PATH.as_bytes() {
+            return Err(invalid("diff manifest not found".to_owned()));
+        }
+        let mut buf = Vec::new();
+        entry.read_to_end(&mut buf)?;
+        serde_yaml::from_slice(&buf).map_err(|e| invalid(e.to_string()))?
+    };
+
+    let mut out = Builder::new(out);
+    for diff_entry in manifest.entries {
+        let path = diff_entry.path_bytes();
+        let not_in_newer = || invalid(format!("{} not found in newer backup", diff_entry.path));
+        match diff_entry.kind {
+            DiffKind::Unchanged => {
+                let new_entry = index.get(path).cloned().ok_or_else(not_in_newer)?;
+                let mut header = new_entry.header.clone();
+                let link_name = new_entry.link_name.as_deref();
+                let data = index.open(&new_entry)?;
+                append_entry(&mut out, &mut header, path, link_name, new_entry.size, data)?;
+            }
+            DiffKind::Whole | DiffKind::Patch => {
+                let mut entry = entries.next().ok_or_else(|| {
+                    invalid(format!("{} not found in diff archive", diff_entry.path))
+                })??;
+                if entry.path_bytes().as_ref() != path {
+                    return Err(invalid(format!(
+                        "{} expected but {} found in diff archive",
+                        diff_entry.path,
+                        String::from_utf8_lossy(&entry.path_bytes()),
+                    )));
+                }
+                let mut header = entry.header().clone();
+                let link_name = entry.link_name_bytes().map(|x| x.into_owned());
+                let link_name = link_name.as_deref();
+                if diff_entry.kind == DiffKind::Whole {
+                    let size = entry.size();
+                    append_entry(&mut out, &mut header, path, link_name, size, &mut entry)?;
+                } else {
+                    let mut patch = Vec::with_capacity(entry.size() as usize);
+                    entry.read_to_end(&mut patch)?;
+                    let (_, _, new_data) = index.read(path)?.ok_or_else(not_in_newer)?;
+                    let mut patched = Vec::new();
+                    bsdiff::patch(&new_data, &mut patch.as_slice(), &mut patched)?;
+                    let size = patched.len() as u64;
+                    append_entry(&mut out, &mut header, path, link_name, size, &*patched)?;
+                }
+            }
+        }
+    }
+    out.into_inner()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::File;
+
+    fn make_tar(files: &[(&str, &[u8])]) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut tar = Builder::new(&mut file);
+        for (path, data) in files {
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, *data).unwrap();
+        }
+        tar.into_inner().unwrap();
+        file
+    }
+
+    fn read_all(file: &mut File) -> Vec<u8> {
+        let mut buf = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    fn manifest(diff: &[u8]) -> DiffManifest {
+        let mut archive = Archive::new(diff);
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let mut buf = Vec::new();
+        entry.read_to_end(&mut buf).unwrap();
+        serde_yaml::from_slice(&buf).unwrap()
+    }
+
+    fn round_trip(mode: BackupMode) -> DiffManifest {
+        let region_prev: Vec<u8> = (0..4096u32).map(|x| (x * 7 % 251) as u8).collect();
+        let mut region_new = region_prev.clone();
+        region_new[100] = 0xFF;
+        region_new[2000] = 0x00;
+
+        let mut prev = make_tar(&[
+            ("level.dat", b"level"),
+            ("region/r.0.0.mca", &region_prev),
+            ("removed.dat", b"removed"),
+        ]);
+        let mut new = make_tar(&[
+            ("added.dat", b"added"),
+            ("level.dat", b"level"),
+            ("region/r.0.0.mca", &region_new),
+        ]);
+
+        let mut diff = Vec::new();
+        create_diff(read_all(&mut prev).as_slice(), &mut new, mode, &mut diff).unwrap();
+        let mut reconstructed = Vec::new();
+        apply_diff(&mut new, diff.as_slice(), &mut reconstructed).unwrap();
+
+        assert_eq!(reconstructed, read_all(&mut prev));
+        manifest(&diff)
+    }
+
+    #[test]
+    fn file_diff() {
+        let manifest = round_trip(BackupMode::FileDiff);
+        let kinds = manifest
+            .entries
+            .iter()
+            .map(|x| (x.path.as_str(), x.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ("level.dat", DiffKind::Unchanged),
+                ("region/r.0.0.mca", DiffKind::Patch),
+                ("removed.dat", DiffKind::Whole),
+            ]
+        );
+    }
+
+    #[test]
+    fn modifies_only() {
+        let manifest = round_trip(BackupMode::ModifiesOnly);
+        let kinds = manifest
+            .entries
+            .iter()
+            .map(|x| (x.path.as_str(), x.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ("level.dat", DiffKind::Unchanged),
+                ("region/r.0.0.mca", DiffKind::Whole),
+                ("removed.dat", DiffKind::Whole),
+            ]
+        );
+    }
+
+    #[test]
+    fn large_entries() {
+        let large: Vec<u8> = (0..200_000u32).map(|x| (x * 7 % 251) as u8).collect();
+        let mut changed = large.clone();
+        changed[150_000] ^= 0xFF;
+        let mut prev = make_tar(&[
+            ("changed.dat", &large),
+            ("level.dat", b"level"),
+            ("unchanged.dat", &large),
+        ]);
+        let mut new = make_tar(&[
+            ("changed.dat", &changed),
+            ("level.dat", b"level"),
+            ("unchanged.dat", &large),
+        ]);
+
+        let mut diff = Vec::new();
+        let prev_data = read_all(&mut prev);
+        create_diff_with_limit(
+            prev_data.as_slice(),
+            &mut new,
+            BackupMode::FileDiff,
+            &mut diff,
+            1024,
+        )
+        .unwrap();
+        let mut reconstructed = Vec::new();
+        apply_diff(&mut new, diff.as_slice(), &mut reconstructed).unwrap();
+        assert_eq!(reconstructed, prev_data);
+
+        // large entries are not patched
+        let kinds = manifest(&diff)
+            .entries
+            .into_iter()
+            .map(|x| (x.path, x.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ("changed.dat".to_owned(), DiffKind::Whole),
+                ("level.dat".to_owned(), DiffKind::Unchanged),
+                ("unchanged.dat".to_owned(), DiffKind::Unchanged),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let changed = OsStr::from_bytes(b"world/\xff.dat");
+        let unchanged = OsStr::from_bytes(b"world/\xfe.dat");
+        let make = |level: &[u8]| {
+            let mut file = tempfile::tempfile().unwrap();
+            let mut tar = Builder::new(&mut file);
+            for (path, data) in [(changed, level), (unchanged, b"same".as_slice())] {
+                let mut header = Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                tar.append_data(&mut header, path, data).unwrap();
+            }
+            tar.into_inner().unwrap();
+            file
+        };
+        let mut prev = make(b"prev");
+        let mut new = make(b"new");
+
+        let mut diff = Vec::new();
+        let prev_data = read_all(&mut prev);
+        create_diff(
+            prev_data.as_slice(),
+            &mut new,
+            BackupMode::FileDiff,
+            &mut diff,
+        )
+        .unwrap();
+        let mut reconstructed = Vec::new();
+        apply_diff(&mut new, diff.as_slice(), &mut reconstructed).unwrap();
+        assert_eq!(reconstructed, prev_data);
+
+        let entries = manifest(&diff).entries;
+        assert_eq!(entries[0].path_bytes(), changed.as_bytes());
+        assert_eq!(entries[0].kind, DiffKind::Patch);
+        assert_eq!(entries[1].path_bytes(), unchanged.as_bytes());
+        assert_eq!(entries[1].kind, DiffKind::Unchanged);
+    }
+
+    #[test]
+    fn long_names() {
+        let long = format!("world/{}/level.dat", "d".repeat(200));
+        let make = |level: &[u8], target: &str| {
+            let mut file = tempfile::tempfile().unwrap();
+            let mut tar = Builder::new(&mut file);
+            let mut header = Header::new_gnu();
+            header.set_size(level.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, &long, level).unwrap();
+            let mut header = Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            tar.append_link(&mut header, "link", target).unwrap();
+            tar.into_inner().unwrap();
+            file
+        };
+        let prev_target = format!("{}/prev", "t".repeat(200));
+        let new_target = format!("{}/new", "t".repeat(200));
+        let mut prev = make(b"prev", &prev_target);
+        let mut new = make(b"new", &new_target);
+
+        let mut diff = Vec::new();
+        let prev_data = read_all(&mut prev);
+        create_diff(
+            prev_data.as_slice(),
+            &mut new,
+            BackupMode::FileDiff,
+            &mut diff,
+        )
+        .unwrap();
+        let mut reconstructed = Vec::new();
+        apply_diff(&mut new, diff.as_slice(), &mut reconstructed).unwrap();
+        assert_eq!(reconstructed, prev_data);
+
+        let mut archive = Archive::new(reconstructed.as_slice());
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|x| {
+                let x = x.unwrap();
+                let link = x.link_name().unwrap().map(|x| x.into_owned());
+                (x.path().unwrap().into_owned(), link)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                (long.into(), None),
+                ("link".into(), Some(prev_target.into())),
+            ]
+        );
+    }
+}
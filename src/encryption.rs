@@ -0,0 +1,181 @@
+//! encryption of backup archives with age.
+//!
+//! encrypted archives are saved with `.age` after the extension of the archive
+//! like `backup-2022-01-01-00-00-00.tar.gz.age`.
+
+use crate::config::Encryption;
+use age::secrecy::SecretString;
+use age::stream::{StreamReader, StreamWriter};
+use age::{Decryptor, Encryptor, Identity, IdentityFile};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// the extension of encrypted archives without leading dot
+pub(crate) const EXTENSION: &str = "age";
+
+/// returns true if the archive at the path is encrypted
+pub(crate) fn is_encrypted(path: &Path) -> bool {
+    path.extension().is_some_and(|x| x == EXTENSION)
+}
+
+/// reader which encrypts bytes read from the inner reader
+pub(crate) struct EncryptingReader<R> {
+    inner: R,
+    /// None after all bytes are encrypted
+    writer: Option<StreamWriter<SharedBuffer>>,
+    encrypted: SharedBuffer,
+    /// buffer to read plain bytes into
+    chunk: Vec<u8>,
+}
+
+/// buffer of encrypted bytes not read yet
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R: Read> EncryptingReader<R> {
+    pub(crate) fn new(inner: R, encryption: &Encryption) -> io::Result<Self> {
+        let encryptor = match encryption {
+            Encryption::Recipient(recipient) => {
+                Encryptor::with_recipients(std::iter::once(recipient as &dyn age::Recipient))
+                    .map_err(io::Error::other)?
+            }
+            Encryption::Passphrase(passphrase) => {
+                Encryptor::with_user_passphrase(passphrase.clone())
+            }
+        };
+        let encrypted = SharedBuffer::default();
+        let writer = encryptor.wrap_output(encrypted.clone())?;
+        Ok(Self {
+            inner,
+            writer: Some(writer),
+            encrypted,
+            chunk: vec![0; 64 * 1024],
+        })
+    }
+}
+
+impl<R: Read> Read for EncryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut encrypted = self.encrypted.0.lock().unwrap();
+                if !encrypted.is_empty() {
+                    let len = buf.len().min(encrypted.len());
+                    buf[..len].copy_from_slice(&encrypted[..len]);
+                    encrypted.drain(..len);
+                    return Ok(len);
+                }
+            }
+            let writer = match &mut self.writer {
+                Some(writer) => writer,
+                None => return Ok(0),
+            };
+            match self.inner.read(&mut self.chunk)? {
+                0 => {
+                    self.writer.take().unwrap().finish()?;
+                }
+                read => writer.write_all(&self.chunk[..read])?,
+            }
+        }
+    }
+}
+
+/// the key to decrypt backups
+pub(crate) enum DecryptionKey {
+    /// the path to age identity file
+    IdentityFile(PathBuf),
+    Passphrase(SecretString),
+}
+
+impl DecryptionKey {
+    fn identities(&self) -> io::Result<Vec<Box<dyn Identity>>> {
+        match self {
+            DecryptionKey::IdentityFile(path) => {
+                IdentityFile::from_file(path.to_string_lossy().into_owned())?
+                    .into_identities()
+                    .map_err(io::Error::other)
+            }
+            DecryptionKey::Passphrase(passphrase) => Ok(vec![Box::new(
+                age::scrypt::Identity::new(passphrase.clone()),
+            )]),
+        }
+    }
+}
+
+/// wraps reader of encrypted archive with decryptor
+pub(crate) fn decrypt<R: Read>(reader: R, key: &DecryptionKey) -> io::Result<StreamReader<R>> {
+    let identities = key.identities()?;
+    Decryptor::new(reader)
+        .map_err(io::Error::other)?
+        .decrypt(identities.iter().map(|x| x.as_ref()))
+        .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let identity = age::x25519::Identity::generate();
+        let encryption = Encryption::Recipient(identity.to_public());
+        let data = (0..200_000u32)
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let mut encrypted = Vec::new();
+        EncryptingReader::new(Cursor::new(&data), &encryption)
+            .unwrap()
+            .read_to_end(&mut encrypted)
+            .unwrap();
+        assert_ne!(&encrypted[..data.len().min(encrypted.len())], &data[..]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let identity_path = dir.path().join("identity.txt");
+        std::fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+        let key = DecryptionKey::IdentityFile(identity_path);
+        let mut decrypted = Vec::new();
+        decrypt(Cursor::new(encrypted), &key)
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn wrong_identity() {
+        let encryption = Encryption::Recipient(age::x25519::Identity::generate().to_public());
+        let mut encrypted = Vec::new();
+        EncryptingReader::new(Cursor::new(b"hello"), &encryption)
+            .unwrap()
+            .read_to_end(&mut encrypted)
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let identity_path = dir.path().join("identity.txt");
+        let other = age::x25519::Identity::generate();
+        std::fs::write(&identity_path, other.to_string().expose_secret()).unwrap();
+        let key = DecryptionKey::IdentityFile(identity_path);
+        assert!(decrypt(Cursor::new(encrypted), &key).is_err());
+    }
+
+    #[test]
+    fn is_encrypted_test() {
+        assert!(is_encrypted(Path::new("backup.tar.gz.age")));
+        assert!(!is_encrypted(Path::new("backup.tar.gz")));
+    }
+}
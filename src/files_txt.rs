@@ -0,0 +1,762 @@
+//! files.txt, the list of backups in the backup directory.
+//!
+//! files of a backup are saved in the following order so a crash at any point can be recovered
+//! by [reconcile_files_txt] on startup:
+//!
+//! 1. the archive is written to `{file}.tmp`, synced, and renamed to the archive
+//! 2. `{name}.sha256` is written and synced, so an archive with checksum is complete
+//! 3. the name is appended to files.txt
+//!
+//! old backups are removed in reverse order: archives first, then checksums,
+//! and finally files.txt is rewritten without them.
+//!
+//! with diff backup modes, the previously newest backup is replaced with diff by
+//! writing `{name}.diff.tar` (with extension of compression), rewriting `{name}.sha256` for the diff, and removing the full archive.
+//! [recover_diff_chain] finishes or reverts the replacement stopped in the middle.
+//!
+//! with dedup backup mode, `{name}.manifest` is saved as the archive after objects it references.
+//! see [crate::store].
+
+use crate::checksum::{hash_file, read_checksum, write_checksum};
+use crate::config::Compression;
+use crate::sink::{temp_file_name, BackupSink};
+use crate::{asyncify, encryption, store};
+use chrono::NaiveDateTime;
+use log::warn;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// the format of backup names passed to [chrono::NaiveDateTime::format]
+pub(crate) const BACKUP_NAME_FORMAT: &str = "backup-%Y-%m-%d-%H-%M-%S";
+
+/// lines of files.txt with the backup name of the line, if any.
+/// comments start with `#` and blank lines have no backup name.
+///
+/// files.txt edited on Windows may have CRLF line endings and UTF-8 BOM so they are removed.
+fn files_txt_lines(buffer: &[u8]) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+    buffer
+        .strip_prefix(b"\xEF\xBB\xBF")
+        .unwrap_or(buffer)
+        .split(|b| *b == b'\n')
+        .map(|s| s.strip_suffix(b"\r").unwrap_or(s))
+        .map(|line| {
+            // whitespaces before comments are not a part of the name
+            let name = line.splitn(2, |b| *b == b'#').next().unwrap().trim_ascii();
+            let name = (!name.is_empty()).then_some(name);
+            (line, name)
+        })
+}
+
+fn is_blank(line: &[u8]) -> bool {
+    line.iter()
+        .all(|b| matches!(*b, b'\t' | b'\n' | b'\x0C' | b'\r' | b' '))
+}
+
+/// parses files.txt into list of backup names, oldest first.
+/// comments start with `#` and blank lines are ignored.
+pub(crate) fn parse_files_txt(buffer: &[u8]) -> Vec<&[u8]> {
+    files_txt_lines(buffer)
+        .filter_map(|(_, name)| name)
+        .collect::<Vec<_>>()
+}
+
+/// names of backups pinned by lines ending with `#keep` comment.
+/// pinned backups are never removed by rotation.
+pub(crate) fn pinned_backups(buffer: &[u8]) -> Vec<&[u8]> {
+    files_txt_lines(buffer)
+        .filter(|(line, _)| line.trim_ascii_end().ends_with(b"#keep"))
+        .filter_map(|(_, name)| name)
+        .collect()
+}
+
+/// the line to be appended to files.txt with `buffer` contents to add the backup.
+/// a newline is inserted only if the last line of files.txt is not terminated.
+pub(crate) fn files_txt_entry(buffer: &[u8], name: &str) -> String {
+    if buffer.is_empty() || buffer.ends_with(b"\n") {
+        format!("{}\n", name)
+    } else {
+        format!("\n{}\n", name)
+    }
+}
+
+/// contents of files.txt without lines of `removed` backups.
+/// comments and blank lines between remaining lines are kept but runs of blank lines are
+/// collapsed into one. the result is normalized to LF without BOM and each line is terminated.
+pub(crate) fn remove_from_files_txt(buffer: &[u8], removed: &[&[u8]]) -> Vec<u8> {
+    let mut lines = Vec::<&[u8]>::new();
+    for (line, name) in files_txt_lines(buffer) {
+        if name.is_some_and(|name| removed.contains(&name)) {
+            continue;
+        }
+        // leading blank lines and blank lines after blank line are removed
+        if is_blank(line) && lines.last().is_none_or(|last| is_blank(last)) {
+            continue;
+        }
+        lines.push(line);
+    }
+    if lines.last().is_some_and(|last| is_blank(last)) {
+        lines.pop();
+    }
+    let mut contents = Vec::new();
+    for line in lines {
+        contents.extend_from_slice(line);
+        contents.push(b'\n');
+    }
+    contents
+}
+
+/// reads files.txt in the directory into list of backup names, oldest first.
+pub(crate) fn read_files_txt(directory: &Path) -> std::io::Result<Vec<String>> {
+    let buffer = std::fs::read(directory.join("files.txt"))?;
+    parse_files_txt(&buffer)
+        .into_iter()
+        .map(|name| {
+            std::str::from_utf8(name)
+                .map(str::to_owned)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+/// finds full backup archive of the backup with any compression.
+/// the archive may be encrypted. see [crate::encryption::is_encrypted].
+pub(crate) fn find_full_backup(directory: &Path, name: &str) -> Option<(Compression, PathBuf)> {
+    full_backup_file_names(name).find_map(|(c, file_name)| {
+        let path = directory.join(file_name);
+        path.exists().then_some((c, path))
+    })
+}
+
+/// names of full backup archive of the backup with any compression, encrypted or not.
+pub(crate) fn full_backup_file_names(
+    name: &str,
+) -> impl Iterator<Item = (Compression, String)> + '_ {
+    Compression::ALL.iter().flat_map(move |c| {
+        let file_name = format!("{}.{}", name, c.extension());
+        let encrypted = format!("{}.{}", file_name, encryption::EXTENSION);
+        [(*c, file_name), (*c, encrypted)]
+    })
+}
+
+/// names of diff archive of the backup with any compression.
+pub(crate) fn diff_file_names(name: &str) -> impl Iterator<Item = (Compression, String)> + '_ {
+    Compression::ALL
+        .iter()
+        .map(move |c| (*c, format!("{}.diff.{}", name, c.extension())))
+}
+
+/// finds diff archive of the backup with any compression.
+pub(crate) fn find_diff(directory: &Path, name: &str) -> Option<(Compression, PathBuf)> {
+    diff_file_names(name).find_map(|(c, file_name)| {
+        let path = directory.join(file_name);
+        path.exists().then_some((c, path))
+    })
+}
+
+/// recovers files.txt from the state the process stopped while rewriting files.txt.
+///
+/// files.txt is rewritten by writing `.files.txt`, removing files.txt, and renaming
+/// `.files.txt` to files.txt. if files.txt exists, `.files.txt` may be partially written
+/// so it's removed. Otherwise, `.files.txt` is fully written so it's renamed to files.txt.
+pub(crate) async fn recover_files_txt(sink: &impl BackupSink) -> std::io::Result<()> {
+    let file_names = sink.list().await?;
+    if !file_names.iter().any(|x| x == ".files.txt") {
+        return Ok(());
+    }
+    let dot_files_txt_path = sink.path(".files.txt");
+    if file_names.iter().any(|x| x == "files.txt") {
+        warn!("removing leftover {}", dot_files_txt_path.display());
+        sink.delete(".files.txt").await
+    } else {
+        warn!("recovering files.txt from {}", dot_files_txt_path.display());
+        sink.rename(".files.txt", "files.txt").await
+    }
+}
+
+/// the backup the file belongs to and whether the file is the checksum of the backup
+fn backup_of_file(file_name: &str) -> Option<(&str, bool)> {
+    if let Some(name) = file_name.strip_suffix(".sha256") {
+        return Some((name, true));
+    }
+    if let Some(name) = file_name.strip_suffix(&format!(".{}", store::MANIFEST_EXTENSION)) {
+        return Some((name, false));
+    }
+    let archive = file_name
+        .strip_suffix(&format!(".{}", encryption::EXTENSION))
+        .unwrap_or(file_name);
+    // diff suffixes are checked first since they end with extensions of full archives
+    let diffs = Compression::ALL
+        .iter()
+        .map(|c| format!(".diff.{}", c.extension()));
+    let fulls = Compression::ALL
+        .iter()
+        .map(|c| format!(".{}", c.extension()));
+    diffs
+        .chain(fulls)
+        .find_map(|suffix| archive.strip_suffix(&suffix))
+        .map(|name| (name, false))
+}
+
+/// makes files.txt consistent with files in the directory after the process stopped
+/// while saving or removing backups. see the module document for the order of saving.
+///
+/// - backups with archive and checksum but not in files.txt are added to files.txt
+/// - archives without checksum and not in files.txt are partially written so removed
+/// - backups in files.txt without archive are removed from files.txt
+/// - temporal files not renamed to the archive are removed
+///
+/// files not in files.txt are only removed or added if their names are in `name_format`.
+/// other files in the directory may be placed by the user so they are logged and kept.
+pub(crate) async fn reconcile_files_txt(
+    sink: &impl BackupSink,
+    name_format: &str,
+) -> std::io::Result<()> {
+    let directory = sink.directory();
+    let is_backup = |name: &str| NaiveDateTime::parse_from_str(name, name_format).is_ok();
+    // (has archive, has checksum) of each backup
+    let mut found = BTreeMap::<String, (bool, bool)>::new();
+    for file_name in sink.list().await? {
+        if let Some(original) = file_name.strip_suffix(&temp_file_name("")) {
+            if backup_of_file(original).is_some_and(|(name, _)| is_backup(name)) {
+                warn!(
+                    "removing partially written {} in {}",
+                    file_name,
+                    directory.display()
+                );
+                sink.delete(&file_name).await?;
+            } else {
+                warn!(
+                    "keeping {} in {} since it's not a file of backups",
+                    file_name,
+                    directory.display()
+                );
+            }
+            continue;
+        }
+        if let Some((name, checksum)) = backup_of_file(&file_name) {
+            let entry = found.entry(name.to_owned()).or_default();
+            if checksum {
+                entry.1 = true;
+            } else {
+                entry.0 = true;
+            }
+        }
+    }
+
+    let buffer = sink.read("files.txt").await?.unwrap_or_default();
+    let mut removed = Vec::new();
+    for name in parse_files_txt(&buffer) {
+        let name_str = String::from_utf8_lossy(name).into_owned();
+        if found.get(&name_str).is_some_and(|(archive, _)| *archive) {
+            found.remove(&name_str);
+        } else {
+            warn!(
+                "removing {} from files.txt of {}: archive not found",
+                name_str,
+                directory.display()
+            );
+            removed.push(name);
+        }
+    }
+
+    let mut untracked = Vec::new();
+    for (name, (archive, checksum)) in found {
+        if !is_backup(&name) {
+            warn!(
+                "keeping files of {} in {} since the name is not in name_format",
+                name,
+                directory.display()
+            );
+            continue;
+        }
+        if archive && checksum {
+            warn!("adding untracked backup {} to files.txt", name);
+            untracked.push(name);
+            continue;
+        }
+        if archive {
+            warn!(
+                "removing incomplete backup {} in {}",
+                name,
+                directory.display()
+            );
+        }
+        let file_names = full_backup_file_names(&name)
+            .chain(diff_file_names(&name))
+            .map(|(_, file_name)| file_name)
+            .chain([store::manifest_file_name(&name), format!("{}.sha256", name)]);
+        for file_name in file_names {
+            sink.delete(&file_name).await?;
+        }
+    }
+
+    if !removed.is_empty() || !untracked.is_empty() {
+        // comments and pins of remaining lines are kept.
+        // untracked backups are usually the newest ones so appended in the order of names
+        let mut contents = remove_from_files_txt(&buffer, &removed);
+        for name in untracked {
+            let entry = files_txt_entry(&contents, &name);
+            contents.extend_from_slice(entry.as_bytes());
+        }
+        sink.replace("files.txt", &contents).await?;
+    }
+    Ok(())
+}
+
+/// resolves backups with both full archive and diff left by the process stopped
+/// while replacing the backup with diff.
+///
+/// if the checksum file is of the diff, the diff is completely written and
+/// the next backup in files.txt the diff is based on exists, the full archive is removed.
+/// otherwise, the diff may be partially written so the diff is removed and
+/// the checksum is written again for the full archive.
+pub(crate) async fn recover_diff_chain(directory: &Path) -> std::io::Result<()> {
+    let names = match read_files_txt(directory) {
+        Ok(names) => names,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for (i, name) in names.iter().enumerate() {
+        let full_path = match find_full_backup(directory, name) {
+            Some((_, path)) => path,
+            None => continue,
+        };
+        let diff_path = match find_diff(directory, name) {
+            Some((_, path)) => path,
+            None => continue,
+        };
+        let diff_file_name = diff_path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+
+        let has_next = i + 1 < names.len();
+        let directory = directory.to_owned();
+        let name = name.clone();
+        asyncify(move || {
+            let diff_complete = has_next
+                && match read_checksum(&directory, &name) {
+                    Ok(Some((hash, file_name))) => {
+                        file_name == diff_file_name && hash == hash_file(&diff_path)?
+                    }
+                    Ok(None) => false,
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidData => false,
+                    Err(e) => return Err(e),
+                };
+            if diff_complete {
+                warn!(
+                    "removing {} already replaced with diff",
+                    full_path.display()
+                );
+                std::fs::remove_file(&full_path)
+            } else {
+                warn!("removing incomplete diff {}", diff_path.display());
+                std::fs::remove_file(&diff_path)?;
+                let file_name = full_path.file_name().unwrap().to_string_lossy();
+                write_checksum(&directory, &name, &file_name, &hash_file(&full_path)?)
+            }
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sink::LocalSink;
+    use std::fs;
+
+    #[test]
+    fn parse_crlf() {
+        assert_eq!(
+            parse_files_txt(b"a\r\nb # comment\r\n\r\nc\r\n"),
+            vec![&b"a"[..], b"b", b"c"]
+        );
+    }
+
+    #[test]
+    fn remove_keeps_comments() {
+        assert_eq!(
+            remove_from_files_txt(
+                b"# backups\na\n\nb\n# before update #keep\nc\n\nd # manual\n",
+                &[b"a", b"c"],
+            ),
+            b"# backups\n\nb\n# before update #keep\n\nd # manual\n"
+        );
+        assert_eq!(remove_from_files_txt(b"\na\n\nb\n", &[b"a"]), b"b\n");
+        assert_eq!(
+            remove_from_files_txt(b"\xEF\xBB\xBFa\r\nb\r\n", &[]),
+            b"a\nb\n"
+        );
+        assert_eq!(remove_from_files_txt(b"a\n", &[b"a"]), b"");
+        assert_eq!(
+            remove_from_files_txt(b"\n\na\n\n\n\nb\n \n\n", &[]),
+            b"a\n\nb\n"
+        );
+    }
+
+    #[test]
+    fn entry() {
+        assert_eq!(files_txt_entry(b"", "a"), "a\n");
+        assert_eq!(files_txt_entry(b"a\n", "b"), "b\n");
+        assert_eq!(files_txt_entry(b"a", "b"), "\nb\n");
+    }
+
+    #[test]
+    fn pinned() {
+        assert_eq!(
+            pinned_backups(b"a #keep\n# note #keep\nb\nc # before update #keep \r\nd # keep\n"),
+            vec![&b"a"[..], b"c"]
+        );
+    }
+
+    #[test]
+    fn parse_bom() {
+        assert_eq!(
+            parse_files_txt(b"\xEF\xBB\xBFa\nb\n"),
+            vec![&b"a"[..], b"b"]
+        );
+        assert_eq!(
+            parse_files_txt(b"\xEF\xBB\xBF# comment\r\na"),
+            vec![&b"a"[..]]
+        );
+    }
+
+    #[tokio::test]
+    async fn recover_removes_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("files.txt"), b"a\nb\n").unwrap();
+        fs::write(dir.path().join(".files.txt"), b"b").unwrap();
+        recover_files_txt(&LocalSink::new(dir.path()))
+            .await
+            .unwrap();
+        assert!(!dir.path().join(".files.txt").exists());
+        assert_eq!(fs::read(dir.path().join("files.txt")).unwrap(), b"a\nb\n");
+    }
+
+    #[tokio::test]
+    async fn recover_renames_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(".files.txt"), b"b\n").unwrap();
+        recover_files_txt(&LocalSink::new(dir.path()))
+            .await
+            .unwrap();
+        assert!(!dir.path().join(".files.txt").exists());
+        assert_eq!(fs::read(dir.path().join("files.txt")).unwrap(), b"b\n");
+    }
+
+    #[tokio::test]
+    async fn recover_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        recover_files_txt(&LocalSink::new(dir.path()))
+            .await
+            .unwrap();
+        assert!(!dir.path().join("files.txt").exists());
+    }
+
+    fn list_sorted(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn backup_of_file_test() {
+        assert_eq!(backup_of_file("a.tar"), Some(("a", false)));
+        assert_eq!(backup_of_file("a.tar.gz.age"), Some(("a", false)));
+        assert_eq!(backup_of_file("a.diff.tar"), Some(("a", false)));
+        assert_eq!(backup_of_file("a.diff.tar.gz"), Some(("a", false)));
+        assert_eq!(backup_of_file("a.manifest"), Some(("a", false)));
+        assert_eq!(backup_of_file("a.sha256"), Some(("a", true)));
+        assert_eq!(backup_of_file("files.txt"), None);
+    }
+
+    /// the name of backup `i` in [BACKUP_NAME_FORMAT]
+    fn name(i: u32) -> String {
+        format!("backup-2021-01-01-00-{:02}-00", i)
+    }
+
+    /// writes empty files of `(backup, extension)` pairs
+    fn touch(dir: &Path, files: &[(u32, &str)]) {
+        for (i, extension) in files {
+            fs::write(dir.join(format!("{}.{}", name(*i), extension)), b"").unwrap();
+        }
+    }
+
+    async fn reconcile(dir: &Path) {
+        reconcile_files_txt(&LocalSink::new(dir), BACKUP_NAME_FORMAT)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconcile_consistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let files_txt = format!("\n{}\n\n{}\n", name(0), name(1));
+        fs::write(dir.path().join("files.txt"), &files_txt).unwrap();
+        touch(
+            dir.path(),
+            &[(0, "diff.tar"), (0, "sha256"), (1, "tar.gz"), (1, "sha256")],
+        );
+        fs::write(dir.path().join("other.txt"), b"").unwrap();
+        reconcile(dir.path()).await;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            files_txt
+        );
+        assert_eq!(list_sorted(dir.path()).len(), 6);
+    }
+
+    #[tokio::test]
+    async fn reconcile_crash_while_writing_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("files.txt"), name(0)).unwrap();
+        touch(dir.path(), &[(0, "tar"), (0, "sha256")]);
+        fs::write(dir.path().join(format!("{}.tar", name(1))), b"partial").unwrap();
+        reconcile(dir.path()).await;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            name(0)
+        );
+        assert_eq!(
+            list_sorted(dir.path()),
+            vec![
+                format!("{}.sha256", name(0)),
+                format!("{}.tar", name(0)),
+                "files.txt".to_owned()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_crash_before_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("files.txt"), name(0)).unwrap();
+        touch(dir.path(), &[(0, "tar"), (0, "sha256")]);
+        fs::write(dir.path().join(format!("{}.tar.tmp", name(1))), b"partial").unwrap();
+        reconcile(dir.path()).await;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            name(0)
+        );
+        assert_eq!(
+            list_sorted(dir.path()),
+            vec![
+                format!("{}.sha256", name(0)),
+                format!("{}.tar", name(0)),
+                "files.txt".to_owned()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_crash_before_appending() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("files.txt"), name(0)).unwrap();
+        touch(
+            dir.path(),
+            &[(0, "tar"), (0, "sha256"), (1, "tar.gz"), (1, "sha256")],
+        );
+        reconcile(dir.path()).await;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            format!("{}\n{}\n", name(0), name(1))
+        );
+        assert_eq!(list_sorted(dir.path()).len(), 5);
+    }
+
+    #[tokio::test]
+    async fn reconcile_crash_while_removing() {
+        let dir = tempfile::tempdir().unwrap();
+        // the archive of 0 is removed but checksum and files.txt are not updated yet
+        fs::write(
+            dir.path().join("files.txt"),
+            format!("{}\n{}", name(0), name(1)),
+        )
+        .unwrap();
+        touch(dir.path(), &[(0, "sha256"), (1, "tar"), (1, "sha256")]);
+        reconcile(dir.path()).await;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            format!("{}\n", name(1))
+        );
+        assert_eq!(
+            list_sorted(dir.path()),
+            vec![
+                format!("{}.sha256", name(1)),
+                format!("{}.tar", name(1)),
+                "files.txt".to_owned()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_keeps_pins() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("files.txt"),
+            format!(
+                "# backups of the survival world\n{} #keep\n\n{}\n{}\n",
+                name(0),
+                name(1),
+                name(2)
+            ),
+        )
+        .unwrap();
+        // the archive of 1 is removed and 3 is not appended yet
+        touch(
+            dir.path(),
+            &[
+                (0, "tar"),
+                (0, "sha256"),
+                (2, "tar"),
+                (2, "sha256"),
+                (3, "tar"),
+                (3, "sha256"),
+            ],
+        );
+        reconcile(dir.path()).await;
+        let files_txt = fs::read(dir.path().join("files.txt")).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&files_txt),
+            format!(
+                "# backups of the survival world\n{} #keep\n\n{}\n{}\n",
+                name(0),
+                name(2),
+                name(3)
+            )
+        );
+        assert_eq!(pinned_backups(&files_txt), vec![name(0).as_bytes()]);
+    }
+
+    #[tokio::test]
+    async fn reconcile_keeps_foreign_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("files.txt"), name(0)).unwrap();
+        touch(dir.path(), &[(0, "tar"), (0, "sha256")]);
+        // files placed by the user look like partially written backups
+        for file in [
+            "export.tar.gz",
+            "notes.tmp",
+            "export.tar.gz.tmp",
+            "world.sha256",
+        ] {
+            fs::write(dir.path().join(file), b"mine").unwrap();
+        }
+        reconcile(dir.path()).await;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            name(0)
+        );
+        for file in [
+            "export.tar.gz",
+            "notes.tmp",
+            "export.tar.gz.tmp",
+            "world.sha256",
+        ] {
+            assert_eq!(
+                fs::read(dir.path().join(file)).unwrap(),
+                b"mine",
+                "{}",
+                file
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_keeps_backups_in_other_format() {
+        let dir = tempfile::tempdir().unwrap();
+        // backups saved before name_format was changed are still in files.txt
+        fs::write(dir.path().join("files.txt"), "old-backup\n").unwrap();
+        for file in ["old-backup.tar", "old-backup.sha256"] {
+            fs::write(dir.path().join(file), b"").unwrap();
+        }
+        reconcile(dir.path()).await;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            "old-backup\n"
+        );
+        assert_eq!(list_sorted(dir.path()).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn reconcile_no_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        reconcile(&dir.path().join("not-exists")).await;
+        assert!(!dir.path().join("not-exists").exists());
+    }
+
+    /// creates backup a replaced with diff based on b, stopped after `steps` steps
+    fn diff_chain(steps: usize, files_txt: &[u8]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        fs::write(path.join("files.txt"), files_txt).unwrap();
+        fs::write(path.join("a.tar"), b"full").unwrap();
+        write_checksum(path, "a", "a.tar", &hash_file(&path.join("a.tar")).unwrap()).unwrap();
+        fs::write(path.join("b.tar"), b"newer").unwrap();
+        write_checksum(path, "b", "b.tar", &hash_file(&path.join("b.tar")).unwrap()).unwrap();
+        match steps {
+            0 => fs::write(path.join("a.diff.tar"), b"parti").unwrap(),
+            1 => {
+                fs::write(path.join("a.diff.tar"), b"diff").unwrap();
+                fs::write(path.join("a.sha256"), b"0123").unwrap();
+            }
+            2 => {
+                fs::write(path.join("a.diff.tar"), b"diff").unwrap();
+                let hash = hash_file(&path.join("a.diff.tar")).unwrap();
+                write_checksum(path, "a", "a.diff.tar", &hash).unwrap();
+            }
+            _ => unreachable!(),
+        }
+        dir
+    }
+
+    fn assert_reverted(path: &Path) {
+        assert_eq!(
+            list_sorted(path),
+            vec!["a.sha256", "a.tar", "b.sha256", "b.tar", "files.txt"]
+        );
+        let hash = hash_file(&path.join("a.tar")).unwrap();
+        assert_eq!(
+            read_checksum(path, "a").unwrap(),
+            Some((hash, "a.tar".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn diff_chain_crash_while_writing_diff() {
+        let dir = diff_chain(0, b"a\nb");
+        recover_diff_chain(dir.path()).await.unwrap();
+        assert_reverted(dir.path());
+    }
+
+    #[tokio::test]
+    async fn diff_chain_crash_while_writing_checksum() {
+        let dir = diff_chain(1, b"a\nb");
+        recover_diff_chain(dir.path()).await.unwrap();
+        assert_reverted(dir.path());
+    }
+
+    #[tokio::test]
+    async fn diff_chain_crash_before_removing_full() {
+        let dir = diff_chain(2, b"a\nb");
+        recover_diff_chain(dir.path()).await.unwrap();
+        assert_eq!(
+            list_sorted(dir.path()),
+            vec!["a.diff.tar", "a.sha256", "b.sha256", "b.tar", "files.txt"]
+        );
+    }
+
+    #[tokio::test]
+    async fn diff_chain_without_next() {
+        // the diff can't be used since the backup it's based on is not in files.txt
+        let dir = diff_chain(2, b"a");
+        recover_diff_chain(dir.path()).await.unwrap();
+        assert_reverted(dir.path());
+    }
+}
@@ -0,0 +1,99 @@
+//! the http endpoint for liveness probes.
+//!
+//! `GET /healthz` responds with 200 if the main loop is alive, or 503 if it seems to be stuck.
+
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// the time the main loop is expected to come back by
+pub(crate) struct Heartbeat {
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl Heartbeat {
+    pub(crate) fn new() -> Self {
+        Self {
+            deadline: Mutex::new(None),
+        }
+    }
+
+    /// records the main loop is alive. it's regarded as stuck if not beaten again within 2 ticks
+    pub(crate) fn beat(&self, tick: Duration) {
+        *self.deadline.lock().unwrap() = Some(Instant::now() + tick * 2);
+    }
+
+    /// returns true if the main loop has started and is not stuck
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.deadline
+            .lock()
+            .unwrap()
+            .is_some_and(|deadline| Instant::now() <= deadline)
+    }
+}
+
+/// starts the health endpoint at `address` in background and returns the bound address
+pub(crate) fn start(address: SocketAddr, heartbeat: Arc<Heartbeat>) -> Result<SocketAddr> {
+    let make_service = make_service_fn(move |_| {
+        let heartbeat = heartbeat.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&request, &heartbeat);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&address)?.serve(make_service);
+    let address = server.local_addr();
+    info!("serving health endpoint at http://{}/healthz", address);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("error serving health endpoint: {}", e);
+        }
+    });
+    Ok(address)
+}
+
+fn respond(request: &Request<Body>, heartbeat: &Heartbeat) -> Response<Body> {
+    let (status, body) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") if heartbeat.is_healthy() => (StatusCode::OK, "ok"),
+        (&Method::GET, "/healthz") => (StatusCode::SERVICE_UNAVAILABLE, "stale"),
+        _ => (StatusCode::NOT_FOUND, "not found"),
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn healthz() {
+        let heartbeat = Arc::new(Heartbeat::new());
+        let address = start("127.0.0.1:0".parse().unwrap(), heartbeat.clone()).unwrap();
+        let status = |path: &'static str| async move {
+            reqwest::get(format!("http://{}{}", address, path))
+                .await
+                .unwrap()
+                .status()
+                .as_u16()
+        };
+
+        // the main loop is not started yet
+        assert_eq!(status("/healthz").await, 503);
+        heartbeat.beat(Duration::from_millis(100));
+        assert_eq!(status("/healthz").await, 200);
+        assert_eq!(status("/other").await, 404);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(status("/healthz").await, 503);
+        heartbeat.beat(Duration::from_millis(100));
+        assert_eq!(status("/healthz").await, 200);
+    }
+}
@@ -0,0 +1,109 @@
+//! commands run after backups
+
+use anyhow::{bail, Context as _, Result};
+use log::{info, trace, warn};
+use std::path::Path;
+use tokio::process::Command;
+
+/// expands `{file}`, `{name}`, and `{dir}` in each argument
+pub(crate) fn expand(args: &[String], file: &Path, name: &str, dir: &Path) -> Vec<String> {
+    let file = file.to_string_lossy();
+    let dir = dir.to_string_lossy();
+    args.iter()
+        .map(|arg| {
+            arg.replace("{file}", &file)
+                .replace("{name}", name)
+                .replace("{dir}", &dir)
+        })
+        .collect()
+}
+
+/// runs post_backup_command for the backup saved at `file`.
+/// output of the command is logged.
+pub(crate) async fn run_post_backup_command(
+    args: &[String],
+    file: &Path,
+    name: &str,
+    dir: &Path,
+) -> Result<()> {
+    let args = expand(args, file, name, dir);
+    trace!("running post backup command: {:?}", args);
+    let output = Command::new(&args[0])
+        .args(&args[1..])
+        .output()
+        .await
+        .with_context(|| format!("running {}", args[0]))?;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("{}: {}", args[0], line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("{}: {}", args[0], line);
+    }
+    if !output.status.success() {
+        bail!("{} exited with {}", args[0], output.status)
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&x| x.to_owned()).collect()
+    }
+
+    #[test]
+    fn expand_placeholders() {
+        assert_eq!(
+            expand(
+                &args(&["rclone", "copy", "{file}", "remote:backups/{name}"]),
+                Path::new("/backups/hourly/backup-1.tar"),
+                "backup-1",
+                Path::new("/backups/hourly"),
+            ),
+            args(&[
+                "rclone",
+                "copy",
+                "/backups/hourly/backup-1.tar",
+                "remote:backups/backup-1"
+            ])
+        );
+        assert_eq!(
+            expand(
+                &args(&["echo", "{dir}/{name}"]),
+                Path::new("/a b/c.tar"),
+                "c",
+                Path::new("/a b"),
+            ),
+            args(&["echo", "/a b/c"])
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn run() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("backup-1.tar");
+        let out = dir.path().join("out");
+        run_post_backup_command(
+            &args(&["cp", "{file}", &out.to_string_lossy()]),
+            &file,
+            "backup-1",
+            dir.path(),
+        )
+        .await
+        .unwrap_err();
+
+        std::fs::write(&file, b"backup").unwrap();
+        run_post_backup_command(
+            &args(&["cp", "{file}", &out.to_string_lossy()]),
+            &file,
+            "backup-1",
+            dir.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), b"backup");
+    }
+}
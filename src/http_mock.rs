@@ -0,0 +1,82 @@
+//! minimal http server for tests
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// the path and body of received requests
+type Requests = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+pub(crate) struct MockHttp {
+    pub(crate) address: SocketAddr,
+    requests: Requests,
+}
+
+impl MockHttp {
+    /// starts server which responds with `statuses` in order, then 204 for remaining requests
+    pub(crate) async fn start(statuses: Vec<u16>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+        let requests1 = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream, requests1.clone(), statuses.clone()));
+            }
+        });
+        Self { address, requests }
+    }
+
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    pub(crate) fn requests(&self) -> Vec<(String, Vec<u8>)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    requests: Requests,
+    statuses: Arc<Mutex<std::vec::IntoIter<u16>>>,
+) {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let path = request_line
+            .split(' ')
+            .nth(1)
+            .unwrap_or_default()
+            .to_owned();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await.unwrap();
+        requests.lock().unwrap().push((path, body));
+
+        let status = statuses.lock().unwrap().next().unwrap_or(204);
+        let response = format!("HTTP/1.1 {} Mock\r\ncontent-length: 0\r\n\r\n", status);
+        if stream.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! backs up save data of game servers at intervals.
+//!
+//! this library is the core of the game-save-backuper command.
+//! [Backuper] takes backups with [Config] in other programs.
+
+mod archive;
+mod backuper;
+mod blocking;
+mod check;
+mod checksum;
+/// the command line interface. not a stable api
+#[doc(hidden)]
+pub mod cli;
+mod config;
+mod diff;
+mod encryption;
+mod files_txt;
+mod health;
+mod hook;
+#[cfg(test)]
+mod http_mock;
+mod list;
+mod lock;
+mod logging;
+mod notify;
+mod prune;
+mod rcon;
+#[cfg(test)]
+mod rcon_mock;
+mod reload;
+mod restore;
+mod rotation;
+mod schedule;
+mod scheduler;
+#[cfg(test)]
+mod sftp_mock;
+mod sink;
+mod status;
+mod store;
+mod tar;
+mod throttle;
+mod tunnel;
+mod verify;
+
+pub use self::backuper::Backuper;
+pub use self::config::interval;
+pub use self::config::{BackupSetting, Config, SaveInterval};
+
+pub(crate) use self::blocking::asyncify;
@@ -0,0 +1,236 @@
+use crate::config::Config;
+use crate::files_txt::{find_diff, find_full_backup, read_files_txt};
+use crate::store::find_manifest;
+use anyhow::{Context as _, Result};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize, Debug, Eq, PartialEq)]
+pub(crate) struct SettingBackups {
+    pub(crate) setting: String,
+    pub(crate) directory: String,
+    /// None if backups are saved to sftp server, which are not listed
+    pub(crate) backups: Option<Vec<BackupInfo>>,
+}
+
+#[derive(Serialize, Debug, Eq, PartialEq)]
+pub(crate) struct BackupInfo {
+    pub(crate) name: String,
+    /// the time parsed from the name. None if the name is not in the default format
+    pub(crate) timestamp: Option<String>,
+    /// the name of archive file. None if missing
+    pub(crate) file: Option<String>,
+    /// the size of archive file in bytes. None if missing
+    pub(crate) size: Option<u64>,
+    /// true if the archive is a diff from the next backup
+    pub(crate) diff: bool,
+    /// true if the archive is listed in files.txt but not found
+    pub(crate) missing: bool,
+}
+
+/// lists backups in the backup directory, oldest first.
+/// timestamps are parsed from names in `name_format`.
+pub(crate) fn list_backups(directory: &Path, name_format: &str) -> Result<Vec<BackupInfo>> {
+    let names = match read_files_txt(directory) {
+        Ok(names) => names,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => {
+            return Err(e).with_context(|| format!("reading files.txt in {}", directory.display()))
+        }
+    };
+
+    names
+        .into_iter()
+        .map(|name| {
+            let timestamp = NaiveDateTime::parse_from_str(&name, name_format)
+                .ok()
+                .map(|x| x.format("%Y-%m-%dT%H:%M:%S").to_string());
+            let (path, diff) = match find_full_backup(directory, &name) {
+                Some((_, path)) => (Some(path), false),
+                None => match find_manifest(directory, &name) {
+                    Some(path) => (Some(path), false),
+                    None => (find_diff(directory, &name).map(|(_, path)| path), true),
+                },
+            };
+            let size = match &path {
+                Some(path) => Some(
+                    std::fs::metadata(path)
+                        .with_context(|| format!("reading metadata of {}", path.display()))?
+                        .len(),
+                ),
+                None => None,
+            };
+            Ok(BackupInfo {
+                timestamp,
+                file: path.map(|x| x.file_name().unwrap().to_string_lossy().into_owned()),
+                size,
+                diff: diff && size.is_some(),
+                missing: size.is_none(),
+                name,
+            })
+        })
+        .collect()
+}
+
+/// prints backups of all backup settings.
+pub(crate) fn run(config: &Config, json: bool) -> Result<()> {
+    let settings = config
+        .backups
+        .iter()
+        .map(|setting| {
+            Ok(match &setting.sftp {
+                Some(sftp) => SettingBackups {
+                    setting: setting.name.clone(),
+                    directory: format!("{}:{}", sftp.host, sftp.remote_dir),
+                    backups: None,
+                },
+                None => SettingBackups {
+                    setting: setting.name.clone(),
+                    directory: setting.directory.display().to_string(),
+                    backups: Some(list_backups(&setting.directory, &setting.name_format)?),
+                },
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&settings)?);
+        return Ok(());
+    }
+
+    for setting in settings {
+        println!("{} ({}):", setting.setting, setting.directory);
+        let Some(backups) = setting.backups else {
+            println!("  listing backups on sftp server is not supported");
+            continue;
+        };
+        if backups.is_empty() {
+            println!("  no backups");
+        }
+        for backup in backups {
+            let timestamp = backup.timestamp.as_deref().unwrap_or("-");
+            match backup.size {
+                Some(size) => println!(
+                    "  {:<30} {:<19} {:<4} {:>10}",
+                    backup.name,
+                    timestamp,
+                    if backup.diff { "diff" } else { "full" },
+                    human_size(size),
+                ),
+                None => println!("  {:<30} {:<19} MISSING", backup.name, timestamp),
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn human_size(size: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::files_txt::BACKUP_NAME_FORMAT;
+    use std::fs;
+
+    #[test]
+    fn list() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("files.txt"),
+            b"backup-2022-01-01-00-00-00\nbackup-2022-01-01-00-05-00\nmanual\nbackup-2022-01-01-00-10-00\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("backup-2022-01-01-00-00-00.diff.tar"),
+            [0; 10],
+        )
+        .unwrap();
+        fs::write(dir.path().join("manual.tar"), [0; 30]).unwrap();
+        fs::write(
+            dir.path().join("backup-2022-01-01-00-10-00.tar.gz"),
+            [0; 20],
+        )
+        .unwrap();
+
+        assert_eq!(
+            list_backups(dir.path(), BACKUP_NAME_FORMAT).unwrap(),
+            vec![
+                BackupInfo {
+                    name: "backup-2022-01-01-00-00-00".to_owned(),
+                    timestamp: Some("2022-01-01T00:00:00".to_owned()),
+                    file: Some("backup-2022-01-01-00-00-00.diff.tar".to_owned()),
+                    size: Some(10),
+                    diff: true,
+                    missing: false,
+                },
+                BackupInfo {
+                    name: "backup-2022-01-01-00-05-00".to_owned(),
+                    timestamp: Some("2022-01-01T00:05:00".to_owned()),
+                    file: None,
+                    size: None,
+                    diff: false,
+                    missing: true,
+                },
+                BackupInfo {
+                    name: "manual".to_owned(),
+                    timestamp: None,
+                    file: Some("manual.tar".to_owned()),
+                    size: Some(30),
+                    diff: false,
+                    missing: false,
+                },
+                BackupInfo {
+                    name: "backup-2022-01-01-00-10-00".to_owned(),
+                    timestamp: Some("2022-01-01T00:10:00".to_owned()),
+                    file: Some("backup-2022-01-01-00-10-00.tar.gz".to_owned()),
+                    size: Some(20),
+                    diff: false,
+                    missing: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn no_files_txt() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            list_backups(dir.path(), BACKUP_NAME_FORMAT).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn custom_name_format() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("files.txt"),
+            b"2022-01-01T00.05.00
+",
+        )
+        .unwrap();
+        let backups = list_backups(dir.path(), "%Y-%m-%dT%H.%M.%S").unwrap();
+        assert_eq!(backups[0].timestamp.as_deref(), Some("2022-01-01T00:05:00"));
+    }
+
+    #[test]
+    fn human_size_test() {
+        assert_eq!(human_size(100), "100 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
@@ -0,0 +1,67 @@
+//! locks of backup directories not to update files.txt of one directory from multiple tasks at once.
+//!
+//! the locks are shared by the process so backups taken by the scheduler and
+//! [Backuper::backup_now](crate::Backuper::backup_now) at once are also serialized.
+
+use crate::config::BackupSetting;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+static LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<AsyncMutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+/// where backups of the setting are saved to
+fn lock_key(setting: &BackupSetting) -> PathBuf {
+    match &setting.sftp {
+        Some(sftp) => PathBuf::from(format!(
+            "sftp://{}@{}:{}/{}",
+            sftp.user,
+            sftp.host,
+            sftp.port,
+            sftp.remote_dir.trim_start_matches('/')
+        )),
+        None => {
+            std::path::absolute(&setting.directory).unwrap_or_else(|_| setting.directory.clone())
+        }
+    }
+}
+
+/// waits until backups of other settings sharing the directory are saved.
+/// the directory is locked until the returned guard is dropped.
+pub(crate) async fn lock_directory(setting: &BackupSetting) -> OwnedMutexGuard<()> {
+    let lock = LOCKS
+        .lock()
+        .unwrap()
+        .entry(lock_key(setting))
+        .or_default()
+        .clone();
+    lock.lock_owned().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn same_directory_is_serialized() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = BackupSetting::for_test("a", &dir.path().join("shared"));
+        let b = BackupSetting::for_test("b", &dir.path().join("shared/."));
+        let c = BackupSetting::for_test("c", &dir.path().join("other"));
+
+        let guard = lock_directory(&a).await;
+        // other directories are not locked
+        tokio::time::timeout(Duration::from_secs(10), lock_directory(&c))
+            .await
+            .unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(50), lock_directory(&b)).await;
+        assert!(waiting.is_err());
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(10), lock_directory(&b))
+            .await
+            .unwrap();
+    }
+}
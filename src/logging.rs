@@ -0,0 +1,134 @@
+//! initialization of logger.
+//!
+//! logs are written in human readable text by default.
+//! with `LOG_FORMAT=json`, each log is written as one JSON object per line
+//! with key-values of the log like `setting` as fields.
+
+use log::kv::{Key, Value, VisitSource};
+use log::{LevelFilter, Record};
+use serde_json::{Map, Value as JsonValue};
+use std::io::Write;
+
+/// the format of logs
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum LogFormat {
+    /// human readable text
+    #[default]
+    Text,
+    /// JSON lines
+    Json,
+}
+
+/// the level specified by `-v` and `-q`. None if neither is specified
+pub(crate) fn level_from_flags(verbose: u8, quiet: u8) -> Option<LevelFilter> {
+    match (verbose, quiet) {
+        (0, 0) => None,
+        (1, _) => Some(LevelFilter::Debug),
+        (2.., _) => Some(LevelFilter::Trace),
+        (_, 1) => Some(LevelFilter::Warn),
+        (_, 2..) => Some(LevelFilter::Error),
+    }
+}
+
+/// initializes logger. this must be called before the first log.
+///
+/// `level` overrides the default level of `RUST_LOG` but levels for specific modules
+/// in `RUST_LOG` like `rcon=trace` are still used.
+pub(crate) fn init(format: LogFormat, level: Option<LevelFilter>) {
+    builder(format, level).init();
+}
+
+fn builder(format: LogFormat, level: Option<LevelFilter>) -> env_logger::Builder {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let timestamp = chrono::Utc::now().to_rfc3339();
+            writeln!(buf, "{}", json_line(&timestamp, record))
+        });
+    }
+    builder
+}
+
+/// formats the log as a JSON object
+fn json_line(timestamp: &str, record: &Record) -> String {
+    let mut fields = Map::new();
+    fields.insert("timestamp".to_owned(), timestamp.into());
+    fields.insert("level".to_owned(), record.level().as_str().into());
+    fields.insert("target".to_owned(), record.target().into());
+    fields.insert("message".to_owned(), record.args().to_string().into());
+    // errors of visitor are never returned
+    let _ = record.key_values().visit(&mut JsonVisitor(&mut fields));
+    JsonValue::Object(fields).to_string()
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if let Some(v) = value.to_u64() {
+            v.into()
+        } else if let Some(v) = value.to_i64() {
+            v.into()
+        } else if let Some(v) = value.to_bool() {
+            v.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_owned(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn level_test() {
+        let level = |verbose, quiet| {
+            builder(LogFormat::Text, level_from_flags(verbose, quiet))
+                .build()
+                .filter()
+        };
+        assert_eq!(level_from_flags(0, 0), None);
+        assert_eq!(level(1, 0), LevelFilter::Debug);
+        assert_eq!(level(2, 0), LevelFilter::Trace);
+        assert_eq!(level(3, 0), LevelFilter::Trace);
+        assert_eq!(level(0, 1), LevelFilter::Warn);
+        assert_eq!(level(0, 2), LevelFilter::Error);
+    }
+
+    #[test]
+    fn json_line_test() {
+        let kvs = [
+            ("setting", Value::from("world")),
+            ("bytes", Value::from(42u64)),
+        ];
+        let line = json_line(
+            "1970-01-01T00:00:00+00:00",
+            &Record::builder()
+                .args(format_args!("saved backup of {}", "world"))
+                .level(Level::Info)
+                .target("game_save_backuper")
+                .key_values(&kvs)
+                .build(),
+        );
+        let parsed: JsonValue = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::json!({
+                "timestamp": "1970-01-01T00:00:00+00:00",
+                "level": "INFO",
+                "target": "game_save_backuper",
+                "message": "saved backup of world",
+                "setting": "world",
+                "bytes": 42,
+            })
+        );
+    }
+}
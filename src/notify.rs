@@ -0,0 +1,346 @@
+//! notifications of backup results
+
+use crate::config::{Config, NotifyOn, Webhook, WebhookNotifyOn};
+use crate::list::human_size;
+use chrono::{NaiveDateTime, TimeZone};
+use log::{error, trace, warn};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the result of backup of a backup setting
+pub(crate) struct BackupReport<'a> {
+    pub(crate) setting: &'a str,
+    pub(crate) time: &'a NaiveDateTime,
+    /// the time taken to save the backup
+    pub(crate) duration: Duration,
+    /// the size of the archive or the error
+    pub(crate) result: Result<u64, &'a anyhow::Error>,
+}
+
+/// sends notifications of the backup result.
+/// errors are logged and never returned not to fail the backup.
+pub(crate) async fn notify(config: &Config, report: &BackupReport<'_>) {
+    if let Some(url) = &config.discord_webhook {
+        if config.notify_on == NotifyOn::All || report.result.is_err() {
+            trace!("sending discord notification for {}", report.setting);
+            if let Err(e) = post(url, &discord_payload(report)).await {
+                error!("error sending discord notification: {}", e);
+            }
+        }
+    }
+    if let Some(webhook) = &config.webhook {
+        let notify = match webhook.notify_on {
+            WebhookNotifyOn::Success => report.result.is_ok(),
+            WebhookNotifyOn::Failure => report.result.is_err(),
+            WebhookNotifyOn::Always => true,
+        };
+        if notify {
+            trace!("sending webhook for {}", report.setting);
+            if let Err(e) = post_webhook(webhook, &webhook_payload(config, report)).await {
+                error!("error sending webhook: {}", e);
+            }
+        }
+    }
+}
+
+/// posts with retrying on server errors
+async fn post_webhook(webhook: &Webhook, payload: &Value) -> reqwest::Result<()> {
+    let mut retries = 0;
+    loop {
+        match post(&webhook.url, payload).await {
+            Err(e)
+                if e.status().is_some_and(|x| x.is_server_error()) && retries < webhook.retries =>
+            {
+                retries += 1;
+                warn!(
+                    "error sending webhook: {}. retrying ({}/{})",
+                    e, retries, webhook.retries
+                );
+                tokio::time::sleep(webhook.retry_delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn post(url: &str, payload: &Value) -> reqwest::Result<()> {
+    reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .build()?
+        .post(url)
+        .json(payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn webhook_payload(config: &Config, report: &BackupReport<'_>) -> Value {
+    let timestamp = match config.timezone.from_local_datetime(report.time).earliest() {
+        Some(time) => time.to_rfc3339(),
+        None => report.time.to_string(),
+    };
+    json!({
+        "name": report.setting,
+        "timestamp": timestamp,
+        "status": if report.result.is_ok() { "success" } else { "failure" },
+        "bytes": report.result.as_ref().ok(),
+        "duration_ms": report.duration.as_millis() as u64,
+        "error": report.result.as_ref().err().map(|e| format!("{:#}", e)),
+    })
+}
+
+fn discord_payload(report: &BackupReport<'_>) -> Value {
+    let mut fields = vec![
+        json!({ "name": "setting", "value": report.setting, "inline": true }),
+        json!({ "name": "time", "value": report.time.to_string(), "inline": true }),
+    ];
+    let (title, color) = match &report.result {
+        Ok(size) => {
+            fields.push(json!({ "name": "size", "value": human_size(*size), "inline": true }));
+            ("Backup succeeded", 0x2ecc71)
+        }
+        Err(e) => {
+            let error = truncate_field(format!("{:#}", e));
+            fields.push(json!({ "name": "error", "value": error }));
+            ("Backup failed", 0xe74c3c)
+        }
+    };
+    json!({
+        "embeds": [{
+            "title": title,
+            "color": color,
+            "fields": fields,
+        }]
+    })
+}
+
+/// the maximum count of characters discord accepts in values of embed fields
+const DISCORD_FIELD_LIMIT: usize = 1024;
+
+/// truncates `value` to [DISCORD_FIELD_LIMIT] characters ending with an ellipsis if it's longer
+fn truncate_field(value: String) -> String {
+    if value.chars().nth(DISCORD_FIELD_LIMIT).is_none() {
+        return value;
+    }
+    let (end, _) = value.char_indices().nth(DISCORD_FIELD_LIMIT - 1).unwrap();
+    format!("{}…", &value[..end])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http_mock::MockHttp;
+
+    fn config(discord_webhook: String, notify_on: NotifyOn) -> Config {
+        Config {
+            discord_webhook: Some(discord_webhook),
+            notify_on,
+            ..webhook_config(None)
+        }
+    }
+
+    fn webhook_config(webhook: Option<Webhook>) -> Config {
+        Config {
+            webhook,
+            ..Config::for_test()
+        }
+    }
+
+    fn time() -> NaiveDateTime {
+        chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc()
+    }
+
+    #[tokio::test]
+    async fn discord() {
+        let server = MockHttp::start(vec![]).await;
+        let config = config(server.url("/webhook"), NotifyOn::All);
+        let error = anyhow::anyhow!("disk full");
+        notify(
+            &config,
+            &BackupReport {
+                setting: "hourly",
+                time: &time(),
+                duration: Duration::from_millis(1500),
+                result: Ok(2048),
+            },
+        )
+        .await;
+        notify(
+            &config,
+            &BackupReport {
+                setting: "hourly",
+                time: &time(),
+                duration: Duration::from_millis(1500),
+                result: Err(&error),
+            },
+        )
+        .await;
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].0, "/webhook");
+        let success: Value = serde_json::from_slice(&requests[0].1).unwrap();
+        assert_eq!(
+            success,
+            json!({
+                "embeds": [{
+                    "title": "Backup succeeded",
+                    "color": 0x2ecc71,
+                    "fields": [
+                        { "name": "setting", "value": "hourly", "inline": true },
+                        { "name": "time", "value": "1970-01-01 00:00:00", "inline": true },
+                        { "name": "size", "value": "2.0 KiB", "inline": true },
+                    ],
+                }]
+            })
+        );
+        let failure: Value = serde_json::from_slice(&requests[1].1).unwrap();
+        assert_eq!(failure["embeds"][0]["title"], "Backup failed");
+        assert_eq!(failure["embeds"][0]["fields"][2]["value"], "disk full");
+    }
+
+    #[test]
+    fn discord_long_error() {
+        let error = anyhow::anyhow!("{}", "é".repeat(2000));
+        let payload = discord_payload(&BackupReport {
+            setting: "hourly",
+            time: &time(),
+            duration: Duration::from_millis(1500),
+            result: Err(&error),
+        });
+        let value = payload["embeds"][0]["fields"][2]["value"].as_str().unwrap();
+        assert_eq!(value.chars().count(), DISCORD_FIELD_LIMIT);
+        assert_eq!(value, format!("{}…", "é".repeat(DISCORD_FIELD_LIMIT - 1)));
+
+        let exact = "é".repeat(DISCORD_FIELD_LIMIT);
+        assert_eq!(truncate_field(exact.clone()), exact);
+    }
+
+    #[tokio::test]
+    async fn discord_failure_only() {
+        let server = MockHttp::start(vec![]).await;
+        let config = config(server.url("/webhook"), NotifyOn::Failure);
+        notify(
+            &config,
+            &BackupReport {
+                setting: "hourly",
+                time: &time(),
+                duration: Duration::from_millis(1500),
+                result: Ok(2048),
+            },
+        )
+        .await;
+        assert_eq!(server.requests().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn discord_error_is_ignored() {
+        let server = MockHttp::start(vec![500]).await;
+        let config = config(server.url("/webhook"), NotifyOn::All);
+        // must not panic
+        notify(
+            &config,
+            &BackupReport {
+                setting: "hourly",
+                time: &time(),
+                duration: Duration::from_millis(1500),
+                result: Ok(2048),
+            },
+        )
+        .await;
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    fn webhook(server: &MockHttp, notify_on: WebhookNotifyOn, retries: u32) -> Config {
+        webhook_config(Some(Webhook {
+            url: server.url("/hook"),
+            notify_on,
+            retries,
+            retry_delay: Duration::from_millis(1),
+        }))
+    }
+
+    async fn send(config: &Config, result: Result<u64, &anyhow::Error>) {
+        let report = BackupReport {
+            setting: "hourly",
+            time: &time(),
+            duration: Duration::from_millis(1500),
+            result,
+        };
+        notify(config, &report).await;
+    }
+
+    #[tokio::test]
+    async fn payload() {
+        let server = MockHttp::start(vec![]).await;
+        let config = webhook(&server, WebhookNotifyOn::Always, 0);
+        let error = anyhow::anyhow!("disk full");
+        send(&config, Ok(2048)).await;
+        send(&config, Err(&error)).await;
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].0, "/hook");
+        assert_eq!(
+            serde_json::from_slice::<Value>(&requests[0].1).unwrap(),
+            json!({
+                "name": "hourly",
+                "timestamp": "1970-01-01T00:00:00+00:00",
+                "status": "success",
+                "bytes": 2048,
+                "duration_ms": 1500,
+                "error": null,
+            })
+        );
+        assert_eq!(
+            serde_json::from_slice::<Value>(&requests[1].1).unwrap(),
+            json!({
+                "name": "hourly",
+                "timestamp": "1970-01-01T00:00:00+00:00",
+                "status": "failure",
+                "bytes": null,
+                "duration_ms": 1500,
+                "error": "disk full",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn notify_on() {
+        let error = anyhow::anyhow!("disk full");
+
+        let server = MockHttp::start(vec![]).await;
+        let config = webhook(&server, WebhookNotifyOn::Success, 0);
+        send(&config, Ok(2048)).await;
+        send(&config, Err(&error)).await;
+        assert_eq!(server.requests().len(), 1);
+
+        let server = MockHttp::start(vec![]).await;
+        let config = webhook(&server, WebhookNotifyOn::Failure, 0);
+        send(&config, Ok(2048)).await;
+        send(&config, Err(&error)).await;
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn retry() {
+        let server = MockHttp::start(vec![503, 500]).await;
+        let config = webhook(&server, WebhookNotifyOn::Always, 2);
+        send(&config, Ok(2048)).await;
+        assert_eq!(server.requests().len(), 3);
+
+        let server = MockHttp::start(vec![503, 500]).await;
+        let config = webhook(&server, WebhookNotifyOn::Always, 1);
+        send(&config, Ok(2048)).await;
+        assert_eq!(server.requests().len(), 2);
+
+        // client errors are not retried
+        let server = MockHttp::start(vec![404]).await;
+        let config = webhook(&server, WebhookNotifyOn::Always, 2);
+        send(&config, Ok(2048)).await;
+        assert_eq!(server.requests().len(), 1);
+    }
+}
@@ -0,0 +1,168 @@
+//! removes old backups with the retention of each backup setting without taking a backup.
+//!
+//! useful after max_backups or retention is lowered since old backups are usually
+//! removed only when a new backup is saved.
+
+use crate::config::{BackupSetting, Config};
+use crate::files_txt::{parse_files_txt, pinned_backups};
+use crate::lock::lock_directory;
+use crate::rotation::rotate_backups;
+use crate::sink::{BackupSink, DryRunSink, LocalSink, SftpSink};
+use anyhow::{bail, Context as _, Result};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use log::{error, info};
+
+/// prunes backups of all backup settings.
+/// errors of each setting are logged and don't stop other settings.
+pub(crate) async fn run(config: &Config, dry_run: bool) -> Result<()> {
+    let now = config
+        .timezone
+        .from_utc_datetime(&Utc::now().naive_utc())
+        .naive_local();
+    let mut failed = 0;
+    for setting in &config.backups {
+        match prune(setting, &now, dry_run).await {
+            Ok(removed) if dry_run => info!(
+                "dry run: would remove {} backup(s) of {}",
+                removed, setting.name
+            ),
+            Ok(removed) => info!("removed {} backup(s) of {}", removed, setting.name),
+            Err(err) => {
+                error!("error pruning backups of {}: {:#}", setting.name, err);
+                failed += 1;
+            }
+        }
+    }
+    if failed != 0 {
+        bail!("{} backup setting(s) failed", failed)
+    }
+    Ok(())
+}
+
+/// removes backups of the setting not to be kept at `now`. returns the count of removed backups
+pub(crate) async fn prune(
+    setting: &BackupSetting,
+    now: &NaiveDateTime,
+    dry_run: bool,
+) -> Result<usize> {
+    let _lock = lock_directory(setting).await;
+    match &setting.sftp {
+        Some(sftp) => {
+            let sink = SftpSink::connect(sftp)
+                .await
+                .with_context(|| format!("connecting to sftp server {}", sftp.host))?;
+            if dry_run {
+                prune_sink(&DryRunSink::new(sink), now, setting, true).await
+            } else {
+                prune_sink(&sink, now, setting, false).await
+            }
+        }
+        None => {
+            let sink = LocalSink::new(&setting.directory);
+            if dry_run {
+                prune_sink(&DryRunSink::new(sink), now, setting, true).await
+            } else {
+                prune_sink(&sink, now, setting, false).await
+            }
+        }
+    }
+}
+
+async fn prune_sink(
+    sink: &impl BackupSink,
+    now: &NaiveDateTime,
+    setting: &BackupSetting,
+    dry_run: bool,
+) -> Result<usize> {
+    let files_txt = match sink.read("files.txt").await.context("reading files.txt")? {
+        Some(files_txt) => files_txt,
+        None => return Ok(0),
+    };
+    let pinned = pinned_backups(&files_txt);
+    let before = parse_files_txt(&files_txt).len();
+    let (kept, _) = rotate_backups(sink, &files_txt, &pinned, now, setting, dry_run).await?;
+    Ok(before - kept.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::SaveInterval;
+    use crate::files_txt::read_files_txt;
+    use std::fs;
+    use std::path::Path;
+
+    fn setting(directory: &Path, max_backups: usize) -> BackupSetting {
+        BackupSetting {
+            max_backups,
+            interval: SaveInterval::Every1Hour,
+            ..BackupSetting::for_test("test", directory)
+        }
+    }
+
+    #[tokio::test]
+    async fn remove_over_count() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("files.txt"),
+            "backup-a\nbackup-b #keep\nbackup-c\nbackup-d\nbackup-e\n",
+        )
+        .unwrap();
+        for name in ["backup-a", "backup-b", "backup-c", "backup-d", "backup-e"] {
+            fs::write(dir.path().join(format!("{}.tar", name)), b"").unwrap();
+            fs::write(dir.path().join(format!("{}.sha256", name)), b"").unwrap();
+        }
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+
+        let removed = prune(&setting(dir.path(), 2), &now, false).await.unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(
+            read_files_txt(dir.path()).unwrap(),
+            vec!["backup-b", "backup-d", "backup-e"]
+        );
+        let mut files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "backup-b.sha256",
+                "backup-b.tar",
+                "backup-d.sha256",
+                "backup-d.tar",
+                "backup-e.sha256",
+                "backup-e.tar",
+                "files.txt",
+            ]
+        );
+
+        // nothing is removed if already within the limit
+        let removed = prune(&setting(dir.path(), 2), &now, false).await.unwrap();
+        assert_eq!(removed, 0);
+    }
+
+    #[tokio::test]
+    async fn dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("files.txt"), "backup-a\nbackup-b\n").unwrap();
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+
+        let removed = prune(&setting(dir.path(), 1), &now, true).await.unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(
+            read_files_txt(dir.path()).unwrap(),
+            vec!["backup-a", "backup-b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_files_txt() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let removed = prune(&setting(dir.path(), 1), &now, false).await.unwrap();
+        assert_eq!(removed, 0);
+        assert!(!dir.path().join("files.txt").exists());
+    }
+}
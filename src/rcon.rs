@@ -0,0 +1,646 @@
+//! sending commands to game servers over rcon around backups.
+
+use crate::config::{
+    BackupSetting, Config, GamePreset, RconFailureAction, RconServer, RconTimeoutAction,
+};
+use crate::status::Status;
+use crate::tunnel;
+use anyhow::{bail, Context as _, Result};
+use log::{debug, error, info, trace, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::ErrorKind;
+
+pub(crate) type Connection = rcon::Connection<tokio::net::TcpStream>;
+
+/// runs `read` between commands_before and commands_after of the setting,
+/// which reads save directories while the game doesn't save.
+pub(crate) async fn pause_saving<T>(
+    ctx: &mut Context<'_>,
+    setting: &BackupSetting,
+    read: impl Future<Output = T>,
+) -> Result<T> {
+    let config = ctx.config;
+    let commands_before = config.commands_before_of(setting);
+    let commands_after = config.commands_after_of(setting);
+    let results = ctx
+        .for_each_server(ctx.all_servers(), |mut client| async move {
+            let result = send_commands_before(&mut client, commands_before, commands_after).await;
+            (client, result)
+        })
+        .await;
+    let succeeded = results
+        .iter()
+        .filter(|(_, result)| result.is_ok())
+        .map(|(index, _)| *index)
+        .collect::<Vec<_>>();
+    if let Err(e) = check_rcon_results(config, results) {
+        // other servers may be left with saving disabled
+        if let Err(e) = send_commands_after(ctx, succeeded, commands_after).await {
+            error!("error sending after commands: {:#}", e);
+        }
+        return Err(e);
+    }
+
+    if !ctx.config.save_settle_delay.is_zero() {
+        trace!(
+            "waiting {:?} for the game to finish saving",
+            ctx.config.save_settle_delay
+        );
+        tokio::time::sleep(ctx.config.save_settle_delay).await;
+    }
+
+    let read = read.await;
+
+    // commands_after must be sent even if building tar failed not to leave auto save disabled
+    send_commands_after(ctx, ctx.all_servers(), commands_after).await?;
+    Ok(read)
+}
+
+/// sends commands_before to the server and checks responses
+async fn send_commands_before(
+    client: &mut RconClient<'_>,
+    commands: &[String],
+    commands_after: &[String],
+) -> Result<()> {
+    let config = client.config;
+    for cmd in commands {
+        let result = client.send_command(cmd).await;
+        if is_timeout(&result) && config.on_rcon_timeout == RconTimeoutAction::Proceed {
+            warn!("rcon timed out while sending {:?}. backing up anyway", cmd);
+            break;
+        }
+        let response = result.context("sending before command")?;
+        if let Some(expected) = config.expect_response.get(cmd) {
+            if !client.dry_run && !response.contains(expected.as_str()) {
+                // the game may be left with saving disabled by former commands
+                send_commands_after_to(client, commands_after).await?;
+                bail!(
+                    "unexpected response to {:?}: {:?} doesn't contain {:?}",
+                    cmd,
+                    response,
+                    expected
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// reports failures of rcon servers.
+/// returns the first error if the backup should be aborted by on_rcon_failure.
+fn check_rcon_results(config: &Config, results: Vec<(usize, Result<()>)>) -> Result<()> {
+    let mut first = None;
+    for (index, result) in results {
+        if let Err(e) = result {
+            let name = &config.rcon_servers[index].name;
+            error!("error sending commands to rcon server {}: {:#}", name, e);
+            first.get_or_insert(e);
+        }
+    }
+    match first {
+        Some(e) if config.on_rcon_failure == RconFailureAction::Abort => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// sends commands_after to `servers` in parallel
+async fn send_commands_after(
+    ctx: &mut Context<'_>,
+    servers: Vec<usize>,
+    commands: &[String],
+) -> Result<()> {
+    let results = ctx
+        .for_each_server(servers, |mut client| async move {
+            let result = send_commands_after_to(&mut client, commands).await;
+            (client, result)
+        })
+        .await;
+    check_rcon_results(ctx.config, results)
+}
+
+async fn send_commands_after_to(client: &mut RconClient<'_>, commands: &[String]) -> Result<()> {
+    for cmd in commands {
+        let result = client.send_command(cmd).await;
+        if is_timeout(&result) && client.config.on_rcon_timeout == RconTimeoutAction::Proceed {
+            warn!("rcon timed out while sending {:?}", cmd);
+            break;
+        }
+        result.context("sending after command")?;
+    }
+    Ok(())
+}
+
+pub(crate) struct Context<'a> {
+    pub(crate) config: &'a Config,
+    /// connections to rcon servers keyed by the index in rcon_servers
+    pub(crate) connections: HashMap<usize, Connection>,
+    /// if true, rcon commands are logged instead of sent
+    pub(crate) dry_run: bool,
+    /// the status results of backups are recorded to
+    pub(crate) status: Option<Status>,
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            connections: HashMap::new(),
+            dry_run: false,
+            status: None,
+        }
+    }
+
+    fn all_servers(&self) -> Vec<usize> {
+        (0..self.config.rcon_servers.len()).collect()
+    }
+
+    /// the client of the rcon server at `index` with the connection kept in the context.
+    /// the connection should be given back with `put_back` to be reused.
+    pub(crate) fn client(&mut self, index: usize) -> RconClient<'a> {
+        RconClient {
+            config: self.config,
+            server: &self.config.rcon_servers[index],
+            connection: self.connections.remove(&index),
+            dry_run: self.dry_run,
+        }
+    }
+
+    fn put_back(&mut self, index: usize, client: RconClient<'a>) {
+        if let Some(connection) = client.connection {
+            self.connections.insert(index, connection);
+        }
+    }
+
+    /// runs `f` with clients of `servers` in parallel.
+    /// `f` gives the client back with the result so that the connection can be reused.
+    async fn for_each_server<F, Fut, T>(&mut self, servers: Vec<usize>, f: F) -> Vec<(usize, T)>
+    where
+        F: Fn(RconClient<'a>) -> Fut,
+        Fut: Future<Output = (RconClient<'a>, T)>,
+    {
+        let clients = servers.iter().map(|&index| f(self.client(index)));
+        let results = futures::future::join_all(clients.collect::<Vec<_>>()).await;
+        servers
+            .into_iter()
+            .zip(results)
+            .map(|(index, (client, result))| {
+                self.put_back(index, client);
+                (index, result)
+            })
+            .collect()
+    }
+}
+
+/// the rcon client of one of rcon servers
+pub(crate) struct RconClient<'a> {
+    config: &'a Config,
+    server: &'a RconServer,
+    connection: Option<Connection>,
+    /// if true, commands are logged instead of sent
+    dry_run: bool,
+}
+
+impl RconClient<'_> {
+    pub(crate) async fn reconnect_rcon(&mut self) -> Result<&mut Connection, rcon::Error> {
+        let builder = Connection::builder();
+        let builder = match self.server.preset {
+            Some(GamePreset::Filesystem) => {
+                unreachable!("rcon is not used with filesystem preset")
+            }
+            None | Some(GamePreset::Terraria | GamePreset::Valheim) => builder,
+            Some(GamePreset::Minecraft) => builder.enable_minecraft_quirks(true),
+            Some(GamePreset::Factorio) => builder.enable_factorio_quirks(true),
+        };
+        let server = self.server;
+        let connect = async move {
+            match &server.ssh_tunnel {
+                Some(tunnel) => {
+                    let local = tunnel::open(tunnel, &server.name).await.map_err(|e| {
+                        // connection errors to be retried
+                        rcon::Error::Io(std::io::Error::new(
+                            ErrorKind::ConnectionRefused,
+                            format!("tunneling through ssh server {}: {:#}", tunnel.host, e),
+                        ))
+                    })?;
+                    builder.connect(local, &server.password).await
+                }
+                None => {
+                    builder
+                        .connect(server.address.as_slice(), &server.password)
+                        .await
+                }
+            }
+        };
+        self.connection = Some(with_timeout(self.config.rcon_timeout, connect).await?);
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    /// sends command with retrying on connection errors with exponential backoff
+    pub(crate) async fn send_command(&mut self, command: &str) -> Result<String, rcon::Error> {
+        if self.dry_run {
+            info!(
+                "dry run: would send {:?} to {} over rcon",
+                command, self.server.name
+            );
+            return Ok(String::new());
+        }
+        let retry = self.config.rcon_retry;
+        let mut attempt = 1;
+        loop {
+            match self.try_send_command(command).await {
+                Ok(s) => {
+                    debug!("response to {:?}: {:?}", command, s);
+                    return Ok(s);
+                }
+                Err(e) if is_connection_error(&e) && attempt < retry.max_attempts => {
+                    self.connection = None;
+                    let delay = retry.delay(attempt - 1);
+                    warn!(
+                        "rcon connection to {} failed ({}), retrying in {:?} ({}/{})",
+                        self.server.name, e, delay, attempt, retry.max_attempts
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn try_send_command(&mut self, command: &str) -> Result<String, rcon::Error> {
+        let timeout = self.config.rcon_timeout;
+        let connection = match self.connection.as_mut() {
+            Some(s) => s,
+            None => self.reconnect_rcon().await?,
+        };
+        let result = with_timeout(timeout, connection.cmd(command)).await;
+        if is_timeout(&result) {
+            // the response may be received later so the connection can't be used anymore
+            self.connection = None;
+        }
+        result
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: std::time::Duration,
+    future: impl Future<Output = Result<T, rcon::Error>>,
+) -> Result<T, rcon::Error> {
+    match tokio::time::timeout(timeout, future).await {
+        Ok(result) => result,
+        Err(_) => Err(rcon::Error::Io(std::io::Error::new(
+            ErrorKind::TimedOut,
+            format!("rcon timed out after {:?}", timeout),
+        ))),
+    }
+}
+
+fn is_timeout<T>(result: &Result<T, rcon::Error>) -> bool {
+    matches!(result, Err(rcon::Error::Io(e)) if e.kind() == ErrorKind::TimedOut)
+}
+
+fn is_connection_error(e: &rcon::Error) -> bool {
+    use std::io::ErrorKind::*;
+    match e {
+        rcon::Error::Io(e) => matches!(
+            e.kind(),
+            ConnectionRefused
+                | ConnectionReset
+                | ConnectionAborted
+                | BrokenPipe
+                | NotConnected
+                | UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::archive::test::setting;
+    use crate::config::{Compression, SaveDir};
+    use crate::files_txt;
+    use crate::scheduler::run_now;
+    use std::path::{Path, PathBuf};
+
+    pub(crate) fn rcon_config(address: std::net::SocketAddr, max_attempts: u32) -> Config {
+        Config {
+            rcon_servers: vec![RconServer {
+                name: address.to_string(),
+                address: vec![address],
+                password: "password".to_owned(),
+                preset: None,
+                ssh_tunnel: None,
+            }],
+            rcon_retry: crate::config::RconRetry {
+                base_delay: std::time::Duration::from_millis(1),
+                max_delay: std::time::Duration::from_millis(10),
+                max_attempts,
+            },
+            ..Config::for_test()
+        }
+    }
+
+    #[tokio::test]
+    async fn rcon_retry_succeeds() {
+        let server = crate::rcon_mock::MockRcon::start(3).await;
+        let config = rcon_config(server.address, 4);
+        let mut client = Context::new(&config).client(0);
+        client.send_command("save-all").await.unwrap();
+        assert_eq!(server.commands(), vec!["save-all"]);
+    }
+
+    #[tokio::test]
+    async fn rcon_retry_gives_up() {
+        let server = crate::rcon_mock::MockRcon::start(3).await;
+        let config = rcon_config(server.address, 3);
+        let mut client = Context::new(&config).client(0);
+        let err = client.send_command("save-all").await.unwrap_err();
+        assert!(is_connection_error(&err), "{}", err);
+        assert_eq!(server.commands(), Vec::<String>::new());
+    }
+
+    fn tunneled_rcon_config(
+        rcon: std::net::SocketAddr,
+        sftp: &crate::sftp_mock::MockSftp,
+        password: &str,
+    ) -> Config {
+        let mut config = rcon_config(rcon, 1);
+        config.rcon_servers[0].address = vec![];
+        config.rcon_servers[0].ssh_tunnel = Some(crate::config::SshTunnel {
+            host: sftp.address.ip().to_string(),
+            port: sftp.address.port(),
+            user: crate::sftp_mock::USER.to_owned(),
+            auth: crate::config::SftpAuth::Password(password.to_owned()),
+            host_key: Some(sftp.host_key.clone()),
+        });
+        config
+    }
+
+    #[tokio::test]
+    async fn rcon_ssh_tunnel() {
+        let server = crate::rcon_mock::MockRcon::start(0).await;
+        let dir = tempfile::tempdir().unwrap();
+        let sftp = crate::sftp_mock::MockSftp::start(dir.path()).await;
+        let config = tunneled_rcon_config(server.address, &sftp, crate::sftp_mock::PASSWORD);
+        let mut client = Context::new(&config).client(0);
+        client.send_command("save-all").await.unwrap();
+        client.send_command("save-on").await.unwrap();
+        assert_eq!(server.commands(), vec!["save-all", "save-on"]);
+    }
+
+    #[tokio::test]
+    async fn rcon_ssh_tunnel_auth_failure() {
+        let server = crate::rcon_mock::MockRcon::start(0).await;
+        let dir = tempfile::tempdir().unwrap();
+        let sftp = crate::sftp_mock::MockSftp::start(dir.path()).await;
+        let config = tunneled_rcon_config(server.address, &sftp, "wrong");
+        let mut client = Context::new(&config).client(0);
+        let err = client.send_command("save-all").await.unwrap_err();
+        assert!(is_connection_error(&err), "{}", err);
+        let message = err.to_string();
+        assert!(
+            message.contains("tunneling through ssh server"),
+            "{}",
+            message
+        );
+        assert!(message.contains("authentication failed"), "{}", message);
+        assert_eq!(server.commands(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn rcon_timeout() {
+        let server = crate::rcon_mock::MockRcon::start_stalled().await;
+        let config = Config {
+            rcon_timeout: std::time::Duration::from_millis(50),
+            ..rcon_config(server.address, 3)
+        };
+        let mut client = Context::new(&config).client(0);
+        let result = client.send_command("save-all").await;
+        assert!(is_timeout(&result));
+        assert!(client.connection.is_none());
+    }
+
+    async fn backup_with_stalled_rcon(on_rcon_timeout: RconTimeoutAction) -> Result<()> {
+        let server = crate::rcon_mock::MockRcon::start_stalled().await;
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let config = Config {
+            rcon_timeout: std::time::Duration::from_millis(50),
+            on_rcon_timeout,
+            commands_before: vec!["save-off".to_owned(), "save-all".to_owned()],
+            commands_after: vec!["save-on".to_owned()],
+            backups: vec![BackupSetting {
+                save_dirs: vec![SaveDir {
+                    path: save.path().to_owned(),
+                    prefix: PathBuf::new(),
+                }],
+                ..setting(backups.path(), Compression::None)
+            }],
+            ..rcon_config(server.address, 3)
+        };
+        let mut ctx = Context::new(&config);
+        run_now(&mut ctx).await?;
+        assert_eq!(files_txt::read_files_txt(backups.path()).unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rcon_timeout_skip() {
+        let err = backup_with_stalled_rcon(RconTimeoutAction::Skip)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "sending before command");
+    }
+
+    #[tokio::test]
+    async fn rcon_timeout_proceed() {
+        backup_with_stalled_rcon(RconTimeoutAction::Proceed)
+            .await
+            .unwrap();
+    }
+
+    async fn backup_with_response(response: &str) -> (Result<()>, Vec<String>, usize) {
+        let server = crate::rcon_mock::MockRcon::start_responding(response).await;
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let config = Config {
+            commands_before: vec!["save-off".to_owned(), "save-all".to_owned()],
+            commands_after: vec!["save-on".to_owned()],
+            expect_response: [("save-all".to_owned(), "Saved the game".to_owned())].into(),
+            backups: vec![BackupSetting {
+                save_dirs: vec![SaveDir {
+                    path: save.path().to_owned(),
+                    prefix: PathBuf::new(),
+                }],
+                ..setting(backups.path(), Compression::None)
+            }],
+            ..rcon_config(server.address, 1)
+        };
+        let mut ctx = Context::new(&config);
+        let result = run_now(&mut ctx).await;
+        let saved = files_txt::read_files_txt(backups.path())
+            .map(|x| x.len())
+            .unwrap_or(0);
+        (result, server.commands(), saved)
+    }
+
+    #[tokio::test]
+    async fn setting_commands_override() {
+        let server = crate::rcon_mock::MockRcon::start(0).await;
+        let world = tempfile::tempdir().unwrap();
+        let nether = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(world.path().join("level.dat"), b"level").unwrap();
+        std::fs::write(nether.path().join("level.dat"), b"nether").unwrap();
+        let save_dir = |path: &Path| SaveDir {
+            path: path.to_owned(),
+            prefix: PathBuf::new(),
+        };
+        let config = Config {
+            commands_before: vec!["save-off".to_owned()],
+            commands_after: vec!["save-on".to_owned()],
+            backups: vec![
+                BackupSetting {
+                    name: "world".to_owned(),
+                    save_dirs: vec![save_dir(world.path())],
+                    ..setting(&backups.path().join("world"), Compression::None)
+                },
+                BackupSetting {
+                    name: "nether".to_owned(),
+                    save_dirs: vec![save_dir(nether.path())],
+                    commands_before: Some(vec!["flush nether".to_owned()]),
+                    commands_after: Some(vec![]),
+                    ..setting(&backups.path().join("nether"), Compression::None)
+                },
+            ],
+            ..rcon_config(server.address, 1)
+        };
+        let mut ctx = Context::new(&config);
+        run_now(&mut ctx).await.unwrap();
+        assert_eq!(
+            server.commands(),
+            vec!["save-off", "save-on", "flush nether"]
+        );
+        for name in ["world", "nether"] {
+            let saved = files_txt::read_files_txt(&backups.path().join(name)).unwrap();
+            assert_eq!(saved.len(), 1);
+        }
+    }
+
+    /// backs up with two rcon servers. the second one fails to connect if `reachable` is false
+    async fn backup_with_two_servers(
+        on_rcon_failure: RconFailureAction,
+        reachable: bool,
+    ) -> (Result<()>, Vec<String>, Vec<String>, usize) {
+        let main = crate::rcon_mock::MockRcon::start(0).await;
+        let nether = crate::rcon_mock::MockRcon::start(0).await;
+        // nothing listens on the port after the listener is dropped
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let nether_address = if reachable {
+            nether.address
+        } else {
+            unreachable
+        };
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let mut config = Config {
+            commands_before: vec!["save-off".to_owned(), "save-all".to_owned()],
+            commands_after: vec!["save-on".to_owned()],
+            on_rcon_failure,
+            backups: vec![BackupSetting {
+                save_dirs: vec![SaveDir {
+                    path: save.path().to_owned(),
+                    prefix: PathBuf::new(),
+                }],
+                ..setting(backups.path(), Compression::None)
+            }],
+            ..rcon_config(main.address, 1)
+        };
+        config.rcon_servers.push(RconServer {
+            name: nether_address.to_string(),
+            address: vec![nether_address],
+            password: "password".to_owned(),
+            preset: None,
+            ssh_tunnel: None,
+        });
+        let mut ctx = Context::new(&config);
+        let result = run_now(&mut ctx).await;
+        let saved = files_txt::read_files_txt(backups.path())
+            .map(|x| x.len())
+            .unwrap_or(0);
+        (result, main.commands(), nether.commands(), saved)
+    }
+
+    #[tokio::test]
+    async fn multiple_rcon_servers() {
+        let (result, main, nether, saved) =
+            backup_with_two_servers(RconFailureAction::Abort, true).await;
+        result.unwrap();
+        assert_eq!(main, vec!["save-off", "save-all", "save-on"]);
+        assert_eq!(nether, vec!["save-off", "save-all", "save-on"]);
+        assert_eq!(saved, 1);
+    }
+
+    #[tokio::test]
+    async fn rcon_server_failure_abort() {
+        let (result, main, _, saved) =
+            backup_with_two_servers(RconFailureAction::Abort, false).await;
+        let err = result.unwrap_err();
+        assert_eq!(err.to_string(), "sending before command");
+        // saving on the other server is enabled again
+        assert_eq!(main, vec!["save-off", "save-all", "save-on"]);
+        assert_eq!(saved, 0);
+    }
+
+    #[tokio::test]
+    async fn rcon_server_failure_continue() {
+        let (result, main, _, saved) =
+            backup_with_two_servers(RconFailureAction::Continue, false).await;
+        result.unwrap();
+        assert_eq!(main, vec!["save-off", "save-all", "save-on"]);
+        assert_eq!(saved, 1);
+    }
+
+    #[tokio::test]
+    async fn expected_response() {
+        let (result, commands, saved) = backup_with_response("Saved the game").await;
+        result.unwrap();
+        assert_eq!(commands, vec!["save-off", "save-all", "save-on"]);
+        assert_eq!(saved, 1);
+    }
+
+    #[tokio::test]
+    async fn unexpected_response() {
+        let (result, commands, saved) = backup_with_response("Server is busy").await;
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("unexpected response"), "{}", err);
+        // saving is enabled again
+        assert_eq!(commands, vec!["save-off", "save-all", "save-on"]);
+        assert_eq!(saved, 0);
+    }
+
+    #[tokio::test]
+    async fn save_settle_delay() {
+        let config = Config {
+            save_settle_delay: std::time::Duration::from_millis(200),
+            ..Config::for_test()
+        };
+        let mut ctx = Context::new(&config);
+        let backups = tempfile::tempdir().unwrap();
+        let setting = setting(backups.path(), Compression::None);
+        let start = std::time::Instant::now();
+        let read_at = pause_saving(&mut ctx, &setting, async { std::time::Instant::now() })
+            .await
+            .unwrap();
+        assert!(read_at - start >= config.save_settle_delay);
+    }
+}
@@ -0,0 +1,116 @@
+//! minimal rcon server for tests
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const AUTH: i32 = 3;
+const AUTH_RESPONSE: i32 = 2;
+const RESPONSE_VALUE: i32 = 0;
+
+pub(crate) struct MockRcon {
+    pub(crate) address: SocketAddr,
+    /// the commands received, excluding empty ones used as response terminator
+    pub(crate) commands: Arc<Mutex<Vec<String>>>,
+}
+
+/// how the server behaves
+#[derive(Clone)]
+enum Behavior {
+    /// responds with the body to each command
+    Respond(String),
+    /// accepts connections but never responds
+    Stall,
+}
+
+impl MockRcon {
+    /// starts server which closes first `fail` connections immediately
+    pub(crate) async fn start(fail: usize) -> Self {
+        Self::start_with(fail, Behavior::Respond(String::new())).await
+    }
+
+    /// starts server which responds with `response` to each command
+    pub(crate) async fn start_responding(response: &str) -> Self {
+        Self::start_with(0, Behavior::Respond(response.to_owned())).await
+    }
+
+    /// starts server which accepts connections but never responds
+    pub(crate) async fn start_stalled() -> Self {
+        Self::start_with(0, Behavior::Stall).await
+    }
+
+    async fn start_with(fail: usize, behavior: Behavior) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let commands1 = commands.clone();
+        tokio::spawn(async move {
+            let mut fail = fail;
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                if fail != 0 {
+                    fail -= 1;
+                    drop(stream);
+                    continue;
+                }
+                match &behavior {
+                    Behavior::Stall => {
+                        // keep the connection open without reading
+                        tokio::spawn(async move {
+                            let _stream = stream;
+                            std::future::pending::<()>().await
+                        });
+                    }
+                    Behavior::Respond(response) => {
+                        tokio::spawn(serve(stream, commands1.clone(), response.clone()));
+                    }
+                }
+            }
+        });
+        Self { address, commands }
+    }
+
+    pub(crate) fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+async fn serve(mut stream: TcpStream, commands: Arc<Mutex<Vec<String>>>, response: String) {
+    while let Some((id, ty, body)) = read_packet(&mut stream).await {
+        let (response_type, response_body) = if ty == AUTH {
+            (AUTH_RESPONSE, "")
+        } else if body.is_empty() {
+            (RESPONSE_VALUE, "")
+        } else {
+            commands.lock().unwrap().push(body);
+            (RESPONSE_VALUE, response.as_str())
+        };
+        if write_packet(&mut stream, id, response_type, response_body)
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+async fn read_packet(stream: &mut TcpStream) -> Option<(i32, i32, String)> {
+    let length = stream.read_i32_le().await.ok()?;
+    let id = stream.read_i32_le().await.ok()?;
+    let ty = stream.read_i32_le().await.ok()?;
+    let mut body = vec![0; (length - 8) as usize];
+    stream.read_exact(&mut body).await.ok()?;
+    body.truncate(body.len() - 2);
+    Some((id, ty, String::from_utf8(body).ok()?))
+}
+
+async fn write_packet(stream: &mut TcpStream, id: i32, ty: i32, body: &str) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(10 + body.len() as i32).to_le_bytes());
+    buf.extend_from_slice(&id.to_le_bytes());
+    buf.extend_from_slice(&ty.to_le_bytes());
+    buf.extend_from_slice(body.as_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    stream.write_all(&buf).await
+}
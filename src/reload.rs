@@ -0,0 +1,147 @@
+use crate::config::{load_config, Config, DirOverrides};
+use anyhow::{Context as _, Result};
+use log::{error, info, trace};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc;
+
+/// the config replaced when the config file is changed.
+pub(crate) struct SharedConfig {
+    config: RwLock<Arc<Config>>,
+}
+
+impl SharedConfig {
+    pub(crate) fn new(config: Arc<Config>) -> Self {
+        Self {
+            config: RwLock::new(config),
+        }
+    }
+
+    /// returns the current config. it's not changed even if the config is reloaded later
+    pub(crate) fn get(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    fn set(&self, config: Arc<Config>) {
+        *self.config.write().unwrap() = config;
+    }
+}
+
+/// starts watching the config file at `path` and reloads `shared` when it's changed.
+/// invalid config is logged and ignored.
+///
+/// the config file is watched until the returned watcher is dropped.
+/// `overrides` are applied to the reloaded config as well as the first one.
+pub(crate) fn watch_config(
+    path: &Path,
+    shared: Arc<SharedConfig>,
+    overrides: DirOverrides,
+) -> Result<RecommendedWatcher> {
+    let path = std::path::absolute(path).context("resolving path")?;
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let file_name = path.file_name().map(ToOwned::to_owned);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event {
+            // editors may replace the file so the directory is watched
+            Ok(event) if event.kind.is_access() => {}
+            Ok(event)
+                if event
+                    .paths
+                    .iter()
+                    .any(|x| x.file_name() == file_name.as_deref()) =>
+            {
+                sender.send(()).ok();
+            }
+            Ok(_) => {}
+            Err(e) => error!("error watching config file: {}", e),
+        }
+    })?;
+    let directory = path.parent().unwrap_or_else(|| Path::new("/"));
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        while receiver.recv().await.is_some() {
+            // one change may be notified as multiple events
+            while receiver.try_recv().is_ok() {}
+            reload(&path, &shared, &overrides).await;
+        }
+    });
+    Ok(watcher)
+}
+
+async fn reload(path: &Path, shared: &SharedConfig, overrides: &DirOverrides) {
+    trace!("reloading {}", path.display());
+    match load_config(Some(path), overrides).await {
+        Ok(config) => {
+            trace!("reloaded config: {:?}", config);
+            shared.set(Arc::from(config));
+            info!("reloaded {}", path.display());
+        }
+        Err(e) => error!(
+            "error reloading {}. keeping the current config: {:#}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    fn config_text(dir: &Path, max_backups: usize) -> String {
+        format!(
+            "save_dir: {:?}\n\
+            backup_dir: {:?}\n\
+            backups:\n\
+            - name: hourly\n  interval: hourly\n  max_backups: {}\n",
+            dir.join("world"),
+            dir.join("backups"),
+            max_backups,
+        )
+    }
+
+    async fn wait_for(shared: &SharedConfig, max_backups: usize) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while shared.get().backups[0].max_backups != max_backups {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_on_change() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("world")).unwrap();
+        let path = dir.path().join("config.yml");
+        fs::write(&path, config_text(dir.path(), 1)).unwrap();
+        let shared = Arc::new(SharedConfig::new(Arc::from(
+            load_config(Some(&path), &DirOverrides::default())
+                .await
+                .unwrap(),
+        )));
+        let _watcher = watch_config(&path, shared.clone(), DirOverrides::default()).unwrap();
+        let snapshot = shared.get();
+
+        fs::write(&path, config_text(dir.path(), 2)).unwrap();
+        wait_for(&shared, 2).await;
+        // snapshot taken before reload is not changed
+        assert_eq!(snapshot.backups[0].max_backups, 1);
+
+        // invalid config is ignored
+        fs::write(&path, "backups: invalid").unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(shared.get().backups[0].max_backups, 2);
+
+        // replacing the file like editors
+        let temp = dir.path().join("config.yml.tmp");
+        fs::write(&temp, config_text(dir.path(), 3)).unwrap();
+        fs::rename(&temp, &path).unwrap();
+        wait_for(&shared, 3).await;
+    }
+}
@@ -0,0 +1,908 @@
+use crate::checksum::{hash_file, read_checksum, HashingWriter};
+use crate::config::{Config, Encryption};
+use crate::diff::apply_diff;
+use crate::encryption::{decrypt, is_encrypted, DecryptionKey};
+use crate::files_txt::{find_diff, find_full_backup, read_files_txt};
+use crate::store::{find_manifest, Store};
+use crate::tar::{ArchiveManifest, RESERVED_DIR};
+use anyhow::{anyhow, bail, Context as _, Result};
+use log::{info, trace, warn};
+use std::fs::File as StdFile;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// how the backup is restored
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct RestoreOptions {
+    /// restores even if `dest` is not empty or the backup is taken for another setting
+    pub(crate) force: bool,
+    /// restores owners of files by numeric uid and gid
+    pub(crate) same_owner: bool,
+    /// verifies checksums of archives used and extracted files
+    pub(crate) verify: bool,
+}
+
+/// restores the backup to `dest`.
+///
+/// if `setting` is not specified, the setting which have the backup is used.
+/// `identity` is the age identity file to decrypt encrypted backups.
+/// if not specified, the passphrase of the setting is used if any.
+pub(crate) async fn run(
+    config: &Config,
+    setting: Option<&str>,
+    backup_name: &str,
+    dest: &Path,
+    identity: Option<&Path>,
+    options: RestoreOptions,
+) -> Result<()> {
+    let setting = match setting {
+        Some(setting) => config
+            .backups
+            .iter()
+            .find(|x| x.name == setting)
+            .ok_or_else(|| anyhow!("backup setting {} not found", setting))?,
+        None => {
+            // backups on sftp servers are not searched
+            let mut found = config.backups.iter().filter(|x| {
+                x.sftp.is_none()
+                    && read_files_txt(&x.directory)
+                        .map(|names| names.iter().any(|n| n == backup_name))
+                        .unwrap_or(false)
+            });
+            let first = found.next().ok_or_else(|| {
+                if config.backups.iter().any(|x| x.sftp.is_some()) {
+                    anyhow!(
+                        "backup {} not found in local backups. \
+                        restoring backups on sftp server is not supported",
+                        backup_name
+                    )
+                } else {
+                    anyhow!("backup {} not found", backup_name)
+                }
+            })?;
+            if let Some(second) = found.next() {
+                bail!(
+                    "backup {} found in both {} and {}. please specify --setting",
+                    backup_name,
+                    first.name,
+                    second.name
+                )
+            }
+            first
+        }
+    };
+    if setting.sftp.is_some() {
+        bail!(
+            "restoring backups of {} is not supported since they are saved to sftp server",
+            setting.name
+        )
+    }
+
+    let key = match (identity, &setting.encryption) {
+        (Some(identity), _) => Some(DecryptionKey::IdentityFile(identity.to_owned())),
+        (None, Some(Encryption::Passphrase(passphrase))) => {
+            Some(DecryptionKey::Passphrase(passphrase.clone()))
+        }
+        (None, _) => None,
+    };
+    let directory = setting.directory.clone();
+    let setting = setting.name.clone();
+    let backup_name = backup_name.to_owned();
+    let dest = dest.to_owned();
+    tokio::task::spawn_blocking(move || {
+        restore(
+            &directory,
+            Some(&setting),
+            &backup_name,
+            &dest,
+            key.as_ref(),
+            options,
+        )
+    })
+    .await?
+}
+
+/// restores the backup in `directory` to `dest`.
+///
+/// if `setting` is specified, the backup is refused unless its manifest says it's taken for
+/// the setting. `force` restores it anyway.
+/// permissions of files are restored as recorded in the backup.
+/// owners are restored by numeric uid and gid if `same_owner` is true.
+/// with `verify`, checksums of archives the backup is reconstructed from are checked before
+/// extracting and extracted files are compared with the reconstructed archive after extracting.
+pub(crate) fn restore(
+    directory: &Path,
+    setting: Option<&str>,
+    backup_name: &str,
+    dest: &Path,
+    key: Option<&DecryptionKey>,
+    options: RestoreOptions,
+) -> Result<()> {
+    let RestoreOptions {
+        force,
+        same_owner,
+        verify,
+    } = options;
+    if !force && is_non_empty_dir(dest)? {
+        bail!(
+            "{} is not empty. use --force to restore anyway",
+            dest.display()
+        )
+    }
+
+    let names = read_files_txt(directory)
+        .with_context(|| format!("reading files.txt in {}", directory.display()))?;
+    let index = names
+        .iter()
+        .position(|n| n == backup_name)
+        .ok_or_else(|| anyhow!("backup {} not found in files.txt", backup_name))?;
+
+    if verify {
+        verify_chain(directory, &names[index..])
+            .with_context(|| format!("verifying archives of {}", backup_name))?;
+    }
+    let mut tar = reconstruct(directory, &names[index..], key)?;
+
+    tar.seek(SeekFrom::Start(0))?;
+    match ArchiveManifest::read(BufReader::new(&mut tar)).context("reading manifest")? {
+        Some(manifest) => {
+            info!(
+                "{} was taken at {} by version {}",
+                backup_name, manifest.timestamp, manifest.version
+            );
+            let names = manifest.settings.iter().map(|x| x.name.as_str());
+            let names = names.collect::<Vec<_>>();
+            match setting {
+                Some(setting) if !names.contains(&setting) && force => {
+                    warn!("restoring {} taken for {}", backup_name, names.join(", "))
+                }
+                Some(setting) if !names.contains(&setting) => bail!(
+                    "{} was taken for {}, not for {}. use --force to restore anyway",
+                    backup_name,
+                    names.join(", "),
+                    setting
+                ),
+                _ => {}
+            }
+        }
+        None => trace!("{} has no manifest", backup_name),
+    }
+
+    info!("extracting {} to {}", backup_name, dest.display());
+    std::fs::create_dir_all(dest).context("creating destination directory")?;
+    tar.seek(SeekFrom::Start(0))?;
+    let mut archive = ::tar::Archive::new(BufReader::new(&mut tar));
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(same_owner);
+    extract(&mut archive, dest).context("extracting backup")?;
+
+    if verify {
+        tar.seek(SeekFrom::Start(0))?;
+        verify_extracted(tar, dest).context("verifying extracted files")?;
+        info!("verified {} restored to {}", backup_name, dest.display());
+    }
+
+    Ok(())
+}
+
+/// checks checksums of archives the first backup of `chain` is reconstructed from.
+///
+/// those are the manifest and objects for deduplicated backups, or the diffs and
+/// the nearest full backup for diff backups like [reconstruct].
+fn verify_chain(directory: &Path, chain: &[String]) -> Result<()> {
+    if let Some(manifest_path) = find_manifest(directory, &chain[0]) {
+        verify_checksum(directory, &chain[0])?;
+        let manifest = std::fs::read(&manifest_path)
+            .with_context(|| format!("reading {}", manifest_path.display()))?;
+        let broken = Store::new(directory).verify(&manifest)?;
+        if !broken.is_empty() {
+            bail!("objects {} are broken", broken.join(", "))
+        }
+        return Ok(());
+    }
+    for name in chain {
+        verify_checksum(directory, name)?;
+        if find_full_backup(directory, name).is_some() {
+            return Ok(());
+        }
+    }
+    bail!("no full backup found for {}", chain[0])
+}
+
+fn verify_checksum(directory: &Path, name: &str) -> Result<()> {
+    let (expected, file) = match read_checksum(directory, name)? {
+        Some(checksum) => checksum,
+        None => {
+            // older backups may not have checksum
+            warn!(
+                "no checksum found for {}. skipping verification of it",
+                name
+            );
+            return Ok(());
+        }
+    };
+    let actual = hash_file(&directory.join(&file))
+        .with_context(|| format!("reading {}", directory.join(&file).display()))?;
+    if actual != expected {
+        bail!(
+            "checksum of {} mismatched: expected {} but was {}",
+            file,
+            expected,
+            actual
+        )
+    }
+    trace!("checksum of {} matched", file);
+    Ok(())
+}
+
+/// compares regular files extracted to `dest` with ones in the archive by SHA-256
+fn verify_extracted(tar: impl Read, dest: &Path) -> Result<()> {
+    let mut archive = ::tar::Archive::new(BufReader::new(tar));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if path.starts_with(RESERVED_DIR) || !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let mut hasher = HashingWriter::new(std::io::sink());
+        std::io::copy(&mut entry, &mut hasher)?;
+        let (_, expected) = hasher.finish();
+        let extracted = dest.join(&path);
+        let actual =
+            hash_file(&extracted).with_context(|| format!("reading {}", extracted.display()))?;
+        if actual != expected {
+            bail!(
+                "{} differs from the backup: expected {} but was {}",
+                path.display(),
+                expected,
+                actual
+            )
+        }
+    }
+    Ok(())
+}
+
+/// extracts entries except ones in the reserved directory to `dest`.
+///
+/// directories are extracted after files like [::tar::Archive::unpack]
+/// so that files can be written into read-only directories.
+fn extract(archive: &mut ::tar::Archive<impl Read>, dest: &Path) -> std::io::Result<()> {
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.starts_with(RESERVED_DIR) {
+            continue;
+        }
+        if entry.header().entry_type() == ::tar::EntryType::Directory {
+            directories.push(entry);
+        } else {
+            entry.unpack_in(dest)?;
+        }
+    }
+    for mut directory in directories {
+        directory.unpack_in(dest)?;
+    }
+    Ok(())
+}
+
+fn is_non_empty_dir(path: &Path) -> Result<bool> {
+    match std::fs::read_dir(path) {
+        Ok(mut entries) => Ok(entries.next().is_some()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// reconstructs the first backup of `chain` as a plain tar file.
+///
+/// `chain` is the list of backup names, oldest first. the first backup is reconstructed from
+/// its manifest or the nearest full backup by applying diffs backward.
+/// `key` is used if the full backup is encrypted.
+pub(crate) fn reconstruct(
+    directory: &Path,
+    chain: &[String],
+    key: Option<&DecryptionKey>,
+) -> Result<StdFile> {
+    if let Some(manifest_path) = find_manifest(directory, &chain[0]) {
+        trace!("reconstructing from {}", manifest_path.display());
+        let manifest = std::fs::read(&manifest_path)
+            .with_context(|| format!("reading {}", manifest_path.display()))?;
+        let mut tar = tempfile::tempfile()?;
+        Store::new(directory)
+            .reconstruct(&manifest, BufWriter::new(&mut tar))
+            .with_context(|| format!("reconstructing from {}", manifest_path.display()))?;
+        return Ok(tar);
+    }
+
+    let (full_index, (compression, full_path)) = chain
+        .iter()
+        .enumerate()
+        .find_map(|(i, name)| find_full_backup(directory, name).map(|x| (i, x)))
+        .ok_or_else(|| anyhow!("no full backup found for {}", chain[0]))?;
+
+    trace!("reconstructing from {}", full_path.display());
+    let full = StdFile::open(&full_path)?;
+    let full: Box<dyn Read> = if is_encrypted(&full_path) {
+        let key = key.ok_or_else(|| {
+            anyhow!(
+                "{} is encrypted. please specify --identity",
+                full_path.display()
+            )
+        })?;
+        Box::new(
+            decrypt(BufReader::new(full), key)
+                .with_context(|| format!("decrypting {}", full_path.display()))?,
+        )
+    } else {
+        Box::new(full)
+    };
+    let mut current = tempfile::tempfile()?;
+    {
+        let mut writer = BufWriter::new(&mut current);
+        std::io::copy(&mut compression.reader(full), &mut writer)
+            .with_context(|| format!("reading {}", full_path.display()))?;
+        writer.flush()?;
+    }
+
+    for name in chain[..full_index].iter().rev() {
+        let (diff_compression, diff_path) =
+            find_diff(directory, name).ok_or_else(|| anyhow!("no diff found for {}", name))?;
+        trace!("applying {}", diff_path.display());
+        let diff = StdFile::open(&diff_path)
+            .with_context(|| format!("opening {}", diff_path.display()))?;
+        let diff = diff_compression.reader(diff);
+        let mut next = tempfile::tempfile()?;
+        {
+            let mut writer = BufWriter::new(&mut next);
+            apply_diff(&mut current, diff, &mut writer)
+                .with_context(|| format!("applying {}", diff_path.display()))?;
+            writer.flush()?;
+        }
+        current = next;
+    }
+
+    Ok(current)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{BackupMode, BackupSetting, Compression, PathFilter, SaveInterval};
+    use crate::tar::{append_dir_all_sorted, Progress, ReadOptions};
+    use age::secrecy::ExposeSecret;
+    use std::fs;
+
+    fn backup(save_dir: &Path) -> StdFile {
+        backup_with(save_dir, ReadOptions::default())
+    }
+
+    fn backup_with(save_dir: &Path, options: ReadOptions) -> StdFile {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut tar = ::tar::Builder::new(&mut file);
+        append_dir_all_sorted(
+            &mut tar,
+            "".as_ref(),
+            save_dir,
+            &PathFilter::default(),
+            None,
+            options,
+            &mut Progress::new(|_, _| {}),
+        )
+        .unwrap();
+        tar.finish().unwrap();
+        drop(tar);
+        file
+    }
+
+    fn assert_same_tree(a: &Path, b: &Path) {
+        let mut a_entries = fs::read_dir(a)
+            .unwrap()
+            .map(|x| x.unwrap().file_name())
+            .collect::<Vec<_>>();
+        let mut b_entries = fs::read_dir(b)
+            .unwrap()
+            .map(|x| x.unwrap().file_name())
+            .collect::<Vec<_>>();
+        a_entries.sort();
+        b_entries.sort();
+        assert_eq!(a_entries, b_entries);
+        for name in a_entries {
+            let (a, b) = (a.join(&name), b.join(&name));
+            if a.is_dir() {
+                assert_same_tree(&a, &b);
+            } else {
+                assert_eq!(fs::read(&a).unwrap(), fs::read(&b).unwrap(), "{:?}", a);
+            }
+        }
+    }
+
+    fn chain_setting(
+        directory: &Path,
+        mode: BackupMode,
+        compression: Compression,
+    ) -> BackupSetting {
+        BackupSetting {
+            max_backups: 3,
+            backup_mode: mode,
+            compression,
+            ..BackupSetting::for_test("test", directory)
+        }
+    }
+
+    async fn restore_chain(mode: BackupMode, compression: Compression) {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        let config = chain_setting(backups.path(), mode, compression);
+
+        fs::create_dir(save.path().join("region")).unwrap();
+        fs::write(save.path().join("level.dat"), b"level 1").unwrap();
+        fs::write(save.path().join("region/r.0.0.mca"), vec![1u8; 8192]).unwrap();
+        fs::write(save.path().join("removed.dat"), b"removed later").unwrap();
+        let snapshot = tempfile::tempdir().unwrap();
+        restore_tree(save.path(), snapshot.path());
+
+        let first = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        crate::archive::do_save_backup(backup(save.path()), &first, &config, false)
+            .await
+            .unwrap();
+
+        fs::write(save.path().join("level.dat"), b"level 2").unwrap();
+        let mut region = vec![1u8; 8192];
+        region[4000] = 2;
+        fs::write(save.path().join("region/r.0.0.mca"), region).unwrap();
+        fs::remove_file(save.path().join("removed.dat")).unwrap();
+        fs::write(save.path().join("added.dat"), b"added").unwrap();
+
+        let second = chrono::DateTime::from_timestamp(300, 0)
+            .unwrap()
+            .naive_utc();
+        crate::archive::do_save_backup(backup(save.path()), &second, &config, false)
+            .await
+            .unwrap();
+        assert!(backups
+            .path()
+            .join(format!(
+                "backup-1970-01-01-00-00-00.diff.{}",
+                compression.extension()
+            ))
+            .exists());
+
+        let dest = tempfile::tempdir().unwrap();
+        restore(
+            backups.path(),
+            None,
+            "backup-1970-01-01-00-00-00",
+            dest.path(),
+            None,
+            RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_same_tree(snapshot.path(), dest.path());
+
+        let dest = tempfile::tempdir().unwrap();
+        restore(
+            backups.path(),
+            None,
+            "backup-1970-01-01-00-05-00",
+            dest.path(),
+            None,
+            RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_same_tree(save.path(), dest.path());
+
+        let dest = tempfile::tempdir().unwrap();
+        let verify = RestoreOptions {
+            verify: true,
+            ..Default::default()
+        };
+        let name = "backup-1970-01-01-00-00-00";
+        restore(backups.path(), None, name, dest.path(), None, verify).unwrap();
+        assert_same_tree(snapshot.path(), dest.path());
+    }
+
+    #[tokio::test]
+    async fn verify_tampered_diff() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        let config = chain_setting(backups.path(), BackupMode::FileDiff, Compression::None);
+        fs::write(save.path().join("level.dat"), b"level 1").unwrap();
+        let first = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        crate::archive::do_save_backup(backup(save.path()), &first, &config, false)
+            .await
+            .unwrap();
+        fs::write(save.path().join("level.dat"), b"level 2").unwrap();
+        let second = chrono::DateTime::from_timestamp(300, 0)
+            .unwrap()
+            .naive_utc();
+        crate::archive::do_save_backup(backup(save.path()), &second, &config, false)
+            .await
+            .unwrap();
+
+        // flip a byte of the diff as if the disk is broken
+        let diff_path = backups.path().join("backup-1970-01-01-00-00-00.diff.tar");
+        let mut diff = fs::read(&diff_path).unwrap();
+        let at = diff.len() / 2;
+        diff[at] ^= 1;
+        fs::write(&diff_path, diff).unwrap();
+
+        let verify = RestoreOptions {
+            verify: true,
+            ..Default::default()
+        };
+        let dest = tempfile::tempdir().unwrap();
+        let name = "backup-1970-01-01-00-00-00";
+        let err = restore(backups.path(), None, name, dest.path(), None, verify).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("checksum of"), "{}", message);
+        assert!(message.contains("mismatched"), "{}", message);
+        assert!(!dest.path().join("level.dat").exists());
+
+        // the newest backup doesn't use the diff
+        let dest = tempfile::tempdir().unwrap();
+        let name = "backup-1970-01-01-00-05-00";
+        restore(backups.path(), None, name, dest.path(), None, verify).unwrap();
+        assert_eq!(fs::read(dest.path().join("level.dat")).unwrap(), b"level 2");
+    }
+
+    #[test]
+    fn verify_extracted_files() {
+        let save = tempfile::tempdir().unwrap();
+        fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        restore_tree(save.path(), dest.path());
+
+        let mut tar = backup(save.path());
+        tar.seek(SeekFrom::Start(0)).unwrap();
+        verify_extracted(&mut tar, dest.path()).unwrap();
+
+        fs::write(dest.path().join("level.dat"), b"broken").unwrap();
+        tar.seek(SeekFrom::Start(0)).unwrap();
+        let err = verify_extracted(&mut tar, dest.path()).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("level.dat differs from the backup"),
+            "{}",
+            err
+        );
+    }
+
+    fn restore_tree(from: &Path, to: &Path) {
+        let mut tar = backup(from);
+        tar.seek(SeekFrom::Start(0)).unwrap();
+        ::tar::Archive::new(tar).unpack(to).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_file_diff() {
+        restore_chain(BackupMode::FileDiff, Compression::Gzip).await;
+    }
+
+    #[tokio::test]
+    async fn restore_file_diff_uncompressed() {
+        restore_chain(BackupMode::FileDiff, Compression::None).await;
+    }
+
+    #[tokio::test]
+    async fn restore_modifies_only() {
+        restore_chain(BackupMode::ModifiesOnly, Compression::Gzip).await;
+    }
+
+    /// backs up and restores directory with 0700 and file with 0600 and returns their modes
+    #[cfg(unix)]
+    async fn restored_modes(preserve_permissions: bool) -> (u32, u32) {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            preserve_permissions,
+            ..chain_setting(backups.path(), BackupMode::Simple, Compression::None)
+        };
+        fs::create_dir(save.path().join("private")).unwrap();
+        fs::write(save.path().join("private/secret.dat"), b"secret").unwrap();
+        let mode = |path: &Path, mode| fs::set_permissions(path, fs::Permissions::from_mode(mode));
+        mode(&save.path().join("private/secret.dat"), 0o600).unwrap();
+        mode(&save.path().join("private"), 0o700).unwrap();
+
+        let options = config.read_options();
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        crate::archive::do_save_backup(backup_with(save.path(), options), &now, &config, false)
+            .await
+            .unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        restore(
+            backups.path(),
+            None,
+            "backup-1970-01-01-00-00-00",
+            dest.path(),
+            None,
+            RestoreOptions::default(),
+        )
+        .unwrap();
+
+        let mode = |path: &str| fs::metadata(dest.path().join(path)).unwrap().mode() & 0o7777;
+        (mode("private"), mode("private/secret.dat"))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn restore_permissions() {
+        assert_eq!(restored_modes(true).await, (0o700, 0o600));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn normalized_permissions() {
+        assert_eq!(restored_modes(false).await, (0o755, 0o644));
+    }
+
+    #[tokio::test]
+    async fn restore_encrypted() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        let identity = age::x25519::Identity::generate();
+        let config = BackupSetting {
+            encryption: Some(Encryption::Recipient(identity.to_public())),
+            ..chain_setting(backups.path(), BackupMode::Simple, Compression::Gzip)
+        };
+        fs::write(save.path().join("level.dat"), b"level").unwrap();
+
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        crate::archive::do_save_backup(backup(save.path()), &now, &config, false)
+            .await
+            .unwrap();
+        let archive = backups.path().join("backup-1970-01-01-00-00-00.tar.gz.age");
+        assert!(archive.exists());
+        assert!(
+            flate2::read::GzDecoder::new(StdFile::open(&archive).unwrap())
+                .read_to_end(&mut vec![])
+                .is_err()
+        );
+
+        let dest = tempfile::tempdir().unwrap();
+        let err = restore(
+            backups.path(),
+            None,
+            "backup-1970-01-01-00-00-00",
+            dest.path(),
+            None,
+            RestoreOptions::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("--identity"), "{}", err);
+
+        let identity_path = backups.path().join("identity.txt");
+        fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        restore(
+            backups.path(),
+            None,
+            "backup-1970-01-01-00-00-00",
+            dest.path(),
+            Some(&DecryptionKey::IdentityFile(identity_path)),
+            RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_same_tree(save.path(), dest.path());
+    }
+
+    #[tokio::test]
+    async fn restore_single_file() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        fs::write(save.path().join("world.db"), b"world").unwrap();
+        let save_dirs = [crate::config::SaveDir {
+            path: save.path().join("world.db"),
+            prefix: Default::default(),
+        }];
+        let mut tar = crate::archive::write_tar(
+            tempfile::tempfile().unwrap(),
+            &save_dirs,
+            &PathFilter::default(),
+            ReadOptions::default(),
+            &[],
+            &mut crate::tar::Progress::new(|_, _| {}),
+        )
+        .unwrap();
+        tar.flush().unwrap();
+        let config = BackupSetting {
+            save_dirs: save_dirs.to_vec(),
+            ..chain_setting(backups.path(), BackupMode::Simple, Compression::Gzip)
+        };
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        crate::archive::do_save_backup(tar, &now, &config, false)
+            .await
+            .unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        restore(
+            backups.path(),
+            None,
+            "backup-1970-01-01-00-00-00",
+            dest.path(),
+            None,
+            RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_same_tree(save.path(), dest.path());
+    }
+
+    #[tokio::test]
+    async fn skip_included_config() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let included_config = crate::config::IncludedConfig {
+            name: "config.yml",
+            content: std::sync::Arc::from(&b"backups: []\n"[..]),
+        };
+        let save_dirs = [crate::config::SaveDir {
+            path: save.path().to_owned(),
+            prefix: Default::default(),
+        }];
+        let mut tar = crate::archive::write_tar(
+            tempfile::tempfile().unwrap(),
+            &save_dirs,
+            &PathFilter::default(),
+            ReadOptions::default(),
+            &[(included_config.name, included_config.content.to_vec())],
+            &mut crate::tar::Progress::new(|_, _| {}),
+        )
+        .unwrap();
+        tar.seek(SeekFrom::Start(0)).unwrap();
+        {
+            let mut archive = ::tar::Archive::new(&mut tar);
+            let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+            assert_eq!(
+                entry.path().unwrap(),
+                Path::new(".game-save-backuper/config.yml")
+            );
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            assert_eq!(content, b"backups: []\n");
+        }
+
+        let config = BackupSetting {
+            save_dirs: save_dirs.to_vec(),
+            included_config: Some(included_config),
+            ..chain_setting(backups.path(), BackupMode::Simple, Compression::None)
+        };
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        crate::archive::do_save_backup(tar, &now, &config, false)
+            .await
+            .unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        restore(
+            backups.path(),
+            None,
+            "backup-1970-01-01-00-00-00",
+            dest.path(),
+            None,
+            RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_same_tree(save.path(), dest.path());
+    }
+
+    #[tokio::test]
+    async fn refuse_sftp_setting() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = crate::config::parse_config(
+            format!(
+                "preset: filesystem\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups:\n\
+                - name: remote\n  interval: hourly\n  max_backups: 24\n  sftp:\n    \
+                host: example.com\n    user: backup\n    password: secret\n    \
+                host_key: ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAd9gvJMWEwzsAmGoILEdD3daY2PpQJ58ltZiUPD2fGh\n    \
+                remote_dir: /backups\n",
+                dir.path(),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let name = "backup-1970-01-01-00-00-00";
+        let restore = |setting| {
+            run(
+                &config,
+                setting,
+                name,
+                dest.path(),
+                None,
+                RestoreOptions::default(),
+            )
+        };
+        let err = restore(Some("remote")).await.unwrap_err();
+        assert!(err.to_string().contains("not supported"), "{}", err);
+        let err = restore(None).await.unwrap_err();
+        assert!(err.to_string().contains("not supported"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn refuse_other_setting() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let config = BackupSetting {
+            name: "hourly".to_owned(),
+            save_dirs: vec![crate::config::SaveDir {
+                path: save.path().to_owned(),
+                prefix: Default::default(),
+            }],
+            interval: SaveInterval::Every1Hour,
+            ..chain_setting(backups.path(), BackupMode::Simple, Compression::None)
+        };
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let tar = crate::archive::build_tar(&[&config], &now).await.unwrap();
+        crate::archive::do_save_backup(tar, &now, &config, false)
+            .await
+            .unwrap();
+
+        let name = "backup-1970-01-01-00-00-00";
+        let dest = tempfile::tempdir().unwrap();
+        let err = restore(
+            backups.path(),
+            Some("daily"),
+            name,
+            dest.path(),
+            None,
+            RestoreOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} was taken for hourly, not for daily. use --force to restore anyway",
+                name
+            )
+        );
+        assert!(!dest.path().join("level.dat").exists());
+
+        restore(
+            backups.path(),
+            Some("daily"),
+            name,
+            dest.path(),
+            None,
+            RestoreOptions {
+                force: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_same_tree(save.path(), dest.path());
+        let dest = tempfile::tempdir().unwrap();
+        restore(
+            backups.path(),
+            Some("hourly"),
+            name,
+            dest.path(),
+            None,
+            RestoreOptions::default(),
+        )
+        .unwrap();
+        assert_same_tree(save.path(), dest.path());
+    }
+
+    #[test]
+    fn refuse_non_empty() {
+        let backups = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        fs::write(dest.path().join("file"), b"").unwrap();
+        let err = restore(
+            backups.path(),
+            None,
+            "backup",
+            dest.path(),
+            None,
+            RestoreOptions::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("not empty"), "{}", err);
+    }
+}
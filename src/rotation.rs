@@ -0,0 +1,1094 @@
+//! removing old backups and replacing previous backups with diffs.
+
+use crate::archive::{SavedBackup, WrittenArchive};
+use crate::checksum::{checksum_line, write_checksum, HashingWriter};
+use crate::config::{BackupMode, BackupSetting, Compression};
+use crate::files_txt::{
+    diff_file_names, files_txt_entry, find_full_backup, full_backup_file_names, parse_files_txt,
+    pinned_backups, remove_from_files_txt,
+};
+use crate::sink::BackupSink;
+use crate::store::Store;
+use crate::{asyncify, diff, encryption, files_txt, restore, sink, store};
+use anyhow::{Context as _, Result};
+use chrono::NaiveDateTime;
+use futures::future::try_join_all;
+use log::{error, info, trace, warn};
+use std::fs::File as StdFile;
+use std::io::{BufWriter, Cursor, Seek, SeekFrom};
+use std::path::Path;
+use tokio::fs::remove_file;
+use tokio::task::spawn_blocking;
+
+/// splits backups listed in files.txt, oldest first, into backups to be kept and to be removed
+/// with the retention policy, max_backups, and max_age of the setting.
+fn select_backups<'a>(
+    all_names: &[&'a [u8]],
+    pinned: &[&[u8]],
+    now: &NaiveDateTime,
+    config: &BackupSetting,
+) -> (Vec<&'a [u8]>, Vec<&'a [u8]>) {
+    // pinned backups are neither counted nor removed
+    let names = all_names
+        .iter()
+        .zip(pinned_flags(all_names, pinned))
+        .filter(|(_, pinned)| !pinned)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+
+    let mut keep = match &config.retention {
+        Some(retention) => {
+            let names = names
+                .iter()
+                .map(|name| String::from_utf8_lossy(name))
+                .collect::<Vec<_>>();
+            retention.keep(
+                &names.iter().map(|x| x.as_ref()).collect::<Vec<_>>(),
+                &config.name_format,
+            )
+        }
+        None => vec![true; names.len()],
+    };
+    // only newest max_backups backups are kept
+    let mut kept = 0;
+    for keep in keep.iter_mut().rev().filter(|keep| **keep) {
+        if kept == config.max_backups {
+            *keep = false;
+        } else {
+            kept += 1;
+        }
+    }
+    // backups older than max_age are removed except for the newest one
+    if let Some(max_age) = config.max_age {
+        let oldest = max_age.oldest(now);
+        let newest = names.len().saturating_sub(1);
+        for (name, keep) in names.iter().zip(keep.iter_mut()).take(newest) {
+            let name = String::from_utf8_lossy(name);
+            match NaiveDateTime::parse_from_str(&name, &config.name_format) {
+                Ok(time) if time < oldest => *keep = false,
+                Ok(_) => {}
+                Err(_) => warn!("keeping {} since time cannot be parsed from the name", name),
+            }
+        }
+    }
+
+    let removed = names
+        .iter()
+        .zip(keep)
+        .filter(|(_, keep)| !keep)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+    let kept = all_names
+        .iter()
+        .filter(|name| !removed.contains(name))
+        .copied()
+        .collect();
+    (kept, removed)
+}
+
+/// whether each backup must not be removed since it's pinned with `#keep`.
+/// pinned diffs are replaced with full archives by [rebase_pinned] before rotation
+/// so backups newer than them are not needed to restore them.
+fn pinned_flags(names: &[&[u8]], pinned: &[&[u8]]) -> Vec<bool> {
+    names.iter().map(|name| pinned.contains(name)).collect()
+}
+
+/// replaces pinned backups saved as diff with full archives.
+///
+/// diffs are applied backward from the newest full archive so a pinned diff would otherwise
+/// require all backups newer than it, which stops rotation forever.
+async fn rebase_pinned(
+    directory: &Path,
+    names: &[&[u8]],
+    pinned: &[&[u8]],
+    config: &BackupSetting,
+    dry_run: bool,
+) -> Result<()> {
+    for (i, name) in names.iter().enumerate() {
+        if !pinned.contains(name) {
+            continue;
+        }
+        let name = String::from_utf8_lossy(name).into_owned();
+        if files_txt::find_diff(directory, &name).is_none() {
+            continue;
+        }
+        if dry_run {
+            info!(
+                "dry run: would replace pinned {} of {} with full archive",
+                name, config.name
+            );
+            continue;
+        }
+        info!(
+            "replacing pinned {} of {} with full archive",
+            name, config.name
+        );
+        let chain = names[i..]
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+            .collect::<Vec<_>>();
+        let directory = directory.to_owned();
+        let compression = config.compression;
+        spawn_blocking(move || rebase_to_full(&directory, &chain, compression))
+            .await?
+            .with_context(|| format!("replacing pinned {} with full archive", name))?;
+    }
+    Ok(())
+}
+
+/// reconstructs the first backup of `chain` and saves it as full archive instead of diff.
+///
+/// the checksum is rewritten for the full archive before the diff is removed
+/// so [recover_diff_chain](crate::files_txt::recover_diff_chain) can finish or revert it after crash.
+fn rebase_to_full(directory: &Path, chain: &[String], compression: Compression) -> Result<()> {
+    let name = &chain[0];
+    let mut tar = restore::reconstruct(directory, chain, None)?;
+    tar.seek(SeekFrom::Start(0))?;
+    let file_name = format!("{}.{}", name, compression.extension());
+    let temp_path = directory.join(sink::temp_file_name(&file_name));
+    let file = StdFile::create(&temp_path)?;
+    let writer = HashingWriter::new(BufWriter::new(file));
+    let writer = compression.write(writer, |w| std::io::copy(&mut tar, w).map(|_| ()))?;
+    let (writer, hash) = writer.finish();
+    writer.into_inner()?.sync_all()?;
+    std::fs::rename(&temp_path, directory.join(&file_name))?;
+    write_checksum(directory, name, &file_name, &hash)?;
+    if let Some((_, diff_path)) = files_txt::find_diff(directory, name) {
+        std::fs::remove_file(&diff_path)
+            .with_context(|| format!("removing {}", diff_path.display()))?;
+    }
+    Ok(())
+}
+
+/// removes backups and rewrites files.txt with `remaining` backups.
+/// returns the new contents of files.txt
+async fn remove_backups(
+    sink: &impl BackupSink,
+    files_txt: &[u8],
+    to_delete: &[&[u8]],
+    remaining: &[&[u8]],
+    config: &BackupSetting,
+    dry_run: bool,
+) -> Result<Vec<u8>> {
+    let cfg_name = &config.name;
+    let joined = remove_from_files_txt(files_txt, to_delete);
+    // only files exist are deleted not to send many requests to remote sinks
+    let existing = sink.list().await.context("listing backups")?;
+
+    // files are removed before files.txt is rewritten.
+    // see files_txt module for the order
+    for name in to_delete {
+        match std::str::from_utf8(name) {
+            Ok(name) => {
+                trace!("deleting of {}: {}", cfg_name, name);
+                let archives = full_backup_file_names(name)
+                    .chain(diff_file_names(name))
+                    .map(|(_, file_name)| file_name)
+                    .chain([store::manifest_file_name(name)])
+                    .filter(|file_name| existing.contains(file_name))
+                    .collect::<Vec<_>>();
+                let result = try_join_all(archives.iter().map(|x| sink.delete(x))).await;
+                let result = match result {
+                    Ok(_) => sink.delete(&format!("{}.sha256", name)).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(err) = result {
+                    error!("error deleting {} of {}: {}", name, cfg_name, err);
+                }
+            }
+            Err(e) => {
+                error!(
+                    "error deleting {:x?} of {}: invalid utf8 at {}",
+                    name,
+                    cfg_name,
+                    e.valid_up_to()
+                );
+            }
+        }
+    }
+
+    sink.replace("files.txt", &joined)
+        .await
+        .context("creating new files.txt")?;
+
+    // objects are removed after manifests referencing them are removed
+    if config.backup_mode == BackupMode::Dedup {
+        if dry_run {
+            info!(
+                "dry run: would remove objects no longer referenced in {}",
+                cfg_name
+            );
+        } else {
+            let store = Store::new(sink.directory());
+            let names = remaining
+                .iter()
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .collect::<Vec<_>>();
+            let removed = asyncify(move || store.collect_garbage(&names))
+                .await
+                .context("removing unreferenced objects")?;
+            trace!("removed {} objects of {}", removed, cfg_name);
+        }
+    }
+    Ok(joined)
+}
+
+/// removes backups in `files_txt` not to be kept with the retention policy, max_backups,
+/// and max_age of the setting.
+///
+/// returns the backups kept, oldest first, and files.txt after removal.
+pub(crate) async fn rotate_backups<'a>(
+    sink: &impl BackupSink,
+    files_txt: &'a [u8],
+    pinned: &[&[u8]],
+    now: &NaiveDateTime,
+    config: &BackupSetting,
+    dry_run: bool,
+) -> Result<(Vec<&'a [u8]>, Vec<u8>)> {
+    let all_names = parse_files_txt(files_txt);
+    // diff modes are only for local backups
+    if config.backup_mode.is_diff() {
+        rebase_pinned(sink.directory(), &all_names, pinned, config, dry_run).await?;
+    }
+    let (kept, to_delete) = select_backups(&all_names, pinned, now, config);
+    if to_delete.is_empty() {
+        trace!(
+            "found backups for {}: expected {}, we have {}",
+            config.name,
+            config.max_backups,
+            kept.len(),
+        );
+        return Ok((kept, files_txt.to_vec()));
+    }
+    trace!(
+        "found backups to be removed for {}: deleting {}, after {}.",
+        config.name,
+        to_delete.len(),
+        kept.len(),
+    );
+    let files_txt = remove_backups(sink, files_txt, &to_delete, &kept, config, dry_run).await?;
+    Ok((kept, files_txt))
+}
+
+/// writes checksum of the archive, appends it to files.txt, and removes old backups.
+pub(crate) async fn record_backup(
+    sink: &impl BackupSink,
+    archive: WrittenArchive,
+    now: &NaiveDateTime,
+    config: &BackupSetting,
+    dry_run: bool,
+) -> Result<SavedBackup> {
+    let cfg_name = &config.name;
+    let WrittenArchive {
+        backup_name,
+        file_name: tar_file_name,
+        hash,
+        size,
+        tar_size,
+        copy_duration,
+        tar: backup_tar,
+    } = archive;
+    let tar_path = sink.path(&tar_file_name);
+
+    let checksum = checksum_line(&tar_file_name, &hash);
+    sink.put(
+        &format!("{}.sha256", backup_name),
+        Cursor::new(checksum.into_bytes()),
+    )
+    .await
+    .context("writing checksum")?;
+
+    // second, append to files.txt
+    let mut buffer = sink
+        .read("files.txt")
+        .await
+        .context("reading files.txt")?
+        .unwrap_or_default();
+    let entry = files_txt_entry(&buffer, &backup_name);
+    sink.append("files.txt", entry.as_bytes())
+        .await
+        .context("appending to files.txt")?;
+    buffer.extend_from_slice(entry.as_bytes());
+    trace!("appended to {}", sink.path("files.txt").display());
+
+    // third, remove oldest backup if needed
+    let pinned = pinned_backups(&buffer);
+    let (mut files_lines, mut files_txt) =
+        rotate_backups(sink, &buffer, &pinned, now, config, dry_run).await?;
+
+    // then, remove oldest backups until enough space is free. the newest backup is never removed
+    if let Some(min_free_bytes) = config.min_free_bytes {
+        loop {
+            // the newest backup is not a candidate
+            let candidates = &files_lines[..files_lines.len().saturating_sub(1)];
+            let Some(oldest) = pinned_flags(candidates, &pinned)
+                .iter()
+                .position(|pinned| !pinned)
+            else {
+                break;
+            };
+            let available = sink
+                .available_space()
+                .await
+                .context("checking available space")?;
+            match available {
+                Some(available) if available < min_free_bytes => {}
+                _ => break,
+            }
+            if dry_run {
+                info!(
+                    "dry run: would remove oldest backups of {} until {} bytes are free",
+                    cfg_name, min_free_bytes
+                );
+                break;
+            }
+            let evicted = files_lines.remove(oldest);
+            info!(
+                "removing {} of {} since only {} bytes are free but {} bytes are required",
+                String::from_utf8_lossy(evicted),
+                cfg_name,
+                available.unwrap_or_default(),
+                min_free_bytes,
+            );
+            files_txt =
+                remove_backups(sink, &files_txt, &[evicted], &files_lines, config, dry_run).await?;
+        }
+    }
+
+    // forth, replace previously newest backup with patch backup if needed.
+    // this is only for local backups since config validation rejects diff modes with sftp
+    // pinned backups are kept as full archives not to be rebased on the next rotation
+    if config.backup_mode.is_diff() && files_lines.len() >= 2 {
+        match std::str::from_utf8(files_lines[files_lines.len() - 2]) {
+            Ok(prev_name) if pinned.contains(&prev_name.as_bytes()) => {
+                trace!(
+                    "keeping pinned {} of {} as full archive",
+                    prev_name,
+                    cfg_name
+                );
+            }
+            Ok(prev_name) if dry_run => {
+                info!(
+                    "dry run: would replace {} of {} with diff",
+                    prev_name, cfg_name
+                );
+            }
+            Ok(prev_name) => {
+                let directory = sink.directory();
+                let backup_tar = backup_tar.context("the tar of backup is not kept for diff")?;
+                replace_with_diff(backup_tar, directory, prev_name, config)
+                    .await
+                    .with_context(|| format!("replacing {} with diff", prev_name))?;
+            }
+            Err(e) => {
+                error!(
+                    "error replacing {:x?} of {} with diff: invalid utf8 at {}",
+                    files_lines[files_lines.len() - 2],
+                    cfg_name,
+                    e.valid_up_to()
+                );
+            }
+        }
+    }
+
+    Ok(SavedBackup {
+        name: backup_name,
+        path: tar_path,
+        directory: sink.directory().to_owned(),
+        size,
+        tar_size,
+        copy_duration,
+    })
+}
+
+async fn replace_with_diff(
+    mut new_tar: StdFile,
+    directory: &Path,
+    prev_name: &str,
+    config: &BackupSetting,
+) -> Result<()> {
+    let (prev_compression, prev_path) = match find_full_backup(directory, prev_name) {
+        Some(prev) => prev,
+        None => {
+            trace!("no full backup found for {}. skipping diff", prev_name);
+            return Ok(());
+        }
+    };
+    if encryption::is_encrypted(&prev_path) {
+        // this happens only if encryption is disabled after the previous backup
+        trace!("{} is encrypted. skipping diff", prev_name);
+        return Ok(());
+    }
+    // diffs are compressed like full archives
+    let compression = config.compression;
+    let mode = config.backup_mode;
+    let file_name = format!("{}.diff.{}", prev_name, compression.extension());
+    let diff_path = directory.join(&file_name);
+
+    {
+        let diff_path = diff_path.clone();
+        let prev_path = prev_path.clone();
+        let directory = directory.to_owned();
+        let prev_name = prev_name.to_owned();
+        asyncify(move || {
+            let prev = prev_compression.reader(StdFile::open(prev_path)?);
+            let diff_file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(diff_path)?;
+            let diff = HashingWriter::new(BufWriter::new(diff_file));
+            let diff = compression.write(diff, |diff| {
+                diff::create_diff(prev, &mut new_tar, mode, diff)
+            })?;
+            let (diff, hash) = diff.finish();
+            diff.into_inner()?.sync_all()?;
+            write_checksum(&directory, &prev_name, &file_name, &hash)
+        })
+        .await?;
+    }
+    trace!("saved diff to {}", diff_path.display());
+
+    remove_file(&prev_path).await?;
+    trace!("removed {}", prev_path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::archive::test::{make_tar, setting};
+    use crate::archive::{do_save_backup, save_to_sink};
+    use crate::files_txt::BACKUP_NAME_FORMAT;
+    use crate::sink::LocalSink;
+    use crate::verify;
+    use std::io::Read;
+    use tokio::io;
+
+    /// local sink on a disk of `capacity` bytes only files in the directory use
+    struct SmallSink {
+        inner: LocalSink,
+        capacity: u64,
+    }
+
+    impl SmallSink {
+        fn new(directory: &Path, capacity: u64) -> Self {
+            Self {
+                inner: LocalSink::new(directory),
+                capacity,
+            }
+        }
+    }
+
+    impl BackupSink for SmallSink {
+        fn directory(&self) -> &Path {
+            self.inner.directory()
+        }
+
+        async fn prepare(&self) -> io::Result<()> {
+            self.inner.prepare().await
+        }
+
+        async fn available_space(&self) -> io::Result<Option<u64>> {
+            let mut used = 0;
+            for entry in std::fs::read_dir(self.directory())? {
+                used += entry?.metadata()?.len();
+            }
+            Ok(Some(self.capacity.saturating_sub(used)))
+        }
+
+        async fn put<R: Read + Send + 'static>(
+            &self,
+            file_name: &str,
+            reader: R,
+        ) -> io::Result<(R, u64)> {
+            self.inner.put(file_name, reader).await
+        }
+
+        async fn read(&self, file_name: &str) -> io::Result<Option<Vec<u8>>> {
+            self.inner.read(file_name).await
+        }
+
+        async fn list(&self) -> io::Result<Vec<String>> {
+            self.inner.list().await
+        }
+
+        async fn write(&self, file_name: &str, data: &[u8]) -> io::Result<()> {
+            self.inner.write(file_name, data).await
+        }
+
+        async fn append(&self, file_name: &str, data: &[u8]) -> io::Result<()> {
+            self.inner.append(file_name, data).await
+        }
+
+        async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.inner.rename(from, to).await
+        }
+
+        async fn delete(&self, file_name: &str) -> io::Result<()> {
+            self.inner.delete(file_name).await
+        }
+    }
+
+    fn make_tar_of(files: &[(&str, &[u8])]) -> StdFile {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut tar = ::tar::Builder::new(&mut file);
+        for (path, data) in files {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, *data).unwrap();
+        }
+        tar.finish().unwrap();
+        drop(tar);
+        file
+    }
+
+    #[tokio::test]
+    async fn dedup_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            backup_mode: BackupMode::Dedup,
+            ..setting(dir.path(), Compression::None)
+        };
+        let first = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let second = chrono::DateTime::from_timestamp(300, 0)
+            .unwrap()
+            .naive_utc();
+        let objects = || {
+            std::fs::read_dir(dir.path().join("objects"))
+                .unwrap()
+                .map(|x| std::fs::read_dir(x.unwrap().path()).unwrap().count())
+                .sum::<usize>()
+        };
+
+        let tar = make_tar_of(&[("shared.dat", b"shared"), ("old.dat", b"old")]);
+        let saved = do_save_backup(tar, &first, &config, false).await.unwrap();
+        assert_eq!(
+            saved.path,
+            dir.path().join("backup-1970-01-01-00-00-00.manifest")
+        );
+        assert_eq!(objects(), 2);
+
+        // the first backup is rotated out since max_backups is 1
+        let mut tar = make_tar_of(&[("shared.dat", b"shared"), ("new.dat", b"new")]);
+        do_save_backup(tar.try_clone().unwrap(), &second, &config, false)
+            .await
+            .unwrap();
+        assert!(!dir
+            .path()
+            .join("backup-1970-01-01-00-00-00.manifest")
+            .exists());
+        // the object of old.dat is removed but shared.dat is kept
+        assert_eq!(objects(), 2);
+
+        let names = files_txt::read_files_txt(dir.path()).unwrap();
+        assert_eq!(names, vec!["backup-1970-01-01-00-05-00"]);
+        let mut reconstructed = restore::reconstruct(dir.path(), &names, None).unwrap();
+        reconstructed.seek(SeekFrom::Start(0)).unwrap();
+        let mut reconstructed_bytes = Vec::new();
+        reconstructed.read_to_end(&mut reconstructed_bytes).unwrap();
+        let mut expected = Vec::new();
+        tar.seek(SeekFrom::Start(0)).unwrap();
+        tar.read_to_end(&mut expected).unwrap();
+        assert!(reconstructed_bytes == expected);
+        assert_eq!(verify::verify_backups(dir.path()).unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn comments_survive_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("files.txt"),
+            "backup-a\nbackup-b\n# before update #keep\nbackup-c\n",
+        )
+        .unwrap();
+        for name in ["backup-a", "backup-b", "backup-c"] {
+            std::fs::write(dir.path().join(format!("{}.tar", name)), b"").unwrap();
+            std::fs::write(dir.path().join(format!("{}.sha256", name)), b"").unwrap();
+        }
+        let config = BackupSetting {
+            max_backups: 3,
+            ..setting(dir.path(), Compression::None)
+        };
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        do_save_backup(make_tar(), &now, &config, false)
+            .await
+            .unwrap();
+
+        assert!(!dir.path().join("backup-a.tar").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            "backup-b\n# before update #keep\nbackup-c\nbackup-1970-01-01-00-00-00\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn pinned_backups_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("files.txt"),
+            "backup-a #keep\nbackup-b\nbackup-c # before update #keep\nbackup-d\n",
+        )
+        .unwrap();
+        for name in ["backup-a", "backup-b", "backup-c", "backup-d"] {
+            std::fs::write(dir.path().join(format!("{}.tar", name)), b"").unwrap();
+            std::fs::write(dir.path().join(format!("{}.sha256", name)), b"").unwrap();
+        }
+        let config = BackupSetting {
+            max_backups: 2,
+            ..setting(dir.path(), Compression::None)
+        };
+        let at = |secs: i64| {
+            chrono::DateTime::from_timestamp(secs, 0)
+                .unwrap()
+                .naive_utc()
+        };
+        for time in [at(0), at(3600)] {
+            do_save_backup(make_tar(), &time, &config, false)
+                .await
+                .unwrap();
+        }
+
+        // pinned backups are not counted for max_backups
+        assert_eq!(
+            files_txt::read_files_txt(dir.path()).unwrap(),
+            vec![
+                "backup-a",
+                "backup-c",
+                "backup-1970-01-01-00-00-00",
+                "backup-1970-01-01-01-00-00"
+            ]
+        );
+        assert!(dir.path().join("backup-a.tar").exists());
+        assert!(!dir.path().join("backup-b.tar").exists());
+        assert!(dir.path().join("backup-c.tar").exists());
+        assert!(!dir.path().join("backup-d.tar").exists());
+    }
+
+    #[tokio::test]
+    async fn pinned_diff_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            max_backups: 2,
+            backup_mode: BackupMode::FileDiff,
+            ..setting(dir.path(), Compression::Gzip)
+        };
+        let at = |minutes: i64| {
+            chrono::DateTime::from_timestamp(minutes * 60, 0)
+                .unwrap()
+                .naive_utc()
+        };
+        let tar_of = |content: &[u8]| {
+            let mut file = tempfile::tempfile().unwrap();
+            let mut tar = ::tar::Builder::new(&mut file);
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, "level.dat", content).unwrap();
+            tar.finish().unwrap();
+            drop(tar);
+            file
+        };
+        for minutes in 0..2 {
+            let content = format!("level {}", minutes);
+            do_save_backup(tar_of(content.as_bytes()), &at(minutes), &config, false)
+                .await
+                .unwrap();
+        }
+        // pins the first backup, which is saved as diff now
+        let pinned = "backup-1970-01-01-00-00-00";
+        std::fs::write(
+            dir.path().join("files.txt"),
+            format!("{} #keep\nbackup-1970-01-01-00-01-00\n", pinned),
+        )
+        .unwrap();
+        assert!(files_txt::find_diff(dir.path(), pinned).is_some());
+        for minutes in 2..6 {
+            let content = format!("level {}", minutes);
+            do_save_backup(tar_of(content.as_bytes()), &at(minutes), &config, false)
+                .await
+                .unwrap();
+        }
+
+        // newer backups are still rotated past max_backups
+        assert_eq!(
+            files_txt::read_files_txt(dir.path()).unwrap(),
+            vec![
+                pinned,
+                "backup-1970-01-01-00-04-00",
+                "backup-1970-01-01-00-05-00"
+            ]
+        );
+        // the pinned backup is kept as full archive and restorable alone
+        assert!(files_txt::find_diff(dir.path(), pinned).is_none());
+        assert!(dir.path().join(format!("{}.tar.gz", pinned)).exists());
+        assert_eq!(verify::verify_backups(dir.path()).unwrap(), vec![]);
+        let mut tar = restore::reconstruct(dir.path(), &[pinned.to_owned()], None).unwrap();
+        tar.seek(SeekFrom::Start(0)).unwrap();
+        let mut archive = ::tar::Archive::new(tar);
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "level 0");
+    }
+
+    #[tokio::test]
+    async fn pinned_previous_backup_is_not_diffed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            max_backups: 10,
+            backup_mode: BackupMode::FileDiff,
+            ..setting(dir.path(), Compression::None)
+        };
+        let at = |minutes: i64| {
+            chrono::DateTime::from_timestamp(minutes * 60, 0)
+                .unwrap()
+                .naive_utc()
+        };
+        do_save_backup(make_tar(), &at(0), &config, false)
+            .await
+            .unwrap();
+        // pins the newest backup before the next backup is saved
+        let pinned = "backup-1970-01-01-00-00-00";
+        std::fs::write(dir.path().join("files.txt"), format!("{} #keep\n", pinned)).unwrap();
+        do_save_backup(make_tar(), &at(1), &config, false)
+            .await
+            .unwrap();
+        assert!(files_txt::find_diff(dir.path(), pinned).is_none());
+        assert!(dir.path().join(format!("{}.tar", pinned)).exists());
+
+        do_save_backup(make_tar(), &at(2), &config, false)
+            .await
+            .unwrap();
+        assert!(files_txt::find_diff(dir.path(), pinned).is_none());
+        // backups not pinned are still saved as diff
+        assert!(files_txt::find_diff(dir.path(), "backup-1970-01-01-00-01-00").is_some());
+        assert_eq!(verify::verify_backups(dir.path()).unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn files_txt_has_no_blank_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            max_backups: 1000,
+            ..setting(dir.path(), Compression::None)
+        };
+        for minutes in 0..100 {
+            let now = chrono::DateTime::from_timestamp(minutes * 60, 0)
+                .unwrap()
+                .naive_utc();
+            do_save_backup(make_tar(), &now, &config, false)
+                .await
+                .unwrap();
+        }
+
+        let files_txt = std::fs::read_to_string(dir.path().join("files.txt")).unwrap();
+        assert_eq!(files_txt.lines().filter(|x| !x.is_empty()).count(), 100);
+        assert!(!files_txt.contains("\n\n"), "{:?}", files_txt);
+        assert!(!files_txt.starts_with('\n'));
+    }
+
+    #[tokio::test]
+    async fn hand_edited_files_txt() {
+        let dir = tempfile::tempdir().unwrap();
+        // edited on Windows with BOM and CRLF
+        std::fs::write(
+            dir.path().join("files.txt"),
+            b"\xEF\xBB\xBFbackup-old\r\nbackup-older\r\n",
+        )
+        .unwrap();
+        for name in ["backup-old", "backup-older"] {
+            std::fs::write(dir.path().join(format!("{}.tar", name)), b"").unwrap();
+            std::fs::write(dir.path().join(format!("{}.sha256", name)), b"").unwrap();
+        }
+        let config = BackupSetting {
+            max_backups: 2,
+            ..setting(dir.path(), Compression::None)
+        };
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        do_save_backup(make_tar(), &now, &config, false)
+            .await
+            .unwrap();
+
+        assert!(!dir.path().join("backup-old.tar").exists());
+        assert!(!dir.path().join("backup-old.sha256").exists());
+        assert!(dir.path().join("backup-older.tar").exists());
+        // rewritten files.txt is normalized
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            "backup-older\nbackup-1970-01-01-00-00-00\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            max_backups: usize::MAX,
+            retention: Some(crate::config::Retention {
+                daily: 2,
+                ..Default::default()
+            }),
+            ..setting(dir.path(), Compression::None)
+        };
+        let at = |secs: i64| {
+            chrono::DateTime::from_timestamp(secs, 0)
+                .unwrap()
+                .naive_utc()
+        };
+        for time in [at(0), at(3600), at(86400)] {
+            do_save_backup(make_tar(), &time, &config, false)
+                .await
+                .unwrap();
+        }
+
+        // the first backup is not the newest of the day
+        assert_eq!(
+            files_txt::read_files_txt(dir.path()).unwrap(),
+            vec!["backup-1970-01-01-01-00-00", "backup-1970-01-02-00-00-00"]
+        );
+        assert!(!dir.path().join("backup-1970-01-01-00-00-00.tar").exists());
+        assert!(!dir
+            .path()
+            .join("backup-1970-01-01-00-00-00.sha256")
+            .exists());
+        assert!(dir.path().join("backup-1970-01-01-01-00-00.tar").exists());
+    }
+
+    #[tokio::test]
+    async fn max_age() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("files.txt"), "custom\n").unwrap();
+        let config = BackupSetting {
+            max_backups: 4,
+            max_age: Some(crate::config::MaxAge::Days(1)),
+            ..setting(dir.path(), Compression::None)
+        };
+        let at = |secs: i64| {
+            chrono::DateTime::from_timestamp(secs, 0)
+                .unwrap()
+                .naive_utc()
+        };
+        for time in [at(0), at(3600), at(86400 + 3600), at(86400 + 7200)] {
+            do_save_backup(make_tar(), &time, &config, false)
+                .await
+                .unwrap();
+        }
+
+        // backups older than a day are removed but one whose name is not a time is kept
+        assert_eq!(
+            files_txt::read_files_txt(dir.path()).unwrap(),
+            vec![
+                "custom",
+                "backup-1970-01-02-01-00-00",
+                "backup-1970-01-02-02-00-00"
+            ]
+        );
+        assert!(!dir.path().join("backup-1970-01-01-00-00-00.tar").exists());
+        assert!(!dir.path().join("backup-1970-01-01-01-00-00.tar").exists());
+        assert!(dir.path().join("backup-1970-01-02-01-00-00.tar").exists());
+
+        // max_backups removes more than max_age
+        let config = BackupSetting {
+            max_backups: 2,
+            ..config
+        };
+        do_save_backup(make_tar(), &at(86400 + 10800), &config, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            files_txt::read_files_txt(dir.path()).unwrap(),
+            vec!["backup-1970-01-02-02-00-00", "backup-1970-01-02-03-00-00"]
+        );
+    }
+
+    #[tokio::test]
+    async fn min_free_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            max_backups: 10,
+            min_free_bytes: Some(5_500),
+            ..setting(dir.path(), Compression::None)
+        };
+        // each backup uses a bit more than 2048 bytes so only two backups leave enough space
+        let sink = SmallSink::new(dir.path(), 10_000);
+        let at = |secs: i64| {
+            chrono::DateTime::from_timestamp(secs, 0)
+                .unwrap()
+                .naive_utc()
+        };
+        for secs in 0..5 {
+            save_to_sink(&sink, make_tar(), &at(secs * 3600), &config, false)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            files_txt::read_files_txt(dir.path()).unwrap(),
+            vec!["backup-1970-01-01-03-00-00", "backup-1970-01-01-04-00-00"]
+        );
+        assert!(!dir.path().join("backup-1970-01-01-02-00-00.tar").exists());
+        assert!(!dir
+            .path()
+            .join("backup-1970-01-01-02-00-00.sha256")
+            .exists());
+
+        // the newest backup is kept even if space is still not enough
+        let config = BackupSetting {
+            min_free_bytes: Some(u64::MAX),
+            ..config
+        };
+        save_to_sink(&sink, make_tar(), &at(5 * 3600), &config, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            files_txt::read_files_txt(dir.path()).unwrap(),
+            vec!["backup-1970-01-01-05-00-00"]
+        );
+    }
+
+    #[tokio::test]
+    async fn not_enough_space() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("files.txt"), "backup-old").unwrap();
+        let config = setting(dir.path(), Compression::None);
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let sink = SmallSink::new(dir.path(), 0);
+        let err = save_to_sink(&sink, make_tar(), &now, &config, false)
+            .await
+            .err()
+            .unwrap();
+
+        assert!(err.to_string().starts_with("not enough space"), "{}", err);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("files.txt")).unwrap(),
+            "backup-old"
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn rotate_removes_any_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let second = chrono::DateTime::from_timestamp(300, 0)
+            .unwrap()
+            .naive_utc();
+        do_save_backup(
+            make_tar(),
+            &first,
+            &setting(dir.path(), Compression::Gzip),
+            false,
+        )
+        .await
+        .unwrap();
+        do_save_backup(
+            make_tar(),
+            &second,
+            &setting(dir.path(), Compression::None),
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert!(!dir
+            .path()
+            .join("backup-1970-01-01-00-00-00.tar.gz")
+            .exists());
+        assert!(dir.path().join("backup-1970-01-01-00-05-00.tar").exists());
+        assert!(!dir
+            .path()
+            .join("backup-1970-01-01-00-00-00.sha256")
+            .exists());
+        assert!(dir
+            .path()
+            .join("backup-1970-01-01-00-05-00.sha256")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn prefixed_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            max_backups: 1,
+            name_format: format!("survival-{}", BACKUP_NAME_FORMAT),
+            ..setting(dir.path(), Compression::None)
+        };
+        let at = |secs: i64| {
+            chrono::DateTime::from_timestamp(secs, 0)
+                .unwrap()
+                .naive_utc()
+        };
+        for time in [at(0), at(300)] {
+            do_save_backup(make_tar(), &time, &config, false)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            files_txt::read_files_txt(dir.path()).unwrap(),
+            vec!["survival-backup-1970-01-01-00-05-00"]
+        );
+        assert!(!dir
+            .path()
+            .join("survival-backup-1970-01-01-00-00-00.tar")
+            .exists());
+        assert!(!dir
+            .path()
+            .join("survival-backup-1970-01-01-00-00-00.sha256")
+            .exists());
+        assert!(dir
+            .path()
+            .join("survival-backup-1970-01-01-00-05-00.tar")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn file_diff_replaces_previous() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackupSetting {
+            max_backups: 3,
+            backup_mode: BackupMode::FileDiff,
+            ..setting(dir.path(), Compression::Gzip)
+        };
+        let first = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let second = chrono::DateTime::from_timestamp(300, 0)
+            .unwrap()
+            .naive_utc();
+        do_save_backup(make_tar(), &first, &config, false)
+            .await
+            .unwrap();
+        do_save_backup(make_tar(), &second, &config, false)
+            .await
+            .unwrap();
+
+        assert!(!dir
+            .path()
+            .join("backup-1970-01-01-00-00-00.tar.gz")
+            .exists());
+        assert!(dir
+            .path()
+            .join("backup-1970-01-01-00-00-00.diff.tar.gz")
+            .exists());
+        assert!(dir
+            .path()
+            .join("backup-1970-01-01-00-05-00.tar.gz")
+            .exists());
+
+        let mut reconstructed = Vec::new();
+        diff::apply_diff(
+            &mut make_tar(),
+            Compression::Gzip.reader(
+                StdFile::open(dir.path().join("backup-1970-01-01-00-00-00.diff.tar.gz")).unwrap(),
+            ),
+            &mut reconstructed,
+        )
+        .unwrap();
+        let mut expected = Vec::new();
+        let mut tar = make_tar();
+        tar.seek(SeekFrom::Start(0)).unwrap();
+        tar.read_to_end(&mut expected).unwrap();
+        assert_eq!(reconstructed, expected);
+    }
+}
@@ -0,0 +1,167 @@
+//! simulates ticks of the main loop to show when backups will be taken.
+
+use crate::config::{BackupSetting, Config};
+use crate::scheduler::{compute_sleep_time, due_settings, to_local_window};
+use chrono::{Duration, NaiveDateTime, Utc};
+use std::collections::HashMap;
+
+/// the local times backups are taken at from `begin` until `end` in UTC with backup settings
+/// backed up at the time.
+/// ticks at which no settings are backed up are not included.
+///
+/// settings out of their active hours or too close to the last simulated backup are skipped
+/// like the main loop. backups taken before `begin` are not considered.
+pub(crate) fn simulate<'a>(
+    config: &'a Config,
+    begin: &NaiveDateTime,
+    end: &NaiveDateTime,
+) -> Vec<(NaiveDateTime, Vec<&'a BackupSetting>)> {
+    let mut fires = Vec::new();
+    let mut last_fires = HashMap::<&str, NaiveDateTime>::new();
+    let mut since = *begin;
+    while since < *end {
+        let until = since + Duration::from_std(compute_sleep_time(since, config.tick)).unwrap();
+        // skipped like the main loop if the local time went backward
+        if let Some((local_since, local_until)) = to_local_window(config.timezone, &since, &until) {
+            let passed = due_settings(config, &local_since, &local_until)
+                .into_iter()
+                .filter(|x| {
+                    last_fires
+                        .get(x.name.as_str())
+                        .is_none_or(|last| x.is_spaced(last, &local_until))
+                })
+                .collect::<Vec<_>>();
+            for setting in &passed {
+                last_fires.insert(&setting.name, local_until);
+            }
+            if !passed.is_empty() {
+                fires.push((local_until, passed));
+            }
+        }
+        since = until;
+    }
+    fires
+}
+
+/// prints when backups will be taken in next `hours` hours.
+pub(crate) fn run(config: &Config, hours: u32) {
+    let now = Utc::now().naive_utc();
+    let fires = simulate(config, &now, &(now + Duration::hours(hours.into())));
+    for (time, settings) in &fires {
+        let names = settings.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+        println!("{}: {}", time, names.join(", "));
+    }
+    for setting in &config.backups {
+        let fired = fires
+            .iter()
+            .any(|(_, settings)| settings.iter().any(|x| x.name == setting.name));
+        if !fired {
+            println!(
+                "{} ({}) is not backed up in {} hours",
+                setting.name, setting.interval, hours
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::parse_config;
+    use chrono::NaiveDate;
+
+    fn at(d: u32, h: u32, m: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2021, 1, d)
+            .unwrap()
+            .and_hms_opt(h, m, 0)
+            .unwrap()
+    }
+
+    fn parse(extra: &str) -> Box<Config> {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        parse_config(
+            format!(
+                "save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                {}\
+                backups:\n\
+                - name: half-hourly\n  interval: 30 minutes\n  max_backups: 1\n\
+                - name: hourly\n  interval: hourly\n  max_backups: 1\n\
+                - name: daily\n  interval: daily\n  max_backups: 1\n",
+                dir.path().join("world"),
+                dir.path().join("backups"),
+                extra,
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn names(fires: Vec<(NaiveDateTime, Vec<&BackupSetting>)>) -> Vec<(NaiveDateTime, Vec<&str>)> {
+        fires
+            .into_iter()
+            .map(|(time, settings)| (time, settings.iter().map(|x| x.name.as_str()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn simulate_ticks() {
+        let config = parse("");
+        let fires = simulate(&config, &at(1, 22, 47), &at(2, 1, 0));
+        assert_eq!(
+            names(fires),
+            vec![
+                (at(1, 23, 0), vec!["half-hourly", "hourly"]),
+                (at(1, 23, 30), vec!["half-hourly"]),
+                (at(2, 0, 0), vec!["half-hourly", "hourly", "daily"]),
+                (at(2, 0, 30), vec!["half-hourly"]),
+                (at(2, 1, 0), vec!["half-hourly", "hourly"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn simulate_timezone() {
+        // 15:00 in UTC is the midnight in Tokyo
+        let config = parse("timezone: Asia/Tokyo\ntick: 1 hour\n");
+        let fires = simulate(&config, &at(1, 13, 30), &at(1, 15, 0));
+        assert_eq!(
+            names(fires),
+            vec![
+                (at(1, 23, 0), vec!["half-hourly", "hourly"]),
+                (at(2, 0, 0), vec!["half-hourly", "hourly", "daily"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn simulate_active_hours_and_min_spacing() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("world")).unwrap();
+        let config = parse_config(
+            format!(
+                "save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups:\n\
+                - name: night\n  interval: hourly\n  max_backups: 1\n  active_hours: 02:00-04:00\n\
+                - name: spaced\n  interval: 30 minutes\n  max_backups: 1\n  min_spacing: 1 hour\n",
+                dir.path().join("world"),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let fires = simulate(&config, &at(1, 23, 47), &at(2, 4, 0));
+        assert_eq!(
+            names(fires),
+            vec![
+                (at(2, 0, 0), vec!["spaced"]),
+                (at(2, 1, 0), vec!["spaced"]),
+                (at(2, 2, 0), vec!["night", "spaced"]),
+                (at(2, 3, 0), vec!["night", "spaced"]),
+                (at(2, 4, 0), vec!["spaced"]),
+            ]
+        );
+    }
+}
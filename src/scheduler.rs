@@ -0,0 +1,1483 @@
+//! the main loop deciding which backups to take and when.
+
+use crate::archive::{
+    build_tars, can_stream, last_fingerprint, save_backup, save_with_fingerprint, stream_archive,
+    write_fingerprint,
+};
+use crate::config::{BackupSetting, Config, Tick};
+use crate::files_txt::{
+    parse_files_txt, reconcile_files_txt, recover_diff_chain, recover_files_txt,
+};
+use crate::health::Heartbeat;
+use crate::rcon::{pause_saving, Connection, Context};
+use crate::reload::SharedConfig;
+use crate::rotation::record_backup;
+use crate::sink::{BackupSink, LocalSink, SftpSink};
+use crate::status::Status;
+use crate::{asyncify, tar};
+use anyhow::{bail, Context as _, Result};
+use chrono::{Duration, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures::future::join_all;
+use log::{error, info, trace, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+/// runs backup steps until `shutdown` completes.
+///
+/// if `shutdown` completes during a step, the step will be finished before return.
+/// each step uses the config at the beginning of the step even if it's reloaded during the step.
+pub(crate) async fn main_loop(
+    shared: &SharedConfig,
+    mut connections: HashMap<usize, Connection>,
+    dry_run: bool,
+    heartbeat: &Heartbeat,
+    shutdown: impl Future<Output = ()>,
+) {
+    let mut begin = chrono::Utc::now().naive_utc();
+    let mut config = shared.get();
+    let mut status = config
+        .status_file
+        .as_deref()
+        .map(Status::load)
+        .unwrap_or_default();
+    tokio::pin!(shutdown);
+
+    info!("backup start!!!");
+
+    loop {
+        let tick = shared.get().tick;
+        heartbeat.beat(std::time::Duration::from_secs(tick.seconds().into()));
+        let now = Utc::now().naive_utc();
+        let sleep = compute_sleep_time(now, tick);
+        tokio::select! {
+            _ = tokio::time::sleep(sleep) => {}
+            _ = &mut shutdown => {
+                info!("shutting down");
+                break;
+            }
+        }
+        let expected = now + Duration::from_std(sleep).unwrap_or_default();
+        let end = chrono::Utc::now().naive_utc();
+
+        trace!("finish sleep. it's {} now. {} passed.", end, end - begin);
+
+        if let Some((since, until)) = step_window(&mut begin, &expected, &end) {
+            let current = shared.get();
+            if !Arc::ptr_eq(&config, &current) {
+                // rcon servers may be changed
+                connections.clear();
+                config = current;
+            }
+            let mut ctx = Context::new(&config);
+            ctx.connections = std::mem::take(&mut connections);
+            ctx.dry_run = dry_run;
+            ctx.status = Some(std::mem::take(&mut status));
+            let start = std::time::Instant::now();
+            if let Some(err) = do_step(&mut ctx, &since, &until).await.err() {
+                error!("error during backup step at {}: {}", end, err)
+            }
+            let elapsed = start.elapsed();
+            connections = ctx.connections;
+            status = ctx.status.unwrap_or_default();
+            status.step_duration_ms = Some(elapsed.as_millis() as u64);
+            let now = config.timezone.from_utc_datetime(&end).naive_local();
+            if let Some(setting) = overrun_setting(&config, elapsed, &now) {
+                warn!(
+                    event = "step_overrun", duration_ms = elapsed.as_millis() as u64;
+                    "backup step took {:?}, longer than the interval of {} ({}). \
+                    ticks are skipped while backing up. \
+                    consider a longer interval or faster storage",
+                    elapsed,
+                    setting.name,
+                    setting.interval
+                );
+            }
+            if let (Some(path), false) = (&config.status_file, dry_run) {
+                status.refresh(&config, &now);
+                if let Err(err) = status.write(path).await {
+                    error!("error writing status file: {:#}", err);
+                }
+            }
+        }
+    }
+}
+
+/// the backup setting with the shortest interval at `now` if a step taking `elapsed` is longer than it
+fn overrun_setting<'a>(
+    config: &'a Config,
+    elapsed: std::time::Duration,
+    now: &NaiveDateTime,
+) -> Option<&'a BackupSetting> {
+    let length = |setting: &BackupSetting| {
+        let last = setting.interval.get_last_date_until(now);
+        setting.interval.get_next_date_after(&last) - last
+    };
+    let shortest = config.backups.iter().min_by_key(|x| length(x))?;
+    match Duration::from_std(elapsed) {
+        Ok(elapsed) if elapsed <= length(shortest) => None,
+        _ => Some(shortest),
+    }
+}
+
+/// the difference from expected wake time regarded as the clock jumped
+fn clock_jump_tolerance() -> Duration {
+    Duration::minutes(1)
+}
+
+/// decides the window of the step woke up at `end` and advances `begin` to the end of the window.
+/// `expected` is the time expected to wake up at.
+///
+/// if the clock jumped forward, the window contains the skipped time so backups for intervals
+/// passed during the jump are taken. if the clock jumped backward, `begin` is not moved
+/// backward so backups are not taken again for the time already checked.
+fn step_window(
+    begin: &mut NaiveDateTime,
+    expected: &NaiveDateTime,
+    end: &NaiveDateTime,
+) -> Option<(NaiveDateTime, NaiveDateTime)> {
+    let drift = *end - *expected;
+    if drift > clock_jump_tolerance() {
+        warn!(
+            "clock jumped forward by {}. backups for intervals passed until {} will be taken",
+            drift, end
+        );
+    } else if drift < -clock_jump_tolerance() {
+        warn!(
+            "clock jumped backward by {}. backups will be skipped until {}",
+            -drift, begin
+        );
+    }
+
+    if *begin < *end {
+        let window = (*begin, *end);
+        *begin = *end;
+        Some(window)
+    } else {
+        None
+    }
+}
+
+/// computes the duration until the next tick.
+/// ticks are at multiples of `tick` from midnight.
+pub(crate) fn compute_sleep_time(now: NaiveDateTime, tick: Tick) -> std::time::Duration {
+    let midnight = now.date().and_time(NaiveTime::MIN);
+    let tick = i64::from(tick.seconds());
+    // the next tick may be the midnight of the next day
+    let until = midnight + Duration::seconds(((now - midnight).num_seconds() / tick + 1) * tick);
+    let duration = (until - now).to_std().unwrap_or_default();
+
+    trace!("wait for {:?} to reach {}", duration, until);
+
+    duration
+}
+
+#[test]
+fn compute_sleep_time_test() {
+    use std::time::Duration as StdDuration;
+
+    let tick = Tick::DEFAULT;
+    let at = |h, m, s| {
+        chrono::NaiveDate::from_ymd_opt(2021, 1, 1)
+            .unwrap()
+            .and_hms_opt(h, m, s)
+            .unwrap()
+    };
+    assert_eq!(
+        compute_sleep_time(at(0, 0, 0), tick),
+        StdDuration::from_secs(5 * 60)
+    );
+    assert_eq!(
+        compute_sleep_time(at(23, 50, 50), tick),
+        StdDuration::from_secs(4 * 60 + 10)
+    );
+    assert_eq!(
+        compute_sleep_time(at(23, 59, 59), tick),
+        StdDuration::from_secs(1)
+    );
+    assert_eq!(
+        compute_sleep_time(at(23, 59, 30), tick),
+        StdDuration::from_secs(30)
+    );
+    // exactly at midnight of the next day waits for the next tick
+    assert_eq!(
+        compute_sleep_time(at(23, 55, 0) + Duration::minutes(5), tick),
+        StdDuration::from_secs(5 * 60)
+    );
+    assert_eq!(
+        compute_sleep_time(at(23, 59, 59) + Duration::milliseconds(999), tick),
+        StdDuration::from_millis(1)
+    );
+}
+
+#[test]
+fn step_window_test() {
+    let at = |h, m, s| {
+        chrono::NaiveDate::from_ymd_opt(2021, 1, 1)
+            .unwrap()
+            .and_hms_opt(h, m, s)
+            .unwrap()
+    };
+    let mut begin = at(12, 0, 0);
+
+    // woke up as expected
+    let window = step_window(&mut begin, &at(12, 5, 0), &at(12, 5, 0));
+    assert_eq!(window, Some((at(12, 0, 0), at(12, 5, 0))));
+    assert_eq!(begin, at(12, 5, 0));
+
+    // the clock jumped forward: the window contains skipped time
+    let window = step_window(&mut begin, &at(12, 10, 0), &at(15, 10, 0));
+    assert_eq!(window, Some((at(12, 5, 0), at(15, 10, 0))));
+    assert_eq!(begin, at(15, 10, 0));
+
+    // the clock jumped backward: time already checked is skipped
+    let window = step_window(&mut begin, &at(15, 15, 0), &at(14, 15, 0));
+    assert_eq!(window, None);
+    assert_eq!(begin, at(15, 10, 0));
+    let window = step_window(&mut begin, &at(14, 20, 0), &at(14, 20, 0));
+    assert_eq!(window, None);
+    // the window restarts after the clock passed the time already checked
+    let window = step_window(&mut begin, &at(15, 15, 0), &at(15, 15, 0));
+    assert_eq!(window, Some((at(15, 10, 0), at(15, 15, 0))));
+    assert_eq!(begin, at(15, 15, 0));
+}
+
+#[test]
+fn compute_sleep_time_short_tick() {
+    use std::time::Duration as StdDuration;
+
+    let at = |h, m, s| {
+        chrono::NaiveDate::from_ymd_opt(2021, 1, 1)
+            .unwrap()
+            .and_hms_opt(h, m, s)
+            .unwrap()
+    };
+
+    let one = Tick::from_minutes(1).unwrap();
+    assert_eq!(
+        compute_sleep_time(at(12, 0, 0), one),
+        StdDuration::from_secs(60)
+    );
+    assert_eq!(
+        compute_sleep_time(at(12, 34, 56) + Duration::milliseconds(500), one),
+        StdDuration::from_millis(3500)
+    );
+    assert_eq!(
+        compute_sleep_time(at(23, 59, 30), one),
+        StdDuration::from_secs(30)
+    );
+
+    let two = Tick::from_minutes(2).unwrap();
+    assert_eq!(
+        compute_sleep_time(at(12, 1, 0), two),
+        StdDuration::from_secs(60)
+    );
+    assert_eq!(
+        compute_sleep_time(at(12, 2, 0), two),
+        StdDuration::from_secs(120)
+    );
+    assert_eq!(
+        compute_sleep_time(at(23, 58, 10), two),
+        StdDuration::from_secs(110)
+    );
+    assert_eq!(
+        compute_sleep_time(at(23, 59, 59), two),
+        StdDuration::from_secs(1)
+    );
+}
+
+#[test]
+fn frequent_interval_schedule() {
+    let at = |h, m, s| {
+        chrono::NaiveDate::from_ymd_opt(2021, 1, 1)
+            .unwrap()
+            .and_hms_opt(h, m, s)
+            .unwrap()
+    };
+
+    // wakes up at each tick and backs up at every 2 minutes
+    let tick = Tick::from_minutes(1).unwrap();
+    let interval = crate::config::SaveInterval::EveryNMinutes(2);
+    let mut now = at(12, 0, 30);
+    let mut backups = vec![];
+    for _ in 0..6 {
+        let next = now + Duration::from_std(compute_sleep_time(now, tick)).unwrap();
+        if interval.is_passed(&now, &next) {
+            backups.push(next);
+        }
+        now = next;
+    }
+    assert_eq!(backups, vec![at(12, 2, 0), at(12, 4, 0), at(12, 6, 0)]);
+}
+
+/// converts the window in UTC to the local time in the timezone.
+///
+/// returns None if the local time goes backward, for example, at the end of DST.
+pub(crate) fn to_local_window(
+    timezone: Tz,
+    begin: &NaiveDateTime,
+    end: &NaiveDateTime,
+) -> Option<(NaiveDateTime, NaiveDateTime)> {
+    let begin = timezone.from_utc_datetime(begin).naive_local();
+    let end = timezone.from_utc_datetime(end).naive_local();
+    (begin < end).then_some((begin, end))
+}
+
+async fn do_step(ctx: &mut Context<'_>, begin: &NaiveDateTime, end: &NaiveDateTime) -> Result<()> {
+    let (begin, end) = match to_local_window(ctx.config.timezone, begin, end) {
+        Some((begin, end)) => (begin, end),
+        None => {
+            trace!("local time went backward. skipping this step.");
+            return Ok(());
+        }
+    };
+    let (begin, end) = (&begin, &end);
+    let mut passed = Vec::new();
+    for setting in due_settings(ctx.config, begin, end) {
+        if is_spaced(setting, end).await {
+            passed.push(setting);
+        }
+    }
+
+    if !passed.is_empty() {
+        info!(
+            "those settings will be used to backup {:?}",
+            passed.iter().map(|x| &x.name).collect::<Vec<_>>()
+        );
+        let results = run_backups(ctx, passed.clone(), end).await;
+        if let Some(status) = &mut ctx.status {
+            match &results {
+                Ok(results) => {
+                    for (setting, succeeded) in results {
+                        status.record(setting, end, *succeeded);
+                    }
+                }
+                // commands_before failed so no settings were backed up
+                Err(_) => {
+                    for setting in &passed {
+                        status.record(setting, end, false);
+                    }
+                }
+            }
+        }
+        results?;
+    } else {
+        trace!("nothing to do for this step.")
+    }
+
+    Ok(())
+}
+
+/// settings whose interval is passed between `begin` and `end`.
+/// settings out of their active hours at `end` are skipped.
+pub(crate) fn due_settings<'a>(
+    config: &'a Config,
+    begin: &NaiveDateTime,
+    end: &NaiveDateTime,
+) -> Vec<&'a BackupSetting> {
+    config
+        .backups
+        .iter()
+        .filter(|x| x.interval.is_passed(begin, end))
+        .filter(|x| {
+            let active = x.is_active_at(end);
+            if !active {
+                trace!("skipping {} out of its active hours", x.name);
+            }
+            active
+        })
+        .collect()
+}
+
+/// backs up with the settings.
+///
+/// returns whether each setting succeeded to save backup.
+/// errors of each setting are logged and don't stop other settings.
+pub(crate) async fn run_backups<'a>(
+    ctx: &mut Context<'_>,
+    settings: Vec<&'a BackupSetting>,
+    now: &NaiveDateTime,
+) -> Result<Vec<(&'a BackupSetting, bool)>> {
+    let config = ctx.config;
+    let dry_run = ctx.dry_run;
+    let (settings, fingerprints, skipped) = skip_unchanged(settings).await;
+    let mut results = skipped.into_iter().map(|x| (x, true)).collect::<Vec<_>>();
+    if settings.is_empty() {
+        return Ok(results);
+    }
+    // settings with same save directories, options to read them, and commands around reading
+    // share one backup tar
+    let mut groups = Vec::<(&BackupSetting, Vec<&'a BackupSetting>)>::new();
+    for backup in settings {
+        match groups.iter_mut().find(|(first, _)| {
+            first.save_dirs == backup.save_dirs
+                && first.filter == backup.filter
+                && first.read_options() == backup.read_options()
+                && first.included_config.is_some() == backup.included_config.is_some()
+                && same_commands(config, first, backup)
+        }) {
+            Some((_, settings)) => settings.push(backup),
+            None => groups.push((backup, vec![backup])),
+        }
+    }
+
+    // the tar of single setting is not shared so it's written to the backup directory directly
+    if let [(backup, settings)] = groups.as_slice() {
+        if settings.len() == 1 && can_stream(backup, dry_run) {
+            let backup = *backup;
+            let start = std::time::Instant::now();
+            let archive = pause_saving(ctx, backup, stream_archive(backup, now)).await?;
+            info!(
+                event = "tar_built", duration_ms = start.elapsed().as_millis() as u64;
+                "built tar of {} in {:?}",
+                backup.name,
+                start.elapsed()
+            );
+            let fingerprint = fingerprints.get(backup.name.as_str());
+            let record = async {
+                let sink = LocalSink::new(&backup.directory);
+                let saved = record_backup(&sink, archive?, now, backup, false).await?;
+                if let Some(fingerprint) = fingerprint {
+                    write_fingerprint(&sink, backup, &saved.name, fingerprint).await;
+                }
+                Ok(saved)
+            };
+            let succeeded = save_backup(ctx.config, record, now, backup, false).await;
+            results.push((backup, succeeded));
+            return Ok(results);
+        }
+    }
+
+    // groups with same commands are read at once. others are read one after another
+    let start = std::time::Instant::now();
+    let max_parallel = config.max_parallel_tars;
+    let mut backup_files = Vec::with_capacity(groups.len());
+    for (i, (first, _)) in groups.iter().enumerate() {
+        if groups[..i]
+            .iter()
+            .any(|(x, _)| same_commands(config, x, first))
+        {
+            continue;
+        }
+        let indices = (i..groups.len())
+            .filter(|&j| same_commands(config, groups[j].0, first))
+            .collect::<Vec<_>>();
+        let sources = indices
+            .iter()
+            .map(|&j| groups[j].1.as_slice())
+            .collect::<Vec<_>>();
+        let built = pause_saving(ctx, first, build_tars(&sources, max_parallel, now)).await?;
+        backup_files.extend(indices.into_iter().zip(built));
+    }
+    backup_files.sort_by_key(|(i, _)| *i);
+    let backup_files = backup_files
+        .into_iter()
+        .map(|(_, file)| file)
+        .collect::<Vec<_>>();
+    info!(
+        event = "tar_built", duration_ms = start.elapsed().as_millis() as u64;
+        "built {} temporal tar(s) in {:?}",
+        backup_files.len(),
+        start.elapsed()
+    );
+
+    let fingerprints = &fingerprints;
+    let futures = groups
+        .iter()
+        .zip(&backup_files)
+        .flat_map(|((_, settings), backup_file)| {
+            settings.iter().map(move |backup| {
+                let save = async move {
+                    // the error is shared by all settings of the group
+                    let backup_file = match backup_file {
+                        Ok(file) => file.try_clone()?,
+                        Err(err) => bail!("{:#}", err),
+                    };
+                    let fingerprint = fingerprints.get(backup.name.as_str());
+                    save_with_fingerprint(backup_file, now, backup, dry_run, fingerprint).await
+                };
+                save_backup(config, save, now, backup, dry_run)
+            })
+        })
+        .collect::<Vec<_>>();
+    let saved = join_all(futures).await;
+
+    let settings = groups
+        .iter()
+        .flat_map(|(_, settings)| settings.iter().copied());
+    results.extend(settings.zip(saved));
+    Ok(results)
+}
+
+/// fingerprints save directories of settings with skip_unchanged, and skips settings whose
+/// save directories are unchanged since their last backup before the game stops saving.
+///
+/// returns settings to back up, the fingerprints of them by their names, and skipped settings.
+/// settings are backed up without fingerprints if errors occur while fingerprinting.
+async fn skip_unchanged(
+    settings: Vec<&BackupSetting>,
+) -> (
+    Vec<&BackupSetting>,
+    HashMap<&str, String>,
+    Vec<&BackupSetting>,
+) {
+    let mut changed = Vec::with_capacity(settings.len());
+    let mut fingerprints = HashMap::new();
+    let mut skipped = Vec::new();
+    for setting in settings {
+        if !setting.skip_unchanged {
+            changed.push(setting);
+            continue;
+        }
+        let save_dirs = setting.save_dirs.clone();
+        let filter = setting.filter.clone();
+        let options = setting.read_options();
+        let fingerprint =
+            match asyncify(move || tar::tree_fingerprint(&save_dirs, &filter, options)).await {
+                Ok(fingerprint) => fingerprint,
+                Err(err) => {
+                    warn!("error computing fingerprint of {}: {}", setting.name, err);
+                    changed.push(setting);
+                    continue;
+                }
+            };
+        match last_fingerprint(setting).await {
+            Ok(last) if last.as_deref() == Some(fingerprint.as_str()) => {
+                info!(
+                    "skipped backup of {} since save directories are unchanged since the last backup",
+                    setting.name
+                );
+                skipped.push(setting);
+                continue;
+            }
+            Ok(_) => {}
+            Err(err) => warn!(
+                "error reading the last fingerprint of {}: {:#}",
+                setting.name, err
+            ),
+        }
+        fingerprints.insert(setting.name.as_str(), fingerprint);
+        changed.push(setting);
+    }
+    (changed, fingerprints, skipped)
+}
+
+/// whether the commands around reading save directories of the settings are same
+fn same_commands(config: &Config, a: &BackupSetting, b: &BackupSetting) -> bool {
+    config.commands_before_of(a) == config.commands_before_of(b)
+        && config.commands_after_of(a) == config.commands_after_of(b)
+}
+
+/// backs up with all settings now regardless of interval.
+pub(crate) async fn run_now(ctx: &mut Context<'_>) -> Result<()> {
+    let now = ctx
+        .config
+        .timezone
+        .from_utc_datetime(&Utc::now().naive_utc())
+        .naive_local();
+    info!("backing up all settings at {}", now);
+    let results = run_backups(ctx, ctx.config.backups.iter().collect(), &now).await?;
+    let failed = results.iter().filter(|(_, succeeded)| !succeeded).count();
+    if failed != 0 {
+        bail!("{} backup setting(s) failed", failed)
+    }
+    Ok(())
+}
+
+/// backs up with settings whose interval has passed since their last backup
+/// to take backups missed while the process was not running.
+pub(crate) async fn catch_up(ctx: &mut Context<'_>, now: &NaiveDateTime) -> Result<()> {
+    let mut missed = Vec::new();
+    for setting in &ctx.config.backups {
+        // other settings are caught up even if files.txt of one setting cannot be read
+        let last = match last_backup_time(setting).await {
+            Ok(last) => last,
+            Err(err) => {
+                warn!(
+                    "not catching up {} since files.txt cannot be read: {:#}",
+                    setting.name, err
+                );
+                continue;
+            }
+        };
+        match last {
+            Some(last) if setting.is_spaced(&last, now) => {
+                if !setting.is_active_at(now) {
+                    info!(
+                        "not catching up {} since it's out of its active hours",
+                        setting.name
+                    );
+                    continue;
+                }
+                info!(
+                    "the last backup of {} at {} is older than the interval",
+                    setting.name, last
+                );
+                missed.push(setting);
+            }
+            Some(_) => {}
+            None => trace!("no backups of {} to catch up", setting.name),
+        }
+    }
+    if missed.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "catching up backups missed while stopped: {:?}",
+        missed.iter().map(|x| &x.name).collect::<Vec<_>>()
+    );
+    let results = run_backups(ctx, missed, now).await?;
+    let failed = results.iter().filter(|(_, succeeded)| !succeeded).count();
+    if failed != 0 {
+        bail!("{} backup setting(s) failed", failed)
+    }
+    Ok(())
+}
+
+/// returns false if the last backup of the setting is too recent to take another at `now`
+/// so that restarts or clock jumps don't take backups in quick succession.
+/// errors reading files.txt are logged and don't prevent the backup.
+async fn is_spaced(setting: &BackupSetting, now: &NaiveDateTime) -> bool {
+    match last_backup_time(setting).await {
+        Ok(Some(last)) if !setting.is_spaced(&last, now) => {
+            info!(
+                "skipping {} since the last backup at {} is too recent",
+                setting.name, last
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(err) => {
+            warn!(
+                "error reading the last backup time of {}: {:#}",
+                setting.name, err
+            );
+            true
+        }
+    }
+}
+
+/// the time of the newest backup of the setting parsed from the names in files.txt
+async fn last_backup_time(config: &BackupSetting) -> Result<Option<NaiveDateTime>> {
+    let buffer = match &config.sftp {
+        Some(sftp) => {
+            SftpSink::connect(sftp)
+                .await
+                .with_context(|| format!("connecting to sftp server {}", sftp.host))?
+                .read("files.txt")
+                .await?
+        }
+        None => LocalSink::new(&config.directory).read("files.txt").await?,
+    };
+    let buffer = buffer.unwrap_or_default();
+    Ok(parse_files_txt(&buffer)
+        .into_iter()
+        .filter_map(|name| {
+            NaiveDateTime::parse_from_str(&String::from_utf8_lossy(name), &config.name_format).ok()
+        })
+        .max())
+}
+
+/// recovers and reconciles files.txt of all backup settings.
+pub(crate) async fn recover_all_files_txt(config: &Config) -> Result<()> {
+    for backup in &config.backups {
+        recover_backup_files_txt(backup).await?;
+    }
+    Ok(())
+}
+
+/// recovers and reconciles files.txt of the backup setting.
+pub(crate) async fn recover_backup_files_txt(backup: &BackupSetting) -> Result<()> {
+    match &backup.sftp {
+        Some(sftp) => {
+            // the server may be down for now. leftovers are recovered on the next start
+            let sink = match SftpSink::connect(sftp).await {
+                Ok(sink) => sink,
+                Err(e) => {
+                    warn!(
+                        "skipping recovery of files.txt of {}: error connecting to sftp server {}: {:#}",
+                        backup.name, sftp.host, e
+                    );
+                    return Ok(());
+                }
+            };
+            recover_sink_files_txt(&sink, backup).await
+        }
+        None => {
+            recover_sink_files_txt(&LocalSink::new(&backup.directory), backup).await?;
+            recover_diff_chain(&backup.directory)
+                .await
+                .with_context(|| format!("recovering diffs of {}", backup.name))
+        }
+    }
+}
+
+async fn recover_sink_files_txt(sink: &impl BackupSink, backup: &BackupSetting) -> Result<()> {
+    recover_files_txt(sink)
+        .await
+        .with_context(|| format!("recovering files.txt of {}", backup.name))?;
+    reconcile_files_txt(sink, &backup.name_format)
+        .await
+        .with_context(|| format!("reconciling files.txt of {}", backup.name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::archive::test::{make_tar, setting, sftp_setting};
+    use crate::archive::{build_tar, do_save_backup, FINGERPRINT_FILE};
+    use crate::config::{Compression, GamePreset, SaveDir, SaveInterval};
+    use crate::files_txt::{self, BACKUP_NAME_FORMAT};
+    use crate::rcon::test::rcon_config;
+    use crate::tar::ArchiveManifest;
+    use crate::{sftp_mock, verify};
+    use std::fs::File as StdFile;
+    use std::io::{Seek, SeekFrom};
+    use std::path::{Path, PathBuf};
+
+    #[tokio::test]
+    async fn sftp_recovery() {
+        let local = tempfile::tempdir().unwrap();
+        let remote = tempfile::tempdir().unwrap();
+        let server = sftp_mock::MockSftp::start(remote.path()).await;
+        let host_key = server.host_key.to_openssh().unwrap();
+        let config = sftp_setting(local.path(), &server, &host_key);
+        let first = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        do_save_backup(make_tar(), &first, &config, false)
+            .await
+            .unwrap();
+
+        // left by the process stopped while uploading and rewriting files.txt
+        let remote_dir = remote.path().join("backups");
+        let interrupted = "backup-1970-01-01-00-05-00.tar.gz";
+        std::fs::write(remote_dir.join(format!("{}.tmp", interrupted)), b"trunc").unwrap();
+        std::fs::write(remote_dir.join("backup-1970-01-01-00-03-00.tar.gz"), b"").unwrap();
+        std::fs::write(remote_dir.join(".files.txt"), b"backup-1970-01-01").unwrap();
+
+        recover_backup_files_txt(&config).await.unwrap();
+        let mut files = std::fs::read_dir(&remote_dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "backup-1970-01-01-00-00-00.sha256",
+                "backup-1970-01-01-00-00-00.tar.gz",
+                "files.txt",
+            ]
+        );
+
+        // the interrupted upload can be retried
+        std::fs::write(remote_dir.join(format!("{}.tmp", interrupted)), b"trunc").unwrap();
+        let second = chrono::DateTime::from_timestamp(300, 0)
+            .unwrap()
+            .naive_utc();
+        do_save_backup(make_tar(), &second, &config, false)
+            .await
+            .unwrap();
+        assert!(!remote_dir.join(format!("{}.tmp", interrupted)).exists());
+        assert_eq!(verify::verify_backups(&remote_dir).unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn group_by_save_dirs() {
+        let world = tempfile::tempdir().unwrap();
+        let server = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(world.path().join("level.dat"), b"level").unwrap();
+        std::fs::write(server.path().join("server.properties"), b"props").unwrap();
+        let save_dir = |path: &Path| SaveDir {
+            path: path.to_owned(),
+            prefix: PathBuf::new(),
+        };
+
+        let config = Config {
+            backups: vec![
+                BackupSetting {
+                    name: "world".to_owned(),
+                    save_dirs: vec![save_dir(world.path())],
+                    ..setting(&backups.path().join("world"), Compression::None)
+                },
+                BackupSetting {
+                    name: "server".to_owned(),
+                    save_dirs: vec![save_dir(server.path())],
+                    ..setting(&backups.path().join("server"), Compression::None)
+                },
+            ],
+            ..Config::for_test()
+        };
+        let mut ctx = Context::new(&config);
+        let begin = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        let end = chrono::DateTime::from_timestamp(300, 0)
+            .unwrap()
+            .naive_utc();
+        do_step(&mut ctx, &begin, &end).await.unwrap();
+
+        let names = |setting: &str| {
+            let file = StdFile::open(
+                backups
+                    .path()
+                    .join(setting)
+                    .join("backup-1970-01-01-00-05-00.tar"),
+            )
+            .unwrap();
+            ::tar::Archive::new(file)
+                .entries()
+                .unwrap()
+                .map(|x| x.unwrap().path().unwrap().into_owned())
+                .collect::<Vec<_>>()
+        };
+        let manifest = PathBuf::from(".game-save-backuper/manifest.json");
+        assert_eq!(
+            names("world"),
+            vec![manifest.clone(), PathBuf::from("level.dat")]
+        );
+        assert_eq!(
+            names("server"),
+            vec![manifest, PathBuf::from("server.properties")]
+        );
+    }
+
+    #[test]
+    fn local_window_spring_forward() {
+        use crate::config::SaveInterval::*;
+        use chrono::NaiveDate;
+
+        let tz: Tz = "America/New_York".parse().unwrap();
+        let utc = |h, m| {
+            NaiveDate::from_ymd_opt(2022, 3, 13)
+                .unwrap()
+                .and_hms_opt(h, m, 0)
+                .unwrap()
+        };
+
+        // 01:55 EST -> 03:00 EDT. 02:00 - 03:00 is skipped
+        let (begin, end) = to_local_window(tz, &utc(6, 55), &utc(7, 0)).unwrap();
+        assert_eq!(begin, utc(1, 55));
+        assert_eq!(end, utc(3, 0));
+        assert!(Every5Minute.is_passed(&begin, &end));
+        assert!(Every1Hour.is_passed(&begin, &end));
+        assert!(Every2Hour.is_passed(&begin, &end));
+        assert!(!Every4Hour.is_passed(&begin, &end));
+        assert!(!Every1Day.is_passed(&begin, &end));
+
+        // local midnight is 05:00 UTC
+        let (begin, end) = to_local_window(tz, &utc(4, 55), &utc(5, 0)).unwrap();
+        assert!(Every1Day.is_passed(&begin, &end));
+    }
+
+    #[test]
+    fn local_window_fall_back() {
+        use chrono::NaiveDate;
+
+        let tz: Tz = "America/New_York".parse().unwrap();
+        let utc = |h, m| {
+            NaiveDate::from_ymd_opt(2022, 11, 6)
+                .unwrap()
+                .and_hms_opt(h, m, 0)
+                .unwrap()
+        };
+
+        // 01:55 EDT -> 01:00 EST
+        assert_eq!(to_local_window(tz, &utc(5, 55), &utc(6, 0)), None);
+        assert!(to_local_window(tz, &utc(6, 0), &utc(6, 5)).is_some());
+    }
+
+    #[tokio::test]
+    async fn main_loop_shutdown() {
+        let config = Config::for_test();
+        let shared = SharedConfig::new(Arc::new(config));
+        tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            main_loop(&shared, HashMap::new(), false, &Heartbeat::new(), async {}),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn filesystem_preset_without_rcon() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+
+        let config = Config {
+            preset: Some(GamePreset::Filesystem),
+            backups: vec![BackupSetting {
+                save_dirs: vec![SaveDir {
+                    path: save.path().to_owned(),
+                    prefix: PathBuf::new(),
+                }],
+                ..setting(backups.path(), Compression::None)
+            }],
+            ..Config::for_test()
+        };
+        let mut ctx = Context::new(&config);
+        run_now(&mut ctx).await.unwrap();
+        assert!(ctx.connections.is_empty());
+        assert_eq!(files_txt::read_files_txt(backups.path()).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn skip_unchanged() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let config = Config {
+            preset: Some(GamePreset::Filesystem),
+            backups: vec![BackupSetting {
+                save_dirs: vec![SaveDir {
+                    path: save.path().to_owned(),
+                    prefix: PathBuf::new(),
+                }],
+                max_backups: 10,
+                skip_unchanged: true,
+                ..setting(backups.path(), Compression::None)
+            }],
+            ..Config::for_test()
+        };
+        let at = |m| {
+            chrono::NaiveDate::from_ymd_opt(2021, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, m, 0)
+                .unwrap()
+        };
+        let mut ctx = Context::new(&config);
+        let settings = || config.backups.iter().collect::<Vec<_>>();
+        let results = run_backups(&mut ctx, settings(), &at(5)).await.unwrap();
+        assert!(results[0].1);
+        // nothing is changed
+        let results = run_backups(&mut ctx, settings(), &at(10)).await.unwrap();
+        assert!(results[0].1);
+        assert_eq!(
+            files_txt::read_files_txt(backups.path()).unwrap(),
+            vec!["backup-2021-01-01-00-05-00"]
+        );
+
+        std::fs::write(save.path().join("level.dat"), b"changed level").unwrap();
+        run_backups(&mut ctx, settings(), &at(15)).await.unwrap();
+        assert_eq!(
+            files_txt::read_files_txt(backups.path()).unwrap(),
+            vec!["backup-2021-01-01-00-05-00", "backup-2021-01-01-00-15-00"]
+        );
+        assert_eq!(
+            std::fs::read_to_string(backups.path().join(FINGERPRINT_FILE))
+                .unwrap()
+                .split(' ')
+                .next(),
+            Some("backup-2021-01-01-00-15-00")
+        );
+    }
+
+    #[tokio::test]
+    async fn min_spacing() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let setting_in = |name: &str, min_spacing| BackupSetting {
+            name: name.to_owned(),
+            save_dirs: vec![SaveDir {
+                path: save.path().to_owned(),
+                prefix: PathBuf::new(),
+            }],
+            interval: SaveInterval::Every5Minute,
+            min_spacing,
+            max_backups: 10,
+            ..setting(&backups.path().join(name), Compression::None)
+        };
+        let config = Config {
+            preset: Some(GamePreset::Filesystem),
+            backups: vec![
+                setting_in("interval", None),
+                setting_in("spacing", Some("10 minutes".parse().unwrap())),
+            ],
+            ..Config::for_test()
+        };
+        let at = |m| {
+            chrono::NaiveDate::from_ymd_opt(2021, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, m, 0)
+                .unwrap()
+        };
+        let mut ctx = Context::new(&config);
+        do_step(&mut ctx, &at(0), &at(5)).await.unwrap();
+        // the clock jumped back and the same step is run again
+        do_step(&mut ctx, &at(0), &at(5)).await.unwrap();
+        do_step(&mut ctx, &at(5), &at(10)).await.unwrap();
+        do_step(&mut ctx, &at(10), &at(15)).await.unwrap();
+
+        assert_eq!(
+            files_txt::read_files_txt(&backups.path().join("interval")).unwrap(),
+            vec![
+                "backup-2021-01-01-00-05-00",
+                "backup-2021-01-01-00-10-00",
+                "backup-2021-01-01-00-15-00",
+            ]
+        );
+        assert_eq!(
+            files_txt::read_files_txt(&backups.path().join("spacing")).unwrap(),
+            vec!["backup-2021-01-01-00-05-00", "backup-2021-01-01-00-15-00"]
+        );
+    }
+
+    #[tokio::test]
+    async fn shared_directory_runs_at_once() {
+        let world = tempfile::tempdir().unwrap();
+        let nether = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(world.path().join("level.dat"), b"world").unwrap();
+        std::fs::write(nether.path().join("level.dat"), b"nether").unwrap();
+        let setting_of = |name: &str, save: &Path| BackupSetting {
+            name: name.to_owned(),
+            save_dirs: vec![SaveDir {
+                path: save.to_owned(),
+                prefix: PathBuf::new(),
+            }],
+            name_format: format!("{}-%Y-%m-%d-%H-%M-%S", name),
+            // rotation counts backups of both settings
+            max_backups: 20,
+            ..setting(backups.path(), Compression::None)
+        };
+        let config = Config {
+            preset: Some(GamePreset::Filesystem),
+            backups: vec![
+                setting_of("world", world.path()),
+                setting_of("nether", nether.path()),
+            ],
+            ..Config::for_test()
+        };
+        let at = |m| {
+            chrono::NaiveDate::from_ymd_opt(2021, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, m, 0)
+                .unwrap()
+        };
+        let settings = || config.backups.iter().collect::<Vec<_>>();
+        // like the scheduler and backup_now running at once
+        let mut scheduler = Context::new(&config);
+        let mut now = Context::new(&config);
+        for m in [0, 10, 20] {
+            let (first, second) = (at(m), at(m + 1));
+            let (a, b) = futures::join!(
+                run_backups(&mut scheduler, settings(), &first),
+                run_backups(&mut now, settings(), &second),
+            );
+            assert!(a.unwrap().iter().all(|(_, succeeded)| *succeeded));
+            assert!(b.unwrap().iter().all(|(_, succeeded)| *succeeded));
+        }
+
+        let mut saved = files_txt::read_files_txt(backups.path()).unwrap();
+        saved.sort();
+        let mut expected = Vec::new();
+        for name in ["nether", "world"] {
+            for m in [0, 1, 10, 11, 20, 21] {
+                expected.push(format!("{}-2021-01-01-00-{:02}-00", name, m));
+            }
+        }
+        assert_eq!(saved, expected);
+        for name in &saved {
+            assert!(backups.path().join(format!("{}.tar", name)).is_file());
+        }
+    }
+
+    #[test]
+    fn active_hours() {
+        let at = |h| {
+            chrono::NaiveDate::from_ymd_opt(2021, 1, 1)
+                .unwrap()
+                .and_hms_opt(h, 0, 0)
+                .unwrap()
+        };
+        let backups = tempfile::tempdir().unwrap();
+        let config = Config {
+            backups: vec![
+                BackupSetting {
+                    name: "always".to_owned(),
+                    interval: SaveInterval::Every1Hour,
+                    ..setting(backups.path(), Compression::None)
+                },
+                BackupSetting {
+                    name: "night".to_owned(),
+                    interval: SaveInterval::Every1Hour,
+                    active_hours: Some("22:00-04:00".parse().unwrap()),
+                    ..setting(backups.path(), Compression::None)
+                },
+            ],
+            ..Config::for_test()
+        };
+        let names = |begin, end| {
+            due_settings(&config, &begin, &end)
+                .iter()
+                .map(|x| x.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(at(11), at(12)), vec!["always"]);
+        assert_eq!(names(at(22), at(23)), vec!["always", "night"]);
+        assert_eq!(names(at(2), at(3)), vec!["always", "night"]);
+        // the end of the window is exclusive
+        assert_eq!(names(at(3), at(4)), vec!["always"]);
+    }
+
+    #[tokio::test]
+    async fn catch_up_missed_backups() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let setting_in = |name: &str, interval| {
+            let directory = backups.path().join(name);
+            BackupSetting {
+                name: name.to_owned(),
+                save_dirs: vec![SaveDir {
+                    path: save.path().to_owned(),
+                    prefix: PathBuf::new(),
+                }],
+                interval,
+                max_backups: 10,
+                ..setting(&directory, Compression::None)
+            }
+        };
+        let config = Config {
+            preset: Some(GamePreset::Filesystem),
+            backups: vec![
+                setting_in("broken", SaveInterval::Every1Day),
+                setting_in("daily", SaveInterval::Every1Day),
+                setting_in("hourly", SaveInterval::Every1Hour),
+                setting_in("empty", SaveInterval::Every1Day),
+            ],
+            ..Config::for_test()
+        };
+        // files.txt cannot be read
+        std::fs::create_dir_all(backups.path().join("broken/files.txt")).unwrap();
+        // the last daily backup was taken several days ago
+        std::fs::create_dir(backups.path().join("daily")).unwrap();
+        std::fs::write(
+            backups.path().join("daily/files.txt"),
+            "backup-2021-01-01-00-00-00\n",
+        )
+        .unwrap();
+        // the last hourly backup was taken in this hour
+        std::fs::create_dir(backups.path().join("hourly")).unwrap();
+        std::fs::write(
+            backups.path().join("hourly/files.txt"),
+            "backup-2021-01-05-12-00-00\n",
+        )
+        .unwrap();
+
+        let now = chrono::NaiveDate::from_ymd_opt(2021, 1, 5)
+            .unwrap()
+            .and_hms_opt(12, 30, 0)
+            .unwrap();
+        let mut ctx = Context::new(&config);
+        catch_up(&mut ctx, &now).await.unwrap();
+
+        assert_eq!(
+            files_txt::read_files_txt(&backups.path().join("daily")).unwrap(),
+            vec!["backup-2021-01-01-00-00-00", "backup-2021-01-05-12-30-00"]
+        );
+        assert_eq!(
+            files_txt::read_files_txt(&backups.path().join("hourly")).unwrap(),
+            vec!["backup-2021-01-05-12-00-00"]
+        );
+        assert!(!backups.path().join("empty").exists());
+        assert_eq!(
+            std::fs::read_dir(backups.path().join("broken"))
+                .unwrap()
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_after_on_tar_failure() {
+        let server = crate::rcon_mock::MockRcon::start(0).await;
+        let backups = tempfile::tempdir().unwrap();
+        let config = Config {
+            commands_before: vec!["save-off".to_owned()],
+            commands_after: vec!["save-on".to_owned()],
+            backups: vec![BackupSetting {
+                save_dirs: vec![SaveDir {
+                    path: backups.path().join("not-exists"),
+                    prefix: PathBuf::new(),
+                }],
+                ..setting(backups.path(), Compression::None)
+            }],
+            ..rcon_config(server.address, 1)
+        };
+        let mut ctx = Context::new(&config);
+        let err = run_now(&mut ctx).await.unwrap_err();
+        assert_eq!(err.to_string(), "1 backup setting(s) failed");
+        assert_eq!(server.commands(), vec!["save-off", "save-on"]);
+    }
+
+    #[tokio::test]
+    async fn skip_unchanged_without_pausing() {
+        let server = crate::rcon_mock::MockRcon::start(0).await;
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let config = Config {
+            commands_before: vec!["save-off".to_owned()],
+            commands_after: vec!["save-on".to_owned()],
+            backups: vec![BackupSetting {
+                save_dirs: vec![SaveDir {
+                    path: save.path().to_owned(),
+                    prefix: PathBuf::new(),
+                }],
+                max_backups: 10,
+                skip_unchanged: true,
+                // archives are built in temporal tars
+                ..setting(backups.path(), Compression::Gzip)
+            }],
+            ..rcon_config(server.address, 1)
+        };
+        let at = |m| {
+            chrono::NaiveDate::from_ymd_opt(2021, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, m, 0)
+                .unwrap()
+        };
+        let mut ctx = Context::new(&config);
+        let settings = || config.backups.iter().collect::<Vec<_>>();
+        run_backups(&mut ctx, settings(), &at(5)).await.unwrap();
+        assert_eq!(server.commands(), vec!["save-off", "save-on"]);
+        let results = run_backups(&mut ctx, settings(), &at(10)).await.unwrap();
+        assert!(results[0].1);
+        // the game is not paused for unchanged save directories
+        assert_eq!(server.commands(), vec!["save-off", "save-on"]);
+        assert_eq!(
+            files_txt::read_files_txt(backups.path()).unwrap(),
+            vec!["backup-2021-01-01-00-05-00"]
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_run() {
+        let server = crate::rcon_mock::MockRcon::start(0).await;
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let config = Config {
+            commands_before: vec!["save-off".to_owned()],
+            commands_after: vec!["save-on".to_owned()],
+            backups: vec![BackupSetting {
+                save_dirs: vec![SaveDir {
+                    path: save.path().to_owned(),
+                    prefix: PathBuf::new(),
+                }],
+                max_backups: 1,
+                post_backup_command: vec!["touch".to_owned(), "{dir}/hook".to_owned()],
+                commands_before: None,
+                commands_after: None,
+                ..setting(backups.path(), Compression::Gzip)
+            }],
+            ..rcon_config(server.address, 1)
+        };
+        let now = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+        do_save_backup(make_tar(), &now, &config.backups[0], false)
+            .await
+            .unwrap();
+        let list = || {
+            let mut files = std::fs::read_dir(backups.path())
+                .unwrap()
+                .map(|x| x.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+        let before = list();
+
+        let mut ctx = Context::new(&config);
+        ctx.dry_run = true;
+        run_now(&mut ctx).await.unwrap();
+
+        assert_eq!(list(), before);
+        assert_eq!(
+            std::fs::read_to_string(backups.path().join("files.txt")).unwrap(),
+            "backup-1970-01-01-00-00-00\n"
+        );
+        assert_eq!(server.commands(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn slow_step_overruns_interval() {
+        let with_interval = |name: &str, interval| BackupSetting {
+            name: name.to_owned(),
+            interval,
+            ..setting(Path::new("backups"), Compression::None)
+        };
+        let config = Config {
+            backups: vec![
+                with_interval("daily", SaveInterval::Every1Day),
+                with_interval("5min", SaveInterval::Every5Minute),
+            ],
+            ..Config::for_test()
+        };
+        let now = chrono::NaiveDate::from_ymd_opt(2021, 1, 1)
+            .unwrap()
+            .and_hms_opt(12, 3, 0)
+            .unwrap();
+        let minutes = |n: u64| std::time::Duration::from_secs(n * 60);
+        // a slow step of 7 minutes skips ticks of the 5 minute interval
+        let overrun = overrun_setting(&config, minutes(7), &now);
+        assert_eq!(overrun.map(|x| x.name.as_str()), Some("5min"));
+        assert!(overrun_setting(&config, minutes(5), &now).is_none());
+        assert!(overrun_setting(&config, minutes(1), &now).is_none());
+        let empty = Config::for_test();
+        assert!(overrun_setting(&empty, minutes(7), &now).is_none());
+    }
+
+    #[tokio::test]
+    async fn shared_directory() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        // not allowed by the config but backups of both settings are saved at once
+        let shared_setting = |name: &str| BackupSetting {
+            name: name.to_owned(),
+            save_dirs: vec![SaveDir {
+                path: save.path().to_owned(),
+                prefix: PathBuf::new(),
+            }],
+            max_backups: 10,
+            name_format: format!("{}-{}", name, BACKUP_NAME_FORMAT),
+            ..setting(backups.path(), Compression::None)
+        };
+        let config = Config {
+            backups: vec![shared_setting("a"), shared_setting("b")],
+            ..Config::for_test()
+        };
+        let mut ctx = Context::new(&config);
+        run_now(&mut ctx).await.unwrap();
+        let names = files_txt::read_files_txt(backups.path()).unwrap();
+        assert_eq!(names.len(), 2, "{:?}", names);
+        for name in &names {
+            assert!(backups.path().join(format!("{}.tar", name)).exists());
+        }
+        let mut prefixes = names.iter().map(|x| &x[..2]).collect::<Vec<_>>();
+        prefixes.sort();
+        assert_eq!(prefixes, vec!["a-", "b-"]);
+    }
+
+    #[tokio::test]
+    async fn run_now_reports_failure() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        // backup directory cannot be created under a file
+        std::fs::write(backups.path().join("file"), b"").unwrap();
+        let save_dirs = vec![SaveDir {
+            path: save.path().to_owned(),
+            prefix: PathBuf::new(),
+        }];
+
+        let config = Config {
+            backups: vec![
+                BackupSetting {
+                    name: "ok".to_owned(),
+                    save_dirs: save_dirs.clone(),
+                    ..setting(&backups.path().join("ok"), Compression::None)
+                },
+                BackupSetting {
+                    name: "fail".to_owned(),
+                    save_dirs,
+                    ..setting(&backups.path().join("file/fail"), Compression::None)
+                },
+            ],
+            ..Config::for_test()
+        };
+        let mut ctx = Context::new(&config);
+        let err = run_now(&mut ctx).await.unwrap_err();
+        assert_eq!(err.to_string(), "1 backup setting(s) failed");
+        assert_eq!(
+            std::fs::read_dir(backups.path().join("ok"))
+                .unwrap()
+                .filter(|x| x
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(".tar"))
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn tar_failure_does_not_stop_other_settings() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let save_dir = |path: PathBuf| SaveDir {
+            path,
+            prefix: PathBuf::new(),
+        };
+
+        let config = Config {
+            max_parallel_tars: 1,
+            backups: vec![
+                BackupSetting {
+                    name: "missing".to_owned(),
+                    save_dirs: vec![save_dir(save.path().join("missing"))],
+                    ..setting(&backups.path().join("missing"), Compression::None)
+                },
+                BackupSetting {
+                    name: "ok".to_owned(),
+                    save_dirs: vec![save_dir(save.path().to_owned())],
+                    ..setting(&backups.path().join("ok"), Compression::None)
+                },
+            ],
+            ..Config::for_test()
+        };
+        let mut ctx = Context::new(&config);
+        let err = run_now(&mut ctx).await.unwrap_err();
+        assert_eq!(err.to_string(), "1 backup setting(s) failed");
+        assert!(!backups.path().join("missing").exists());
+        assert_eq!(
+            files_txt::read_files_txt(&backups.path().join("ok"))
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn shared_tar_manifest() {
+        let save = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        std::fs::write(save.path().join("level.dat"), b"level").unwrap();
+        let setting_of = |name: &str, interval| BackupSetting {
+            name: name.to_owned(),
+            save_dirs: vec![SaveDir {
+                path: save.path().to_owned(),
+                prefix: PathBuf::new(),
+            }],
+            interval,
+            ..setting(&backups.path().join(name), Compression::None)
+        };
+        let hourly = setting_of("hourly", crate::config::SaveInterval::Every1Hour);
+        let daily = setting_of("daily", crate::config::SaveInterval::Every1Day);
+        let now = chrono::NaiveDate::from_ymd_opt(2021, 1, 2)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+
+        let mut tar = build_tar(&[&hourly, &daily], &now).await.unwrap();
+        tar.seek(SeekFrom::Start(0)).unwrap();
+        let manifest = ArchiveManifest::read(tar).unwrap().unwrap();
+        assert_eq!(manifest.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(manifest.timestamp, "2021-01-02T00:00:00");
+        assert_eq!(manifest.save_dirs, vec![save.path().to_owned()]);
+        let settings = manifest
+            .settings
+            .iter()
+            .map(|x| (x.name.as_str(), x.interval.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            settings,
+            vec![("hourly", "every 1 hour"), ("daily", "every 1 day")]
+        );
+    }
+}
@@ -0,0 +1,822 @@
+//! destinations backups are saved to.
+//!
+//! [LocalSink] saves backups to the local backup directory and
+//! [SftpSink] streams backups to remote host over SFTP.
+//! [DryRunSink] wraps another sink and only logs changes.
+
+use crate::asyncify;
+use crate::config::{Sftp, SftpAuth};
+use log::{error, info, trace};
+use russh::client;
+use russh::keys::{
+    check_known_hosts, load_secret_key, PrivateKeyWithHashAlg, PublicKeyOrCertificate,
+};
+use russh_sftp::client::error::Error as SftpError;
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::{OpenFlags, StatusCode};
+use std::collections::HashMap;
+use std::fs::OpenOptions as StdOpenOptions;
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::{remove_file, rename, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
+
+/// the name of the file the file is written to before renamed to `file_name`.
+/// temporal files are left only if the process stopped while writing them.
+pub(crate) fn temp_file_name(file_name: &str) -> String {
+    format!("{}.tmp", file_name)
+}
+
+/// the destination of backups of a backup setting.
+/// all files are placed in one directory and specified with file names.
+pub(crate) trait BackupSink {
+    /// the directory files are saved in
+    fn directory(&self) -> &Path;
+
+    /// the path to the file in the destination
+    fn path(&self, file_name: &str) -> PathBuf {
+        self.directory().join(file_name)
+    }
+
+    /// creates the directory if not exists
+    async fn prepare(&self) -> io::Result<()>;
+
+    /// bytes can be written to the directory. None if unknown
+    async fn available_space(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// creates new file with contents read from `reader`.
+    /// returns the reader and the count of bytes written.
+    /// it's an error if the file already exists.
+    async fn put<R: Read + Send + 'static>(
+        &self,
+        file_name: &str,
+        reader: R,
+    ) -> io::Result<(R, u64)>;
+
+    /// reads whole the file. returns None if not found
+    async fn read(&self, file_name: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// names of files in the directory. empty if the directory doesn't exist
+    async fn list(&self) -> io::Result<Vec<String>>;
+
+    /// writes data to the file. the file is created or truncated
+    async fn write(&self, file_name: &str, data: &[u8]) -> io::Result<()>;
+
+    /// appends data to the file. the file is created if not exists
+    async fn append(&self, file_name: &str, data: &[u8]) -> io::Result<()>;
+
+    /// renames the file. `to` must not exist
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// removes the file. it's not an error if the file doesn't exist
+    async fn delete(&self, file_name: &str) -> io::Result<()>;
+
+    /// replaces the file with data.
+    /// the data is written to `.{file_name}` first and then moved to `file_name`
+    /// so the file is never partially written.
+    async fn replace(&self, file_name: &str, data: &[u8]) -> io::Result<()> {
+        let dot_file_name = format!(".{}", file_name);
+        self.write(&dot_file_name, data).await?;
+        self.delete(file_name).await?;
+        self.rename(&dot_file_name, file_name).await
+    }
+}
+
+/// the sink saves backups to local directory
+pub(crate) struct LocalSink {
+    directory: PathBuf,
+    /// the size of buffer to write files with [BackupSink::put]
+    buffer_size: usize,
+}
+
+impl LocalSink {
+    pub(crate) fn new(directory: &Path) -> Self {
+        Self {
+            directory: directory.to_owned(),
+            buffer_size: 8 * 1024,
+        }
+    }
+
+    /// sets the size of buffer. bytes are copied in blocks of this size
+    pub(crate) fn with_buffer_size(self, buffer_size: usize) -> Self {
+        Self {
+            buffer_size,
+            ..self
+        }
+    }
+}
+
+impl BackupSink for LocalSink {
+    fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    async fn prepare(&self) -> io::Result<()> {
+        tokio::fs::create_dir_all(&self.directory).await
+    }
+
+    #[cfg(unix)]
+    async fn available_space(&self) -> io::Result<Option<u64>> {
+        let directory = self.directory.clone();
+        asyncify(move || {
+            let stat = nix::sys::statvfs::statvfs(&directory)?;
+            Ok(Some(
+                stat.blocks_available() as u64 * stat.fragment_size() as u64,
+            ))
+        })
+        .await
+    }
+
+    async fn put<R: Read + Send + 'static>(
+        &self,
+        file_name: &str,
+        reader: R,
+    ) -> io::Result<(R, u64)> {
+        let path = self.path(file_name);
+        let temp_path = self.path(&temp_file_name(file_name));
+        let buffer_size = self.buffer_size;
+        asyncify(move || {
+            if path.exists() {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} already exists", path.display()),
+                ));
+            }
+            // the file is written to temporal file and renamed after synced
+            // so the file never exists partially written.
+            // temporal file left by previous crash is overwritten
+            let written = (|| {
+                let mut reader = reader;
+                let file = StdOpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&temp_path)?;
+                // io::copy reads directly into the buffer of BufWriter
+                let mut writer = BufWriter::with_capacity(buffer_size, file);
+                std::io::copy(&mut reader, &mut writer)?;
+                writer.flush()?;
+                let file = writer.into_inner()?;
+                file.sync_all()?;
+                let size = file.metadata()?.len();
+                std::fs::rename(&temp_path, &path)?;
+                Ok((reader, size))
+            })();
+            if written.is_err() {
+                if let Err(e) = std::fs::remove_file(&temp_path) {
+                    if e.kind() != ErrorKind::NotFound {
+                        error!("error removing {}: {}", temp_path.display(), e);
+                    }
+                }
+            }
+            written
+        })
+        .await
+    }
+
+    async fn read(&self, file_name: &str) -> io::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(file_name)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut entries = match tokio::fs::read_dir(&self.directory).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
+
+    async fn write(&self, file_name: &str, data: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.path(file_name))
+            .await?;
+
+        file.write_all(data).await?;
+        file.flush().await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    async fn append(&self, file_name: &str, data: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.path(file_name))
+            .await?;
+        file.seek(io::SeekFrom::End(0)).await?;
+        file.write_all(data).await?;
+        file.flush().await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        rename(self.path(from), self.path(to)).await
+    }
+
+    async fn delete(&self, file_name: &str) -> io::Result<()> {
+        match remove_file(self.path(file_name)).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// the sink which logs files would be written instead of writing them.
+///
+/// files are read from the inner sink and changes are kept in memory
+/// so files.txt read after appending reflects the appended line.
+pub(crate) struct DryRunSink<S> {
+    inner: S,
+    /// files changed in this dry run. None if deleted
+    changes: Mutex<HashMap<String, Option<Vec<u8>>>>,
+}
+
+impl<S: BackupSink> DryRunSink<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Self {
+            inner,
+            changes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn change(&self, file_name: &str, data: Option<Vec<u8>>) {
+        self.changes
+            .lock()
+            .unwrap()
+            .insert(file_name.to_owned(), data);
+    }
+}
+
+impl<S: BackupSink> BackupSink for DryRunSink<S> {
+    fn directory(&self) -> &Path {
+        self.inner.directory()
+    }
+
+    async fn prepare(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn available_space(&self) -> io::Result<Option<u64>> {
+        match self.inner.available_space().await {
+            // the directory is not created in dry run
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            result => result,
+        }
+    }
+
+    async fn put<R: Read + Send + 'static>(
+        &self,
+        file_name: &str,
+        reader: R,
+    ) -> io::Result<(R, u64)> {
+        // the reader is consumed to compute the size and hashes like real backup
+        let (reader, size) = asyncify(move || {
+            let mut reader = reader;
+            let size = std::io::copy(&mut reader, &mut io::sink())?;
+            Ok((reader, size))
+        })
+        .await?;
+        info!(
+            "dry run: would save {} ({} bytes)",
+            self.path(file_name).display(),
+            size
+        );
+        self.change(file_name, Some(vec![]));
+        Ok((reader, size))
+    }
+
+    async fn read(&self, file_name: &str) -> io::Result<Option<Vec<u8>>> {
+        if let Some(data) = self.changes.lock().unwrap().get(file_name) {
+            return Ok(data.clone());
+        }
+        self.inner.read(file_name).await
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = self.inner.list().await?;
+        let changes = self.changes.lock().unwrap();
+        names.retain(|name| !changes.contains_key(name));
+        names.extend(
+            changes
+                .iter()
+                .filter(|(_, data)| data.is_some())
+                .map(|(name, _)| name.clone()),
+        );
+        Ok(names)
+    }
+
+    async fn write(&self, file_name: &str, data: &[u8]) -> io::Result<()> {
+        info!("dry run: would write {}", self.path(file_name).display());
+        self.change(file_name, Some(data.to_vec()));
+        Ok(())
+    }
+
+    async fn append(&self, file_name: &str, data: &[u8]) -> io::Result<()> {
+        info!(
+            "dry run: would append {:?} to {}",
+            String::from_utf8_lossy(data),
+            self.path(file_name).display()
+        );
+        let mut contents = self.read(file_name).await?.unwrap_or_default();
+        contents.extend_from_slice(data);
+        self.change(file_name, Some(contents));
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        info!(
+            "dry run: would rename {} to {}",
+            self.path(from).display(),
+            self.path(to).display()
+        );
+        let data = self.read(from).await?;
+        self.change(from, None);
+        self.change(to, data);
+        Ok(())
+    }
+
+    async fn delete(&self, file_name: &str) -> io::Result<()> {
+        info!("dry run: would delete {}", self.path(file_name).display());
+        self.change(file_name, None);
+        Ok(())
+    }
+
+    async fn replace(&self, file_name: &str, data: &[u8]) -> io::Result<()> {
+        info!("dry run: would replace {}", self.path(file_name).display());
+        self.change(file_name, Some(data.to_vec()));
+        Ok(())
+    }
+}
+
+/// the size of chunks sent to sftp server
+const SFTP_CHUNK_SIZE: usize = 64 * 1024;
+
+/// the sink streams backups to remote directory over SFTP
+pub(crate) struct SftpSink {
+    directory: PathBuf,
+    sftp: SftpSession,
+    // the ssh session must be kept while the sftp session is used
+    _session: client::Handle<HostKeyCheck>,
+}
+
+impl SftpSink {
+    /// connects and authenticates to the sftp server
+    pub(crate) async fn connect(config: &Sftp) -> anyhow::Result<Self> {
+        use anyhow::Context as _;
+
+        let session = connect_ssh(
+            &config.host,
+            config.port,
+            &config.user,
+            &config.auth,
+            config.host_key.as_ref(),
+        )
+        .await?;
+
+        let channel = session
+            .channel_open_session()
+            .await
+            .context("opening ssh channel")?;
+        channel
+            .request_subsystem(true, "sftp")
+            .await
+            .context("requesting sftp subsystem")?;
+        let sftp = SftpSession::new(channel.into_stream())
+            .await
+            .context("starting sftp session")?;
+        trace!("connected to sftp server {}:{}", config.host, config.port);
+
+        Ok(Self {
+            directory: PathBuf::from(&config.remote_dir),
+            sftp,
+            _session: session,
+        })
+    }
+
+    /// writes contents read from `reader` to the file. the file is created or truncated
+    async fn upload<R: Read + Send + 'static>(
+        &self,
+        file_name: &str,
+        reader: R,
+    ) -> io::Result<(R, u64)> {
+        let mut file = self
+            .sftp
+            .open_with_flags(
+                self.remote_path(file_name),
+                OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE,
+            )
+            .await?;
+
+        // read in blocking thread and send chunks to the server
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(4);
+        let reading = spawn_blocking(move || {
+            let mut reader = reader;
+            loop {
+                let mut chunk = vec![0; SFTP_CHUNK_SIZE];
+                let read = reader.read(&mut chunk)?;
+                if read == 0 {
+                    break;
+                }
+                chunk.truncate(read);
+                if sender.blocking_send(chunk).is_err() {
+                    // sending failed
+                    break;
+                }
+            }
+            Ok::<_, io::Error>(reader)
+        });
+
+        let mut size = 0;
+        while let Some(chunk) = receiver.recv().await {
+            file.write_all(&chunk).await?;
+            size += chunk.len() as u64;
+        }
+        file.shutdown().await?;
+
+        let reader = match reading.await {
+            Ok(res) => res?,
+            Err(_) => return Err(io::Error::other("background task failed")),
+        };
+        Ok((reader, size))
+    }
+
+    /// checks the remote directory is a directory, or can be created by [BackupSink::prepare]
+    /// since its parent is a directory. nothing is created on the server.
+    pub(crate) async fn check_directory(&self) -> io::Result<()> {
+        let directory = self.directory.to_string_lossy().into_owned();
+        let not_directory = |path: &str| io::Error::other(format!("{} is not a directory", path));
+        match self.sftp.metadata(directory.as_str()).await {
+            Ok(metadata) if metadata.is_dir() => return Ok(()),
+            Ok(_) => return Err(not_directory(&directory)),
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+        // prepare creates only the directory itself
+        let parent = match directory.trim_end_matches('/').rsplit_once('/') {
+            Some(("", _)) => "/",
+            Some((parent, _)) => parent,
+            None => ".",
+        };
+        match self.sftp.metadata(parent).await {
+            Ok(metadata) if metadata.is_dir() => Ok(()),
+            Ok(_) => Err(not_directory(parent)),
+            Err(e) if is_not_found(&e) => Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("neither {} nor {} exists", directory, parent),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// the remote path to the file. sftp paths are always separated with `/`
+    fn remote_path(&self, file_name: &str) -> String {
+        let directory = self.directory.to_string_lossy();
+        format!("{}/{}", directory.trim_end_matches('/'), file_name)
+    }
+}
+
+fn is_not_found(e: &SftpError) -> bool {
+    matches!(e, SftpError::Status(status) if status.status_code == StatusCode::NoSuchFile)
+}
+
+impl BackupSink for SftpSink {
+    fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    async fn prepare(&self) -> io::Result<()> {
+        let directory = self.directory.to_string_lossy().into_owned();
+        if !self.sftp.try_exists(directory.as_str()).await? {
+            self.sftp.create_dir(directory).await?;
+        }
+        Ok(())
+    }
+
+    async fn put<R: Read + Send + 'static>(
+        &self,
+        file_name: &str,
+        reader: R,
+    ) -> io::Result<(R, u64)> {
+        let path = self.remote_path(file_name);
+        if self.sftp.try_exists(path.as_str()).await? {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists", path),
+            ));
+        }
+        // the file is uploaded to temporal file and renamed after written like LocalSink.
+        // temporal file left by previous interrupted upload is overwritten
+        let temp_name = temp_file_name(file_name);
+        let written = self.upload(&temp_name, reader).await;
+        let written = match written {
+            Ok(written) => self.rename(&temp_name, file_name).await.map(|_| written),
+            Err(e) => Err(e),
+        };
+        if written.is_err() {
+            if let Err(e) = self.delete(&temp_name).await {
+                error!("error removing {}: {}", self.remote_path(&temp_name), e);
+            }
+        }
+        written
+    }
+
+    async fn read(&self, file_name: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.sftp.read(self.remote_path(file_name)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let directory = self.directory.to_string_lossy().into_owned();
+        match self.sftp.read_dir(directory).await {
+            Ok(entries) => Ok(entries
+                .map(|entry| entry.file_name())
+                .filter(|name| name != "." && name != "..")
+                .collect()),
+            Err(e) if is_not_found(&e) => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn write(&self, file_name: &str, data: &[u8]) -> io::Result<()> {
+        let mut file = self.sftp.create(self.remote_path(file_name)).await?;
+        file.write_all(data).await?;
+        file.shutdown().await?;
+        Ok(())
+    }
+
+    async fn append(&self, file_name: &str, data: &[u8]) -> io::Result<()> {
+        // some servers ignore APPEND flag so seek to the end explicitly
+        let mut file = self
+            .sftp
+            .open_with_flags(
+                self.remote_path(file_name),
+                OpenFlags::CREATE | OpenFlags::WRITE,
+            )
+            .await?;
+        file.seek(io::SeekFrom::End(0)).await?;
+        file.write_all(data).await?;
+        file.shutdown().await?;
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        // rename of sftp v3 fails if `to` exists
+        self.sftp
+            .rename(self.remote_path(from), self.remote_path(to))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, file_name: &str) -> io::Result<()> {
+        match self.sftp.remove_file(self.remote_path(file_name)).await {
+            Ok(()) => Ok(()),
+            Err(e) if is_not_found(&e) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// connects and authenticates to the ssh server
+pub(crate) async fn connect_ssh(
+    host: &str,
+    port: u16,
+    user: &str,
+    auth: &SftpAuth,
+    host_key: Option<&russh::keys::PublicKey>,
+) -> anyhow::Result<client::Handle<HostKeyCheck>> {
+    use anyhow::{bail, Context as _};
+
+    let handler = HostKeyCheck {
+        host: host.to_owned(),
+        port,
+        host_key: host_key.cloned(),
+    };
+    let ssh_config = Arc::new(client::Config {
+        inactivity_timeout: Some(Duration::from_secs(60)),
+        ..Default::default()
+    });
+    let mut session = tokio::time::timeout(
+        Duration::from_secs(30),
+        client::connect(ssh_config, (host, port), handler),
+    )
+    .await
+    .context("connecting to ssh server: timed out")?
+    .context("connecting to ssh server")?;
+
+    let result = match auth {
+        SftpAuth::Password(password) => session
+            .authenticate_password(user, password)
+            .await
+            .context("authenticating with password")?,
+        SftpAuth::PrivateKey(path) => {
+            let key = load_secret_key(path, None)
+                .with_context(|| format!("loading private key {}", path.display()))?;
+            let hash_alg = session
+                .best_supported_rsa_hash()
+                .await
+                .context("negotiating rsa hash")?
+                .flatten();
+            let key = PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg);
+            session
+                .authenticate_publickey(user, key)
+                .await
+                .context("authenticating with private key")?
+        }
+    };
+    if !result.success() {
+        bail!("authentication failed for {}", user);
+    }
+    Ok(session)
+}
+
+/// checks host key of the ssh server with configured key or known_hosts
+pub(crate) struct HostKeyCheck {
+    host: String,
+    port: u16,
+    host_key: Option<russh::keys::PublicKey>,
+}
+
+impl client::Handler for HostKeyCheck {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        let key = match server_public_key {
+            PublicKeyOrCertificate::PublicKey { key, .. } => key,
+            PublicKeyOrCertificate::Certificate(_) => return Ok(false),
+        };
+        match &self.host_key {
+            Some(host_key) => Ok(host_key.key_data() == key.key_data()),
+            None => Ok(check_known_hosts(&self.host, self.port, key).unwrap_or(false)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn list_sorted(dir: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn local_put() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = LocalSink::new(&dir.path().join("backups"));
+        sink.prepare().await.unwrap();
+        let (_, size) = sink
+            .put("backup.tar", Cursor::new(b"hello world".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(size, 11);
+        assert_eq!(
+            std::fs::read(dir.path().join("backups/backup.tar")).unwrap(),
+            b"hello world"
+        );
+        // put never overwrites
+        assert!(sink
+            .put("backup.tar", Cursor::new(b"other".to_vec()))
+            .await
+            .is_err());
+        assert_eq!(
+            sink.read("backup.tar").await.unwrap().unwrap(),
+            b"hello world"
+        );
+        assert_eq!(sink.read("other.tar").await.unwrap(), None);
+    }
+
+    /// reader fails in the middle like the process stopped while copying
+    struct FailingReader(usize);
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::Error::other("crashed"));
+            }
+            let len = buf.len().min(self.0);
+            buf[..len].fill(b'a');
+            self.0 -= len;
+            Ok(len)
+        }
+    }
+
+    #[tokio::test]
+    async fn local_put_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = LocalSink::new(dir.path());
+        assert!(sink
+            .put("backup.tar", FailingReader(100_000))
+            .await
+            .is_err());
+        assert_eq!(list_sorted(dir.path()), Vec::<String>::new());
+
+        // temporal file left by crash before renaming is never seen as the archive
+        std::fs::write(dir.path().join("backup.tar.tmp"), b"partial").unwrap();
+        assert_eq!(sink.read("backup.tar").await.unwrap(), None);
+        sink.put("backup.tar", Cursor::new(b"hello".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(list_sorted(dir.path()), vec!["backup.tar"]);
+        assert_eq!(sink.read("backup.tar").await.unwrap().unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn local_files_txt() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = LocalSink::new(dir.path());
+        sink.append("files.txt", b"\na\n").await.unwrap();
+        sink.append("files.txt", b"\nb\n").await.unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join("files.txt")).unwrap(),
+            b"\na\n\nb\n"
+        );
+
+        // leftover of previous replace is overwritten
+        std::fs::write(dir.path().join(".files.txt"), b"garbage garbage").unwrap();
+        sink.replace("files.txt", b"b").await.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("files.txt")).unwrap(), b"b");
+        assert_eq!(list_sorted(dir.path()), vec!["files.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn local_available_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = LocalSink::new(dir.path());
+        assert!(sink.available_space().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn local_list_rename_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = LocalSink::new(&dir.path().join("backups"));
+        assert_eq!(sink.list().await.unwrap(), Vec::<String>::new());
+
+        sink.prepare().await.unwrap();
+        sink.write("a", b"a").await.unwrap();
+        sink.rename("a", "b").await.unwrap();
+        assert_eq!(sink.list().await.unwrap(), vec!["b"]);
+
+        sink.delete("b").await.unwrap();
+        sink.delete("b").await.unwrap();
+        assert_eq!(sink.list().await.unwrap(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn dry_run_keeps_changes_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("files.txt"), b"a").unwrap();
+        std::fs::write(dir.path().join("a.tar"), b"a").unwrap();
+        let sink = DryRunSink::new(LocalSink::new(dir.path()));
+
+        let (_, size) = sink
+            .put("b.tar", Cursor::new(b"hello".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(size, 5);
+        sink.append("files.txt", b"\nb\n").await.unwrap();
+        assert_eq!(sink.read("files.txt").await.unwrap().unwrap(), b"a\nb\n");
+        sink.replace("files.txt", b"b").await.unwrap();
+        sink.delete("a.tar").await.unwrap();
+        let mut names = sink.list().await.unwrap();
+        names.sort();
+        assert_eq!(names, vec!["b.tar", "files.txt"]);
+
+        assert_eq!(list_sorted(dir.path()), vec!["a.tar", "files.txt"]);
+        assert_eq!(std::fs::read(dir.path().join("files.txt")).unwrap(), b"a");
+    }
+}
@@ -0,0 +1,269 @@
+//! the status file summarizing backups of each setting in JSON for dashboards.
+//!
+//! the file is rewritten after each step of the main loop.
+
+use crate::config::{BackupSetting, Config, Tick};
+use crate::list::list_backups;
+use anyhow::{Context as _, Result};
+use chrono::{Duration, NaiveDateTime, NaiveTime, Timelike};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+#[derive(Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Status {
+    /// the time the status is written at
+    pub(crate) updated: Option<String>,
+    /// how long the last step of the scheduler took in milliseconds
+    pub(crate) step_duration_ms: Option<u64>,
+    pub(crate) settings: BTreeMap<String, SettingStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Eq, PartialEq)]
+pub(crate) struct SettingStatus {
+    /// the time the last backup was tried at
+    pub(crate) last_run: Option<String>,
+    pub(crate) last_success: Option<String>,
+    pub(crate) last_failure: Option<String>,
+    /// the count of backups in files.txt. None if the backups are not saved locally
+    pub(crate) backup_count: Option<usize>,
+    /// the total size of archives in bytes. None if the backups are not saved locally
+    pub(crate) total_bytes: Option<u64>,
+    /// the time the next backup will be taken at
+    pub(crate) next_scheduled: Option<String>,
+}
+
+impl Status {
+    /// reads the status file written before. returns empty status if not found or broken
+    pub(crate) fn load(path: &Path) -> Self {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("ignoring broken status file {}: {}", path.display(), e);
+                Self::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                warn!("error reading status file {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    /// records the result of the backup of the setting at `now`
+    pub(crate) fn record(&mut self, setting: &BackupSetting, now: &NaiveDateTime, succeeded: bool) {
+        let status = self.settings.entry(setting.name.clone()).or_default();
+        let now = now.format(TIME_FORMAT).to_string();
+        if succeeded {
+            status.last_success = Some(now.clone());
+        } else {
+            status.last_failure = Some(now.clone());
+        }
+        status.last_run = Some(now);
+    }
+
+    /// updates backups and next scheduled time of all settings at `now`
+    /// and removes settings no longer in the config.
+    pub(crate) fn refresh(&mut self, config: &Config, now: &NaiveDateTime) {
+        self.updated = Some(now.format(TIME_FORMAT).to_string());
+        self.settings
+            .retain(|name, _| config.backups.iter().any(|x| &x.name == name));
+        for setting in &config.backups {
+            let status = self.settings.entry(setting.name.clone()).or_default();
+            let backups = match setting.sftp {
+                Some(_) => None,
+                None => list_backups(&setting.directory, &setting.name_format)
+                    .map_err(|e| warn!("error listing backups of {}: {:#}", setting.name, e))
+                    .ok(),
+            };
+            status.backup_count = backups.as_ref().map(Vec::len);
+            status.total_bytes = backups.map(|x| x.iter().filter_map(|x| x.size).sum());
+            let last_success = status
+                .last_success
+                .as_deref()
+                .and_then(|x| NaiveDateTime::parse_from_str(x, TIME_FORMAT).ok());
+            status.next_scheduled =
+                next_scheduled(setting, config.tick, now, last_success.as_ref())
+                    .map(|x| x.format(TIME_FORMAT).to_string());
+        }
+    }
+
+    /// writes the status to `path` via temporal file not to leave partially written file
+    pub(crate) async fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        let temp_path = temp_path(path);
+        tokio::fs::write(&temp_path, json)
+            .await
+            .with_context(|| format!("writing {}", temp_path.display()))?;
+        tokio::fs::rename(&temp_path, path)
+            .await
+            .with_context(|| format!("renaming to {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// `.{file name}.tmp` in the same directory so that it can be renamed
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// the first tick at or after a boundary of the interval after `now` the setting is backed up at.
+/// ticks out of active_hours or too close to the `last` backup are skipped like the main loop.
+/// None if no backup is taken in a year
+fn next_scheduled(
+    setting: &BackupSetting,
+    tick: Tick,
+    now: &NaiveDateTime,
+    last: Option<&NaiveDateTime>,
+) -> Option<NaiveDateTime> {
+    let limit = *now + Duration::days(366);
+    let tick = i64::from(tick.seconds());
+    let mut boundary = *now;
+    loop {
+        boundary = setting.interval.get_next_date_after(&boundary);
+        if boundary > limit {
+            return None;
+        }
+        let seconds = i64::from(boundary.num_seconds_from_midnight());
+        let midnight = boundary.date().and_time(NaiveTime::MIN);
+        let at = midnight + Duration::seconds((seconds + tick - 1) / tick * tick);
+        if setting.is_active_at(&at) && last.is_none_or(|last| setting.is_spaced(last, &at)) {
+            return Some(at);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::SaveInterval;
+    use chrono::NaiveDate;
+    use std::fs;
+
+    fn at(d: u32, h: u32, m: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2021, 1, d)
+            .unwrap()
+            .and_hms_opt(h, m, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn next_scheduled_test() {
+        let tick = Tick::DEFAULT;
+        let setting = |interval| BackupSetting {
+            interval,
+            ..BackupSetting::for_test("test", Path::new("backups"))
+        };
+        assert_eq!(
+            next_scheduled(&setting(SaveInterval::Every1Hour), tick, &at(1, 0, 0), None),
+            Some(at(1, 1, 0))
+        );
+        assert_eq!(
+            next_scheduled(
+                &setting(SaveInterval::Every1Hour),
+                tick,
+                &at(1, 23, 58),
+                None
+            ),
+            Some(at(2, 0, 0))
+        );
+        assert_eq!(
+            next_scheduled(&setting(SaveInterval::Every1Day), tick, &at(1, 12, 3), None),
+            Some(at(2, 0, 0))
+        );
+        assert_eq!(
+            next_scheduled(&setting(SaveInterval::Every1Year), tick, &at(1, 0, 0), None),
+            NaiveDate::from_ymd_opt(2022, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn next_scheduled_active_hours_and_min_spacing() {
+        let tick = Tick::DEFAULT;
+        let night = BackupSetting {
+            interval: SaveInterval::Every1Hour,
+            active_hours: Some("02:00-04:00".parse().unwrap()),
+            ..BackupSetting::for_test("night", Path::new("backups"))
+        };
+        assert_eq!(
+            next_scheduled(&night, tick, &at(1, 12, 0), None),
+            Some(at(2, 2, 0))
+        );
+        assert_eq!(
+            next_scheduled(&night, tick, &at(2, 2, 0), None),
+            Some(at(2, 3, 0))
+        );
+        let spaced = BackupSetting {
+            interval: SaveInterval::Every30Minute,
+            min_spacing: Some("2 hours".parse().unwrap()),
+            ..BackupSetting::for_test("spaced", Path::new("backups"))
+        };
+        assert_eq!(
+            next_scheduled(&spaced, tick, &at(1, 12, 0), Some(&at(1, 11, 30))),
+            Some(at(1, 13, 30))
+        );
+        assert_eq!(
+            next_scheduled(&spaced, tick, &at(1, 12, 0), None),
+            Some(at(1, 12, 30))
+        );
+    }
+
+    #[tokio::test]
+    async fn status_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = dir.path().join("hourly");
+        fs::create_dir(&backups).unwrap();
+        fs::write(
+            backups.join("files.txt"),
+            "backup-2021-01-01-00-00-00\nbackup-2021-01-01-01-00-00\n",
+        )
+        .unwrap();
+        fs::write(backups.join("backup-2021-01-01-00-00-00.tar"), [0; 10]).unwrap();
+        fs::write(backups.join("backup-2021-01-01-01-00-00.tar"), [0; 20]).unwrap();
+        let setting = BackupSetting {
+            max_backups: 2,
+            interval: SaveInterval::Every1Hour,
+            ..BackupSetting::for_test("hourly", &backups)
+        };
+        let config = Config {
+            backups: vec![setting],
+            ..Config::for_test()
+        };
+        let path = dir.path().join("status.json");
+
+        let mut status = Status::load(&path);
+        status.record(&config.backups[0], &at(1, 1, 0), true);
+        status.record(&config.backups[0], &at(1, 2, 0), false);
+        status.refresh(&config, &at(1, 2, 0));
+        status.write(&path).await.unwrap();
+
+        let written: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({
+                "updated": "2021-01-01T02:00:00",
+                "step_duration_ms": null,
+                "settings": {
+                    "hourly": {
+                        "last_run": "2021-01-01T02:00:00",
+                        "last_success": "2021-01-01T01:00:00",
+                        "last_failure": "2021-01-01T02:00:00",
+                        "backup_count": 2,
+                        "total_bytes": 30,
+                        "next_scheduled": "2021-01-01T03:00:00",
+                    }
+                }
+            })
+        );
+        assert!(!dir.path().join(".status.json.tmp").exists());
+        // the status is kept over restart
+        assert_eq!(Status::load(&path), status);
+    }
+}
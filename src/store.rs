@@ -0,0 +1,354 @@
+//! content-addressed store of backups with dedup backup mode.
+//!
+//! contents of entries in the backup tar are saved once in `objects/` of the backup directory
+//! named by their SHA-256, and each backup is saved as `{name}.manifest`,
+//! the list of tar headers and hashes of contents of the entries.
+//! the tar of the backup can be reconstructed byte by byte from the manifest and objects.
+//!
+//! objects are shared by backups so they are removed only when no manifest references them.
+//! see [Store::collect_garbage].
+
+use crate::checksum::HashingWriter;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File as StdFile;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// the extension of manifest files without leading dot
+pub(crate) const MANIFEST_EXTENSION: &str = "manifest";
+
+/// the name of directory objects are saved in
+const OBJECTS_DIR: &str = "objects";
+
+const BLOCK_SIZE: usize = 512;
+
+/// the name of manifest file of the backup
+pub(crate) fn manifest_file_name(name: &str) -> String {
+    format!("{}.{}", name, MANIFEST_EXTENSION)
+}
+
+/// finds manifest of the backup
+pub(crate) fn find_manifest(directory: &Path, name: &str) -> Option<PathBuf> {
+    let path = directory.join(manifest_file_name(name));
+    path.exists().then_some(path)
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+/// an entry of the tar including extension entries like PAX headers
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+struct ManifestEntry {
+    /// hex encoded tar header
+    header: String,
+    /// the hash of the content. None if the entry has no content
+    object: Option<String>,
+}
+
+/// the objects of backups in the backup directory
+pub(crate) struct Store {
+    directory: PathBuf,
+    /// only computes hashes and doesn't save objects if true
+    dry_run: bool,
+}
+
+impl Store {
+    /// the store in the backup directory
+    pub(crate) fn new(directory: &Path) -> Self {
+        Self {
+            directory: directory.to_owned(),
+            dry_run: false,
+        }
+    }
+
+    /// the store which doesn't save objects but computes manifests
+    pub(crate) fn dry_run(directory: &Path) -> Self {
+        Self {
+            dry_run: true,
+            ..Self::new(directory)
+        }
+    }
+
+    fn objects(&self) -> PathBuf {
+        self.directory.join(OBJECTS_DIR)
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.objects().join(&hash[..2]).join(hash)
+    }
+
+    /// saves contents of entries in the tar as objects and returns the manifest of the tar.
+    pub(crate) fn save(&self, tar: impl Read) -> io::Result<Vec<u8>> {
+        let mut archive = ::tar::Archive::new(tar);
+        let mut entries = Vec::new();
+        // raw entries to keep PAX and GNU long name entries as is
+        for entry in archive.entries()?.raw(true) {
+            let mut entry = entry?;
+            let header = to_hex(entry.header().as_bytes());
+            let object = match entry.size() {
+                0 => None,
+                _ => Some(self.save_object(&mut entry)?),
+            };
+            entries.push(ManifestEntry { header, object });
+        }
+        serde_json::to_vec(&Manifest { entries }).map_err(io::Error::other)
+    }
+
+    /// saves the content as an object and returns the hash.
+    /// the object is written to a temporal file and renamed so no partial object is left.
+    fn save_object(&self, mut content: impl Read) -> io::Result<String> {
+        if self.dry_run {
+            let mut writer = HashingWriter::new(io::sink());
+            io::copy(&mut content, &mut writer)?;
+            return Ok(writer.finish().1);
+        }
+        let objects = self.objects();
+        std::fs::create_dir_all(&objects)?;
+        let temp = tempfile::NamedTempFile::new_in(&objects)?;
+        let mut writer = HashingWriter::new(BufWriter::new(temp));
+        io::copy(&mut content, &mut writer)?;
+        let (writer, hash) = writer.finish();
+        let temp = writer.into_inner()?;
+
+        let path = self.object_path(&hash);
+        if path.exists() {
+            // the same content is already saved
+            return Ok(hash);
+        }
+        temp.as_file().sync_all()?;
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        temp.persist(&path).map_err(|e| e.error)?;
+        Ok(hash)
+    }
+
+    /// writes the tar of the manifest to `write`.
+    pub(crate) fn reconstruct(&self, manifest: &[u8], mut write: impl Write) -> io::Result<()> {
+        for entry in parse_manifest(manifest)?.entries {
+            write.write_all(&from_hex(&entry.header)?)?;
+            if let Some(hash) = &entry.object {
+                let object = StdFile::open(self.object_path(hash))?;
+                let size = io::copy(&mut BufReader::new(object), &mut write)?;
+                let padding = (BLOCK_SIZE - size as usize % BLOCK_SIZE) % BLOCK_SIZE;
+                write.write_all(&[0; BLOCK_SIZE][..padding])?;
+            }
+        }
+        // the end of archive is two zero blocks
+        write.write_all(&[0; BLOCK_SIZE * 2])?;
+        write.flush()
+    }
+
+    /// counts references to each object from manifests of the backups.
+    pub(crate) fn reference_counts(&self, names: &[String]) -> io::Result<HashMap<String, usize>> {
+        let mut counts = HashMap::new();
+        for name in names {
+            let manifest = match std::fs::read(self.directory.join(manifest_file_name(name))) {
+                Ok(manifest) => manifest,
+                // backups saved before changing backup_mode have no manifest
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in parse_manifest(&manifest)?.entries {
+                if let Some(hash) = entry.object {
+                    *counts.entry(hash).or_default() += 1;
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    /// removes objects not referenced by manifests of the backups.
+    /// temporal files left by the process stopped while saving objects are also removed.
+    ///
+    /// returns the count of removed objects.
+    pub(crate) fn collect_garbage(&self, names: &[String]) -> io::Result<usize> {
+        let counts = self.reference_counts(names)?;
+        let objects = match std::fs::read_dir(self.objects()) {
+            Ok(objects) => objects,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut removed = 0;
+        for entry in objects {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                // temporal file
+                std::fs::remove_file(entry.path())?;
+                continue;
+            }
+            for object in std::fs::read_dir(entry.path())? {
+                let object = object?;
+                let hash = object.file_name().to_string_lossy().into_owned();
+                if !counts.contains_key(&hash) {
+                    std::fs::remove_file(object.path())?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// returns the problems of objects referenced by the manifest.
+    pub(crate) fn verify(&self, manifest: &[u8]) -> io::Result<Vec<String>> {
+        let mut problems = Vec::new();
+        for entry in parse_manifest(manifest)?.entries {
+            let hash = match entry.object {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let path = self.object_path(&hash);
+            if !path.exists() {
+                problems.push(format!("object {} is missing", hash));
+            } else if crate::checksum::hash_file(&path)? != hash {
+                problems.push(format!("object {} is corrupt", hash));
+            }
+        }
+        Ok(problems)
+    }
+}
+
+fn parse_manifest(manifest: &[u8]) -> io::Result<Manifest> {
+    let manifest = serde_json::from_slice::<Manifest>(manifest)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    for entry in &manifest.entries {
+        let valid_hash =
+            |hash: &String| hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit());
+        if entry.header.len() != BLOCK_SIZE * 2 || !entry.object.iter().all(valid_hash) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid entry in manifest",
+            ));
+        }
+    }
+    Ok(manifest)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> io::Result<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid hex"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn make_tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = ::tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, *data).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    fn save(store: &Store, directory: &Path, name: &str, tar: &[u8]) {
+        let manifest = store.save(Cursor::new(tar)).unwrap();
+        std::fs::write(directory.join(manifest_file_name(name)), manifest).unwrap();
+    }
+
+    fn object_count(directory: &Path) -> usize {
+        std::fs::read_dir(directory.join(OBJECTS_DIR))
+            .unwrap()
+            .map(|x| std::fs::read_dir(x.unwrap().path()).unwrap().count())
+            .sum()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path());
+        let long_name = format!("world/{}/level.dat", "a".repeat(150));
+        let tar = make_tar(&[
+            ("level.dat", b"level"),
+            ("empty", b""),
+            (&long_name, &[1; 1000]),
+        ]);
+        let manifest = store.save(Cursor::new(&tar)).unwrap();
+        let mut reconstructed = Vec::new();
+        store.reconstruct(&manifest, &mut reconstructed).unwrap();
+        assert!(reconstructed == tar);
+        assert_eq!(store.verify(&manifest).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn dedup() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path());
+        save(
+            &store,
+            dir.path(),
+            "a",
+            &make_tar(&[("x", b"same"), ("y", b"same")]),
+        );
+        save(&store, dir.path(), "b", &make_tar(&[("x", b"same")]));
+        assert_eq!(object_count(dir.path()), 1);
+        let counts = store
+            .reference_counts(&["a".to_owned(), "b".to_owned()])
+            .unwrap();
+        assert_eq!(counts.values().copied().collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn collect_garbage() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path());
+        save(
+            &store,
+            dir.path(),
+            "a",
+            &make_tar(&[("x", b"shared"), ("y", b"old")]),
+        );
+        save(
+            &store,
+            dir.path(),
+            "b",
+            &make_tar(&[("x", b"shared"), ("z", b"new")]),
+        );
+        assert_eq!(object_count(dir.path()), 3);
+        // temporal file of object left by crash
+        std::fs::write(dir.path().join(OBJECTS_DIR).join(".tmpabc"), b"").unwrap();
+
+        // a is rotated out
+        std::fs::remove_file(dir.path().join(manifest_file_name("a"))).unwrap();
+        let names = ["b".to_owned()];
+        assert_eq!(store.collect_garbage(&names).unwrap(), 1);
+        assert_eq!(object_count(dir.path()), 2);
+        assert!(!dir.path().join(OBJECTS_DIR).join(".tmpabc").exists());
+
+        let manifest = std::fs::read(dir.path().join(manifest_file_name("b"))).unwrap();
+        let mut reconstructed = Vec::new();
+        store.reconstruct(&manifest, &mut reconstructed).unwrap();
+        assert!(reconstructed == make_tar(&[("x", b"shared"), ("z", b"new")]));
+
+        // nothing is removed if all objects are referenced
+        assert_eq!(store.collect_garbage(&names).unwrap(), 0);
+    }
+
+    #[test]
+    fn verify_missing_object() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path());
+        let manifest = store
+            .save(Cursor::new(make_tar(&[("x", b"data")])))
+            .unwrap();
+        store.collect_garbage(&[]).unwrap();
+        let problems = store.verify(&manifest).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].ends_with("is missing"), "{:?}", problems);
+    }
+}
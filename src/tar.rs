@@ -1,32 +1,1009 @@
-use std::io::Write;
-use std::path::Path;
+use crate::config::{BackupSetting, PathFilter, SaveDir};
+use chrono::NaiveDateTime;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::{fs, io};
-use tar::Builder;
+use tar::{Builder, Header, HeaderMode};
 
+/// the name of file with gitignore-style patterns to exclude files from backup
+pub(crate) const BACKUP_IGNORE: &str = ".backupignore";
+
+/// the directory in archives files written by this tool are placed at.
+/// it's not extracted on restore.
+pub(crate) const RESERVED_DIR: &str = ".game-save-backuper";
+
+/// the name of the manifest in the reserved directory
+pub(crate) const MANIFEST_NAME: &str = "manifest.json";
+
+/// metadata of the backup written as the first entry of archives
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub(crate) struct ArchiveManifest {
+    /// the version of this tool the archive is written by
+    pub(crate) version: String,
+    /// the local time the backup is taken at
+    pub(crate) timestamp: String,
+    /// the settings the archive is taken for.
+    /// settings with same save directories share one archive.
+    pub(crate) settings: Vec<ManifestSetting>,
+    pub(crate) save_dirs: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub(crate) struct ManifestSetting {
+    pub(crate) name: String,
+    pub(crate) interval: String,
+}
+
+impl ArchiveManifest {
+    /// the manifest of the archive shared by `settings` taken at `now`
+    pub(crate) fn new(settings: &[&BackupSetting], now: &NaiveDateTime) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            timestamp: now.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            settings: settings
+                .iter()
+                .map(|x| ManifestSetting {
+                    name: x.name.clone(),
+                    interval: x.interval.to_string(),
+                })
+                .collect(),
+            save_dirs: settings[0]
+                .save_dirs
+                .iter()
+                .map(|x| x.path.clone())
+                .collect(),
+        }
+    }
+
+    /// reads the manifest from the first entry of the tar.
+    /// returns None for archives written before manifests are added.
+    pub(crate) fn read(tar: impl Read) -> io::Result<Option<Self>> {
+        let mut archive = tar::Archive::new(tar);
+        let entry = match archive.entries()?.next() {
+            Some(entry) => entry?,
+            None => return Ok(None),
+        };
+        if entry.path()? != Path::new(RESERVED_DIR).join(MANIFEST_NAME) {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(entry)?))
+    }
+}
+
+/// creates the builder of backup archives.
+///
+/// archives are written in GNU tar format. paths and link names longer than 100 bytes
+/// are written in GNU long name entries and sizes of files larger than 8 GiB are written
+/// in base-256, so neither is truncated.
+/// sparse files are archived with their holes as zeros since diff backup modes
+/// compare contents of entries.
+pub(crate) fn new_builder<W: Write>(write: W) -> Builder<W> {
+    let mut builder = Builder::new(write);
+    builder.sparse(false);
+    builder
+}
+
+/// appends `content` at `name` in the reserved directory
+pub(crate) fn append_reserved(
+    dst: &mut Builder<impl Write>,
+    name: &str,
+    content: &[u8],
+) -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(content.len() as u64);
+    // the config may have passwords
+    header.set_mode(0o600);
+    header.set_cksum();
+    dst.append_data(&mut header, Path::new(RESERVED_DIR).join(name), content)
+}
+
+/// reads `.backupignore` in the directory if exists.
+pub(crate) fn read_backup_ignore(dir: &Path) -> io::Result<Option<Gitignore>> {
+    let path = dir.join(BACKUP_IGNORE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(err) = builder.add(&path) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+    }
+    let ignore = builder
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Some(ignore))
+}
+
+/// the chain of `.backupignore` from the deepest directory to the root
+struct IgnoreChain {
+    ignore: Gitignore,
+    parent: Option<Rc<IgnoreChain>>,
+}
+
+impl IgnoreChain {
+    fn push(parent: Option<Rc<IgnoreChain>>, ignore: Option<Gitignore>) -> Option<Rc<Self>> {
+        match ignore {
+            Some(ignore) => Some(Rc::new(IgnoreChain { ignore, parent })),
+            None => parent,
+        }
+    }
+
+    fn is_ignored(chain: &Option<Rc<IgnoreChain>>, path: &Path, is_dir: bool) -> bool {
+        let mut current = chain.as_deref();
+        while let Some(chain) = current {
+            match chain.ignore.matched(path, is_dir) {
+                Match::None => current = chain.parent.as_deref(),
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+}
+
+/// canonical paths of directories from the directory being read to the save directory.
+/// used to detect symlink loops
+struct DirChain {
+    path: PathBuf,
+    parent: Option<Rc<DirChain>>,
+}
+
+impl DirChain {
+    fn contains(chain: &Option<Rc<DirChain>>, path: &Path) -> bool {
+        let mut current = chain.as_deref();
+        while let Some(chain) = current {
+            if chain.path == path {
+                return true;
+            }
+            current = chain.parent.as_deref();
+        }
+        false
+    }
+}
+
+/// an entry found in the save directory and not read yet
+struct Pending {
+    src: PathBuf,
+    is_dir: bool,
+    is_symlink: bool,
+    parent_included: bool,
+    ignore: Option<Rc<IgnoreChain>>,
+    /// None if symlinks are not followed
+    ancestors: Option<Rc<DirChain>>,
+}
+
+/// options to read files into archive
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ReadOptions {
+    /// the count of retries to read a file changed while reading. 0 to not check changes
+    pub(crate) retry_on_change: usize,
+    /// records permissions, owners, and modified time of files if true.
+    /// otherwise, they are normalized like [HeaderMode::Deterministic].
+    pub(crate) preserve_permissions: bool,
+    /// archives files and directories symlinks point to if true.
+    /// otherwise, symlinks are archived as symlinks.
+    pub(crate) follow_symlinks: bool,
+}
+
+impl ReadOptions {
+    fn header_mode(self) -> HeaderMode {
+        if self.preserve_permissions {
+            HeaderMode::Complete
+        } else {
+            HeaderMode::Deterministic
+        }
+    }
+}
+
+/// the counts of files and bytes appended to archives.
+/// `report` is called with the total counts of files and bytes after each file is appended.
+pub(crate) struct Progress<F> {
+    files: u64,
+    bytes: u64,
+    report: F,
+}
+
+impl<F: FnMut(u64, u64)> Progress<F> {
+    pub(crate) fn new(report: F) -> Self {
+        Self {
+            files: 0,
+            bytes: 0,
+            report,
+        }
+    }
+
+    fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+        (self.report)(self.files, self.bytes);
+    }
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            retry_on_change: 0,
+            preserve_permissions: true,
+            follow_symlinks: true,
+        }
+    }
+}
+
+/// appends all files in `src_path` to `dst` at `path` in sorted order.
+/// if `src_path` is a file, the file is appended at `path`, or at its file name if `path` is empty.
+///
+/// a path is skipped if it matches `exclude` of `filter`, or ignored by `.backupignore`.
+/// `ignore` is `.backupignore` in `src_path` and nested `.backupignore`s are read while
+/// traversal. since `exclude` is checked first, `.backupignore` cannot re-include paths
+/// excluded by `exclude`.
+///
+/// if `retry_on_change` of `options` is not zero, files are read again if changed while reading
+/// up to `retry_on_change` times, and skipped if they are still changing.
+///
+/// appended files are counted in `progress`.
 pub(crate) fn append_dir_all_sorted(
     dst: &mut Builder<impl Write>,
     path: &Path,
     src_path: &Path,
+    filter: &PathFilter,
+    ignore: Option<Gitignore>,
+    options: ReadOptions,
+    progress: &mut Progress<impl FnMut(u64, u64)>,
+) -> io::Result<()> {
+    let retry_on_change = options.retry_on_change;
+    dst.mode(options.header_mode());
+    // buffer to read files checked changes
+    let mut buffer = None;
+    walk_sorted(
+        path,
+        src_path,
+        filter,
+        ignore,
+        options,
+        |kind, dest, src| {
+            match kind {
+                EntryKind::Dir => dst.append_dir(dest, src)?,
+                EntryKind::Symlink => {
+                    let mut header = Header::new_gnu();
+                    header.set_metadata_in_mode(&fs::symlink_metadata(src)?, options.header_mode());
+                    dst.append_link(&mut header, dest, fs::read_link(src)?)?;
+                    progress.add(0);
+                }
+                EntryKind::File if retry_on_change == 0 => {
+                    let mut file = fs::File::open(src)?;
+                    let len = file.metadata()?.len();
+                    dst.append_file(dest, &mut file)?;
+                    progress.add(len);
+                }
+                EntryKind::File => {
+                    let buffer = match &mut buffer {
+                        Some(buffer) => buffer,
+                        None => buffer.insert(tempfile::tempfile()?),
+                    };
+                    let read = read_unchanged(src, retry_on_change, buffer, |file, buffer| {
+                        io::copy(file, buffer)
+                    })?;
+                    match read {
+                        Some(metadata) => {
+                            let mut header = Header::new_gnu();
+                            header.set_metadata_in_mode(&metadata, options.header_mode());
+                            dst.append_data(&mut header, dest, &mut *buffer)?;
+                            progress.add(metadata.len());
+                        }
+                        None => warn!(
+                            "skipping {} since it's changed while reading {} times",
+                            src.display(),
+                            retry_on_change + 1
+                        ),
+                    }
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+/// the kind of entries appended to archives
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum EntryKind {
+    Dir,
+    /// symlinks not followed
+    Symlink,
+    /// files, or symlinks to files followed
+    File,
+}
+
+/// calls `visit` with the kind, the path in archives, and the path of each entry
+/// [append_dir_all_sorted] appends to archives in the order they are appended.
+fn walk_sorted(
+    path: &Path,
+    src_path: &Path,
+    filter: &PathFilter,
+    ignore: Option<Gitignore>,
+    options: ReadOptions,
+    mut visit: impl FnMut(EntryKind, &Path, &Path) -> io::Result<()>,
 ) -> io::Result<()> {
-    let mut stack = vec![(src_path.to_path_buf(), true, false)];
-    while let Some((src, is_dir, is_symlink)) = stack.pop() {
-        let dest = path.join(src.strip_prefix(&src_path).unwrap());
+    let root_ignore = IgnoreChain::push(None, ignore);
+    let root_is_dir = fs::metadata(src_path)?.is_dir();
+    let root_dest = match src_path.file_name() {
+        Some(name) if !root_is_dir && path == Path::new("") => Path::new(name),
+        _ => path,
+    };
+    let mut stack = vec![Pending {
+        src: src_path.to_path_buf(),
+        is_dir: root_is_dir,
+        is_symlink: false,
+        parent_included: false,
+        ignore: root_ignore,
+        ancestors: None,
+    }];
+    while let Some(pending) = stack.pop() {
+        let Pending {
+            src,
+            is_dir,
+            is_symlink,
+            parent_included,
+            ignore,
+            ancestors,
+        } = pending;
+        let dest = if root_is_dir {
+            root_dest.join(src.strip_prefix(src_path).unwrap())
+        } else {
+            root_dest.to_path_buf()
+        };
+        // symlinks are followed only if follow_symlinks. save directory itself is always followed
+        let follow = options.follow_symlinks || src == src_path;
         // In case of a symlink pointing to a directory, is_dir is false, but src.is_dir() will return true
-        if is_dir || (is_symlink && src.is_dir()) {
-            let mut entries = fs::read_dir(&src)?
-                .into_iter()
-                .collect::<Result<Vec<_>, _>>()?;
+        let is_dir = is_dir || (is_symlink && follow && src.is_dir());
+        // excluded directories are pruned here so we never read them
+        if src != src_path
+            && (filter.is_excluded(&dest) || IgnoreChain::is_ignored(&ignore, &src, is_dir))
+        {
+            continue;
+        }
+        let included = parent_included || filter.is_included(&dest);
+        if is_dir {
+            // loops can be made only with symlinks
+            let ancestors = if options.follow_symlinks {
+                let canonical = fs::canonicalize(&src)?;
+                if DirChain::contains(&ancestors, &canonical) {
+                    warn!(
+                        "skipping {} since it's a symlink loop to {}",
+                        src.display(),
+                        canonical.display()
+                    );
+                    continue;
+                }
+                Some(Rc::new(DirChain {
+                    path: canonical,
+                    parent: ancestors,
+                }))
+            } else {
+                None
+            };
+            let ignore = if src == src_path {
+                ignore
+            } else {
+                IgnoreChain::push(ignore, read_backup_ignore(&src)?)
+            };
+            let mut entries = fs::read_dir(&src)?.collect::<Result<Vec<_>, _>>()?;
             entries.sort_by_key(|x| x.file_name());
             for entry in entries {
                 let file_type = entry.file_type()?;
-                stack.push((entry.path(), file_type.is_dir(), file_type.is_symlink()));
+                stack.push(Pending {
+                    src: entry.path(),
+                    is_dir: file_type.is_dir(),
+                    is_symlink: file_type.is_symlink(),
+                    parent_included: included,
+                    ignore: ignore.clone(),
+                    ancestors: ancestors.clone(),
+                });
             }
-            if dest != Path::new("") {
-                dst.append_dir(&dest, &src)?;
+            if dest != Path::new("") && included {
+                visit(EntryKind::Dir, &dest, &src)?;
             }
-        } else {
-            dst.append_file(&dest, &mut fs::File::open(src)?)?;
+        } else if included && is_symlink && !follow {
+            visit(EntryKind::Symlink, &dest, &src)?;
+        } else if included {
+            visit(EntryKind::File, &dest, &src)?;
         }
     }
     Ok(())
 }
+
+/// the fingerprint of files in the save directories in hex, which changes if files are
+/// added, removed, renamed, or modified.
+///
+/// computed from paths, sizes and modification times of files read like
+/// [append_dir_all_sorted] without reading contents of files
+/// so that it can be compared before the game stops saving.
+pub(crate) fn tree_fingerprint(
+    save_dirs: &[SaveDir],
+    filter: &PathFilter,
+    options: ReadOptions,
+) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut update = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+    for save_dir in save_dirs {
+        let ignore = read_backup_ignore(&save_dir.path)?;
+        walk_sorted(
+            &save_dir.prefix,
+            &save_dir.path,
+            filter,
+            ignore,
+            options,
+            |kind, dest, src| {
+                update(&[kind as u8]);
+                update(dest.as_os_str().as_encoded_bytes());
+                match kind {
+                    EntryKind::Dir => {}
+                    EntryKind::Symlink => {
+                        update(fs::read_link(src)?.as_os_str().as_encoded_bytes())
+                    }
+                    EntryKind::File => {
+                        let metadata = fs::metadata(src)?;
+                        let modified = metadata
+                            .modified()?
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap_or_default();
+                        update(&metadata.len().to_le_bytes());
+                        update(&modified.as_nanos().to_le_bytes());
+                    }
+                }
+                Ok(())
+            },
+        )?;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// reads the file into `buffer` with `read` again if the file is changed while reading.
+///
+/// returns the metadata of the file and `buffer` is rewound to be read.
+/// returns None if the file is changed every time it's read.
+fn read_unchanged(
+    src: &Path,
+    retries: usize,
+    buffer: &mut fs::File,
+    mut read: impl FnMut(&mut fs::File, &mut fs::File) -> io::Result<u64>,
+) -> io::Result<Option<fs::Metadata>> {
+    for _ in 0..=retries {
+        buffer.set_len(0)?;
+        buffer.seek(SeekFrom::Start(0))?;
+        let mut file = fs::File::open(src)?;
+        let before = file.metadata()?;
+        let len = read(&mut file, buffer)?;
+        let after = fs::metadata(src)?;
+        if len == before.len()
+            && after.len() == before.len()
+            && after.modified()? == before.modified()?
+        {
+            buffer.seek(SeekFrom::Start(0))?;
+            return Ok(Some(before));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn archive(src: &Path, filter: &PathFilter) -> Vec<String> {
+        let mut tar = Builder::new(Vec::new());
+        let ignore = read_backup_ignore(src).unwrap();
+        append_dir_all_sorted(
+            &mut tar,
+            "".as_ref(),
+            src,
+            filter,
+            ignore,
+            ReadOptions::default(),
+            &mut Progress::new(|_, _| {}),
+        )
+        .unwrap();
+        let data = tar.into_inner().unwrap();
+        tar::Archive::new(data.as_slice())
+            .entries()
+            .unwrap()
+            .map(|x| x.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn save_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("logs")).unwrap();
+        fs::create_dir_all(dir.path().join("world/region")).unwrap();
+        fs::write(dir.path().join("logs/latest.log"), b"").unwrap();
+        fs::write(dir.path().join("world/level.dat"), b"").unwrap();
+        fs::write(dir.path().join("world/region/r.0.0.mca"), b"").unwrap();
+        fs::write(dir.path().join("world/session.lock"), b"").unwrap();
+        dir
+    }
+
+    #[test]
+    fn exclude() {
+        let dir = save_dir();
+        let filter =
+            PathFilter::new(vec![], vec!["logs".to_owned(), "**/*.lock".to_owned()]).unwrap();
+        assert_eq!(
+            archive(dir.path(), &filter),
+            vec![
+                "world",
+                "world/region",
+                "world/region/r.0.0.mca",
+                "world/level.dat",
+            ]
+        );
+    }
+
+    #[test]
+    fn include() {
+        let dir = save_dir();
+        let filter = PathFilter::new(
+            vec!["world/region".to_owned(), "**/*.dat".to_owned()],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(
+            archive(dir.path(), &filter),
+            vec!["world/region", "world/region/r.0.0.mca", "world/level.dat"]
+        );
+    }
+
+    #[test]
+    fn backup_ignore() {
+        let dir = save_dir();
+        fs::write(dir.path().join(".backupignore"), b"*.log\n*.lock\n").unwrap();
+        fs::write(dir.path().join("logs/important.log"), b"").unwrap();
+        fs::write(dir.path().join("logs/.backupignore"), b"!important.log\n").unwrap();
+        fs::write(dir.path().join("world/region/.backupignore"), b"*.mca\n").unwrap();
+        assert_eq!(
+            archive(dir.path(), &PathFilter::default()),
+            vec![
+                "world",
+                "world/region",
+                "world/region/.backupignore",
+                "world/level.dat",
+                "logs",
+                "logs/important.log",
+                "logs/.backupignore",
+                ".backupignore",
+            ]
+        );
+    }
+
+    #[test]
+    fn exclude_precedes_backup_ignore() {
+        let dir = save_dir();
+        fs::write(dir.path().join(".backupignore"), b"!*.lock\n").unwrap();
+        let filter = PathFilter::new(vec![], vec!["**/*.lock".to_owned()]).unwrap();
+        assert!(!archive(dir.path(), &filter).contains(&"world/session.lock".to_owned()));
+    }
+
+    /// read function which appends to the file before reading the first `changes` times
+    fn change_while_reading(
+        src: &Path,
+        changes: usize,
+    ) -> impl FnMut(&mut fs::File, &mut fs::File) -> io::Result<u64> + '_ {
+        let mut count = 0;
+        move |file, buffer| {
+            if count < changes {
+                fs::OpenOptions::new()
+                    .append(true)
+                    .open(src)?
+                    .write_all(b" more")?;
+            }
+            count += 1;
+            io::copy(file, buffer)
+        }
+    }
+
+    #[test]
+    fn retry_changed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("level.dat");
+        fs::write(&src, b"level").unwrap();
+        let mut buffer = tempfile::tempfile().unwrap();
+        let metadata = read_unchanged(&src, 1, &mut buffer, change_while_reading(&src, 1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.len(), 10);
+        let mut read = Vec::new();
+        io::Read::read_to_end(&mut buffer, &mut read).unwrap();
+        assert_eq!(read, b"level more");
+    }
+
+    #[test]
+    fn skip_always_changing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("level.dat");
+        fs::write(&src, b"level").unwrap();
+        let mut buffer = tempfile::tempfile().unwrap();
+        let read = read_unchanged(&src, 2, &mut buffer, change_while_reading(&src, 3)).unwrap();
+        assert!(read.is_none());
+        assert_eq!(fs::read(&src).unwrap(), b"level more more more");
+    }
+
+    #[test]
+    fn progress() {
+        let dir = save_dir();
+        fs::write(dir.path().join("world/level.dat"), b"level").unwrap();
+        fs::write(dir.path().join("world/region/r.0.0.mca"), [0; 1000]).unwrap();
+        for options in [
+            ReadOptions::default(),
+            ReadOptions {
+                retry_on_change: 1,
+                ..ReadOptions::default()
+            },
+        ] {
+            let mut reported = vec![];
+            let mut tar = Builder::new(Vec::new());
+            append_dir_all_sorted(
+                &mut tar,
+                "".as_ref(),
+                dir.path(),
+                &PathFilter::default(),
+                None,
+                options,
+                &mut Progress::new(|files, bytes| reported.push((files, bytes))),
+            )
+            .unwrap();
+            // directories are not counted
+            assert_eq!(reported.len(), 4);
+            assert!(reported
+                .windows(2)
+                .all(|x| x[0].0 < x[1].0 && x[0].1 <= x[1].1));
+            assert_eq!(reported.last(), Some(&(4, 1005)));
+        }
+    }
+
+    #[test]
+    fn tree_fingerprint_test() {
+        let dir = save_dir();
+        let save_dirs = [SaveDir {
+            path: dir.path().to_owned(),
+            prefix: PathBuf::new(),
+        }];
+        let filter = PathFilter::new(vec![], vec!["logs".to_owned()]).unwrap();
+        let fingerprint_of =
+            || tree_fingerprint(&save_dirs, &filter, ReadOptions::default()).unwrap();
+        let before = fingerprint_of();
+        assert_eq!(fingerprint_of(), before);
+        // excluded files are not a part of the fingerprint
+        fs::write(dir.path().join("logs/latest.log"), b"log").unwrap();
+        assert_eq!(fingerprint_of(), before);
+        fs::write(dir.path().join("world/new.dat"), b"").unwrap();
+        let added = fingerprint_of();
+        assert_ne!(added, before);
+        fs::rename(
+            dir.path().join("world/new.dat"),
+            dir.path().join("world/renamed.dat"),
+        )
+        .unwrap();
+        assert_ne!(fingerprint_of(), added);
+    }
+
+    #[test]
+    fn tree_fingerprint_same_size_rewrite() {
+        let dir = save_dir();
+        let save_dirs = [SaveDir {
+            path: dir.path().to_owned(),
+            prefix: PathBuf::new(),
+        }];
+        let fingerprint_of = || {
+            tree_fingerprint(&save_dirs, &PathFilter::default(), ReadOptions::default()).unwrap()
+        };
+        let level = dir.path().join("world/level.dat");
+        let write = |content: &[u8], secs| {
+            fs::write(&level, content).unwrap();
+            let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            fs::File::options()
+                .write(true)
+                .open(&level)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+        write(b"first", 1000);
+        let before = fingerprint_of();
+        write(b"other", 2000);
+        assert_ne!(fingerprint_of(), before);
+    }
+
+    #[test]
+    fn retry_on_change_archive() {
+        let dir = save_dir();
+        fs::write(dir.path().join("world/level.dat"), b"level").unwrap();
+        let mut tar = Builder::new(Vec::new());
+        append_dir_all_sorted(
+            &mut tar,
+            "".as_ref(),
+            dir.path(),
+            &PathFilter::default(),
+            None,
+            ReadOptions {
+                retry_on_change: 3,
+                ..ReadOptions::default()
+            },
+            &mut Progress::new(|_, _| {}),
+        )
+        .unwrap();
+        let data = tar.into_inner().unwrap();
+        let mut archive = tar::Archive::new(data.as_slice());
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|x| {
+                let mut entry = x.unwrap();
+                let mut content = Vec::new();
+                io::Read::read_to_end(&mut entry, &mut content).unwrap();
+                (
+                    entry.path().unwrap().to_string_lossy().into_owned(),
+                    content,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                ("world".to_owned(), vec![]),
+                ("world/session.lock".to_owned(), vec![]),
+                ("world/region".to_owned(), vec![]),
+                ("world/region/r.0.0.mca".to_owned(), vec![]),
+                ("world/level.dat".to_owned(), b"level".to_vec()),
+                ("logs".to_owned(), vec![]),
+                ("logs/latest.log".to_owned(), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("world.db");
+        fs::write(&src, b"world").unwrap();
+        let entries = |path: &str| {
+            let mut tar = Builder::new(Vec::new());
+            let options = ReadOptions::default();
+            let filter = PathFilter::default();
+            append_dir_all_sorted(
+                &mut tar,
+                path.as_ref(),
+                &src,
+                &filter,
+                None,
+                options,
+                &mut Progress::new(|_, _| {}),
+            )
+            .unwrap();
+            let data = tar.into_inner().unwrap();
+            let mut archive = tar::Archive::new(data.as_slice());
+            archive
+                .entries()
+                .unwrap()
+                .map(|x| {
+                    let mut entry = x.unwrap();
+                    let mut content = Vec::new();
+                    io::Read::read_to_end(&mut entry, &mut content).unwrap();
+                    (
+                        entry.path().unwrap().to_string_lossy().into_owned(),
+                        content,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            entries(""),
+            vec![("world.db".to_owned(), b"world".to_vec())]
+        );
+        assert_eq!(
+            entries("saves/world.db"),
+            vec![("saves/world.db".to_owned(), b"world".to_vec())]
+        );
+    }
+
+    /// archives save directory with symlinks to directory and file.
+    /// returns path, entry type and link name or content of each entry
+    #[cfg(unix)]
+    fn archive_symlinks(follow_symlinks: bool) -> Vec<(String, tar::EntryType, String)> {
+        use std::os::unix::fs::symlink;
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("real")).unwrap();
+        fs::write(dir.path().join("real/a.txt"), b"a").unwrap();
+        fs::write(dir.path().join("file.txt"), b"f").unwrap();
+        symlink("real", dir.path().join("link_dir")).unwrap();
+        symlink("file.txt", dir.path().join("link_file")).unwrap();
+
+        let mut tar = Builder::new(Vec::new());
+        let options = ReadOptions {
+            follow_symlinks,
+            ..ReadOptions::default()
+        };
+        let filter = PathFilter::default();
+        append_dir_all_sorted(
+            &mut tar,
+            "".as_ref(),
+            dir.path(),
+            &filter,
+            None,
+            options,
+            &mut Progress::new(|_, _| {}),
+        )
+        .unwrap();
+        let data = tar.into_inner().unwrap();
+        let mut archive = tar::Archive::new(data.as_slice());
+        let mut entries = archive
+            .entries()
+            .unwrap()
+            .map(|x| {
+                let mut entry = x.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let kind = entry.header().entry_type();
+                let value = match entry.link_name().unwrap() {
+                    Some(link) => link.to_string_lossy().into_owned(),
+                    None => {
+                        let mut content = String::new();
+                        io::Read::read_to_string(&mut entry, &mut content).unwrap();
+                        content
+                    }
+                };
+                (path, kind, value)
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_symlinks() {
+        use tar::EntryType::*;
+        assert_eq!(
+            archive_symlinks(true),
+            vec![
+                ("file.txt".to_owned(), Regular, "f".to_owned()),
+                ("link_dir".to_owned(), Directory, "".to_owned()),
+                ("link_dir/a.txt".to_owned(), Regular, "a".to_owned()),
+                ("link_file".to_owned(), Regular, "f".to_owned()),
+                ("real".to_owned(), Directory, "".to_owned()),
+                ("real/a.txt".to_owned(), Regular, "a".to_owned()),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn not_follow_symlinks() {
+        use tar::EntryType::*;
+        assert_eq!(
+            archive_symlinks(false),
+            vec![
+                ("file.txt".to_owned(), Regular, "f".to_owned()),
+                ("link_dir".to_owned(), Symlink, "real".to_owned()),
+                ("link_file".to_owned(), Symlink, "file.txt".to_owned()),
+                ("real".to_owned(), Directory, "".to_owned()),
+                ("real/a.txt".to_owned(), Regular, "a".to_owned()),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loop() {
+        use std::os::unix::fs::symlink;
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a/level.dat"), b"").unwrap();
+        // a/parent -> . and x -> a/y -> x
+        symlink("..", dir.path().join("a/parent")).unwrap();
+        fs::create_dir(dir.path().join("b")).unwrap();
+        symlink("b", dir.path().join("x")).unwrap();
+        symlink("../x", dir.path().join("b/y")).unwrap();
+
+        let mut entries = archive(dir.path(), &PathFilter::default());
+        entries.sort();
+        assert_eq!(entries, vec!["a", "a/level.dat", "b", "x"]);
+    }
+
+    #[test]
+    fn long_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = (0..6)
+            .map(|i| format!("{}{}", i, "d".repeat(50)))
+            .collect::<PathBuf>();
+        let long = Path::new("world").join(nested).join("level.dat");
+        assert!(long.as_os_str().len() > 256);
+        fs::create_dir_all(dir.path().join(long.parent().unwrap())).unwrap();
+        fs::write(dir.path().join(&long), b"level").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&long, dir.path().join("link")).unwrap();
+
+        let mut tar = new_builder(Vec::new());
+        let options = ReadOptions {
+            follow_symlinks: false,
+            ..ReadOptions::default()
+        };
+        let filter = PathFilter::default();
+        append_dir_all_sorted(
+            &mut tar,
+            "".as_ref(),
+            dir.path(),
+            &filter,
+            None,
+            options,
+            &mut Progress::new(|_, _| {}),
+        )
+        .unwrap();
+        let data = tar.into_inner().unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        tar::Archive::new(data.as_slice())
+            .unpack(dest.path())
+            .unwrap();
+        assert_eq!(fs::read(dest.path().join(&long)).unwrap(), b"level");
+        #[cfg(unix)]
+        assert_eq!(fs::read_link(dest.path().join("link")).unwrap(), long);
+    }
+
+    #[test]
+    fn large_file() {
+        /// keeps only the first header and counts the rest not to hold the content
+        struct HeaderWriter {
+            header: Vec<u8>,
+            written: u64,
+        }
+
+        impl Write for HeaderWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let rest = 512usize.saturating_sub(self.header.len()).min(buf.len());
+                self.header.extend_from_slice(&buf[..rest]);
+                self.written += buf.len() as u64;
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("huge.dat");
+        // a sparse placeholder larger than the 8 GiB octal size field can hold
+        let size = (8 << 30) + 1;
+        fs::File::create(&src).unwrap().set_len(size).unwrap();
+
+        let mut tar = new_builder(HeaderWriter {
+            header: Vec::new(),
+            written: 0,
+        });
+        let options = ReadOptions::default();
+        let filter = PathFilter::default();
+        append_dir_all_sorted(
+            &mut tar,
+            "".as_ref(),
+            &src,
+            &filter,
+            None,
+            options,
+            &mut Progress::new(|_, _| {}),
+        )
+        .unwrap();
+        let written = tar.into_inner().unwrap();
+
+        let header = Header::from_byte_slice(&written.header);
+        assert_eq!(header.path().unwrap(), Path::new("huge.dat"));
+        assert_eq!(header.entry_type(), tar::EntryType::Regular);
+        assert_eq!(header.size().unwrap(), size);
+        // the header, the content padded to blocks, and two end blocks
+        assert_eq!(written.written, 512 + size.next_multiple_of(512) + 1024);
+    }
+}
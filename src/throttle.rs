@@ -0,0 +1,113 @@
+//! limits the rate of reading and writing backups not to saturate disk IO of the game server.
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// token bucket refilled with `rate` bytes per second up to one second of bytes.
+/// the bucket starts empty so that a short backup doesn't burst.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: 0.0,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// takes tokens for `bytes` bytes and blocks the thread until the bucket is paid back
+    fn consume(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate));
+        }
+    }
+}
+
+/// the reader or writer limited to the rate in bytes per second. not limited if the rate is None.
+///
+/// this blocks the thread so it must be used in blocking tasks.
+pub(crate) struct Throttled<T> {
+    inner: T,
+    bucket: Option<TokenBucket>,
+}
+
+impl<T> Throttled<T> {
+    pub(crate) fn new(inner: T, rate: Option<u64>) -> Self {
+        Self {
+            inner,
+            bucket: rate.map(TokenBucket::new),
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        if let Some(bucket) = &mut self.bucket {
+            bucket.consume(bytes);
+        }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.consume(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn throttled_copy() {
+        let data = vec![0u8; 300 * 1024];
+        let rate = 1024 * 1024;
+        let start = Instant::now();
+        let mut reader = Throttled::new(data.as_slice(), Some(rate));
+        let mut written = Throttled::new(Vec::new(), None);
+        io::copy(&mut reader, &mut written).unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(written.inner, data);
+        // 300 KiB at 1 MiB/s
+        assert!(
+            elapsed >= Duration::from_secs_f64(data.len() as f64 / rate as f64),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn throttled_write() {
+        let rate = 100 * 1024;
+        let start = Instant::now();
+        let mut writer = Throttled::new(Vec::new(), Some(rate));
+        for _ in 0..10 {
+            writer.write_all(&[1; 4096]).unwrap();
+        }
+        assert_eq!(writer.inner.len(), 40960);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+}
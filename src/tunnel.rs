@@ -0,0 +1,50 @@
+//! forwards rcon connections through ssh servers like `ssh -L`.
+
+use crate::config::{tunnel_target, SshTunnel};
+use crate::sink::connect_ssh;
+use anyhow::{Context as _, Result};
+use log::{debug, warn};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// how long the local port waits for the connection
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// opens the tunnel to `address` through the ssh server and returns the local address forwarded to it.
+///
+/// the local address accepts only one connection and the ssh session is closed with it.
+pub(crate) async fn open(tunnel: &SshTunnel, address: &str) -> Result<SocketAddr> {
+    let (host, port) = tunnel_target(address)?;
+    let session = connect_ssh(
+        &tunnel.host,
+        tunnel.port,
+        &tunnel.user,
+        &tunnel.auth,
+        tunnel.host_key.as_ref(),
+    )
+    .await?;
+    let channel = session
+        .channel_open_direct_tcpip(host, u32::from(port), Ipv4Addr::LOCALHOST.to_string(), 0)
+        .await
+        .with_context(|| format!("forwarding to {}", address))?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .context("listening on local port")?;
+    let local = listener.local_addr()?;
+    let address = address.to_owned();
+    tokio::spawn(async move {
+        // the ssh session must be kept while the channel is used
+        let _session = session;
+        let mut stream = match tokio::time::timeout(ACCEPT_TIMEOUT, listener.accept()).await {
+            Ok(Ok((stream, _))) => stream,
+            Ok(Err(e)) => return warn!("error accepting tunnel to {}: {}", address, e),
+            Err(_) => return warn!("tunnel to {} is not used in {:?}", address, ACCEPT_TIMEOUT),
+        };
+        let mut channel = channel.into_stream();
+        if let Err(e) = tokio::io::copy_bidirectional(&mut stream, &mut channel).await {
+            debug!("tunnel to {} closed: {}", address, e);
+        }
+    });
+    Ok(local)
+}
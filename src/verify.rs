@@ -0,0 +1,279 @@
+use crate::checksum::{hash_file, read_checksum};
+use crate::config::{Compression, Config};
+use crate::encryption::is_encrypted;
+use crate::files_txt::BACKUP_NAME_FORMAT;
+use crate::list::list_backups;
+use crate::store::{Store, MANIFEST_EXTENSION};
+use anyhow::{bail, Result};
+use log::{error, info, trace, warn};
+use std::fs::File as StdFile;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Problem {
+    /// the archive is listed in files.txt but not found
+    Missing,
+    /// the checksum doesn't match
+    Mismatch { expected: String, actual: String },
+    /// the archive cannot be read
+    Corrupt(String),
+    /// the archive or its checksum cannot be read because of I/O error
+    Unreadable(String),
+}
+
+/// verifies backups in the directory. returns the backups with problems.
+/// errors reading a backup are reported as its problem so other backups are still verified.
+pub(crate) fn verify_backups(directory: &Path) -> Result<Vec<(String, Problem)>> {
+    let mut problems = Vec::new();
+    // timestamps of backups are not used here
+    for backup in list_backups(directory, BACKUP_NAME_FORMAT)? {
+        let file = match &backup.file {
+            Some(file) => file,
+            None => {
+                problems.push((backup.name, Problem::Missing));
+                continue;
+            }
+        };
+        match verify_backup(directory, &backup.name, file) {
+            Ok(None) => {}
+            Ok(Some(problem)) => problems.push((backup.name, problem)),
+            Err(e) => problems.push((backup.name, Problem::Unreadable(e.to_string()))),
+        }
+    }
+    Ok(problems)
+}
+
+/// verifies the archive `file` of the backup. returns the problem if any.
+fn verify_backup(directory: &Path, name: &str, file: &str) -> io::Result<Option<Problem>> {
+    match read_checksum(directory, name)? {
+        // older backups may not have checksum
+        None => warn!("no checksum found for {}", name),
+        Some((expected, checksum_file)) => {
+            if checksum_file != file {
+                warn!(
+                    "checksum of {} is for {} but archive is {}",
+                    name, checksum_file, file
+                );
+            }
+            let actual = hash_file(&directory.join(file))?;
+            if actual != expected {
+                return Ok(Some(Problem::Mismatch { expected, actual }));
+            }
+        }
+    }
+    if file.ends_with(&format!(".{}", MANIFEST_EXTENSION)) {
+        let manifest = std::fs::read(directory.join(file))?;
+        return Ok(match Store::new(directory).verify(&manifest) {
+            Ok(objects) if objects.is_empty() => None,
+            Ok(objects) => Some(Problem::Corrupt(objects.join(", "))),
+            Err(e) => Some(Problem::Corrupt(e.to_string())),
+        });
+    }
+    if is_encrypted(Path::new(file)) {
+        // encrypted archives cannot be read without the key
+        trace!("skipping archive check of encrypted {}", file);
+        return Ok(None);
+    }
+    Ok(check_archive(&directory.join(file))
+        .err()
+        .map(|e| Problem::Corrupt(e.to_string())))
+}
+
+/// reads all entries of the archive to confirm the archive is valid tar.
+fn check_archive(path: &Path) -> io::Result<()> {
+    let file_name = path.file_name().unwrap().to_string_lossy();
+    // the longest extension is used since `.tar.gz` also ends with `.tar`
+    let compression = Compression::ALL
+        .iter()
+        .copied()
+        .filter(|c| file_name.ends_with(&format!(".{}", c.extension())))
+        .max_by_key(|c| c.extension().len())
+        .unwrap_or(Compression::None);
+    let mut archive = ::tar::Archive::new(compression.reader(BufReader::new(StdFile::open(path)?)));
+    for entry in archive.entries()? {
+        io::copy(&mut entry?, &mut io::sink())?;
+    }
+    // read to end to check trailer of compression
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    Ok(())
+}
+
+/// verifies backups of all backup settings.
+/// settings which can't be verified are reported at the end with failed backups.
+pub(crate) fn run(config: &Config) -> Result<()> {
+    let mut failed = 0;
+    let mut unverified = 0;
+    for setting in &config.backups {
+        if setting.sftp.is_some() {
+            error!(
+                "{}: verifying backups on sftp server is not supported",
+                setting.name
+            );
+            unverified += 1;
+            continue;
+        }
+        info!("verifying backups of {}", setting.name);
+        let problems = match verify_backups(&setting.directory) {
+            Ok(problems) => problems,
+            Err(e) => {
+                error!("{}: error listing backups: {:#}", setting.name, e);
+                unverified += 1;
+                continue;
+            }
+        };
+        for (name, problem) in problems {
+            failed += 1;
+            match problem {
+                Problem::Missing => error!("{} of {}: missing", name, setting.name),
+                Problem::Mismatch { expected, actual } => error!(
+                    "{} of {}: checksum mismatch: expected {} but was {}",
+                    name, setting.name, expected, actual
+                ),
+                Problem::Corrupt(e) => error!("{} of {}: corrupt: {}", name, setting.name, e),
+                Problem::Unreadable(e) => error!("{} of {}: unreadable: {}", name, setting.name, e),
+            }
+        }
+    }
+    match (failed, unverified) {
+        (0, 0) => Ok(()),
+        (failed, 0) => bail!("{} backup(s) failed verification", failed),
+        (0, unverified) => bail!("{} backup setting(s) could not be verified", unverified),
+        (failed, unverified) => bail!(
+            "{} backup(s) failed verification and {} backup setting(s) could not be verified",
+            failed,
+            unverified
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::checksum::write_checksum;
+    use std::fs;
+
+    #[test]
+    fn verify() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("files.txt"),
+            b"ok\nbroken\nmissing\nno-checksum\n",
+        )
+        .unwrap();
+
+        make_tar(&dir.path().join("ok.tar"), Compression::None);
+        let hash = hash_file(&dir.path().join("ok.tar")).unwrap();
+        write_checksum(dir.path(), "ok", "ok.tar", &hash).unwrap();
+
+        make_tar(&dir.path().join("broken.diff.tar"), Compression::None);
+        let hash = hash_file(&dir.path().join("broken.diff.tar")).unwrap();
+        write_checksum(dir.path(), "broken", "broken.diff.tar", &hash).unwrap();
+        // corrupt the content of level.dat
+        corrupt(&dir.path().join("broken.diff.tar"), 512);
+
+        make_tar(&dir.path().join("no-checksum.tar"), Compression::None);
+
+        let problems = verify_backups(dir.path()).unwrap();
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].0, "broken");
+        assert!(matches!(problems[0].1, Problem::Mismatch { .. }));
+        assert_eq!(problems[1], ("missing".to_owned(), Problem::Missing));
+    }
+
+    #[test]
+    fn unreadable_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("files.txt"), b"unreadable\nok\n").unwrap();
+        // reading a directory as the archive fails
+        fs::create_dir(dir.path().join("unreadable.tar")).unwrap();
+        write_checksum(dir.path(), "unreadable", "unreadable.tar", "00").unwrap();
+        make_tar(&dir.path().join("ok.tar"), Compression::None);
+        let hash = hash_file(&dir.path().join("ok.tar")).unwrap();
+        write_checksum(dir.path(), "ok", "ok.tar", &hash).unwrap();
+
+        let problems = verify_backups(dir.path()).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, "unreadable");
+        assert!(matches!(problems[0].1, Problem::Unreadable(_)));
+    }
+
+    #[test]
+    fn sftp_setting_is_not_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = crate::config::parse_config(
+            format!(
+                "preset: filesystem\n\
+                save_dir: {:?}\n\
+                backup_dir: {:?}\n\
+                backups:\n\
+                - name: remote\n  interval: hourly\n  max_backups: 24\n  sftp:\n    \
+                host: example.com\n    user: backup\n    password: secret\n    \
+                host_key: ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAd9gvJMWEwzsAmGoILEdD3daY2PpQJ58ltZiUPD2fGh\n    \
+                remote_dir: /backups\n",
+                dir.path(),
+                dir.path().join("backups"),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let err = run(&config).unwrap_err();
+        assert!(err.to_string().contains("could not be verified"), "{}", err);
+    }
+
+    fn make_tar(path: &Path, compression: Compression) {
+        let mut data = Vec::new();
+        {
+            let mut tar = ::tar::Builder::new(&mut data);
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(11);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, "level.dat", &b"hello world"[..])
+                .unwrap();
+            tar.finish().unwrap();
+        }
+        if compression == Compression::Gzip {
+            use std::io::Write;
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&data).unwrap();
+            data = encoder.finish().unwrap();
+        }
+        fs::write(path, data).unwrap();
+    }
+
+    fn corrupt(path: &Path, index: usize) {
+        let mut data = fs::read(path).unwrap();
+        data[index] ^= 0xFF;
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn corrupt_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("files.txt"),
+            b"ok
+ok-gz
+header
+gz
+",
+        )
+        .unwrap();
+        make_tar(&dir.path().join("ok.tar"), Compression::None);
+        make_tar(&dir.path().join("ok-gz.tar.gz"), Compression::Gzip);
+        make_tar(&dir.path().join("header.diff.tar"), Compression::None);
+        corrupt(&dir.path().join("header.diff.tar"), 0);
+        make_tar(&dir.path().join("gz.tar.gz"), Compression::Gzip);
+        let len = fs::metadata(dir.path().join("gz.tar.gz")).unwrap().len() as usize;
+        // CRC32 in the trailer
+        corrupt(&dir.path().join("gz.tar.gz"), len - 8);
+
+        let problems = verify_backups(dir.path()).unwrap();
+        let names = problems.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["header", "gz"]);
+        assert!(problems
+            .iter()
+            .all(|(_, p)| matches!(p, Problem::Corrupt(_))));
+    }
+}